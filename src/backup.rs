@@ -0,0 +1,172 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Copies of VG metadata kept outside of the PVs, as LVM2 keeps them in
+//! /etc/lvm.
+//!
+//! The backup of a VG is its metadata as of the last commit, in
+//! `<backup_dir>/<vg>`. Archives are the metadata as it was before each
+//! commit, in `<archive_dir>/<vg>_<index>-<random>.vg`. Both are in the
+//! format of LVM2's, so `vgcfgrestore` can restore from them.
+
+use std::fs::{create_dir_all, metadata, read_dir, remove_file, rename, write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use time::{at, Timespec};
+
+use crate::parser::{textmap_to_pretty_buf, Entry, LvmTextMap};
+use crate::Result;
+
+/// Whether, and where, to keep backups and archives of VG metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupConfig {
+    /// Whether to write a backup of a VG's metadata after each commit.
+    pub backup: bool,
+    /// Where to write backups.
+    pub backup_dir: PathBuf,
+    /// Whether to archive a VG's metadata before each commit.
+    pub archive: bool,
+    /// Where to write archives.
+    pub archive_dir: PathBuf,
+    /// The fewest archives of a VG to keep, however old they are.
+    pub retain_min: usize,
+    /// Archives older than this many days are removed, apart from the
+    /// `retain_min` newest.
+    pub retain_days: u64,
+}
+
+/// Neither backups nor archives are written by default. When enabled,
+/// they go where LVM2's do, and are kept as long.
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            backup: false,
+            backup_dir: PathBuf::from("/etc/lvm/backup"),
+            archive: false,
+            archive_dir: PathBuf::from("/etc/lvm/archive"),
+            retain_min: 10,
+            retain_days: 30,
+        }
+    }
+}
+
+// Metadata laid out as LVM2 writes it to files, with a comment saying
+// when and the given description in place of the one in the metadata.
+pub(crate) fn metadata_file_text(map: &LvmTextMap, description: &str) -> Vec<u8> {
+    let mut map = map.clone();
+    // LVM2 refuses to read files with any other contents.
+    map.insert(
+        "contents".to_string(),
+        Entry::String("Text Format Volume Group".to_string()),
+    );
+    map.insert(
+        "description".to_string(),
+        Entry::String(description.to_string()),
+    );
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut text = format!(
+        "# Generated by Melvin version {}: {}\n\n",
+        env!("CARGO_PKG_VERSION"),
+        at(Timespec::new(now as i64, 0)).ctime()
+    )
+    .into_bytes();
+    text.extend(textmap_to_pretty_buf(&map));
+
+    text
+}
+
+// Write metadata to a file, as `metadata_file_text` gives it. The file
+// is written under a temporary name first, so it is never partial.
+fn write_metadata_file(path: &Path, map: &LvmTextMap, description: &str) -> Result<()> {
+    let text = metadata_file_text(map, description);
+    let tmp_path = path.with_extension("tmp");
+    write(&tmp_path, &text)?;
+    rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Write the backup of a VG, from its metadata after a commit whose
+/// operations are described by `ops`.
+pub fn backup(config: &BackupConfig, vg_name: &str, map: &LvmTextMap, ops: &str) -> Result<()> {
+    create_dir_all(&config.backup_dir)?;
+    write_metadata_file(
+        &config.backup_dir.join(vg_name),
+        map,
+        &format!("Created *after* executing '{}'", ops),
+    )
+}
+
+/// Remove the backup of a VG, as it no longer exists. Its archives are
+/// kept.
+pub fn remove_backup(config: &BackupConfig, vg_name: &str) -> Result<()> {
+    let path = config.backup_dir.join(vg_name);
+    if path.exists() {
+        remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+// The archives of a VG, by index.
+fn archives(config: &BackupConfig, vg_name: &str) -> Result<Vec<(u32, PathBuf)>> {
+    let prefix = format!("{}_", vg_name);
+    let mut archives = Vec::new();
+
+    for entry in read_dir(&config.archive_dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(".vg"))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|index| index.parse().ok());
+        if let Some(index) = index {
+            archives.push((index, path));
+        }
+    }
+    archives.sort();
+
+    Ok(archives)
+}
+
+/// Archive a VG's metadata from before a commit whose operations are
+/// described by `ops`, then remove archives past their retention.
+pub fn archive(config: &BackupConfig, vg_name: &str, map: &LvmTextMap, ops: &str) -> Result<()> {
+    create_dir_all(&config.archive_dir)?;
+
+    let mut archives = archives(config, vg_name)?;
+    let index = archives.last().map_or(0, |(index, _)| index + 1);
+    // LVM2 adds a random number, so that names differ between hosts
+    // sharing the directory.
+    let random = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let path = config
+        .archive_dir
+        .join(format!("{}_{:05}-{}.vg", vg_name, index, random));
+    write_metadata_file(&path, map, &format!("Created *before* executing '{}'", ops))?;
+    archives.push((index, path));
+
+    let max_age = Duration::from_secs(config.retain_days * 24 * 60 * 60);
+    let excess = archives.len().saturating_sub(config.retain_min);
+    for (_, path) in archives.into_iter().take(excess) {
+        let expired = metadata(&path)?
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age > max_age);
+        if expired {
+            remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Block device geometry queries.
+
+use std::fs::{read_to_string, File};
+use std::io;
+use std::io::ErrorKind::Other;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use nix::libc::{c_int, c_uint};
+use nix::sys::stat::{self, SFlag};
+use nix::{ioctl_read_bad, ioctl_readwrite, ioctl_write_ptr_bad};
+use nix::{request_code_none, request_code_read};
+
+use crate::device::{Device, DeviceExt};
+use crate::{Error, Result};
+
+// BLKGETSIZE64 is defined by the kernel as _IOR(0x12, 114, size_t), so the
+// size encoded in the request differs between 32- and 64-bit targets, even
+// though the kernel always writes a u64.
+ioctl_read_bad!(
+    blkgetsize64,
+    request_code_read!(0x12, 114, size_of::<usize>()),
+    u64
+);
+ioctl_read_bad!(blkroget, request_code_none!(0x12, 94), c_int);
+ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), c_int);
+ioctl_read_bad!(blkpbszget, request_code_none!(0x12, 123), c_uint);
+ioctl_write_ptr_bad!(blkdiscard, request_code_none!(0x12, 119), [u64; 2]);
+
+// struct fstrim_range from linux/fs.h
+#[repr(C)]
+struct FstrimRange {
+    start: u64,
+    len: u64,
+    minlen: u64,
+}
+
+ioctl_readwrite!(fitrim, b'X', 121, FstrimRange);
+
+/// The geometry of a block device.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Geometry {
+    /// Size in bytes.
+    pub size: u64,
+    /// The smallest unit the device can address, in bytes.
+    pub logical_block_size: u32,
+    /// The smallest unit the device can write without read-modify-write,
+    /// in bytes.
+    pub physical_block_size: u32,
+    /// The granularity of discards in bytes, or 0 if discard is unsupported.
+    pub discard_granularity: u64,
+}
+
+/// Get the size of a block device in bytes.
+pub fn size(file: &File) -> Result<u64> {
+    let mut val: u64 = 0;
+
+    unsafe { blkgetsize64(file.as_raw_fd(), &mut val) }?;
+
+    Ok(val)
+}
+
+/// Whether a block device is read-only, such as a write-protected disk
+/// or a snapshot a SAN presents read-only.
+pub fn is_read_only(file: &File) -> Result<bool> {
+    let mut val: c_int = 0;
+
+    unsafe { blkroget(file.as_raw_fd(), &mut val) }?;
+
+    Ok(val != 0)
+}
+
+/// Get the logical block (sector) size of a block device in bytes.
+pub fn logical_block_size(file: &File) -> Result<u32> {
+    let mut val: c_int = 0;
+
+    unsafe { blksszget(file.as_raw_fd(), &mut val) }?;
+
+    Ok(val as u32)
+}
+
+/// Get the physical block size of a block device in bytes.
+pub fn physical_block_size(file: &File) -> Result<u32> {
+    let mut val: c_uint = 0;
+
+    unsafe { blkpbszget(file.as_raw_fd(), &mut val) }?;
+
+    Ok(val as u32)
+}
+
+// Find the sysfs queue directory for a block device. Partitions do not
+// have their own, so use the parent disk's.
+fn sysfs_queue_dir(file: &File) -> Result<PathBuf> {
+    let st = stat::fstat(file.as_raw_fd())?;
+
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT != SFlag::S_IFBLK {
+        return Err(Error::Io(io::Error::new(Other, "Not a block device")));
+    }
+
+    let mut path = Device {
+        major: stat::major(st.st_rdev) as u32,
+        minor: stat::minor(st.st_rdev) as u32,
+    }
+    .to_sys_path();
+    if path.join("partition").exists() {
+        path.push("..");
+    }
+    path.push("queue");
+
+    Ok(path)
+}
+
+/// Get the discard granularity of a block device in bytes. Returns 0
+/// if the device does not support discard.
+pub fn discard_granularity(file: &File) -> Result<u64> {
+    let path = sysfs_queue_dir(file)?.join("discard_granularity");
+
+    read_to_string(&path)?.trim().parse::<u64>().map_err(|_| {
+        Error::Io(io::Error::new(
+            Other,
+            format!("Could not parse {}", path.display()),
+        ))
+    })
+}
+
+/// Discard a range of a block device, in bytes. The data in the range
+/// is lost.
+pub fn discard(file: &File, offset: u64, len: u64) -> Result<()> {
+    let range = [offset, len];
+
+    unsafe { blkdiscard(file.as_raw_fd(), &range) }?;
+
+    Ok(())
+}
+
+/// Discard the unused blocks of a mounted filesystem, given an open
+/// directory within it. Returns how many bytes were discarded.
+pub fn trim_fs(dir: &File) -> Result<u64> {
+    trim_fs_range(dir, 0, u64::MAX)
+}
+
+/// Discard the unused blocks in a range of a mounted filesystem, in
+/// bytes, given an open directory within it. Returns how many bytes were
+/// discarded.
+pub fn trim_fs_range(dir: &File, start: u64, len: u64) -> Result<u64> {
+    let mut range = FstrimRange {
+        start,
+        len,
+        minlen: 0,
+    };
+
+    unsafe { fitrim(dir.as_raw_fd(), &mut range) }?;
+
+    Ok(range.len)
+}
+
+/// Find where a block device is mounted, if anywhere.
+pub fn mount_point(dev: Device) -> Result<Option<PathBuf>> {
+    let dev = dev.to_string();
+
+    // Fields: mount ID, parent ID, major:minor, root, mount point, ...
+    for line in read_to_string("/proc/self/mountinfo")?.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() > 4 && fields[2] == dev {
+            return Ok(Some(PathBuf::from(unescape_mount_path(fields[4]))));
+        }
+    }
+
+    Ok(None)
+}
+
+// Mount points in mountinfo have spaces and some other characters
+// escaped as octal, e.g. "\040".
+fn unescape_mount_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match (bytes[i], escaped) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Get all geometry information for a block device.
+pub fn geometry(file: &File) -> Result<Geometry> {
+    Ok(Geometry {
+        size: size(file)?,
+        logical_block_size: logical_block_size(file)?,
+        physical_block_size: physical_block_size(file)?,
+        discard_granularity: discard_granularity(file)?,
+    })
+}
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Working out which LVs to activate at boot.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+
+use crate::lv::split_dm_name;
+use crate::parser::{Entry, TextMapOps};
+use crate::{PvHeader, Result};
+
+/// An entry in an fstab file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FstabEntry {
+    /// The device to mount, e.g. "/dev/mapper/vg0-root" or "UUID=...".
+    pub spec: String,
+    /// Where it is mounted.
+    pub mount_point: PathBuf,
+}
+
+/// An LV that must be activated.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Activation {
+    /// The name of the VG.
+    pub vg_name: String,
+    /// The name of the LV.
+    pub lv_name: String,
+}
+
+// fstab escapes whitespace in fields as octal.
+fn unescape_fstab(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\134", "\\")
+}
+
+/// Parse the contents of an fstab file.
+pub fn parse_fstab(text: &str) -> Vec<FstabEntry> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(spec), Some(mount_point)) => Some(FstabEntry {
+                    spec: unescape_fstab(spec),
+                    mount_point: PathBuf::from(unescape_fstab(mount_point)),
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Read and parse an fstab file, usually /etc/fstab.
+pub fn read_fstab(path: &Path) -> Result<Vec<FstabEntry>> {
+    Ok(parse_fstab(&read_to_string(path)?))
+}
+
+// Work out the VG and LV that an fstab device spec refers to, if it
+// refers to one by name.
+fn spec_to_lv(spec: &str) -> Option<(String, String)> {
+    let path = Path::new(spec);
+
+    // A layer of an LV, such as a thin pool's metadata, is not the LV.
+    if let Ok(rest) = path.strip_prefix("/dev/mapper") {
+        return match split_dm_name(&rest.to_string_lossy()) {
+            Some((vg, lv, None)) => Some((vg, lv)),
+            _ => None,
+        };
+    }
+
+    if let Ok(rest) = path.strip_prefix("/dev") {
+        let parts: Vec<_> = rest.iter().collect();
+        if parts.len() == 2 {
+            return Some((
+                parts[0].to_string_lossy().into_owned(),
+                parts[1].to_string_lossy().into_owned(),
+            ));
+        }
+    }
+
+    None
+}
+
+// Collect the names of the LVs in each VG, from the metadata on the
+// given PVs.
+fn lv_names(pv_paths: &[PathBuf]) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let mut names = BTreeMap::new();
+
+    for path in pv_paths {
+        // PVs not in a VG have no metadata
+        let map = match PvHeader::find_in_dev(path)?.read_metadata() {
+            Ok(map) => map,
+            Err(_) => continue,
+        };
+
+        for (vg_name, value) in map {
+            if let Entry::TextMap(vg_map) = value {
+                let lvs = names.entry(vg_name).or_insert_with(BTreeSet::new);
+                if let Some(lv_map) = vg_map.textmap_from_textmap("logical_volumes") {
+                    lvs.extend(lv_map.keys().cloned());
+                }
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+fn depth(path: &Path) -> usize {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count()
+}
+
+/// Work out which LVs must be activated to mount the given mount
+/// points, as well as any mount points they are nested under, using the
+/// PVs found by a scan. LVs are returned in the order they should be
+/// activated: parent mount points first.
+///
+/// fstab entries that do not refer to an LV in a VG on the given PVs by
+/// name (e.g. UUID= or plain partitions) are not included.
+pub fn boot_activations(
+    pv_paths: &[PathBuf],
+    fstab: &[FstabEntry],
+    mount_points: &[&Path],
+) -> Result<Vec<Activation>> {
+    let names = lv_names(pv_paths)?;
+
+    let mut needed: Vec<&FstabEntry> = fstab
+        .iter()
+        .filter(|entry| {
+            mount_points
+                .iter()
+                .any(|mp| mp.starts_with(&entry.mount_point))
+        })
+        .collect();
+    needed.sort_by_key(|entry| depth(&entry.mount_point));
+
+    let mut activations: Vec<Activation> = Vec::new();
+    for entry in needed {
+        if let Some((vg_name, lv_name)) = spec_to_lv(&entry.spec) {
+            let known = matches!(names.get(&vg_name), Some(lvs) if lvs.contains(&lv_name));
+            let activation = Activation { vg_name, lv_name };
+            if known && !activations.contains(&activation) {
+                activations.push(activation);
+            }
+        }
+    }
+
+    Ok(activations)
+}
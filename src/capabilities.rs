@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! What the running kernel's device-mapper can do.
+//!
+//! `Capabilities` lists the DM targets the kernel has loaded, so that
+//! activating an LV whose target is missing fails with an error naming
+//! the kernel module, rather than with an ioctl error. Some limits on DM
+//! devices are fixed by the kernel rather than by Melvin.
+//! `kernel_limits` reports them, along with the versions of the DM
+//! targets they apply to, so that LVs are created within what the
+//! kernel will accept.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind::Other;
+use std::process::Command;
+
+use nix::unistd::{sysconf, SysconfVar};
+
+use crate::dm;
+pub use crate::dm::TargetVersion;
+use crate::pvlabel::SECTOR_SIZE;
+use crate::{Error, Result};
+
+// Targets built into dm-mod, which are always available.
+const BUILTIN_TARGETS: &[&str] = &["linear", "striped", "error", "zero"];
+
+/// The largest thin pool metadata device any version of dm-thin-pool
+/// uses, in sectors: 255 * 2^14 blocks of 4KiB, just under 16GiB.
+/// Space beyond it is never used.
+const THIN_MAX_METADATA_SECTORS: u64 = 255 * (1 << 14) * 8;
+
+/// The most devices in a dm-raid array, MD's limit.
+const RAID_MAX_DEVICES: u64 = 253;
+/// The most regions dm-raid's write-intent bitmap tracks.
+const RAID_MAX_REGIONS: u64 = 1 << 21;
+
+/// Limits the kernel places on DM devices.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KernelLimits {
+    /// The version of the thin-pool target, if it is loaded. Targets
+    /// that are not loaded yet may be loaded when first used, so the
+    /// limits below hold either way.
+    pub thin_pool_version: Option<TargetVersion>,
+    /// The version of the raid target, if it is loaded.
+    pub raid_version: Option<TargetVersion>,
+    /// The largest thin pool metadata LV worth creating, in sectors.
+    pub max_thin_metadata_sectors: u64,
+    /// The most devices, i.e. stripes and parity or mirror images, in a
+    /// RAID LV.
+    pub max_raid_devices: u64,
+    /// The smallest RAID region size, in sectors: one page.
+    pub min_raid_region_sectors: u64,
+}
+
+impl KernelLimits {
+    /// The smallest RAID region size the kernel accepts for a RAID LV
+    /// of `sectors`, as the write-intent bitmap has a limited number of
+    /// regions. Region sizes must also be powers of 2.
+    pub fn raid_min_region_sectors_for(&self, sectors: u64) -> u64 {
+        sectors
+            .div_ceil(RAID_MAX_REGIONS)
+            .max(self.min_raid_region_sectors)
+            .next_power_of_two()
+    }
+}
+
+/// The limits assumed when the kernel cannot be asked, such as when
+/// preparing an image without access to device-mapper.
+impl Default for KernelLimits {
+    fn default() -> KernelLimits {
+        KernelLimits {
+            thin_pool_version: None,
+            raid_version: None,
+            max_thin_metadata_sectors: THIN_MAX_METADATA_SECTORS,
+            max_raid_devices: RAID_MAX_DEVICES,
+            min_raid_region_sectors: 8,
+        }
+    }
+}
+
+/// Find the limits of the running kernel's device-mapper.
+pub fn kernel_limits() -> Result<KernelLimits> {
+    Ok(Capabilities::probe()?.kernel_limits())
+}
+
+/// The kernel module that provides a DM target type.
+pub fn target_module(target: &str) -> String {
+    match target {
+        "thin" | "thin-pool" => "dm-thin-pool".to_string(),
+        "snapshot" | "snapshot-origin" | "snapshot-merge" => "dm-snapshot".to_string(),
+        t if t.starts_with("raid") => "dm-raid".to_string(),
+        t => format!("dm-{}", t),
+    }
+}
+
+// Whether a target type is built into dm-mod, so is always available.
+pub(crate) fn is_builtin(target: &str) -> bool {
+    BUILTIN_TARGETS.contains(&target)
+}
+
+// The name the kernel lists a target type under.
+fn listed_name(target: &str) -> &str {
+    if target.starts_with("raid") {
+        "raid"
+    } else {
+        target
+    }
+}
+
+/// The DM target types the running kernel has loaded.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capabilities {
+    targets: BTreeMap<String, TargetVersion>,
+}
+
+impl Capabilities {
+    /// Ask the kernel which DM targets it has loaded.
+    pub fn probe() -> Result<Capabilities> {
+        Ok(Capabilities {
+            targets: dm::list_versions()?.into_iter().collect(),
+        })
+    }
+
+    /// The loaded DM targets, with their versions.
+    pub fn targets(&self) -> &BTreeMap<String, TargetVersion> {
+        &self.targets
+    }
+
+    /// The version of a DM target type, if it is loaded. All RAID
+    /// levels are provided by the "raid" target.
+    pub fn target_version(&self, target: &str) -> Option<TargetVersion> {
+        self.targets.get(listed_name(target)).copied()
+    }
+
+    /// Whether a DM target type is loaded.
+    pub fn has_target(&self, target: &str) -> bool {
+        is_builtin(target) || self.target_version(target).is_some()
+    }
+
+    /// Make sure a DM target type is available, loading its kernel
+    /// module with `modprobe` if it is not loaded yet, as LVM2 does.
+    /// Fails with an error naming the module if it cannot be loaded.
+    pub fn require(&mut self, target: &str) -> Result<()> {
+        if self.has_target(target) {
+            return Ok(());
+        }
+
+        let module = target_module(target);
+        // Whether modprobe worked is checked by listing the targets
+        // again.
+        let _ = Command::new("modprobe").arg(&module).output();
+        *self = Capabilities::probe()?;
+
+        if self.has_target(target) {
+            Ok(())
+        } else {
+            Err(Error::Io(io::Error::new(
+                Other,
+                format!("{} not available", module),
+            )))
+        }
+    }
+
+    /// The limits that follow from these targets and the system.
+    pub fn kernel_limits(&self) -> KernelLimits {
+        let page_size = match sysconf(SysconfVar::PAGE_SIZE) {
+            Ok(Some(size)) if size > 0 => size as u64,
+            _ => 4096,
+        };
+
+        KernelLimits {
+            thin_pool_version: self.target_version("thin-pool"),
+            raid_version: self.target_version("raid"),
+            max_thin_metadata_sectors: THIN_MAX_METADATA_SECTORS,
+            max_raid_devices: RAID_MAX_DEVICES,
+            min_raid_region_sectors: (page_size / SECTOR_SIZE as u64).max(1),
+        }
+    }
+}
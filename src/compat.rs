@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Checking Melvin's work against the LVM2 tools.
+//!
+//! Where LVM2 is installed, `verify_against_lvm2` has it read the
+//! metadata Melvin wrote, and compares the tables of Melvin's active
+//! DM devices with what `dmsetup table` reports. Running it on a test
+//! system before trusting Melvin with real data shows whether the two
+//! agree for the setups in use there.
+
+use std::fs;
+use std::io;
+use std::io::ErrorKind::{NotFound, Other};
+use std::process::{Command, Output};
+
+use crate::dm::TableLine;
+use crate::parser::{buf_to_textmap, TextMapOps};
+use crate::{Error, Result, VG};
+
+/// The findings of `verify_against_lvm2`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CompatReport {
+    /// The LVs whose DM tables were compared.
+    pub checked_lvs: Vec<String>,
+    /// Each disagreement found.
+    pub problems: Vec<String>,
+}
+
+impl CompatReport {
+    /// Whether LVM2 agreed with Melvin about everything checked.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Run an LVM2 tool. Fails only if it could not be run at all.
+fn run(cmd: &str, args: &[&str]) -> Result<Output> {
+    Command::new(cmd).args(args).output().map_err(|e| {
+        if e.kind() == NotFound {
+            Error::Io(io::Error::new(NotFound, format!("{} not found", cmd)))
+        } else {
+            Error::Io(e)
+        }
+    })
+}
+
+fn failure(cmd: &str, output: &Output) -> String {
+    format!(
+        "{} failed ({}): {}",
+        cmd,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+// Compare the VG as LVM2 read it with Melvin's view of it.
+fn check_metadata(vg: &VG, report: &mut CompatReport) -> Result<()> {
+    let output = run("vgck", &[vg.name()])?;
+    if !output.status.success() {
+        report.problems.push(failure("vgck", &output));
+    }
+
+    let path = std::env::temp_dir().join(format!("melvin-compat-{}.vg", std::process::id()));
+    let path_str = path.to_string_lossy().into_owned();
+    let output = run("vgcfgbackup", &["-f", &path_str, vg.name()])?;
+    if !output.status.success() {
+        report.problems.push(failure("vgcfgbackup", &output));
+        return Ok(());
+    }
+    let text = fs::read(&path);
+    let _ = fs::remove_file(&path);
+
+    let map = buf_to_textmap(&text?)?;
+    let lvm_vg = match map.textmap_from_textmap(vg.name()) {
+        Some(lvm_vg) => lvm_vg,
+        None => {
+            report
+                .problems
+                .push(format!("vgcfgbackup output has no VG {}", vg.name()));
+            return Ok(());
+        }
+    };
+
+    let seqno = lvm_vg.i64_from_textmap("seqno").unwrap_or(-1);
+    if seqno != vg.seqno() as i64 {
+        report.problems.push(format!(
+            "LVM2 read seqno {}, Melvin has {}",
+            seqno,
+            vg.seqno()
+        ));
+    }
+
+    let extent_size = lvm_vg.i64_from_textmap("extent_size").unwrap_or(-1);
+    if extent_size != vg.extent_size() as i64 {
+        report.problems.push(format!(
+            "LVM2 read extent size {}, Melvin has {}",
+            extent_size,
+            vg.extent_size()
+        ));
+    }
+
+    let lvm_lvs: Vec<String> = lvm_vg
+        .textmap_from_textmap("logical_volumes")
+        .map(|lvs| lvs.keys().cloned().collect())
+        .unwrap_or_default();
+    if lvm_lvs != vg.lv_list() {
+        report.problems.push(format!(
+            "LVM2 read LVs {:?}, Melvin has {:?}",
+            lvm_lvs,
+            vg.lv_list()
+        ));
+    }
+
+    Ok(())
+}
+
+// Tables as `dmsetup table` prints them, with any runs of spaces in the
+// parameters collapsed.
+fn format_table(table: &[TableLine]) -> Vec<String> {
+    table
+        .iter()
+        .map(|(start, len, target, params)| {
+            let params: Vec<_> = params.split_whitespace().collect();
+            format!("{} {} {} {}", start, len, target, params.join(" "))
+        })
+        .collect()
+}
+
+// Compare the table of each active LV with the one dmsetup reports.
+fn check_tables(vg: &VG, report: &mut CompatReport) -> Result<()> {
+    for name in vg.lv_list() {
+        let lv = match vg.lv_get(&name) {
+            Some(lv) if lv.device.is_some() => lv,
+            _ => continue,
+        };
+
+        // The LV's own table is in the layer, if it is stacked on one.
+        let dm_name = match ["tpool", "real", "cow"]
+            .iter()
+            .find(|layer| lv.layers.contains_key(**layer))
+        {
+            Some(layer) => format!("{}-{}", vg.lv_dm_name(lv), layer),
+            None => vg.lv_dm_name(lv),
+        };
+
+        let output = run("dmsetup", &["table", &dm_name])?;
+        if !output.status.success() {
+            report.problems.push(failure("dmsetup table", &output));
+            continue;
+        }
+
+        let actual: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|line| !line.is_empty())
+            .collect();
+        let expected = format_table(&lv.dm_table(vg)?);
+        if actual != expected {
+            report.problems.push(format!(
+                "{}: dmsetup has table {:?}, Melvin expects {:?}",
+                dm_name, actual, expected
+            ));
+        }
+        report.checked_lvs.push(name);
+    }
+
+    Ok(())
+}
+
+/// Have the LVM2 tools check `vg`: `vgck` must accept its metadata,
+/// `vgcfgbackup` must read the same seqno, extent size and LVs as
+/// Melvin has, and `dmsetup table` must show the tables Melvin loads for
+/// its active LVs. Fails if the tools are not installed. Disagreements
+/// are listed in the report rather than being errors.
+pub fn verify_against_lvm2(vg: &VG) -> Result<CompatReport> {
+    if vg.is_dirty() {
+        return Err(Error::Io(io::Error::new(
+            Other,
+            "VG has changes that have not been written yet",
+        )));
+    }
+
+    let mut report = CompatReport::default();
+    check_metadata(vg, &mut report)?;
+    check_tables(vg, &mut report)?;
+
+    Ok(report)
+}
@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Runtime configuration.
+
+use std::path::PathBuf;
+
+use nix::sys::utsname::uname;
+
+use crate::backup::BackupConfig;
+use crate::filter::DeviceFilter;
+use crate::health::RepairPolicy;
+#[cfg(feature = "monitor")]
+use crate::monitor::DEFAULT_MONITOR_DIR;
+use crate::profile::DEFAULT_PROFILE_DIR;
+use crate::retry::RetryPolicy;
+use crate::throttle::Throttles;
+
+/// Settings that affect how Melvin operates on a VG, but that are not
+/// part of the VG's metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// The name recorded as `creation_host` in metadata. If None, the
+    /// system's hostname is used.
+    pub creation_host: Option<String>,
+    /// How to retry device-mapper operations that fail transiently.
+    pub retry: RetryPolicy,
+    /// If set, operations that are estimated to make the metadata too
+    /// large for the metadata areas fail up front. Otherwise they go
+    /// ahead, and a warning is recorded in the VG.
+    pub refuse_metadata_overflow: bool,
+    /// If set, known signatures, such as PV labels and filesystem
+    /// superblocks, are wiped from the start of LVs as they are removed,
+    /// so that they are not found again if the space is reused. LVs
+    /// whose data is not directly on PVs, such as thin LVs, are skipped.
+    pub wipe_signatures_on_remove: bool,
+    /// Limits on the I/O of wiping and discarding, so that they do not
+    /// starve other users of the PVs.
+    pub throttles: Throttles,
+    /// Whether to keep backups and archives of the metadata, as LVM2
+    /// does in /etc/lvm.
+    pub backup: BackupConfig,
+    /// Which devices may be added to VGs, and are scanned for PVs.
+    pub filter: DeviceFilter,
+    /// Where to find the profiles VGs and LVs name. If None,
+    /// "/etc/lvm/profile" is used.
+    pub profile_dir: Option<PathBuf>,
+    /// If set, `Melvin::with_config` checks the system's health and
+    /// makes the repairs the policy allows. See `Melvin::startup_log`.
+    pub startup_repairs: Option<RepairPolicy>,
+    #[cfg(feature = "monitor")]
+    /// Where to keep which LVs are monitored. If None,
+    /// "/run/melvin/monitor" is used.
+    pub monitor_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// The name to record as `creation_host` in metadata.
+    pub fn creation_host(&self) -> String {
+        match self.creation_host {
+            Some(ref host) => host.clone(),
+            None => uname().nodename().to_string(),
+        }
+    }
+
+    /// Where to find the profiles VGs and LVs name.
+    pub fn profile_dir(&self) -> PathBuf {
+        match self.profile_dir {
+            Some(ref dir) => dir.clone(),
+            None => PathBuf::from(DEFAULT_PROFILE_DIR),
+        }
+    }
+
+    #[cfg(feature = "monitor")]
+    /// Where to keep which LVs are monitored.
+    pub fn monitor_dir(&self) -> PathBuf {
+        match self.monitor_dir {
+            Some(ref dir) => dir.clone(),
+            None => PathBuf::from(DEFAULT_MONITOR_DIR),
+        }
+    }
+}
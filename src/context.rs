@@ -0,0 +1,430 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Process-wide state and operations.
+
+use std::collections::BTreeSet;
+use std::fs::read_to_string;
+use std::io;
+use std::io::ErrorKind::{NotFound, Other};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::libc::{semctl, IPC_RMID};
+
+#[cfg(feature = "spec")]
+use crate::device::{Device, DeviceExt};
+#[cfg(feature = "spec")]
+use crate::dm;
+use crate::health;
+#[cfg(feature = "daemon-clients")]
+use crate::lvmetad;
+use crate::parser::{
+    buf_to_textmap, buf_to_textmap_borrowed, merge_textmap, textmap_ref_to_owned, Entry,
+    LvmTextMap, TextMapRefOps,
+};
+#[cfg(feature = "spec")]
+use crate::spec::{LvType, Spec};
+use crate::{
+    pvheader_scan_report_with_filter, recover_commit, ActivationName, Config, Error, Finding,
+    Flock, HealthThresholds, LockScope, PvHeader, Result, VG,
+};
+#[cfg(feature = "spec")]
+use crate::{Size, VgDiff};
+
+const LVM_CONF_PATH: &str = "/etc/lvm/lvm.conf";
+
+pub(crate) const DEV_DIR: &str = "/dev";
+
+// How long to wait for udev to process outstanding events, in seconds.
+const SETTLE_TIMEOUT: u32 = 30;
+
+// The upper 16 bits of the SysV semaphore key of a DM udev cookie.
+const DM_COOKIE_MAGIC: u64 = 0x0D4D;
+
+/// The entry point for operations that are not specific to one VG.
+#[derive(Debug, Default)]
+pub struct Melvin {
+    config: Config,
+    lvm_conf_override: LvmTextMap,
+    startup_log: Vec<String>,
+}
+
+impl Melvin {
+    /// Create a new context with default settings.
+    pub fn new() -> Melvin {
+        Melvin::default()
+    }
+
+    /// Create a new context with the given settings. If
+    /// `config.startup_repairs` is set, the system is checked as by
+    /// `health`, and the repairs found to be needed are made, as far as
+    /// the policy allows.
+    pub fn with_config(config: Config) -> Melvin {
+        let mut melvin = Melvin {
+            config,
+            lvm_conf_override: LvmTextMap::new(),
+            startup_log: Vec::new(),
+        };
+
+        if let Some(ref policy) = melvin.config.startup_repairs {
+            melvin.startup_log = match melvin.vg_config() {
+                Ok(config) => health::sweep(&config, policy),
+                Err(e) => vec![format!("could not read lvm.conf: {:?}", e)],
+            };
+        }
+
+        melvin
+    }
+
+    /// What the check made by `with_config` found, and each repair made
+    /// or not made, one line each. Empty unless `Config::startup_repairs`
+    /// was set.
+    pub fn startup_log(&self) -> &[String] {
+        &self.startup_log
+    }
+
+    /// Returns the settings used by this context.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Override settings from lvm.conf for the lifetime of this context,
+    /// like LVM2's `--config` option. `text` is in the same format as
+    /// lvm.conf, e.g. `devices { filter = [ "a|/dev/sdb|", "r|.*|" ] }`.
+    /// Overrides are merged over any given earlier.
+    pub fn override_lvm_conf(&mut self, text: &str) -> Result<()> {
+        let map = buf_to_textmap(text.as_bytes())?;
+        merge_textmap(&mut self.lvm_conf_override, &map);
+
+        Ok(())
+    }
+
+    /// Returns the contents of lvm.conf with any overrides merged over
+    /// it. A missing lvm.conf is treated as empty.
+    pub fn lvm_conf(&self) -> Result<LvmTextMap> {
+        let mut map = match read_to_string(LVM_CONF_PATH) {
+            Ok(text) => buf_to_textmap(text.as_bytes())?,
+            Err(ref e) if e.kind() == NotFound => LvmTextMap::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        merge_textmap(&mut map, &self.lvm_conf_override);
+
+        Ok(map)
+    }
+
+    // The settings for VGs, with lvm.conf's device filters added to the
+    // configured one.
+    fn vg_config(&self) -> Result<Config> {
+        let mut config = self.config.clone();
+        config.filter.add_lvm_conf(&self.lvm_conf()?)?;
+
+        Ok(config)
+    }
+
+    /// Check the PVs, VGs and active LVs on the system, as described in
+    /// the `health` module, and return what was found, most serious
+    /// first. An empty list means everything is healthy. Thin pools are
+    /// reported at the default `HealthThresholds`.
+    pub fn health(&self) -> Result<Vec<Finding>> {
+        self.health_with_thresholds(&HealthThresholds::default())
+    }
+
+    /// Like `health`, but thin pool usage is reported at `thresholds`.
+    pub fn health_with_thresholds(&self, thresholds: &HealthThresholds) -> Result<Vec<Finding>> {
+        health::check(&self.vg_config()?, thresholds)
+    }
+
+    /// The devices under /dev with PV labels that the device filter
+    /// accepts.
+    pub fn pv_paths(&self) -> Result<Vec<PathBuf>> {
+        let config = self.vg_config()?;
+        Ok(pvheader_scan_report_with_filter(&[Path::new(DEV_DIR)], &config.filter)?.pvs)
+    }
+
+    /// The names of the VGs that the PVs in `pv_paths` have metadata
+    /// for, in sorted order.
+    pub fn vg_names(&self) -> Result<Vec<String>> {
+        let mut names = BTreeSet::new();
+        for path in self.pv_paths()? {
+            if let Ok(map) = PvHeader::find_in_dev(&path).and_then(|pvh| pvh.read_metadata()) {
+                names.extend(
+                    map.iter()
+                        .filter(|(_, value)| matches!(value, Entry::TextMap(_)))
+                        .map(|(key, _)| key.clone()),
+                );
+            }
+        }
+
+        Ok(names.into_iter().collect())
+    }
+
+    /// Read the VG `name` from its PVs.
+    pub fn vg_open(&self, name: &str) -> Result<VG> {
+        let config = self.vg_config()?;
+        recover_vg(name, &config)?;
+        let _lock = Flock::lock_shared(LockScope::VG(name.to_string()))?;
+        let map = vg_metadata(name, &config)?;
+
+        VG::from_textmap_with_config(name, &map, config)
+    }
+
+    /// Activate a single LV, named like "vg0/data" or "/dev/vg0/data",
+    /// and any LVs it depends on. Other LVs in the VG are left alone.
+    pub fn activate(&self, path: &str) -> Result<()> {
+        self.activate_as(path, &ActivationName::Default)
+    }
+
+    /// Like `activate`, but the LV's DM device is named as `naming`
+    /// says, rather than "vg-lv". LVs it depends on get their usual
+    /// names. The name is not recorded anywhere, so to Melvin and LVM2
+    /// the LV appears inactive afterwards.
+    pub fn activate_as(&self, path: &str, naming: &ActivationName) -> Result<()> {
+        let path = path.trim_start_matches("/dev/");
+        let (vg_name, lv_name) = match path.find('/') {
+            Some(idx) => (&path[..idx], &path[idx + 1..]),
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("Expected an LV path like vg/lv, got {}", path),
+                )))
+            }
+        };
+
+        let config = self.vg_config()?;
+        recover_vg(vg_name, &config)?;
+        let _lock = Flock::lock_shared(LockScope::VG(vg_name.to_string()))?;
+        let map = vg_metadata(vg_name, &config)?;
+        let mut vg = VG::from_textmap_with_config(vg_name, &map, config)?;
+
+        vg.lv_activate_as(lv_name, naming)
+    }
+
+    /// Make the system match `spec`: devices are initialized as PVs, the
+    /// VG is created or has PVs added, and missing LVs are created. All
+    /// LVs in the VG are activated. If `resize` is set, linear LVs
+    /// smaller than in the spec are extended.
+    /// Nothing is ever removed or shrunk. Returns a description of each
+    /// change made, so an empty list means the system already matched.
+    #[cfg(feature = "spec")]
+    pub fn apply(&self, spec: &Spec, resize: bool) -> Result<Vec<String>> {
+        dm::cached(|| self.apply_uncached(spec, resize))
+    }
+
+    #[cfg(feature = "spec")]
+    fn apply_uncached(&self, spec: &Spec, resize: bool) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+        let _lock = Flock::lock_exclusive(LockScope::VG(spec.vg.clone()))?;
+
+        for path in &spec.pvs {
+            if PvHeader::find_in_dev(path).is_err() {
+                PvHeader::initialize(path)?;
+                changes.push(format!("initialized PV {}", path.display()));
+            }
+        }
+
+        let config = self.vg_config()?;
+        recover_vg(&spec.vg, &config)?;
+        let mut vg = match vg_metadata(&spec.vg, &config) {
+            Ok(map) => {
+                let (vg, failures) =
+                    VG::from_textmap_with_failures(&spec.vg, &map, config.clone())?;
+                if let Some((_, e)) = failures.into_iter().next() {
+                    return Err(e);
+                }
+                vg
+            }
+            Err(_) => {
+                let paths = spec.pvs.iter().map(|p| p.as_path()).collect();
+                let vg = VG::create_with_config(&spec.vg, paths, config)?;
+                changes.push(format!("created VG {}", spec.vg));
+                vg
+            }
+        };
+
+        let before = vg.clone_state();
+
+        for path in &spec.pvs {
+            if !vg.pv_list().contains(&Device::from_path(path)?) {
+                vg.pv_add(path)?;
+            }
+        }
+
+        for lv in &spec.lvs {
+            let extents = vg.size_to_extents(Size::Bytes(lv.size))?;
+
+            let current = match vg.lv_get(&lv.name) {
+                Some(existing) => existing.used_extents(),
+                None => {
+                    match lv.lv_type {
+                        LvType::Linear => vg.lv_create_linear(&lv.name, extents)?,
+                        LvType::ThinPool => vg.lv_create_thinpool(&lv.name, extents)?,
+                        LvType::Thin(ref pool) => vg.lv_create_thin(pool, &lv.name, extents)?,
+                    }
+                    vg.lv_activate(&lv.name)?;
+                    continue;
+                }
+            };
+
+            if resize && lv.lv_type == LvType::Linear && current < extents {
+                vg.lv_extend(&lv.name, extents - current)?;
+            }
+        }
+
+        let diff = VgDiff::new(&before, &vg.clone_state());
+        changes.extend(diff.to_string().lines().map(|line| line.to_string()));
+
+        Ok(changes)
+    }
+
+    /// Wait for udev to finish processing the events generated by
+    /// device activations, so device nodes exist once this returns.
+    pub fn settle(&self) -> Result<()> {
+        let status = Command::new("udevadm")
+            .arg("settle")
+            .arg(format!("--timeout={}", SETTLE_TIMEOUT))
+            .status()?;
+        if !status.success() {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("udevadm settle failed: {}", status),
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Remove DM udev cookies that have not changed for at least
+    /// `max_age`, as left behind by processes that exited before udev
+    /// completed. Which process a cookie belongs to can't be told, so a
+    /// process still waiting on one this old loses it: `max_age` should
+    /// be well beyond how long udev may take. Returns how many were
+    /// removed.
+    pub fn remove_stale_cookies(&self, max_age: Duration) -> Result<usize> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut removed = 0;
+        // Columns: key semid perms nsems uid gid cuid cgid otime ctime
+        for line in read_to_string("/proc/sysvipc/sem")?.lines().skip(1) {
+            let fields: Vec<u64> = line
+                .split_whitespace()
+                .filter_map(|f| f.parse().ok())
+                .collect();
+            if fields.len() < 10 {
+                continue;
+            }
+
+            let (key, semid, ctime) = (fields[0], fields[1], fields[9]);
+            if key >> 16 != DM_COOKIE_MAGIC || now.saturating_sub(ctime) < max_age.as_secs() {
+                continue;
+            }
+
+            if unsafe { semctl(semid as i32, 0, IPC_RMID) } < 0 {
+                match Errno::last() {
+                    // Already gone, or not ours
+                    Errno::EINVAL | Errno::EIDRM | Errno::EPERM => {}
+                    e => return Err(Error::Nix(nix::Error::Sys(e))),
+                }
+            } else {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+// The metadata lvmetad has cached for the named VG, if it is running
+// and has it.
+#[cfg(feature = "daemon-clients")]
+fn lvmetad_metadata(name: &str, config: &Config) -> Option<LvmTextMap> {
+    lvmetad::dump(&config.retry)
+        .ok()?
+        .vgs
+        .values()
+        .find(|vg| vg.name.as_deref() == Some(name))
+        .and_then(|vg| vg.metadata.clone())
+}
+
+#[cfg(not(feature = "daemon-clients"))]
+fn lvmetad_metadata(_name: &str, _config: &Config) -> Option<LvmTextMap> {
+    None
+}
+
+// Finish or undo a commit to the named VG that was interrupted and left
+// its PVs disagreeing, as `recover_commit` does. This is only done if a
+// PV has precommitted metadata, and then under the VG's exclusive lock,
+// so that a commit still in progress is waited for rather than undone.
+// The calling thread must not hold the VG's shared lock.
+pub(crate) fn recover_vg(name: &str, config: &Config) -> Result<()> {
+    // LVM2 recovers VGs that lvmetad is caching itself.
+    if lvmetad_metadata(name, config).is_some() {
+        return Ok(());
+    }
+
+    let pv_paths = pvheader_scan_report_with_filter(&[Path::new(DEV_DIR)], &config.filter)?.pvs;
+    let precommitted = pv_paths.iter().any(|path| {
+        PvHeader::find_in_dev(path)
+            .and_then(|pvh| pvh.precommitted_rlocns())
+            .is_ok_and(|rlocns| rlocns.iter().any(Option::is_some))
+    });
+    if !precommitted {
+        return Ok(());
+    }
+
+    let _lock = Flock::lock_exclusive(LockScope::VG(name.to_string()))?;
+    recover_commit(name, &pv_paths)?;
+
+    Ok(())
+}
+
+// Find the metadata of the named VG, from lvmetad if it is running and
+// has it, or else by scanning the devices `config.filter` accepts for
+// PVs.
+// Callers should lock the VG, and call `recover_vg` first.
+pub(crate) fn vg_metadata(name: &str, config: &Config) -> Result<LvmTextMap> {
+    if let Some(map) = lvmetad_metadata(name, config) {
+        return Ok(map);
+    }
+
+    let pv_paths = pvheader_scan_report_with_filter(&[Path::new(DEV_DIR)], &config.filter)?.pvs;
+
+    // PVs may still disagree if a commit failed partway, so the newest
+    // metadata any of them has is used. `VG::repair_stale_metadata`
+    // brings the others up to date.
+    // Only the newest is copied out of the text it is parsed from.
+    let mut newest: Option<(i64, LvmTextMap)> = None;
+    for pv_path in pv_paths {
+        // PVs without metadata areas, or with damaged ones, may be
+        // skipped as long as another has the VG's metadata.
+        let text = match PvHeader::find_in_dev(&pv_path).and_then(|pvh| pvh.read_metadata_text()) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        let map = match buf_to_textmap_borrowed(&text) {
+            Ok(map) => map,
+            Err(_) => continue,
+        };
+        let vg = match map.textmap_from_textmap(name) {
+            Some(vg) => vg,
+            None => continue,
+        };
+        let seqno = match vg.i64_from_textmap("seqno") {
+            Some(seqno) => seqno,
+            None => continue,
+        };
+        if newest.as_ref().is_none_or(|(newest, _)| seqno > *newest) {
+            newest = Some((seqno, textmap_ref_to_owned(vg)));
+        }
+    }
+
+    newest
+        .map(|(_, vg)| vg)
+        .ok_or_else(|| Error::VgNotFound(name.to_string()))
+}
@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Encrypting an LV with dm-crypt.
+//!
+//! The crypt device is stacked on top of an active LV, and is not
+//! recorded in the VG's metadata: it must be set up again by the caller
+//! after each activation. The key is never handled by Melvin; it must
+//! already be in the kernel keyring.
+
+use std::io;
+use std::io::ErrorKind::Other;
+
+use crate::device::Device;
+use crate::dm;
+use crate::{Error, Result, VG};
+
+/// The kind of kernel keyring key holding the encryption key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KeyType {
+    /// A `logon` key, which cannot be read back by userspace.
+    Logon,
+    /// A `user` key.
+    User,
+}
+
+/// How to encrypt the LV.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CryptParams {
+    /// The cipher specification, e.g. "aes-xts-plain64".
+    pub cipher: String,
+    /// The size of the key in bytes.
+    pub key_size: u32,
+    /// The type of the key in the kernel keyring.
+    pub key_type: KeyType,
+    /// The description of the key in the kernel keyring.
+    pub key_description: String,
+    /// The IV offset, in sectors.
+    pub iv_offset: u64,
+    /// The sector of the LV at which encrypted data starts.
+    pub offset: u64,
+}
+
+impl CryptParams {
+    // The parameters of a crypt DM target using this configuration on top
+    // of the given device.
+    fn dm_params(&self, dev: Device) -> String {
+        let key_type = match self.key_type {
+            KeyType::Logon => "logon",
+            KeyType::User => "user",
+        };
+
+        format!(
+            "{} :{}:{}:{} {} {}:{} {}",
+            self.cipher,
+            self.key_size,
+            key_type,
+            self.key_description,
+            self.iv_offset,
+            dev.major,
+            dev.minor,
+            self.offset
+        )
+    }
+}
+
+/// A dm-crypt device stacked on top of an active LV.
+#[derive(Debug)]
+pub struct CryptDev {
+    name: String,
+    device: Device,
+}
+
+impl CryptDev {
+    /// Create a DM device named `name` that encrypts the contents of the
+    /// active LV `lv_name`.
+    pub fn setup(name: &str, vg: &VG, lv_name: &str, params: &CryptParams) -> Result<CryptDev> {
+        let lv = vg.lv_get(lv_name).ok_or_else(|| Error::LvNotFound {
+            vg: vg.name().to_string(),
+            lv: lv_name.to_string(),
+        })?;
+
+        let device = lv.device.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                Other,
+                format!("LV {} is not active", lv_name),
+            ))
+        })?;
+
+        let lv_sectors = lv.used_extents() * vg.extent_size();
+        if params.offset >= lv_sectors {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Crypt data offset is beyond the end of the LV",
+            )));
+        }
+
+        let table = vec![(
+            0,
+            lv_sectors - params.offset,
+            "crypt".to_string(),
+            params.dm_params(device),
+        )];
+
+        let device = dm::create_device(name, &table, &vg.config().retry)?;
+
+        Ok(CryptDev {
+            name: name.to_string(),
+            device,
+        })
+    }
+
+    /// The name of the DM device.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The device number of the DM device.
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    /// Remove the crypt device. The LV underneath remains active.
+    pub fn teardown(self, vg: &VG) -> Result<()> {
+        dm::remove_device(&self.name, &vg.config().retry)
+    }
+}
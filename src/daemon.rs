@@ -0,0 +1,12 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Talking to LVM2's daemons.
+//!
+//! lvmetad, lvmpolld and lvmlockd all listen on unix sockets and speak
+//! the same protocol: requests and responses in LVM's text format, each
+//! ended by a line of "##". `transport` implements it once for all of
+//! their clients.
+
+pub mod transport;
@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The protocol shared by LVM2's daemons.
+//!
+//! A request is a text map with a "request" key naming the operation,
+//! and a response is one with a "response" key that is "OK" on success.
+//! Each message ends with `TERMINATOR`. Responses are read through a
+//! buffer, so that anything received past the end of one is kept for
+//! the next.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::parser::{buf_to_textmap, textmap_to_buf, Entry, LvmTextMap, TextMapOps};
+use crate::retry::{is_transient_connect, RetryPolicy};
+use crate::{Error, Result};
+
+/// Ends every request and response.
+pub const TERMINATOR: &[u8] = b"\n##\n";
+
+/// How long to wait for a daemon to accept or answer a message before
+/// giving up, unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The largest datagram read at once.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum Socket {
+    Stream(UnixStream),
+    Datagram(UnixDatagram),
+}
+
+/// A connection to an LVM2 daemon.
+#[derive(Debug)]
+pub struct Transport {
+    daemon: String,
+    socket: Socket,
+    buf: Vec<u8>,
+}
+
+impl Transport {
+    /// Connect to the daemon listening on a unix stream socket, as they
+    /// all do. `daemon` names it in errors. Connecting is retried as
+    /// `retry` says while the daemon may be starting. Reads and writes
+    /// fail once they have waited for `timeout`; if None, they wait
+    /// forever.
+    pub fn connect(
+        daemon: &str,
+        path: &Path,
+        timeout: Option<Duration>,
+        retry: &RetryPolicy,
+    ) -> Result<Transport> {
+        let stream = retry.run_with(is_transient_connect, || Ok(UnixStream::connect(path)?))?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+
+        Ok(Transport::new(daemon, Socket::Stream(stream)))
+    }
+
+    /// Like `connect`, but over a unix datagram socket, each message in
+    /// a datagram of its own.
+    pub fn connect_datagram(
+        daemon: &str,
+        path: &Path,
+        timeout: Option<Duration>,
+        retry: &RetryPolicy,
+    ) -> Result<Transport> {
+        let socket = UnixDatagram::unbound()?;
+        retry.run_with(is_transient_connect, || Ok(socket.connect(path)?))?;
+        socket.set_read_timeout(timeout)?;
+        socket.set_write_timeout(timeout)?;
+
+        Ok(Transport::new(daemon, Socket::Datagram(socket)))
+    }
+
+    fn new(daemon: &str, socket: Socket) -> Transport {
+        Transport {
+            daemon: daemon.to_string(),
+            socket,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Send a message, adding the terminator.
+    pub fn send(&mut self, msg: &[u8]) -> Result<()> {
+        let mut framed = msg.to_vec();
+        framed.extend_from_slice(TERMINATOR);
+
+        match self.socket {
+            Socket::Stream(ref mut stream) => stream.write_all(&framed)?,
+            Socket::Datagram(ref socket) => {
+                if socket.send(&framed)? != framed.len() {
+                    return Err(self.err("message truncated"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive a message, without its terminator.
+    pub fn receive(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = self
+                .buf
+                .windows(TERMINATOR.len())
+                .position(|w| w == TERMINATOR)
+            {
+                let msg = self.buf[..pos].to_vec();
+                self.buf.drain(..pos + TERMINATOR.len());
+                return Ok(msg);
+            }
+
+            let mut chunk = vec![0u8; MAX_DATAGRAM];
+            let len = match self.socket {
+                Socket::Stream(ref mut stream) => stream.read(&mut chunk)?,
+                Socket::Datagram(ref socket) => socket.recv(&mut chunk)?,
+            };
+            if len == 0 {
+                return Err(self.err("unexpected end of response"));
+            }
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    /// Send a request with the given arguments, and return the response
+    /// if the daemon says it succeeded.
+    pub fn request(&mut self, name: &str, args: &LvmTextMap) -> Result<LvmTextMap> {
+        let mut msg = args.clone();
+        msg.insert("request".to_string(), Entry::String(name.to_string()));
+        self.send(&textmap_to_buf(&msg))?;
+
+        let map = buf_to_textmap(&self.receive()?)?;
+        match map.string_from_textmap("response") {
+            Some("OK") => Ok(map),
+            Some(x) => Err(self.err(&format!(
+                "request {} failed: {} {}",
+                name,
+                x,
+                map.string_from_textmap("reason").unwrap_or("")
+            ))),
+            None => Err(self.err("no response")),
+        }
+    }
+
+    fn err(&self, what: &str) -> Error {
+        Error::Io(io::Error::new(Other, format!("{}: {}", self.daemon, what)))
+    }
+}
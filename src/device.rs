@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Device numbers.
+//!
+//! Melvin identifies block devices by `Device`, which displays as
+//! "major:minor". `DeviceExt` adds ways to get one from a path and to
+//! find the device in sysfs.
+
+use std::fmt;
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::{Path, PathBuf};
+
+use nix::sys::stat::{self, SFlag};
+
+use crate::{Error, Result};
+
+/// A block device's number.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Device {
+    /// The major number
+    pub major: u32,
+    /// The minor number
+    pub minor: u32,
+}
+
+/// From a `dev_t`, as encoded by glibc's makedev().
+impl From<u64> for Device {
+    fn from(val: u64) -> Device {
+        Device {
+            major: (((val >> 32) & 0xffff_f000) | ((val >> 8) & 0xfff)) as u32,
+            minor: (((val >> 12) & 0xffff_ff00) | (val & 0xff)) as u32,
+        }
+    }
+}
+
+impl From<Device> for u64 {
+    fn from(dev: Device) -> u64 {
+        let major = u64::from(dev.major);
+        let minor = u64::from(dev.minor);
+
+        ((major & 0xffff_f000) << 32)
+            | ((major & 0xfff) << 8)
+            | ((minor & 0xffff_ff00) << 12)
+            | (minor & 0xff)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+/// Helpers for `Device`.
+pub trait DeviceExt: Sized {
+    /// The device number of the block device at `path`.
+    fn from_path(path: &Path) -> Result<Self>;
+    /// The device's directory in sysfs.
+    fn to_sys_path(&self) -> PathBuf;
+    /// The device's node under /dev/block, as created by udev.
+    fn to_dev_path(&self) -> PathBuf;
+}
+
+impl DeviceExt for Device {
+    fn from_path(path: &Path) -> Result<Device> {
+        let st = stat::stat(path)?;
+
+        if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT != SFlag::S_IFBLK {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("{} is not a block device", path.display()),
+            )));
+        }
+
+        Ok(Device {
+            major: stat::major(st.st_rdev) as u32,
+            minor: stat::minor(st.st_rdev) as u32,
+        })
+    }
+
+    fn to_sys_path(&self) -> PathBuf {
+        PathBuf::from(format!("/sys/dev/block/{}:{}", self.major, self.minor))
+    }
+
+    fn to_dev_path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/block/{}:{}", self.major, self.minor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_t_round_trip() {
+        // As glibc's makedev() encodes them
+        for (major, minor, dev_t) in &[
+            (8, 1, 0x801),
+            (253, 4096, 0x100fd00),
+            (259, 0x12_3456, 0x1_2341_0356),
+            (0x1_2345, 0x12_3456, 0x0001_2001_2343_4556),
+        ] {
+            let dev = Device {
+                major: *major,
+                minor: *minor,
+            };
+            assert_eq!(u64::from(dev), *dev_t);
+            assert_eq!(Device::from(*dev_t), dev);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Differences between two states of a VG.
+//!
+//! A `VgDiff` lists what changed between an earlier and a later state:
+//! PVs and LVs added or removed, LVs resized, and status or flag words
+//! set or cleared. Comparing a VG before and after an operation shows
+//! what the operation did, or would do if it was made on a copy.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::device::Device;
+use crate::state::VgState;
+
+/// What a `FlagChange` applies to.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiffTarget {
+    /// The VG itself.
+    Vg,
+    /// The PV on this device.
+    Pv(Device),
+    /// The LV with this name.
+    Lv(String),
+}
+
+/// Status or flag words set or cleared on a VG, PV or LV.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FlagChange {
+    /// What changed.
+    pub target: DiffTarget,
+    /// Which list changed, "status" or "flags".
+    pub field: &'static str,
+    /// Words that were set.
+    pub added: Vec<String>,
+    /// Words that were cleared.
+    pub removed: Vec<String>,
+}
+
+/// An LV whose size changed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LvResize {
+    /// The LV's name.
+    pub name: String,
+    /// The size before, in extents.
+    pub old_extents: u64,
+    /// The size after, in extents.
+    pub new_extents: u64,
+}
+
+/// The changes between two states of a VG.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct VgDiff {
+    /// PVs in the later state only.
+    pub pvs_added: Vec<Device>,
+    /// PVs in the earlier state only.
+    pub pvs_removed: Vec<Device>,
+    /// LVs in the later state only.
+    pub lvs_added: Vec<String>,
+    /// LVs in the earlier state only.
+    pub lvs_removed: Vec<String>,
+    /// LVs in both states, with different sizes.
+    pub lvs_resized: Vec<LvResize>,
+    /// Status and flag changes to the VG, and to PVs and LVs in both
+    /// states.
+    pub flag_changes: Vec<FlagChange>,
+}
+
+impl VgDiff {
+    /// Compare two states of a VG.
+    pub fn new(old: &VgState, new: &VgState) -> VgDiff {
+        let mut diff = VgDiff::default();
+
+        diff.push_flags(DiffTarget::Vg, "status", old.status(), new.status());
+        diff.push_flags(DiffTarget::Vg, "flags", old.flags(), new.flags());
+
+        for dev in new.pv_list() {
+            match (old.pv_get(dev), new.pv_get(dev)) {
+                (None, _) => diff.pvs_added.push(dev),
+                (Some(old_pv), Some(new_pv)) => {
+                    diff.push_flags(
+                        DiffTarget::Pv(dev),
+                        "status",
+                        &old_pv.status,
+                        &new_pv.status,
+                    );
+                    diff.push_flags(DiffTarget::Pv(dev), "flags", &old_pv.flags, &new_pv.flags);
+                }
+                (Some(_), None) => {}
+            }
+        }
+        diff.pvs_removed = old
+            .pv_list()
+            .into_iter()
+            .filter(|dev| new.pv_get(*dev).is_none())
+            .collect();
+
+        for name in new.lv_list() {
+            let new_lv = match new.lv_get(&name) {
+                Some(lv) => lv,
+                None => continue,
+            };
+            let old_lv = match old.lv_get(&name) {
+                Some(lv) => lv,
+                None => {
+                    diff.lvs_added.push(name);
+                    continue;
+                }
+            };
+
+            if old_lv.extents != new_lv.extents {
+                diff.lvs_resized.push(LvResize {
+                    name: name.clone(),
+                    old_extents: old_lv.extents,
+                    new_extents: new_lv.extents,
+                });
+            }
+            diff.push_flags(
+                DiffTarget::Lv(name.clone()),
+                "status",
+                &old_lv.status,
+                &new_lv.status,
+            );
+            diff.push_flags(DiffTarget::Lv(name), "flags", &old_lv.flags, &new_lv.flags);
+        }
+        diff.lvs_removed = old
+            .lv_list()
+            .into_iter()
+            .filter(|name| new.lv_get(name).is_none())
+            .collect();
+
+        diff
+    }
+
+    fn push_flags(
+        &mut self,
+        target: DiffTarget,
+        field: &'static str,
+        old: &[String],
+        new: &[String],
+    ) {
+        let old: BTreeSet<_> = old.iter().collect();
+        let new: BTreeSet<_> = new.iter().collect();
+
+        let added: Vec<_> = new.difference(&old).map(|s| s.to_string()).collect();
+        let removed: Vec<_> = old.difference(&new).map(|s| s.to_string()).collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+
+        self.flag_changes.push(FlagChange {
+            target,
+            field,
+            added,
+            removed,
+        });
+    }
+
+    /// Whether the two states were the same.
+    pub fn is_empty(&self) -> bool {
+        self.pvs_added.is_empty()
+            && self.pvs_removed.is_empty()
+            && self.lvs_added.is_empty()
+            && self.lvs_removed.is_empty()
+            && self.lvs_resized.is_empty()
+            && self.flag_changes.is_empty()
+    }
+}
+
+impl fmt::Display for DiffTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffTarget::Vg => write!(f, "VG"),
+            DiffTarget::Pv(dev) => write!(f, "PV {}", dev),
+            DiffTarget::Lv(name) => write!(f, "LV {}", name),
+        }
+    }
+}
+
+/// One change per line.
+impl fmt::Display for VgDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for dev in &self.pvs_added {
+            writeln!(f, "add PV {}", dev)?;
+        }
+        for dev in &self.pvs_removed {
+            writeln!(f, "remove PV {}", dev)?;
+        }
+        for name in &self.lvs_added {
+            writeln!(f, "create LV {}", name)?;
+        }
+        for name in &self.lvs_removed {
+            writeln!(f, "remove LV {}", name)?;
+        }
+        for resize in &self.lvs_resized {
+            writeln!(
+                f,
+                "resize LV {} from {} to {} extents",
+                resize.name, resize.old_extents, resize.new_extents
+            )?;
+        }
+        for change in &self.flag_changes {
+            for word in &change.added {
+                writeln!(f, "set {} {} {}", change.target, change.field, word)?;
+            }
+            for word in &change.removed {
+                writeln!(f, "clear {} {} {}", change.target, change.field, word)?;
+            }
+        }
+        Ok(())
+    }
+}
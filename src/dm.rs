@@ -0,0 +1,471 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Device-mapper operations on devices described by raw table lines.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(feature = "devicemapper"))]
+use std::io;
+#[cfg(not(feature = "devicemapper"))]
+use std::io::ErrorKind::Other;
+
+#[cfg(feature = "devicemapper")]
+use devicemapper::{DevId, DmFlags, DmName, DmOptions, DM};
+
+use crate::device::Device;
+#[cfg(feature = "raw-dm")]
+use crate::dm_ioctl::RawDm;
+use crate::retry::RetryPolicy;
+#[cfg(not(feature = "devicemapper"))]
+use crate::Error;
+use crate::Result;
+
+/// The device-mapper operations Melvin builds on, on devices named by
+/// their DM names. The devicemapper crate provides them with the
+/// "devicemapper" feature, on by default, and with the "raw-dm" feature,
+/// so can Melvin's own use of the DM ioctls.
+pub trait DmBackend {
+    /// Create a DM device with no table.
+    fn device_create(&self, name: &str) -> Result<Device>;
+    /// Remove a DM device, or with `deferred`, mark it to be removed
+    /// once nothing has it open.
+    fn device_remove(&self, name: &str, deferred: bool) -> Result<()>;
+    /// Suspend a DM device.
+    fn device_suspend(&self, name: &str) -> Result<()>;
+    /// Resume a DM device, making any loaded table live.
+    fn device_resume(&self, name: &str) -> Result<()>;
+    /// Rename a DM device.
+    fn device_rename(&self, old_name: &str, new_name: &str) -> Result<()>;
+    /// Load a table into a DM device's inactive slot.
+    fn table_load(&self, name: &str, table: &[TableLine], read_only: bool) -> Result<()>;
+    /// Get the status of each target of a DM device, or with `table`,
+    /// their parameters.
+    fn table_status(&self, name: &str, table: bool) -> Result<Vec<TableLine>>;
+    /// Send a message to a DM device's target.
+    fn target_msg(&self, name: &str, msg: &str) -> Result<()>;
+    /// List the DM devices by name.
+    fn list_devices(&self) -> Result<BTreeMap<String, Device>>;
+    /// List the loaded DM target types, with their versions.
+    fn list_versions(&self) -> Result<Vec<(String, TargetVersion)>>;
+    /// Get the count of events a DM device has had.
+    fn event_nr(&self, name: &str) -> Result<u32>;
+    /// Wait until a DM device's count of events is no longer `event_nr`,
+    /// and return the new count.
+    fn device_wait(&self, name: &str, event_nr: u32) -> Result<u32>;
+}
+
+/// Which `DmBackend` to use.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DmBackendKind {
+    /// The devicemapper crate. This is the default. Without the
+    /// "devicemapper" feature, the raw backend is used instead.
+    Library,
+    /// Melvin's own use of the DM ioctls, for kernels the devicemapper
+    /// crate does not get along with.
+    #[cfg(feature = "raw-dm")]
+    Raw,
+}
+
+static USE_RAW: AtomicBool = AtomicBool::new(false);
+
+/// Select the device-mapper backend for the whole process. `cached`
+/// scopes that have already opened a backend keep using it.
+pub fn set_dm_backend(kind: DmBackendKind) {
+    USE_RAW.store(kind != DmBackendKind::Library, Ordering::Relaxed);
+}
+
+fn new_backend() -> Result<Rc<dyn DmBackend>> {
+    #[cfg(feature = "raw-dm")]
+    {
+        if USE_RAW.load(Ordering::Relaxed) || cfg!(not(feature = "devicemapper")) {
+            return Ok(Rc::new(RawDm::new()?));
+        }
+    }
+    #[cfg(feature = "devicemapper")]
+    {
+        Ok(Rc::new(LibDm(DM::new()?)))
+    }
+    #[cfg(not(feature = "devicemapper"))]
+    {
+        Err(Error::Io(io::Error::new(
+            Other,
+            "Melvin was built without a device-mapper backend",
+        )))
+    }
+}
+
+#[cfg(feature = "devicemapper")]
+fn from_dm_device(dev: devicemapper::Device) -> Device {
+    Device {
+        major: dev.major,
+        minor: dev.minor,
+    }
+}
+
+// The devicemapper crate's implementation.
+#[cfg(feature = "devicemapper")]
+struct LibDm(DM);
+
+#[cfg(feature = "devicemapper")]
+impl DmBackend for LibDm {
+    fn device_create(&self, name: &str) -> Result<Device> {
+        let info = self
+            .0
+            .device_create(DmName::new(name)?, None, &DmOptions::new())?;
+        Ok(from_dm_device(info.device()))
+    }
+
+    fn device_remove(&self, name: &str, deferred: bool) -> Result<()> {
+        let id = DevId::Name(DmName::new(name)?);
+        let flags = if deferred {
+            DmFlags::DM_DEFERRED_REMOVE
+        } else {
+            DmFlags::empty()
+        };
+        self.0
+            .device_remove(&id, &DmOptions::new().set_flags(flags))?;
+        Ok(())
+    }
+
+    fn device_suspend(&self, name: &str) -> Result<()> {
+        let id = DevId::Name(DmName::new(name)?);
+        self.0
+            .device_suspend(&id, &DmOptions::new().set_flags(DmFlags::DM_SUSPEND))?;
+        Ok(())
+    }
+
+    fn device_resume(&self, name: &str) -> Result<()> {
+        let id = DevId::Name(DmName::new(name)?);
+        self.0.device_suspend(&id, &DmOptions::new())?;
+        Ok(())
+    }
+
+    fn device_rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        let new = DevId::Name(DmName::new(new_name)?);
+        self.0.device_rename(DmName::new(old_name)?, &new)?;
+        Ok(())
+    }
+
+    fn table_load(&self, name: &str, table: &[TableLine], read_only: bool) -> Result<()> {
+        let id = DevId::Name(DmName::new(name)?);
+        let flags = if read_only {
+            DmFlags::DM_READONLY
+        } else {
+            DmFlags::empty()
+        };
+        self.0
+            .table_load(&id, table, &DmOptions::new().set_flags(flags))?;
+        Ok(())
+    }
+
+    fn table_status(&self, name: &str, table: bool) -> Result<Vec<TableLine>> {
+        let id = DevId::Name(DmName::new(name)?);
+        let flags = if table {
+            DmFlags::DM_STATUS_TABLE
+        } else {
+            DmFlags::empty()
+        };
+        let (_, status) = self
+            .0
+            .table_status(&id, &DmOptions::new().set_flags(flags))?;
+        Ok(status)
+    }
+
+    fn target_msg(&self, name: &str, msg: &str) -> Result<()> {
+        let id = DevId::Name(DmName::new(name)?);
+        self.0.target_msg(&id, None, msg)?;
+        Ok(())
+    }
+
+    fn list_devices(&self) -> Result<BTreeMap<String, Device>> {
+        Ok(self
+            .0
+            .list_devices()?
+            .into_iter()
+            .map(|(name, device, _)| {
+                (
+                    String::from_utf8_lossy(name.as_ref().as_bytes()).into_owned(),
+                    from_dm_device(device),
+                )
+            })
+            .collect())
+    }
+
+    fn list_versions(&self) -> Result<Vec<(String, TargetVersion)>> {
+        Ok(self
+            .0
+            .list_versions()?
+            .into_iter()
+            .map(|(name, major, minor, patch)| (name, (major, minor, patch)))
+            .collect())
+    }
+
+    fn event_nr(&self, name: &str) -> Result<u32> {
+        let id = DevId::Name(DmName::new(name)?);
+        Ok(self.0.device_info(&id)?.event_nr())
+    }
+
+    fn device_wait(&self, name: &str, event_nr: u32) -> Result<u32> {
+        let id = DevId::Name(DmName::new(name)?);
+        let info = self.0.device_info(&id)?;
+        if info.event_nr() != event_nr {
+            return Ok(info.event_nr());
+        }
+        let (info, _) = self.0.device_wait(&id, &DmOptions::new())?;
+        Ok(info.event_nr())
+    }
+}
+
+// State shared by the DM operations within a `cached` scope: one
+// backend, and the list of DM devices by name, listed when first needed
+// and kept up to date as devices are created, removed and renamed
+// here. Devices changed by other processes meanwhile are
+// not noticed, so scopes should last only as long as one operation.
+#[derive(Default)]
+struct Cache {
+    depth: usize,
+    dm: Option<Rc<dyn DmBackend>>,
+    devices: Option<BTreeMap<String, Device>>,
+}
+
+thread_local! {
+    static CACHE: RefCell<Cache> = RefCell::new(Cache::default());
+}
+
+/// Run `f`, with DM operations within it sharing one DM handle and one
+/// listing of DM devices, instead of each opening and listing their
+/// own. Scopes may nest; the outermost one ends the sharing.
+pub fn cached<T>(f: impl FnOnce() -> T) -> T {
+    CACHE.with(|cache| cache.borrow_mut().depth += 1);
+    let res = f();
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.depth -= 1;
+        if cache.depth == 0 {
+            *cache = Cache::default();
+        }
+    });
+    res
+}
+
+// A DM backend, shared within a `cached` scope.
+fn handle() -> Result<Rc<dyn DmBackend>> {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.depth == 0 {
+            return new_backend();
+        }
+        if cache.dm.is_none() {
+            cache.dm = Some(new_backend()?);
+        }
+        Ok(Rc::clone(cache.dm.as_ref().expect("set above")))
+    })
+}
+
+// Update the cached device list, if there is one.
+fn update_cache(f: impl FnOnce(&mut BTreeMap<String, Device>)) {
+    CACHE.with(|cache| {
+        if let Some(ref mut devices) = cache.borrow_mut().devices {
+            f(devices);
+        }
+    })
+}
+
+/// A line of a DM table: start sector, length in sectors, target type,
+/// and target parameters.
+pub type TableLine = (u64, u64, String, String);
+
+/// Create a DM device with the given table and activate it. If any step
+/// fails, the device is removed again.
+pub fn create_device(name: &str, table: &[TableLine], retry: &RetryPolicy) -> Result<Device> {
+    create(name, table, false, retry)
+}
+
+/// Like `create_device`, but the device rejects writes.
+pub fn create_read_only_device(
+    name: &str,
+    table: &[TableLine],
+    retry: &RetryPolicy,
+) -> Result<Device> {
+    create(name, table, true, retry)
+}
+
+fn create(name: &str, table: &[TableLine], read_only: bool, retry: &RetryPolicy) -> Result<Device> {
+    let dm = handle()?;
+
+    let device = retry.run(|| dm.device_create(name))?;
+
+    let res = retry
+        .run(|| dm.table_load(name, table, read_only))
+        .and_then(|_| retry.run(|| dm.device_resume(name)));
+
+    match res {
+        Ok(_) => {
+            update_cache(|devices| {
+                devices.insert(name.to_string(), device);
+            });
+            Ok(device)
+        }
+        Err(e) => {
+            let _ = dm.device_remove(name, false);
+            Err(e)
+        }
+    }
+}
+
+/// Find the DM device with the given name, if it exists. Within a
+/// `cached` scope, DM devices are only listed once.
+pub fn find_device(name: &str) -> Result<Option<Device>> {
+    let cached = CACHE.with(|cache| {
+        let cache = cache.borrow();
+        match (cache.depth, &cache.devices) {
+            (0, _) | (_, None) => None,
+            (_, Some(devices)) => Some(devices.get(name).copied()),
+        }
+    });
+    if let Some(found) = cached {
+        return Ok(found);
+    }
+
+    let dm = handle()?;
+    let devices = dm.list_devices()?;
+    let found = devices.get(name).copied();
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.depth != 0 {
+            cache.devices = Some(devices);
+        }
+    });
+
+    Ok(found)
+}
+
+/// Suspend and remove a DM device.
+pub fn remove_device(name: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.device_suspend(name))?;
+    retry.run(|| dm.device_remove(name, false))?;
+    update_cache(|devices| {
+        devices.remove(name);
+    });
+
+    Ok(())
+}
+
+/// Remove a DM device once nothing has it open, like `dmsetup remove
+/// --deferred`, or at once if nothing does. It is not suspended first,
+/// so its users can carry on until they close it.
+pub fn remove_device_deferred(name: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.device_remove(name, true))?;
+    update_cache(|devices| {
+        devices.remove(name);
+    });
+
+    Ok(())
+}
+
+/// Suspend a DM device, so that it queues I/O until resumed.
+pub fn suspend_device(name: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.device_suspend(name))
+}
+
+/// Resume a DM device, making any table loaded since it was suspended
+/// live.
+pub fn resume_device(name: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.device_resume(name))
+}
+
+/// Load a new table into a DM device. It takes effect when the device
+/// is next resumed.
+pub fn load_table(name: &str, table: &[TableLine], retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.table_load(name, table, false))
+}
+
+/// Like `load_table`, but once the table takes effect the device rejects
+/// writes.
+pub fn load_read_only_table(name: &str, table: &[TableLine], retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.table_load(name, table, true))
+}
+
+/// Send a message to the target of a DM device.
+pub fn message(name: &str, msg: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.target_msg(name, msg))
+}
+
+/// Rename a DM device.
+pub fn rename_device(old_name: &str, new_name: &str, retry: &RetryPolicy) -> Result<()> {
+    let dm = handle()?;
+
+    retry.run(|| dm.device_rename(old_name, new_name))?;
+    update_cache(|devices| {
+        if let Some(device) = devices.remove(old_name) {
+            devices.insert(new_name.to_string(), device);
+        }
+    });
+
+    Ok(())
+}
+
+/// Get the status of each target in a DM device's table.
+pub fn table_status(name: &str, retry: &RetryPolicy) -> Result<Vec<TableLine>> {
+    let dm = handle()?;
+
+    retry.run(|| dm.table_status(name, false))
+}
+
+/// Get the table a DM device is running, with the parameters of each
+/// target as the kernel reports them.
+pub fn table(name: &str, retry: &RetryPolicy) -> Result<Vec<TableLine>> {
+    let dm = handle()?;
+
+    retry.run(|| dm.table_status(name, true))
+}
+
+/// A DM target's version: major, minor and patch level.
+pub type TargetVersion = (u32, u32, u32);
+
+/// The DM target types the kernel has loaded, with their versions.
+pub fn list_versions() -> Result<Vec<(String, TargetVersion)>> {
+    handle()?.list_versions()
+}
+
+/// Get the count of events a DM device has had, such as a thin pool
+/// crossing its low water mark, for passing to `wait_event`.
+pub fn event_nr(name: &str, retry: &RetryPolicy) -> Result<u32> {
+    let dm = handle()?;
+
+    retry.run(|| dm.event_nr(name))
+}
+
+/// Wait until a DM device has had an event since its count of events was
+/// `event_nr`, and return the new count. Returns at once if it already
+/// has.
+pub fn wait_event(name: &str, event_nr: u32, retry: &RetryPolicy) -> Result<u32> {
+    let dm = handle()?;
+
+    let current = retry.run(|| dm.event_nr(name))?;
+    if current != event_nr {
+        return Ok(current);
+    }
+
+    // DM_DEV_WAIT blocks until the next event, so is not retried.
+    dm.device_wait(name, event_nr)
+}
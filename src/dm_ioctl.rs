@@ -0,0 +1,356 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A `DmBackend` that issues the DM ioctls itself, rather than through
+//! the devicemapper crate.
+//!
+//! Only what `DmBackend` needs is here. Requests are made with version
+//! 4.0.0 of the ioctl interface, which every kernel with DM supports;
+//! the kernel answers in the same layout whatever its own version.
+//! Device nodes are left to udev, without waiting on it.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::ErrorKind::Other;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use nix::libc::ioctl;
+use nix::request_code_readwrite;
+
+use crate::dm::{DmBackend, TableLine, TargetVersion};
+use crate::{Device, Error, Result};
+
+const DM_CONTROL: &str = "/dev/mapper/control";
+
+const DM_IOCTL: u8 = 0xfd;
+const DM_VERSION: [u32; 3] = [4, 0, 0];
+
+// Commands
+const DM_LIST_DEVICES: u8 = 2;
+const DM_DEV_CREATE: u8 = 3;
+const DM_DEV_REMOVE: u8 = 4;
+const DM_DEV_RENAME: u8 = 5;
+const DM_DEV_SUSPEND: u8 = 6;
+const DM_DEV_STATUS: u8 = 7;
+const DM_DEV_WAIT: u8 = 8;
+const DM_TABLE_LOAD: u8 = 9;
+const DM_TABLE_STATUS: u8 = 12;
+const DM_LIST_VERSIONS: u8 = 13;
+const DM_TARGET_MSG: u8 = 14;
+
+// Flags
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_SUSPEND_FLAG: u32 = 1 << 1;
+const DM_STATUS_TABLE_FLAG: u32 = 1 << 4;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+const DM_DEFERRED_REMOVE: u32 = 1 << 17;
+
+// struct dm_ioctl, up to the start of its data area.
+const DM_IOCTL_SIZE: usize = 312;
+const DM_NAME_LEN: usize = 128;
+// struct dm_target_spec, which precedes each target's parameters.
+const DM_TARGET_SPEC_SIZE: usize = 40;
+const DM_MAX_TYPE_NAME: usize = 16;
+
+// Replies that don't fit are retried with a buffer twice the size.
+const INITIAL_BUFFER_SIZE: usize = 16 * 1024;
+
+fn reply_err() -> Error {
+    Error::Io(io::Error::new(Other, "Malformed DM ioctl reply"))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32> {
+    let bytes = buf.get(offset..offset + 4).ok_or_else(reply_err)?;
+    Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(buf.get(offset..offset + 8).ok_or_else(reply_err)?);
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+// A NUL-terminated string starting at `offset`.
+fn read_str(buf: &[u8], offset: usize) -> Result<String> {
+    let rest = buf.get(offset..).ok_or_else(reply_err)?;
+    let len = rest.iter().position(|b| *b == 0).ok_or_else(reply_err)?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+// The kernel encodes device numbers as huge_encode_dev() does.
+fn decode_dev(dev: u64) -> Device {
+    Device {
+        major: ((dev >> 8) & 0xfff) as u32,
+        minor: ((dev & 0xff) | ((dev >> 12) & 0xfff00)) as u32,
+    }
+}
+
+// The fields of struct dm_ioctl a request sets, and its data.
+#[derive(Default)]
+struct Request<'a> {
+    name: Option<&'a str>,
+    flags: u32,
+    event_nr: u32,
+    target_count: u32,
+    data: Vec<u8>,
+}
+
+// The fields of struct dm_ioctl the kernel sets, and its data.
+struct Reply {
+    event_nr: u32,
+    target_count: u32,
+    dev: u64,
+    data: Vec<u8>,
+}
+
+/// A handle on the DM control device, for issuing DM ioctls directly.
+pub struct RawDm {
+    file: File,
+}
+
+impl RawDm {
+    /// Open the DM control device.
+    pub fn new() -> Result<RawDm> {
+        Ok(RawDm {
+            file: OpenOptions::new().read(true).write(true).open(DM_CONTROL)?,
+        })
+    }
+
+    fn ioctl(&self, cmd: u8, req: Request) -> Result<Reply> {
+        let mut size = INITIAL_BUFFER_SIZE.max(DM_IOCTL_SIZE + req.data.len());
+
+        loop {
+            let mut buf = vec![0u8; size];
+            for (idx, v) in DM_VERSION.iter().enumerate() {
+                buf[idx * 4..idx * 4 + 4].copy_from_slice(&v.to_ne_bytes());
+            }
+            buf[12..16].copy_from_slice(&(size as u32).to_ne_bytes());
+            buf[16..20].copy_from_slice(&(DM_IOCTL_SIZE as u32).to_ne_bytes());
+            buf[20..24].copy_from_slice(&req.target_count.to_ne_bytes());
+            buf[28..32].copy_from_slice(&req.flags.to_ne_bytes());
+            buf[32..36].copy_from_slice(&req.event_nr.to_ne_bytes());
+            if let Some(name) = req.name {
+                // Room is left for the NUL.
+                if name.len() >= DM_NAME_LEN {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        format!("DM name {} is too long", name),
+                    )));
+                }
+                buf[48..48 + name.len()].copy_from_slice(name.as_bytes());
+            }
+            buf[DM_IOCTL_SIZE..DM_IOCTL_SIZE + req.data.len()].copy_from_slice(&req.data);
+
+            Errno::result(unsafe {
+                ioctl(
+                    self.file.as_raw_fd(),
+                    request_code_readwrite!(DM_IOCTL, cmd, DM_IOCTL_SIZE),
+                    buf.as_mut_ptr(),
+                )
+            })?;
+
+            if read_u32(&buf, 28)? & DM_BUFFER_FULL_FLAG != 0 {
+                size *= 2;
+                continue;
+            }
+
+            let data_size = read_u32(&buf, 12)? as usize;
+            let data_start = read_u32(&buf, 16)? as usize;
+            return Ok(Reply {
+                event_nr: read_u32(&buf, 32)?,
+                target_count: read_u32(&buf, 20)?,
+                dev: read_u64(&buf, 40)?,
+                data: buf
+                    .get(data_start..data_size.max(data_start))
+                    .ok_or_else(reply_err)?
+                    .to_vec(),
+            });
+        }
+    }
+
+    fn named(&self, cmd: u8, name: &str, flags: u32) -> Result<Reply> {
+        self.ioctl(
+            cmd,
+            Request {
+                name: Some(name),
+                flags,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+impl DmBackend for RawDm {
+    fn device_create(&self, name: &str) -> Result<Device> {
+        Ok(decode_dev(self.named(DM_DEV_CREATE, name, 0)?.dev))
+    }
+
+    fn device_remove(&self, name: &str, deferred: bool) -> Result<()> {
+        let flags = if deferred { DM_DEFERRED_REMOVE } else { 0 };
+        self.named(DM_DEV_REMOVE, name, flags)?;
+        Ok(())
+    }
+
+    fn device_suspend(&self, name: &str) -> Result<()> {
+        self.named(DM_DEV_SUSPEND, name, DM_SUSPEND_FLAG)?;
+        Ok(())
+    }
+
+    fn device_resume(&self, name: &str) -> Result<()> {
+        self.named(DM_DEV_SUSPEND, name, 0)?;
+        Ok(())
+    }
+
+    fn device_rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        let mut data = new_name.as_bytes().to_vec();
+        data.push(0);
+        self.ioctl(
+            DM_DEV_RENAME,
+            Request {
+                name: Some(old_name),
+                data,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    // Each target is a struct dm_target_spec followed by its parameters,
+    // padded to 8 bytes. `next` is the offset of the following one.
+    fn table_load(&self, name: &str, table: &[TableLine], read_only: bool) -> Result<()> {
+        let mut data = Vec::new();
+        for (start, length, target_type, params) in table {
+            if target_type.len() >= DM_MAX_TYPE_NAME {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("DM target type {} is too long", target_type),
+                )));
+            }
+
+            let len = (DM_TARGET_SPEC_SIZE + params.len() + 1).div_ceil(8) * 8;
+            let mut spec = vec![0u8; len];
+            spec[0..8].copy_from_slice(&start.to_ne_bytes());
+            spec[8..16].copy_from_slice(&length.to_ne_bytes());
+            spec[20..24].copy_from_slice(&(len as u32).to_ne_bytes());
+            spec[24..24 + target_type.len()].copy_from_slice(target_type.as_bytes());
+            spec[DM_TARGET_SPEC_SIZE..DM_TARGET_SPEC_SIZE + params.len()]
+                .copy_from_slice(params.as_bytes());
+            data.extend(spec);
+        }
+
+        self.ioctl(
+            DM_TABLE_LOAD,
+            Request {
+                name: Some(name),
+                flags: if read_only { DM_READONLY_FLAG } else { 0 },
+                target_count: table.len() as u32,
+                data,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    // In replies, `next` is the offset from the start of the data.
+    fn table_status(&self, name: &str, table: bool) -> Result<Vec<TableLine>> {
+        let flags = if table { DM_STATUS_TABLE_FLAG } else { 0 };
+        let reply = self.named(DM_TABLE_STATUS, name, flags)?;
+
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for _ in 0..reply.target_count {
+            lines.push((
+                read_u64(&reply.data, offset)?,
+                read_u64(&reply.data, offset + 8)?,
+                read_str(&reply.data, offset + 24)?,
+                read_str(&reply.data, offset + DM_TARGET_SPEC_SIZE)?,
+            ));
+            offset = read_u32(&reply.data, offset + 20)? as usize;
+        }
+
+        Ok(lines)
+    }
+
+    // struct dm_target_msg: the sector, then the message.
+    fn target_msg(&self, name: &str, msg: &str) -> Result<()> {
+        let mut data = vec![0u8; 8];
+        data.extend(msg.as_bytes());
+        data.push(0);
+        self.ioctl(
+            DM_TARGET_MSG,
+            Request {
+                name: Some(name),
+                data,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    // A list of struct dm_name_list, each the device number, the offset
+    // to the next from this one, and the name. A device number of 0
+    // means there are no devices.
+    fn list_devices(&self) -> Result<BTreeMap<String, Device>> {
+        let reply = self.ioctl(DM_LIST_DEVICES, Request::default())?;
+
+        let mut devices = BTreeMap::new();
+        let mut offset = 0;
+        loop {
+            let dev = read_u64(&reply.data, offset)?;
+            if dev == 0 {
+                break;
+            }
+            devices.insert(read_str(&reply.data, offset + 12)?, decode_dev(dev));
+
+            match read_u32(&reply.data, offset + 8)? {
+                0 => break,
+                next => offset += next as usize,
+            }
+        }
+
+        Ok(devices)
+    }
+
+    // A list of struct dm_target_versions, each the offset to the next
+    // from this one, the version, and the name.
+    fn list_versions(&self) -> Result<Vec<(String, TargetVersion)>> {
+        let reply = self.ioctl(DM_LIST_VERSIONS, Request::default())?;
+
+        let mut versions = Vec::new();
+        let mut offset = 0;
+        while offset + 16 <= reply.data.len() {
+            let version = (
+                read_u32(&reply.data, offset + 4)?,
+                read_u32(&reply.data, offset + 8)?,
+                read_u32(&reply.data, offset + 12)?,
+            );
+            versions.push((read_str(&reply.data, offset + 16)?, version));
+
+            match read_u32(&reply.data, offset)? {
+                0 => break,
+                next => offset += next as usize,
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn event_nr(&self, name: &str) -> Result<u32> {
+        Ok(self.named(DM_DEV_STATUS, name, 0)?.event_nr)
+    }
+
+    fn device_wait(&self, name: &str, event_nr: u32) -> Result<u32> {
+        let reply = self.ioctl(
+            DM_DEV_WAIT,
+            Request {
+                name: Some(name),
+                event_nr,
+                ..Default::default()
+            },
+        )?;
+        Ok(reply.event_nr)
+    }
+}
@@ -2,23 +2,198 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
+use crate::device::Device;
+use crate::parser::ParseError;
+
+/// Errors from Melvin. Failures callers may want to handle are kinds of
+/// their own, with what they concern; `Io` errors come from the OS.
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    #[cfg(feature = "devicemapper")]
     Dm(devicemapper::DmError),
     Nix(nix::Error),
+    Parse(ParseError),
+    /// Metadata that could be parsed but is not valid, or not usable by
+    /// this version of Melvin.
+    Metadata(String),
+    /// A checksum on a device does not match what it covers. `what` is
+    /// the part of the PV it is for, such as "label".
+    ChecksumMismatch {
+        path: PathBuf,
+        what: &'static str,
+    },
+    /// A VG does not have the free extents to allocate, or not in
+    /// enough contiguous space if `contiguous` is set.
+    Alloc {
+        vg: String,
+        extents: u64,
+        contiguous: bool,
+    },
+    /// No device could be found for a PV of a VG.
+    DeviceNotFound(Device),
+    /// No VG of this name could be found.
+    VgNotFound(String),
+    /// The VG has no LV of this name.
+    LvNotFound {
+        vg: String,
+        lv: String,
+    },
+    /// The VG has no PV on this device.
+    PvNotFound {
+        vg: String,
+        device: Device,
+    },
+    /// The VG already has an LV of this name.
+    LvExists {
+        vg: String,
+        lv: String,
+    },
+    /// A device is already a PV, of the VG `vg` if it is in one, and
+    /// used by the LV `lv` if one uses it.
+    PvInUse {
+        path: PathBuf,
+        vg: Option<String>,
+        lv: Option<String>,
+    },
+    /// A device has no PV label.
+    NotAPv(PathBuf),
+    /// The LV is active, and must not be for the operation.
+    LvActive {
+        vg: String,
+        lv: String,
+    },
+    /// The LV is not active, and must be for the operation.
+    LvNotActive {
+        vg: String,
+        lv: String,
+    },
+    /// The LV is used by the LV `user`, as a thin pool is by its thin
+    /// LVs.
+    LvInUse {
+        vg: String,
+        lv: String,
+        user: String,
+    },
+    /// The LV is not of the kind the operation needs, which `expected`
+    /// describes, such as "a thin pool".
+    InvalidLvType {
+        vg: String,
+        lv: String,
+        expected: &'static str,
+    },
+    /// An argument is not valid, such as a malformed name, an unknown
+    /// policy, or a device too small for what was asked of it.
+    InvalidArgument(String),
+    /// The operation is not possible in the current state of the VG or
+    /// something in it, such as while the VG is not resizeable.
+    NotPermitted(String),
+    /// The operation, or metadata it needs, uses a feature Melvin does
+    /// not support.
+    Unsupported(String),
+    /// Data read back from a device does not match what was written.
+    /// `what` is what the data is.
+    DataMismatch {
+        path: PathBuf,
+        what: &'static str,
+    },
+    /// DM reported a status for a device that could not be parsed.
+    DmStatus(String),
+    /// An external command, such as `thin_repair`, failed. `detail` is
+    /// its exit status and what it printed.
+    CommandFailed {
+        command: String,
+        detail: String,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            #[cfg(feature = "devicemapper")]
+            Error::Dm(e) => write!(f, "{}", e),
+            Error::Nix(e) => write!(f, "{}", e),
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Metadata(msg) => write!(f, "Invalid metadata: {}", msg),
+            Error::ChecksumMismatch { path, what } => {
+                write!(f, "{} checksum mismatch on {}", what, path.display())
+            }
+            Error::Alloc {
+                vg,
+                extents,
+                contiguous,
+            } => write!(
+                f,
+                "VG {} does not have {} free {}extents",
+                vg,
+                extents,
+                if *contiguous { "contiguous " } else { "" }
+            ),
+            Error::DeviceNotFound(dev) => write!(f, "Could not find PV device {}", dev),
+            Error::VgNotFound(vg) => write!(f, "VG {} not found", vg),
+            Error::LvNotFound { vg, lv } => write!(f, "LV {} not found in VG {}", lv, vg),
+            Error::PvNotFound { vg, device } => {
+                write!(f, "PV {} not found in VG {}", device, vg)
+            }
+            Error::LvExists { vg, lv } => write!(f, "LV {} already exists in VG {}", lv, vg),
+            Error::PvInUse { path, vg, lv } => match (vg, lv) {
+                (Some(vg), Some(lv)) => {
+                    write!(f, "PV {} is in use by LV {}/{}", path.display(), vg, lv)
+                }
+                (Some(vg), None) => write!(f, "PV {} already in VG {}", path.display(), vg),
+                _ => write!(f, "Device {} is already a PV", path.display()),
+            },
+            Error::NotAPv(path) => write!(f, "No PV label found on {}", path.display()),
+            Error::LvActive { vg, lv } => write!(f, "LV {} in VG {} is active", lv, vg),
+            Error::LvNotActive { vg, lv } => write!(f, "LV {} in VG {} is not active", lv, vg),
+            Error::LvInUse { vg, lv, user } => {
+                write!(f, "LV {} in VG {} is in use by LV {}", lv, vg, user)
+            }
+            Error::InvalidLvType { vg, lv, expected } => {
+                write!(f, "LV {} in VG {} is not {}", lv, vg, expected)
+            }
+            Error::InvalidArgument(msg)
+            | Error::NotPermitted(msg)
+            | Error::Unsupported(msg)
+            | Error::DmStatus(msg) => write!(f, "{}", msg),
+            Error::DataMismatch { path, what } => write!(
+                f,
+                "{} on {} does not match what was written",
+                what,
+                path.display()
+            ),
+            Error::CommandFailed { command, detail } => write!(f, "{} failed: {}", command, detail),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            #[cfg(feature = "devicemapper")]
+            Error::Dm(e) => Some(e),
+            Error::Nix(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::Io(err)
     }
 }
 
+#[cfg(feature = "devicemapper")]
 impl From<devicemapper::DmError> for Error {
     fn from(err: devicemapper::DmError) -> Error {
         Error::Dm(err)
@@ -30,3 +205,9 @@ impl From<nix::Error> for Error {
         Error::Nix(err)
     }
 }
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::Parse(err)
+    }
+}
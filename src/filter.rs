@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Which devices may be scanned for PVs and added to VGs, as lvm.conf's
+//! `devices/filter` and `devices/global_filter` say for LVM2.
+//!
+//! A filter is a list of patterns like "a|^/dev/sd|" to accept devices
+//! or "r|.*|" to reject them. The character after "a" or "r" delimits a
+//! regular expression, which is matched against the device's path and
+//! the path it resolves to. The first pattern that matches decides, and
+//! a device no pattern matches is accepted. A `DeviceFilter` may hold
+//! several lists, and a device must be accepted by all of them.
+//!
+//! Devices that are paths to a multipath device are also rejected, as
+//! using one directly would bypass multipath.
+
+use std::fs::{canonicalize, read_dir, read_to_string};
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::device::{Device, DeviceExt};
+use crate::parser::{Entry, LvmTextMap, TextMapOps};
+use crate::{Error, Result};
+
+#[derive(Debug, Clone)]
+struct Rule {
+    accept: bool,
+    regex: Regex,
+}
+
+fn parse_rule(pattern: &str) -> Result<Rule> {
+    let err = || {
+        Error::Io(io::Error::new(
+            Other,
+            format!("Invalid filter pattern {}", pattern),
+        ))
+    };
+
+    let mut chars = pattern.chars();
+    let accept = match chars.next() {
+        Some('a') => true,
+        Some('r') => false,
+        _ => return Err(err()),
+    };
+    let delim = chars.next().ok_or_else(err)?;
+    let re = chars.as_str().strip_suffix(delim).ok_or_else(err)?;
+
+    Ok(Rule {
+        accept,
+        regex: Regex::new(re).map_err(|_| err())?,
+    })
+}
+
+/// Which devices to use, from lists of accept and reject patterns.
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    // The patterns as given, and as parsed.
+    lists: Vec<(Vec<String>, Vec<Rule>)>,
+    multipath_component_detection: bool,
+}
+
+/// Accepts every device apart from paths to multipath devices.
+impl Default for DeviceFilter {
+    fn default() -> DeviceFilter {
+        DeviceFilter {
+            lists: Vec::new(),
+            multipath_component_detection: true,
+        }
+    }
+}
+
+/// Filters are equal if they were made from the same patterns.
+impl PartialEq for DeviceFilter {
+    fn eq(&self, other: &DeviceFilter) -> bool {
+        self.multipath_component_detection == other.multipath_component_detection
+            && self.lists.len() == other.lists.len()
+            && self
+                .lists
+                .iter()
+                .zip(&other.lists)
+                .all(|((a, _), (b, _))| a == b)
+    }
+}
+
+impl DeviceFilter {
+    /// A filter from one list of patterns, such as `["a|/dev/sdb|",
+    /// "r|.*|"]`.
+    pub fn new(patterns: &[&str]) -> Result<DeviceFilter> {
+        let mut filter = DeviceFilter::default();
+        filter.add_list(patterns)?;
+        Ok(filter)
+    }
+
+    /// Add a list of patterns that devices must also be accepted by.
+    pub fn add_list(&mut self, patterns: &[&str]) -> Result<()> {
+        let rules = patterns
+            .iter()
+            .map(|pattern| parse_rule(pattern))
+            .collect::<Result<Vec<_>>>()?;
+        self.lists
+            .push((patterns.iter().map(|p| p.to_string()).collect(), rules));
+
+        Ok(())
+    }
+
+    /// Add the filters lvm.conf sets, `devices/global_filter` and
+    /// `devices/filter`, and follow its `devices/multipath_component_detection`.
+    pub fn add_lvm_conf(&mut self, lvm_conf: &LvmTextMap) -> Result<()> {
+        let devices = match lvm_conf.textmap_from_textmap("devices") {
+            Some(devices) => devices,
+            None => return Ok(()),
+        };
+
+        for key in &["global_filter", "filter"] {
+            let patterns: Vec<_> = match devices.get(*key) {
+                Some(Entry::String(pattern)) => vec![pattern.as_str()],
+                Some(Entry::List(list)) => list
+                    .iter()
+                    .filter_map(|entry| match entry {
+                        Entry::String(pattern) => Some(pattern.as_str()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => continue,
+            };
+            self.add_list(&patterns)?;
+        }
+        if let Some(detect) = devices.i64_from_textmap("multipath_component_detection") {
+            self.multipath_component_detection = detect != 0;
+        }
+
+        Ok(())
+    }
+
+    /// Set whether paths to multipath devices are rejected.
+    pub fn set_multipath_component_detection(&mut self, detect: bool) {
+        self.multipath_component_detection = detect;
+    }
+
+    /// Whether the device at `path` may be used.
+    pub fn accepts(&self, path: &Path) -> bool {
+        let mut names = vec![path.to_string_lossy().into_owned()];
+        if let Ok(real) = canonicalize(path) {
+            if real != path {
+                names.push(real.to_string_lossy().into_owned());
+            }
+        }
+
+        let listed = self.lists.iter().all(|(_, rules)| {
+            rules
+                .iter()
+                .find(|rule| names.iter().any(|name| rule.regex.is_match(name)))
+                .is_none_or(|rule| rule.accept)
+        });
+        if !listed {
+            return false;
+        }
+
+        !(self.multipath_component_detection
+            && Device::from_path(path).is_ok_and(is_multipath_component))
+    }
+}
+
+// Whether a device is a path to a multipath device: if a DM device that
+// multipathd created holds it.
+fn is_multipath_component(dev: Device) -> bool {
+    let holders = match read_dir(dev.to_sys_path().join("holders")) {
+        Ok(holders) => holders,
+        Err(_) => return false,
+    };
+
+    holders.filter_map(|entry| entry.ok()).any(|entry| {
+        read_to_string(entry.path().join("dm/uuid")).is_ok_and(|uuid| uuid.starts_with("mpath-"))
+    })
+}
@@ -1,16 +1,36 @@
 use std::borrow::Cow;
-use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io;
+use std::io::ErrorKind::Other;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
 
 use nix::fcntl::{flock, FlockArg};
 
-use crate::Result;
+use crate::{Error, Result};
 
 const LVM_LOCK_DIR: &str = "/run/lock/lvm";
 
-pub struct Flock {
+thread_local! {
+    // The locks this thread holds, so that taking one it already holds
+    // shares it instead of waiting on itself forever.
+    static HELD: RefCell<HashMap<PathBuf, Weak<HeldLock>>> = RefCell::new(HashMap::new());
+}
+
+struct HeldLock {
     _locked_file: File,
+    exclusive: bool,
+}
+
+/// A lock on a scope, as LVM2 takes. Locking a scope the thread already
+/// holds is allowed, and the lock is released when the last `Flock` for
+/// it is dropped. A thread holding a shared lock can't take the
+/// exclusive one.
+pub struct Flock {
+    _held: Rc<HeldLock>,
 }
 
 pub enum LockScope {
@@ -34,15 +54,38 @@ impl Flock {
             LockScope::VG(name) => Cow::Owned(PathBuf::from(format!("V_{}", name))),
         };
         pathbuf.push(filename);
+        let exclusive = lock_type == FlockArg::LockExclusive;
 
+        let held = HELD.with(|held| held.borrow().get(&pathbuf).and_then(Weak::upgrade));
+        if let Some(held) = held {
+            if exclusive && !held.exclusive {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!(
+                        "Can't lock {} exclusively while holding a shared lock on it",
+                        pathbuf.display()
+                    ),
+                )));
+            }
+            return Ok(Flock { _held: held });
+        }
+
+        create_dir_all(LVM_LOCK_DIR)?;
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(&pathbuf)?;
         flock(f.as_raw_fd(), lock_type)?;
-        Ok(Flock { _locked_file: f })
+
+        let held = Rc::new(HeldLock {
+            _locked_file: f,
+            exclusive,
+        });
+        HELD.with(|h| h.borrow_mut().insert(pathbuf, Rc::downgrade(&held)));
+        Ok(Flock { _held: held })
     }
 
-    // When the file is closed the lock is released.
+    // When the last Flock sharing the file is dropped, the file is
+    // closed and the lock is released.
 }
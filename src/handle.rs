@@ -0,0 +1,64 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Deferred-commit access to Volume Groups
+
+use std::ops::{Deref, DerefMut};
+
+use crate::{Result, VG};
+
+/// A handle to a VG whose changes are only written to the PVs when
+/// `flush()` is called.
+///
+/// If the handle is dropped with unflushed changes, they are rolled
+/// back: the VG and its LVs return to their state as of the last flush,
+/// including the VG's tags, profile and metadata copies, and its DM
+/// devices are put back as they were, as far as possible. Renamed
+/// devices get their old names, changed tables are reloaded, LVs
+/// activated since then are deactivated, and DM devices for LVs created
+/// since then are removed. LVs removed since then keep their DM devices
+/// until the removal is flushed.
+#[derive(Debug)]
+pub struct VgHandle {
+    vg: VG,
+}
+
+impl VgHandle {
+    /// Start deferring changes to the VG. Fails if the VG's state can't
+    /// be recorded to roll back to.
+    pub fn new(mut vg: VG) -> Result<VgHandle> {
+        vg.begin_deferred()?;
+        Ok(VgHandle { vg })
+    }
+
+    /// Write changes made since the last flush to the PVs.
+    pub fn flush(&mut self) -> Result<()> {
+        self.vg.flush_deferred()
+    }
+
+    /// Whether there are changes that have not been flushed.
+    pub fn is_dirty(&self) -> bool {
+        self.vg.is_dirty()
+    }
+}
+
+impl Deref for VgHandle {
+    type Target = VG;
+
+    fn deref(&self) -> &VG {
+        &self.vg
+    }
+}
+
+impl DerefMut for VgHandle {
+    fn deref_mut(&mut self) -> &mut VG {
+        &mut self.vg
+    }
+}
+
+impl Drop for VgHandle {
+    fn drop(&mut self) {
+        self.vg.rollback_deferred();
+    }
+}
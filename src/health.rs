@@ -0,0 +1,539 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A check of everything Melvin can see, for `Melvin::health`.
+//!
+//! Each PV's label and metadata areas are checked as by
+//! `PvHeader::verify`, then each VG found on them is read, and its PVs
+//! and active LVs are checked: that every PV is present and has the
+//! latest metadata, that thin pools are not too full, that RAID arrays
+//! are in sync, and that the kernel is running the tables the metadata
+//! gives. Nothing is changed.
+//!
+//! Some findings can be fixed safely without anyone deciding how, and
+//! say so with a `Repair`. When `Config::startup_repairs` is set, a
+//! `Melvin` makes those repairs as it is created, as allowed by the
+//! `RepairPolicy`, so that an unattended system recovers from a crash
+//! on its own. Interrupted pvmoves are only reported, as Melvin does not
+//! move data and they must be resumed with LVM2's pvmove.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use crate::context::{recover_vg, vg_metadata, DEV_DIR};
+use crate::parser::Entry;
+use crate::pvlabel::pvheader_scan_report_with_filter;
+use crate::status::{RaidStatus, ThinPoolMode, ThinPoolStatus};
+use crate::{
+    Config, Flock, LockScope, PvHeader, PvckProblem, Result, ScanProblem, TableLine, LV, VG,
+};
+
+/// How serious a finding is.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Severity {
+    /// Worth knowing, but nothing is wrong.
+    Info,
+    /// Something needs attention before it becomes a problem.
+    Warning,
+    /// Data is unavailable or at risk.
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Something `Melvin::health` found.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Finding {
+    /// How serious it is.
+    pub severity: Severity,
+    /// What it is about: a device path, a VG name, or an LV as "vg/lv".
+    pub subject: String,
+    /// What was found.
+    pub message: String,
+    /// The repair that fixes it, if it can be fixed safely.
+    pub repair: Option<Repair>,
+}
+
+/// A repair that `Melvin` can make on its own.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Repair {
+    /// Rewrite the metadata of the VG's PVs that have an old copy, with
+    /// `VG::repair_stale_metadata`.
+    StaleMetadata,
+    /// Load the LV's table again from the metadata.
+    RefreshTable,
+}
+
+/// Which repairs are made when a `Melvin` is created, if
+/// `Config::startup_repairs` is set.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RepairPolicy {
+    /// Make `Repair::StaleMetadata` repairs. Defaults to true.
+    pub resync_stale_metadata: bool,
+    /// Make `Repair::RefreshTable` repairs. Defaults to true.
+    pub refresh_tables: bool,
+}
+
+impl Default for RepairPolicy {
+    fn default() -> RepairPolicy {
+        RepairPolicy {
+            resync_stale_metadata: true,
+            refresh_tables: true,
+        }
+    }
+}
+
+impl RepairPolicy {
+    /// Whether `repair` may be made.
+    pub fn allows(&self, repair: Repair) -> bool {
+        match repair {
+            Repair::StaleMetadata => self.resync_stale_metadata,
+            Repair::RefreshTable => self.refresh_tables,
+        }
+    }
+}
+
+/// Shown as "<severity>: <subject>: <message>".
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.subject, self.message)
+    }
+}
+
+/// How full a thin pool may get before `Melvin::health` reports it,
+/// as fractions from 0 to 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HealthThresholds {
+    /// Data usage reported as a warning. Defaults to 0.8.
+    pub thin_data_warning: f64,
+    /// Data usage reported as critical. Defaults to 0.95.
+    pub thin_data_critical: f64,
+    /// Metadata usage reported as a warning. Defaults to 0.8.
+    pub thin_metadata_warning: f64,
+    /// Metadata usage reported as critical. Defaults to 0.95.
+    pub thin_metadata_critical: f64,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> HealthThresholds {
+        HealthThresholds {
+            thin_data_warning: 0.8,
+            thin_data_critical: 0.95,
+            thin_metadata_warning: 0.8,
+            thin_metadata_critical: 0.95,
+        }
+    }
+}
+
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn add(&mut self, severity: Severity, subject: &str, message: String) {
+        self.add_repairable(severity, subject, message, None);
+    }
+
+    fn add_repairable(
+        &mut self,
+        severity: Severity,
+        subject: &str,
+        message: String,
+        repair: Option<Repair>,
+    ) {
+        self.0.push(Finding {
+            severity,
+            subject: subject.to_string(),
+            message,
+            repair,
+        });
+    }
+}
+
+/// Check the PVs that `config`'s filter accepts, and the VGs on them.
+/// Findings are returned most serious first.
+pub(crate) fn check(config: &Config, thresholds: &HealthThresholds) -> Result<Vec<Finding>> {
+    let mut findings = Findings(Vec::new());
+
+    let report = pvheader_scan_report_with_filter(&[Path::new(DEV_DIR)], &config.filter)?;
+    for (path, problem) in &report.skipped {
+        let subject = path.to_string_lossy();
+        match problem {
+            ScanProblem::Io(e) => findings.add(
+                Severity::Info,
+                &subject,
+                format!("could not be scanned: {:?}", e),
+            ),
+            ScanProblem::BadLabel(e) => findings.add(
+                Severity::Critical,
+                &subject,
+                format!("has a damaged PV label: {:?}", e),
+            ),
+            ScanProblem::DuplicateUuid(uuid, other) => findings.add(
+                Severity::Critical,
+                &subject,
+                format!("has the same PV UUID {} as {}", uuid, other.display()),
+            ),
+            ScanProblem::Filtered => {}
+        }
+    }
+
+    let mut vg_names = BTreeSet::new();
+    for path in &report.pvs {
+        let subject = path.to_string_lossy();
+        match PvHeader::verify(path) {
+            Ok(pvck) => {
+                for problem in pvck.problems {
+                    findings.add(
+                        problem_severity(&problem),
+                        &subject,
+                        format!("{:?}", problem),
+                    );
+                }
+            }
+            Err(e) => findings.add(
+                Severity::Warning,
+                &subject,
+                format!("could not be checked: {:?}", e),
+            ),
+        }
+
+        if let Ok(map) = PvHeader::find_in_dev(path).and_then(|pvh| pvh.read_metadata()) {
+            vg_names.extend(
+                map.iter()
+                    .filter(|(_, value)| matches!(value, Entry::TextMap(_)))
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+    }
+
+    for name in &vg_names {
+        check_vg(name, config, thresholds, &mut findings);
+    }
+
+    let mut findings = findings.0;
+    findings.sort_by_key(|finding| Reverse(finding.severity));
+
+    Ok(findings)
+}
+
+// Problems with a metadata area still leave the PV usable, and its VG
+// readable from the other copies.
+fn problem_severity(problem: &PvckProblem) -> Severity {
+    match *problem {
+        PvckProblem::LabelMisplaced(_) | PvckProblem::ExtraLabel(_) => Severity::Info,
+        PvckProblem::MdaHeaderCrc(_)
+        | PvckProblem::MdaMagic(_)
+        | PvckProblem::MdaVersion(_, _)
+        | PvckProblem::MdaLocation(_)
+        | PvckProblem::RlocnOutOfRange(_, _)
+        | PvckProblem::MdaTextCrc(_) => Severity::Warning,
+        _ => Severity::Critical,
+    }
+}
+
+fn check_vg(name: &str, config: &Config, thresholds: &HealthThresholds, findings: &mut Findings) {
+    let vg = match recover_vg(name, config).and_then(|_| {
+        let _lock = Flock::lock_shared(LockScope::VG(name.to_string()))?;
+        let map = vg_metadata(name, config)?;
+        VG::from_textmap_with_config(name, &map, config.clone())
+    }) {
+        Ok(vg) => vg,
+        Err(e) => {
+            findings.add(
+                Severity::Critical,
+                name,
+                format!("could not be read: {:?}", e),
+            );
+            return;
+        }
+    };
+
+    for dev in vg.pv_list() {
+        if let Some(pv) = vg.pv_get(dev) {
+            if pv.path().is_none() {
+                findings.add(
+                    Severity::Critical,
+                    name,
+                    format!("PV {} ({}) is missing", pv.id, pv.device),
+                );
+            }
+        }
+    }
+
+    match vg.stale_pvs() {
+        Ok(stale) => {
+            for dev in stale {
+                findings.add_repairable(
+                    Severity::Warning,
+                    name,
+                    format!("PV {} has metadata older than seqno {}", dev, vg.seqno()),
+                    Some(Repair::StaleMetadata),
+                );
+            }
+        }
+        Err(e) => findings.add(
+            Severity::Warning,
+            name,
+            format!("could not compare metadata seqnos: {:?}", e),
+        ),
+    }
+
+    for lv_name in vg.lv_list() {
+        if let Some(lv) = vg.lv_get(&lv_name) {
+            if lv.status.iter().any(|s| s == "PVMOVE") {
+                findings.add(
+                    Severity::Warning,
+                    &format!("{}/{}", name, lv_name),
+                    "pvmove was interrupted; resume it with LVM2's pvmove".to_string(),
+                );
+            }
+            if lv.device.is_some() {
+                check_lv(&vg, lv, thresholds, findings);
+            }
+        }
+    }
+}
+
+fn check_lv(vg: &VG, lv: &LV, thresholds: &HealthThresholds, findings: &mut Findings) {
+    let subject = format!("{}/{}", vg.name(), lv.name);
+
+    match vg
+        .lv_loaded_table(&lv.name)
+        .and_then(|loaded| Ok((loaded, vg.lv_table(lv)?)))
+    {
+        Ok((loaded, expected)) => {
+            if !tables_match(&loaded, &expected) {
+                findings.add_repairable(
+                    Severity::Warning,
+                    &subject,
+                    "the loaded table differs from the metadata".to_string(),
+                    Some(Repair::RefreshTable),
+                );
+            }
+        }
+        Err(e) => findings.add(
+            Severity::Warning,
+            &subject,
+            format!("could not compare the loaded table: {:?}", e),
+        ),
+    }
+
+    let status = match vg.lv_status(&lv.name) {
+        Ok(status) => status,
+        Err(e) => {
+            findings.add(
+                Severity::Warning,
+                &subject,
+                format!("could not get status: {:?}", e),
+            );
+            return;
+        }
+    };
+    for (_, _, target, params) in &status {
+        if target == "thin-pool" {
+            match params.parse::<ThinPoolStatus>() {
+                Ok(pool) => check_thinpool(&subject, &pool, thresholds, findings),
+                Err(_) => findings.add(
+                    Severity::Critical,
+                    &subject,
+                    format!("thin pool has failed: {}", params),
+                ),
+            }
+        } else if target.starts_with("raid") {
+            match params.parse::<RaidStatus>() {
+                Ok(raid) => check_raid(&subject, &raid, findings),
+                Err(e) => findings.add(
+                    Severity::Warning,
+                    &subject,
+                    format!("could not parse RAID status: {:?}", e),
+                ),
+            }
+        }
+    }
+}
+
+// Tables match if their targets cover the same ranges. Parameters are
+// only compared for linear and striped targets, as the kernel reports
+// those of others in its own form, with features reordered or added.
+fn tables_match(loaded: &[TableLine], expected: &[TableLine]) -> bool {
+    loaded.len() == expected.len()
+        && loaded.iter().zip(expected).all(|(l, e)| {
+            l.0 == e.0
+                && l.1 == e.1
+                && l.2 == e.2
+                && (!["linear", "striped"].contains(&l.2.as_str()) || l.3.trim() == e.3.trim())
+        })
+}
+
+fn check_thinpool(
+    subject: &str,
+    pool: &ThinPoolStatus,
+    thresholds: &HealthThresholds,
+    findings: &mut Findings,
+) {
+    if pool.needs_check {
+        findings.add(
+            Severity::Critical,
+            subject,
+            "thin pool metadata needs thin_check".to_string(),
+        );
+    }
+    match pool.mode {
+        ThinPoolMode::ReadWrite => {}
+        ThinPoolMode::OutOfDataSpace => findings.add(
+            Severity::Critical,
+            subject,
+            "thin pool is out of data space".to_string(),
+        ),
+        ThinPoolMode::ReadOnly => findings.add(
+            Severity::Critical,
+            subject,
+            "thin pool is read only".to_string(),
+        ),
+    }
+
+    for (what, ratio, warning, critical) in &[
+        (
+            "data",
+            pool.data_ratio(),
+            thresholds.thin_data_warning,
+            thresholds.thin_data_critical,
+        ),
+        (
+            "metadata",
+            pool.metadata_ratio(),
+            thresholds.thin_metadata_warning,
+            thresholds.thin_metadata_critical,
+        ),
+    ] {
+        let severity = if ratio >= critical {
+            Severity::Critical
+        } else if ratio >= warning {
+            Severity::Warning
+        } else {
+            continue;
+        };
+        findings.add(
+            severity,
+            subject,
+            format!("thin pool {} is {:.1}% full", what, ratio * 100.0),
+        );
+    }
+}
+
+fn check_raid(subject: &str, raid: &RaidStatus, findings: &mut Findings) {
+    if raid.is_degraded() {
+        findings.add(
+            Severity::Critical,
+            subject,
+            "RAID array is degraded".to_string(),
+        );
+    }
+    if raid.sync_ratio() < 1.0 {
+        findings.add(
+            Severity::Warning,
+            subject,
+            format!(
+                "RAID array is {:.1}% in sync ({})",
+                raid.sync_ratio() * 100.0,
+                raid.sync_action.as_deref().unwrap_or("resync")
+            ),
+        );
+    }
+    if let Some(count) = raid.mismatch_count.filter(|count| *count > 0) {
+        findings.add(
+            Severity::Warning,
+            subject,
+            format!("RAID array has {} mismatched sectors", count),
+        );
+    }
+}
+
+/// Check the system as `check` does, and make the repairs that the
+/// findings call for and `policy` allows. Returns a log of what was
+/// found and what was done about it, one line each.
+pub(crate) fn sweep(config: &Config, policy: &RepairPolicy) -> Vec<String> {
+    let findings = match check(config, &HealthThresholds::default()) {
+        Ok(findings) => findings,
+        Err(e) => return vec![format!("health check failed: {:?}", e)],
+    };
+
+    let mut log = Vec::new();
+    // The LVs to refresh, or None to resync the metadata, by VG.
+    let mut repairs: BTreeMap<String, BTreeSet<Option<String>>> = BTreeMap::new();
+    for finding in &findings {
+        log.push(finding.to_string());
+        match finding.repair {
+            Some(repair) if policy.allows(repair) => {
+                let (vg_name, lv_name) = match repair {
+                    Repair::StaleMetadata => (finding.subject.as_str(), None),
+                    Repair::RefreshTable => match finding.subject.split_once('/') {
+                        Some((vg_name, lv_name)) => (vg_name, Some(lv_name.to_string())),
+                        None => continue,
+                    },
+                };
+                repairs
+                    .entry(vg_name.to_string())
+                    .or_default()
+                    .insert(lv_name);
+            }
+            Some(repair) => log.push(format!(
+                "{}: {:?} not allowed by policy, left alone",
+                finding.subject, repair
+            )),
+            None => {}
+        }
+    }
+
+    for (vg_name, lvs) in repairs {
+        if let Err(e) = repair_vg(&vg_name, &lvs, config, &mut log) {
+            log.push(format!("{}: repair failed: {:?}", vg_name, e));
+        }
+    }
+
+    log
+}
+
+fn repair_vg(
+    name: &str,
+    lvs: &BTreeSet<Option<String>>,
+    config: &Config,
+    log: &mut Vec<String>,
+) -> Result<()> {
+    let _lock = Flock::lock_exclusive(LockScope::VG(name.to_string()))?;
+    recover_vg(name, config)?;
+    let map = vg_metadata(name, config)?;
+    let mut vg = VG::from_textmap_with_config(name, &map, config.clone())?;
+
+    // The metadata is made consistent before any tables are loaded from
+    // it.
+    if lvs.contains(&None) {
+        for dev in vg.repair_stale_metadata()? {
+            log.push(format!("{}: rewrote metadata on PV {}", name, dev));
+        }
+    }
+    for lv_name in lvs.iter().flatten() {
+        if vg.lv_get(lv_name).is_none() {
+            continue;
+        }
+        match vg.lv_refresh(lv_name) {
+            Ok(()) => log.push(format!("{}/{}: reloaded table", name, lv_name)),
+            Err(e) => log.push(format!(
+                "{}/{}: could not reload table: {:?}",
+                name, lv_name, e
+            )),
+        }
+    }
+
+    Ok(())
+}
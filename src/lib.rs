@@ -7,18 +7,81 @@
 //! Melvin is a library for configuring logical volumes in the style of
 //! [LVM](https://www.sourceware.org/lvm2/)
 
+mod backup;
+pub mod blockdev;
+pub mod boot;
+pub mod capabilities;
+#[cfg(feature = "lvm2-compat")]
+pub mod compat;
+mod config;
+mod context;
+mod crypt;
+#[cfg(feature = "daemon-clients")]
+pub mod daemon;
+mod device;
+mod diff;
+mod dm;
+#[cfg(feature = "raw-dm")]
+mod dm_ioctl;
 mod error;
+mod filter;
 mod flock;
+mod handle;
+mod health;
 mod lv;
+#[cfg(feature = "daemon-clients")]
+pub mod lvmetad;
+mod lvreader;
+mod migrate;
+#[cfg(feature = "monitor")]
+mod monitor;
 pub mod parser;
+mod profile;
 mod pv;
+mod pvck;
 mod pvlabel;
+#[cfg(feature = "python")]
+mod python;
+pub mod report;
+mod retry;
+mod signature;
+mod size;
+#[cfg(feature = "spec")]
+pub mod spec;
+mod state;
+pub mod status;
+mod throttle;
 mod util;
 mod vg;
 
+pub use backup::BackupConfig;
+pub use config::Config;
+pub use context::Melvin;
+pub use crypt::{CryptDev, CryptParams, KeyType};
+pub use device::{Device, DeviceExt};
+pub use diff::{DiffTarget, FlagChange, LvResize, VgDiff};
+pub use dm::{set_dm_backend, DmBackendKind, TableLine};
 pub use error::{Error, Result};
+pub use filter::DeviceFilter;
 pub use flock::{Flock, LockScope};
-pub use lv::LV;
+pub use handle::VgHandle;
+pub use health::{Finding, HealthThresholds, Repair, RepairPolicy, Severity};
+pub use lv::{dm_layer_name, dm_name, segment, split_dm_name, ActivationName, ScheduleHints, LV};
+pub use lvreader::LvReader;
+pub use migrate::METADATA_VERSION;
+pub use profile::Profile;
 pub use pv::PV;
-pub use pvlabel::{pvheader_scan, PvHeader};
-pub use vg::VG;
+pub use pvck::{PvckProblem, PvckReport};
+pub use pvlabel::{
+    pvheader_scan, pvheader_scan_report, pvheader_scan_report_with_filter, recover_commit,
+    DeviceClass, MdaInfo, MdaUsage, MetadataGeneration, PvArea, PvHeader, RawLocn, ScanProblem,
+    ScanReport,
+};
+pub use retry::RetryPolicy;
+pub use size::Size;
+pub use state::{LvState, SegmentState, VgState};
+pub use throttle::{IoPriority, Throttle, Throttles};
+pub use vg::{
+    AllocOptions, AllocPlan, AllocPolicy, AllocRequest, CommitHook, DeactivateOptions, ExtentRange,
+    ExtentUsage, MetadataHeadroom, ThinPoolOptions, TrimReport, VG,
+};
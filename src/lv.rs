@@ -5,16 +5,114 @@
 //! Logical Volumes
 
 use std::collections::BTreeMap;
-use std::io;
-use std::io::ErrorKind::Other;
-
-use devicemapper::{
-    Device, DmName, LinearDev, LinearDevTargetParams, LinearTargetParams, Sectors, TargetLine, DM,
-};
+use std::fs::{File, OpenOptions};
 
+use crate::blockdev;
+use crate::device::{Device, DeviceExt};
+use crate::dm::TableLine;
 use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
-use crate::PV;
+use crate::throttle::{Limiter, Throttle};
 use crate::{Error, Result};
+use crate::{PV, VG};
+
+/// The LV flag that keeps an LV from being activated along with the
+/// rest of its VG.
+pub(crate) const ACTIVATION_SKIP: &str = "ACTIVATION_SKIP";
+
+/// The LV flag that keeps an LV from being activated automatically.
+pub(crate) const NOAUTOACTIVATE: &str = "NOAUTOACTIVATE";
+
+// Keys of an LV's scheduling hints in its metadata. Other hints are
+// stored under SCHEDULE_PREFIX followed by their key.
+const SNAPSHOT_INTERVAL_KEY: &str = "melvin_snapshot_interval";
+const SNAPSHOT_RETENTION_KEY: &str = "melvin_snapshot_retention";
+const SCHEDULE_PREFIX: &str = "melvin_schedule_";
+
+/// Hints for a scheduler that takes snapshots of an LV, kept in the
+/// LV's metadata so that the policy lives with the LV. Melvin only
+/// stores them; acting on them is up to the scheduler.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ScheduleHints {
+    /// How often to take a snapshot, in seconds.
+    pub snapshot_interval: Option<u64>,
+    /// How many snapshots to keep.
+    pub snapshot_retention: Option<u64>,
+    /// Any other hints, by key. Keys are made of ASCII letters, digits
+    /// and "_".
+    pub other: BTreeMap<String, String>,
+}
+
+impl ScheduleHints {
+    /// Whether no hints are set.
+    pub fn is_empty(&self) -> bool {
+        self.snapshot_interval.is_none()
+            && self.snapshot_retention.is_none()
+            && self.other.is_empty()
+    }
+
+    fn from_textmap(map: &LvmTextMap) -> ScheduleHints {
+        let number = |key| {
+            map.i64_from_textmap(key)
+                .filter(|x| *x >= 0)
+                .map(|x| x as u64)
+        };
+        let other = map
+            .iter()
+            .filter_map(|(key, value)| match value {
+                Entry::String(ref value) if key.starts_with(SCHEDULE_PREFIX) => {
+                    Some((key[SCHEDULE_PREFIX.len()..].to_string(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        ScheduleHints {
+            snapshot_interval: number(SNAPSHOT_INTERVAL_KEY),
+            snapshot_retention: number(SNAPSHOT_RETENTION_KEY),
+            other,
+        }
+    }
+
+    fn to_textmap(&self, map: &mut LvmTextMap) {
+        if let Some(interval) = self.snapshot_interval {
+            map.insert(
+                SNAPSHOT_INTERVAL_KEY.to_string(),
+                Entry::Number(interval as i64),
+            );
+        }
+        if let Some(retention) = self.snapshot_retention {
+            map.insert(
+                SNAPSHOT_RETENTION_KEY.to_string(),
+                Entry::Number(retention as i64),
+            );
+        }
+        for (key, value) in &self.other {
+            map.insert(
+                format!("{}{}", SCHEDULE_PREFIX, key),
+                Entry::String(value.clone()),
+            );
+        }
+    }
+
+    // Make sure the hints can be stored in the metadata.
+    pub(crate) fn check(&self) -> Result<()> {
+        let too_big = |x: Option<u64>| x.is_some_and(|x| x > i64::MAX as u64);
+        if too_big(self.snapshot_interval) || too_big(self.snapshot_retention) {
+            return Err(Error::InvalidArgument(
+                "Schedule hint values must fit in an i64".to_string(),
+            ));
+        }
+        if let Some(key) = self.other.keys().find(|key| {
+            key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }) {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid schedule hint key {}",
+                key
+            )));
+        }
+        Ok(())
+    }
+}
 
 /// A Logical Volume that is created from a Volume Group.
 #[derive(Debug)]
@@ -27,14 +125,27 @@ pub struct LV {
     pub status: Vec<String>,
     /// Flags.
     pub flags: Vec<String>,
+    /// Tags.
+    pub tags: Vec<String>,
     /// Created by this host.
     pub creation_host: String,
     /// Created at this Unix time.
     pub creation_time: i64,
+    /// Hints for a snapshot scheduler.
+    pub schedule: ScheduleHints,
+    /// The name of the LV's profile, whose settings take precedence
+    /// over the VG's profile.
+    pub profile: Option<String>,
     /// A list of the segments comprising the LV.
     pub segments: Vec<Box<dyn segment::Segment>>,
-    /// The major/minor number of the LV.
-    pub device: LinearDev,
+    /// The major/minor number of the LV's DM device, if it is active.
+    pub device: Option<Device>,
+    // Active DM devices the LV's device is stacked on, by the suffix of
+    // their names, e.g. "real" for the origin of a snapshot.
+    pub(crate) layers: BTreeMap<String, Device>,
+    // The name of the LV's DM device, if it was activated under a name
+    // other than the usual one. It is not kept in the metadata.
+    pub(crate) activation_name: Option<String>,
 }
 
 impl LV {
@@ -42,6 +153,98 @@ impl LV {
     pub fn used_extents(&self) -> u64 {
         self.segments.iter().map(|x| x.extent_count()).sum()
     }
+
+    /// Whether the LV has the ACTIVATION_SKIP flag, so that it is left
+    /// out when all of the VG's LVs are activated.
+    pub fn activation_skip(&self) -> bool {
+        self.flags.iter().any(|f| f == ACTIVATION_SKIP)
+    }
+
+    /// Whether the LV is activated automatically, e.g. at boot. This is
+    /// so unless it has the NOAUTOACTIVATE flag.
+    pub fn autoactivate(&self) -> bool {
+        !self.flags.iter().any(|f| f == NOAUTOACTIVATE)
+    }
+
+    /// Discard the LV's unused space. If the LV holds a mounted
+    /// filesystem, it is trimmed. Otherwise, if it is a thin LV, the
+    /// whole LV is discarded, losing its contents. Returns how many bytes
+    /// were discarded.
+    pub fn trim(&self) -> Result<u64> {
+        self.trim_throttled(&Throttle::default())
+    }
+
+    /// Like `trim`, but discarding a piece at a time, within the limits
+    /// of `throttle`.
+    pub fn trim_throttled(&self, throttle: &Throttle) -> Result<u64> {
+        let device = self
+            .device
+            .ok_or_else(|| Error::NotPermitted(format!("LV {} is not active", self.name)))?;
+        let mut limiter = Limiter::new(throttle)?;
+
+        if let Some(path) = blockdev::mount_point(device)? {
+            let dir = File::open(path)?;
+            let size = blockdev::size(&File::open(device.to_dev_path())?)?;
+            let chunk = limiter.chunk_size();
+            let mut trimmed = 0;
+            let mut start = 0;
+            while start < size {
+                let done = blockdev::trim_fs_range(&dir, start, chunk)?;
+                limiter.account(done);
+                trimmed += done;
+                start = start.saturating_add(chunk);
+            }
+            return Ok(trimmed);
+        }
+
+        if !self.segments.iter().any(|seg| seg.dm_type() == "thin") {
+            return Err(Error::Unsupported(
+                "LV is not mounted, and only thin LVs are discarded whole".to_string(),
+            ));
+        }
+
+        let f = OpenOptions::new().write(true).open(device.to_dev_path())?;
+        let size = blockdev::size(&f)?;
+        let mut start = 0;
+        while start < size {
+            let len = limiter.chunk_size().min(size - start);
+            blockdev::discard(&f, start, len)?;
+            limiter.account(len);
+            start += len;
+        }
+
+        Ok(size)
+    }
+
+    /// The DM table Melvin loads for the LV's segments, one line per
+    /// segment, as `dmsetup table` shows them: start and length in
+    /// sectors, target type, and parameters. For an LV stacked on a
+    /// layer, such as a thin pool or snapshot origin, this is the
+    /// layer's table, e.g. that of "vg-pool-tpool".
+    ///
+    /// Tables refer to the devices of the LVs an LV is built on, so this
+    /// fails if any of them is not active.
+    pub fn dm_table(&self, vg: &VG) -> Result<Vec<TableLine>> {
+        vg.lv_table(self)
+    }
+
+    /// Returns where the LV's data is on its PVs, in LV order, so that
+    /// it can be read directly from the PVs without activating the LV.
+    ///
+    /// Fails if any segment's data is not stored directly on PVs.
+    pub fn extent_map(&self, vg: &VG) -> Result<Vec<segment::PhysicalRange>> {
+        let mut v = Vec::new();
+        for seg in &self.segments {
+            let ranges = seg.extent_map(vg).ok_or_else(|| {
+                Error::Unsupported(format!(
+                    "LV {} has segments not stored directly on PVs",
+                    self.name
+                ))
+            })?;
+            v.extend(ranges);
+        }
+        Ok(v)
+    }
 }
 
 impl PartialEq for LV {
@@ -50,6 +253,62 @@ impl PartialEq for LV {
     }
 }
 
+/// The name of the DM device for an LV. Hyphens within the VG and LV
+/// names are doubled, so the two can be told apart.
+pub fn dm_name(vg_name: &str, lv_name: &str) -> String {
+    format!(
+        "{}-{}",
+        vg_name.replace("-", "--"),
+        lv_name.replace("-", "--")
+    )
+}
+
+/// The name of the DM device for a layer beneath an LV, such as "real"
+/// for the original table of a snapshot origin.
+pub fn dm_layer_name(vg_name: &str, lv_name: &str, layer: &str) -> String {
+    format!("{}-{}", dm_name(vg_name, lv_name), layer)
+}
+
+/// How to name an LV's DM device when activating it, e.g. so that a
+/// container's activations don't collide with the host's.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ActivationName {
+    /// The usual name, as `dm_name` gives.
+    Default,
+    /// The usual name with a prefix, e.g. "ctr1-" for "ctr1-vg0-data".
+    Prefix(String),
+    /// This name instead.
+    Custom(String),
+}
+
+/// Split the name of a DM device for an LV into the VG name, the LV
+/// name, and the layer, if any. Returns None if the name is not in the
+/// form `dm_name` or `dm_layer_name` produce.
+pub fn split_dm_name(name: &str) -> Option<(String, String, Option<String>)> {
+    let mut parts = vec![String::new()];
+    let mut chars = name.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                parts.last_mut().expect("never empty").push('-');
+            }
+            '-' => parts.push(String::new()),
+            c => parts.last_mut().expect("never empty").push(c),
+        }
+    }
+
+    if parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    let mut parts = parts.into_iter();
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(vg), Some(lv), layer, None) => Some((vg, lv, layer)),
+        _ => None,
+    }
+}
+
 pub fn used_areas(lv: &LV) -> Vec<(Device, u64, u64)> {
     let mut v = Vec::new();
     for seg in &lv.segments {
@@ -58,28 +317,27 @@ pub fn used_areas(lv: &LV) -> Vec<(Device, u64, u64)> {
     v
 }
 
-/// Construct an LV from an LvmTextMap.
-pub fn from_textmap(
-    name: &str,
-    vg_name: &str,
-    map: &LvmTextMap,
-    pvs: &BTreeMap<String, PV>,
-) -> Result<LV> {
-    let err = || Error::Io(io::Error::new(Other, "lv textmap parsing error"));
+/// Construct an LV from an LvmTextMap. The LV is not activated.
+pub fn from_textmap(name: &str, map: &LvmTextMap, pvs: &BTreeMap<String, PV>) -> Result<LV> {
+    let err = || Error::Metadata(format!("LV {} is missing a required value", name));
 
     let id = map.string_from_textmap("id").ok_or_else(err)?;
     let creation_host = map.string_from_textmap("creation_host").ok_or_else(err)?;
     let creation_time = map.i64_from_textmap("creation_time").ok_or_else(err)?;
     let segment_count = map.i64_from_textmap("segment_count").ok_or_else(err)?;
 
-    let segments: Vec<_> = (0..segment_count)
-        .filter_map(|num| {
-            let name = format!("segment{}", num + 1);
-            map.textmap_from_textmap(&name)
-                .map(|seg_dict| segment::from_textmap(seg_dict, pvs))
+    // A segment that can't be read fails the whole LV, rather than it
+    // being left out and its extents looking free.
+    let segments = (0..segment_count)
+        .map(|num| {
+            let seg_name = format!("segment{}", num + 1);
+            let seg_dict = map
+                .textmap_from_textmap(&seg_name)
+                .ok_or_else(|| Error::Metadata(format!("LV {} has no {}", name, seg_name)))?;
+            segment::from_textmap(seg_dict, pvs)
+                .map_err(|e| Error::Metadata(format!("LV {} {}: {}", name, seg_name, e)))
         })
-        .filter_map(|seg| seg.ok())
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
 
     let status = status_from_textmap(map)?;
 
@@ -93,34 +351,33 @@ pub fn from_textmap(
         })
         .collect();
 
-    let dev_name = format!("{}-{}", vg_name.replace("-", "--"), name.replace("-", "--"));
-
-    let dm = DM::new()?;
-    let mut logical_start_offset = Sectors(0);
-
-    let mut lines = Vec::new();
-    for segment in &segments {
-        // TODO: sketchy [0]
-        let (dev, off, len) = segment.used_areas()[0];
-        // TODO: need to convert from extents to segments???
-        lines.push(TargetLine::new(
-            logical_start_offset,
-            len.into(),
-            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, off.into())),
-        ));
-        logical_start_offset += len.into();
-    }
-    let linear_dev = LinearDev::setup(&dm, DmName::new(&dev_name)?, None, lines)?;
+    // "tags" may be absent
+    let tags: Vec<_> = map
+        .list_from_textmap("tags")
+        .map(|list| {
+            list.iter()
+                .filter_map(|item| match item {
+                    Entry::String(ref x) => Some(x.clone()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
 
     Ok(LV {
         name: name.to_string(),
         id: id.to_string(),
         status,
         flags,
+        tags,
         creation_host: creation_host.to_string(),
         creation_time,
+        schedule: ScheduleHints::from_textmap(map),
+        profile: map.string_from_textmap("profile").map(|p| p.to_string()),
         segments,
-        device: linear_dev,
+        device: None,
+        layers: BTreeMap::new(),
+        activation_name: None,
     })
 }
 
@@ -139,6 +396,13 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
         Entry::List(lv.flags.iter().map(|x| Entry::String(x.clone())).collect()),
     );
 
+    if !lv.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(lv.tags.iter().map(|x| Entry::String(x.clone())).collect()),
+        );
+    }
+
     map.insert(
         "creation_host".to_string(),
         Entry::String(lv.creation_host.clone()),
@@ -148,6 +412,12 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
         Entry::Number(lv.creation_time as i64),
     );
 
+    lv.schedule.to_textmap(&mut map);
+
+    if let Some(ref profile) = lv.profile {
+        map.insert("profile".to_string(), Entry::String(profile.clone()));
+    }
+
     map.insert(
         "segment_count".to_string(),
         Entry::Number(lv.segments.len() as i64),
@@ -164,18 +434,60 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
 }
 
 pub mod segment {
+    use std::any::Any;
     use std::collections::BTreeMap;
     use std::fmt;
     use std::io::Error;
     use std::io::ErrorKind::Other;
     use std::io::Result;
 
-    use devicemapper::Device;
-
+    use crate::device::Device;
     use crate::parser::{Entry, LvmTextMap, TextMapOps};
     use crate::PV;
     use crate::VG;
 
+    /// Where a range of an LV's sectors is stored on PVs.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct PhysicalRange {
+        /// The first sector of the range, from the start of the LV.
+        pub lv_start: u64,
+        /// How many sectors are in the range.
+        pub length: u64,
+        /// The PV devices the range is striped across, each with the
+        /// sector its stripe starts at. With one, the range is stored
+        /// contiguously.
+        pub stripes: Vec<(Device, u64)>,
+        /// How many sectors go to each stripe in turn. Unused with one
+        /// stripe.
+        pub stripe_size: u64,
+    }
+
+    impl PhysicalRange {
+        /// Where the sector `offset` sectors into the range is stored:
+        /// its PV device, its sector on the device, and how many sectors
+        /// from there are stored contiguously.
+        pub fn locate(&self, offset: u64) -> (Device, u64, u64) {
+            let left = self.length - offset;
+            if let [(device, start)] = self.stripes[..] {
+                return (device, start + offset, left);
+            }
+
+            // Chunks of stripe_size go to each stripe round-robin, so
+            // each row of chunks takes one from every stripe.
+            let count = self.stripes.len() as u64;
+            let chunk = offset / self.stripe_size;
+            let within = offset % self.stripe_size;
+            let (device, start) = self.stripes[(chunk % count) as usize];
+            let row = chunk / count;
+
+            (
+                device,
+                start + row * self.stripe_size + within,
+                (self.stripe_size - within).min(left),
+            )
+        }
+    }
+
     /// Used to treat segment types polymorphically
     pub trait Segment: fmt::Debug {
         /// Convert this segment to an LvmTextMap.
@@ -186,18 +498,58 @@ pub mod segment {
         fn extent_count(&self) -> u64;
         /// Returns which PVs the segment depends on.
         fn pv_dependencies(&self) -> Vec<Device>;
+        /// Returns the names of other LVs in the VG the segment depends on.
+        fn lv_dependencies(&self) -> Vec<String>;
         /// Returns areas that make up the segment.
         fn used_areas(&self) -> Vec<(Device, u64, u64)>;
         /// Returns the name of the DM target that handles this segment.
         fn dm_type(&self) -> &'static str;
         /// Generates the parameters to send to DM for this segment.
-        fn dm_params(&self, vg: &VG) -> String;
+        /// Fails if an LV or PV it refers to by device is not active or
+        /// not in the VG.
+        fn dm_params(&self, vg: &VG) -> Result<String>;
+        /// Returns where the segment's data is stored on PVs, in LV order,
+        /// or None if it is not stored directly on PVs.
+        fn extent_map(&self, vg: &VG) -> Option<Vec<PhysicalRange>>;
+        /// Returns whether an LV with this segment may only be activated
+        /// read-only.
+        fn read_only(&self) -> bool {
+            false
+        }
+        /// Allows access to the concrete segment type.
+        fn as_any(&self) -> &dyn Any;
+        /// Allows mutable access to the concrete segment type.
+        fn as_any_mut(&mut self) -> &mut dyn Any;
+    }
+
+    // The device of an active LV that a segment's table refers to.
+    fn active_device(vg: &VG, lv_name: &str) -> Result<Device> {
+        vg.lv_get(lv_name)
+            .and_then(|lv| lv.device)
+            .ok_or_else(|| Error::new(Other, format!("LV {} is not active", lv_name)))
+    }
+
+    // The device of a layer beneath an active LV, such as a thin pool's
+    // "tpool".
+    fn active_layer(vg: &VG, lv_name: &str, layer: &str) -> Result<Device> {
+        vg.lv_get(lv_name)
+            .and_then(|lv| lv.layers.get(layer).copied())
+            .ok_or_else(|| Error::new(Other, format!("LV {} is not active", lv_name)))
     }
 
     pub fn from_textmap(map: &LvmTextMap, pvs: &BTreeMap<String, PV>) -> Result<Box<dyn Segment>> {
         match map.string_from_textmap("type") {
             Some("striped") => StripedSegment::from_textmap(map, pvs),
-            _ => unimplemented!(),
+            Some("snapshot") => SnapshotSegment::from_textmap(map),
+            Some("thin-pool") => ThinPoolSegment::from_textmap(map),
+            Some("thin") => ThinSegment::from_textmap(map),
+            Some("cache-pool") => CachePoolSegment::from_textmap(map),
+            Some("cache") => CacheSegment::from_textmap(map),
+            Some("writecache") => WritecacheSegment::from_textmap(map),
+            Some("mirror") => MirrorSegment::from_textmap(map),
+            Some(x) if x.starts_with("raid5") => Raid5Segment::from_textmap(map),
+            Some(x) if x.starts_with("raid6") => Raid6Segment::from_textmap(map),
+            _ => OpaqueSegment::from_textmap(map),
         }
     }
 
@@ -222,6 +574,9 @@ pub mod segment {
             let err = || Error::new(Other, "striped segment textmap parsing error");
 
             let stripe_list = map.list_from_textmap("stripes").ok_or_else(err)?;
+            if stripe_list.is_empty() || stripe_list.len() % 2 != 0 {
+                return Err(err());
+            }
 
             let mut stripes = Vec::new();
             for slc in stripe_list.chunks(2) {
@@ -239,12 +594,19 @@ pub mod segment {
                 stripes.push((dev, val as u64));
             }
 
+            // Only needed with more than one stripe.
+            let stripe_size = map.i64_from_textmap("stripe_size").map(|x| x as u64);
+            match stripe_size {
+                Some(0) => return Err(err()),
+                None if stripes.len() > 1 => return Err(err()),
+                _ => {}
+            }
+
             Ok(Box::new(StripedSegment {
                 start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
                 extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
                 stripes,
-                // optional
-                stripe_size: map.i64_from_textmap("start_extent").map(|x| x as u64),
+                stripe_size,
             }))
         }
     }
@@ -298,11 +660,17 @@ pub mod segment {
             self.stripes.iter().map(|&(dev, _)| dev).collect()
         }
 
+        fn lv_dependencies(&self) -> Vec<String> {
+            Vec::new()
+        }
+
         // returns (device, start_extent, length)
+        // Each stripe holds an equal share of the segment's extents.
         fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            let area_len = self.extent_count / self.stripes.len() as u64;
             self.stripes
                 .iter()
-                .map(|&(dev, ext)| (dev, ext, self.extent_count))
+                .map(|&(dev, ext)| (dev, ext, area_len))
                 .collect()
         }
 
@@ -314,38 +682,1597 @@ pub mod segment {
             }
         }
 
-        fn dm_params(&self, vg: &VG) -> String {
-            if self.stripes.len() == 1 {
-                let (dev, start_ext) = self.stripes[0];
-                let pv = vg.pv_get(dev).unwrap();
-                format!(
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            let stripe = |&(dev, start_ext): &(Device, u64)| {
+                let pv = vg
+                    .pv_get(dev)
+                    .ok_or_else(|| Error::new(Other, format!("No PV {} in the VG", dev)))?;
+                Ok(format!(
                     "{}:{} {}",
                     dev.major,
                     dev.minor,
                     (start_ext * vg.extent_size()) + pv.pe_start
-                )
+                ))
+            };
+
+            if self.stripes.len() == 1 {
+                stripe(&self.stripes[0])
             } else {
-                let stripes: Vec<_> = self
+                let stripes = self
                     .stripes
                     .iter()
-                    .map(|&(dev, start_ext)| {
-                        let pv = vg.pv_get(dev).unwrap();
-                        format!(
-                            "{}:{} {}",
-                            dev.major,
-                            dev.minor,
-                            (start_ext * vg.extent_size()) + pv.pe_start
-                        )
-                    })
-                    .collect();
+                    .map(stripe)
+                    .collect::<Result<Vec<_>>>()?;
 
-                format!(
+                let stripe_size = self
+                    .stripe_size
+                    .ok_or_else(|| Error::new(Other, "Striped segment has no stripe size"))?;
+
+                Ok(format!(
                     "{} {} {}",
                     self.stripes.len(),
-                    self.stripe_size.unwrap(),
+                    stripe_size,
                     stripes.join(" ")
-                )
+                ))
+            }
+        }
+
+        fn extent_map(&self, vg: &VG) -> Option<Vec<PhysicalRange>> {
+            let extent_size = vg.extent_size();
+
+            let mut stripes = Vec::new();
+            for &(dev, start_ext) in &self.stripes {
+                let pv = vg.pv_get(dev)?;
+                stripes.push((dev, pv.pe_start + start_ext * extent_size));
+            }
+
+            let stripe_size = match stripes.len() {
+                1 => 0,
+                _ => self.stripe_size?,
+            };
+
+            Some(vec![PhysicalRange {
+                lv_start: self.start_extent * extent_size,
+                length: self.extent_count * extent_size,
+                stripes,
+                stripe_size,
+            }])
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A snapshot of another LV. It belongs to a hidden LV, and ties the
+    /// origin LV to the LV that stores the chunks that have changed
+    /// since the snapshot was taken (the COW store).
+    #[derive(Debug, PartialEq)]
+    pub struct SnapshotSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. The same as the origin.
+        pub extent_count: u64,
+        /// How many 512-byte sectors per chunk of the COW store.
+        pub chunk_size: u64,
+        /// The name of the origin LV.
+        pub origin: String,
+        /// The name of the LV used as the COW store.
+        pub cow_store: String,
+    }
+
+    impl SnapshotSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "snapshot segment textmap parsing error");
+
+            Ok(Box::new(SnapshotSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                chunk_size: map.i64_from_textmap("chunk_size").ok_or_else(err)? as u64,
+                origin: map
+                    .string_from_textmap("origin")
+                    .ok_or_else(err)?
+                    .to_string(),
+                cow_store: map
+                    .string_from_textmap("cow_store")
+                    .ok_or_else(err)?
+                    .to_string(),
+            }))
+        }
+    }
+
+    impl Segment for SnapshotSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("snapshot".to_string()));
+            map.insert(
+                "chunk_size".to_string(),
+                Entry::Number(self.chunk_size as i64),
+            );
+            map.insert("origin".to_string(), Entry::String(self.origin.clone()));
+            map.insert(
+                "cow_store".to_string(),
+                Entry::String(self.cow_store.clone()),
+            );
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Always the origin, then the COW store.
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.origin.clone(), self.cow_store.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "snapshot"
+        }
+
+        // The origin's "real" device and the COW store's "cow" device must
+        // be active.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            Ok(format!(
+                "{} {} P {}",
+                active_layer(vg, &self.origin, "real")?,
+                active_layer(vg, &self.cow_store, "cow")?,
+                self.chunk_size
+            ))
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A pool that thin LVs allocate their data from as it is written.
+    /// It belongs to the pool LV, and ties together the hidden LVs that
+    /// hold the pool's metadata and data.
+    #[derive(Debug, PartialEq)]
+    pub struct ThinPoolSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. The same as the data LV.
+        pub extent_count: u64,
+        /// The name of the LV holding the pool's metadata.
+        pub metadata: String,
+        /// The name of the LV holding the pool's data.
+        pub pool: String,
+        /// Incremented with each change to the thin devices in the pool, so
+        /// the kernel's pool metadata can be matched with the VG's.
+        pub transaction_id: u64,
+        /// How many 512-byte sectors per chunk of the data LV.
+        pub chunk_size: u64,
+        /// How discards are handled: "ignore", "nopassdown" or "passdown".
+        pub discards: String,
+        /// Whether newly-provisioned chunks are zeroed.
+        pub zero_new_blocks: bool,
+        /// Whether writes fail when the pool runs out of data space,
+        /// rather than being queued until it is extended.
+        pub error_if_no_space: bool,
+    }
+
+    impl ThinPoolSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "thin-pool segment textmap parsing error");
+
+            Ok(Box::new(ThinPoolSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                metadata: map
+                    .string_from_textmap("metadata")
+                    .ok_or_else(err)?
+                    .to_string(),
+                pool: map.string_from_textmap("pool").ok_or_else(err)?.to_string(),
+                transaction_id: map.i64_from_textmap("transaction_id").ok_or_else(err)? as u64,
+                chunk_size: map.i64_from_textmap("chunk_size").ok_or_else(err)? as u64,
+                // optional
+                discards: map
+                    .string_from_textmap("discards")
+                    .unwrap_or("passdown")
+                    .to_string(),
+                zero_new_blocks: map.i64_from_textmap("zero_new_blocks").unwrap_or(1) != 0,
+                error_if_no_space: map.i64_from_textmap("error_if_no_space").unwrap_or(0) != 0,
+            }))
+        }
+    }
+
+    impl Segment for ThinPoolSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("thin-pool".to_string()));
+            map.insert("metadata".to_string(), Entry::String(self.metadata.clone()));
+            map.insert("pool".to_string(), Entry::String(self.pool.clone()));
+            map.insert(
+                "transaction_id".to_string(),
+                Entry::Number(self.transaction_id as i64),
+            );
+            map.insert(
+                "chunk_size".to_string(),
+                Entry::Number(self.chunk_size as i64),
+            );
+            map.insert("discards".to_string(), Entry::String(self.discards.clone()));
+            map.insert(
+                "zero_new_blocks".to_string(),
+                Entry::Number(self.zero_new_blocks as i64),
+            );
+            // Absent unless set, so other readers see nothing new
+            if self.error_if_no_space {
+                map.insert("error_if_no_space".to_string(), Entry::Number(1));
+            }
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Always the metadata LV, then the data LV.
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.metadata.clone(), self.pool.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "thin-pool"
+        }
+
+        // The metadata and data LVs must be active.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            let mut features = Vec::new();
+            if !self.zero_new_blocks {
+                features.push("skip_block_zeroing");
+            }
+            match self.discards.as_str() {
+                "ignore" => features.push("ignore_discard"),
+                "nopassdown" => features.push("no_discard_passdown"),
+                _ => {}
+            }
+            if self.error_if_no_space {
+                features.push("error_if_no_space");
             }
+
+            // A low water mark of 0 never triggers an event.
+            let mut params = format!(
+                "{} {} {} 0 {}",
+                active_device(vg, &self.metadata)?,
+                active_device(vg, &self.pool)?,
+                self.chunk_size,
+                features.len()
+            );
+            for feature in features {
+                params.push(' ');
+                params.push_str(feature);
+            }
+            Ok(params)
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A thin LV, whose data is allocated from a thin pool.
+    #[derive(Debug, PartialEq)]
+    pub struct ThinSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. They are only
+        /// allocated from the pool as they are written.
+        pub extent_count: u64,
+        /// The name of the thin pool LV.
+        pub thin_pool: String,
+        /// The pool's transaction id when the thin device was created.
+        pub transaction_id: u64,
+        /// The thin device's id within the pool.
+        pub device_id: u64,
+        /// The thin LV this was created as a snapshot of, if any.
+        pub origin: Option<String>,
+    }
+
+    impl ThinSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "thin segment textmap parsing error");
+
+            Ok(Box::new(ThinSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                thin_pool: map
+                    .string_from_textmap("thin_pool")
+                    .ok_or_else(err)?
+                    .to_string(),
+                transaction_id: map.i64_from_textmap("transaction_id").ok_or_else(err)? as u64,
+                device_id: map.i64_from_textmap("device_id").ok_or_else(err)? as u64,
+                origin: map.string_from_textmap("origin").map(|s| s.to_string()),
+            }))
         }
     }
+
+    impl Segment for ThinSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("thin".to_string()));
+            map.insert(
+                "thin_pool".to_string(),
+                Entry::String(self.thin_pool.clone()),
+            );
+            map.insert(
+                "transaction_id".to_string(),
+                Entry::Number(self.transaction_id as i64),
+            );
+            map.insert(
+                "device_id".to_string(),
+                Entry::Number(self.device_id as i64),
+            );
+            if let Some(ref origin) = self.origin {
+                map.insert("origin".to_string(), Entry::String(origin.clone()));
+            }
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.thin_pool.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "thin"
+        }
+
+        // The pool's "tpool" device must be active.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            Ok(format!(
+                "{} {}",
+                active_layer(vg, &self.thin_pool, "tpool")?,
+                self.device_id
+            ))
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// The metadata and data sub-LVs of one device in a RAID LV.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct RaidImage {
+        /// The name of the hidden LV holding the device's RAID metadata.
+        pub meta: String,
+        /// The name of the hidden LV holding the device's data.
+        pub data: String,
+    }
+
+    // "raids" lists each device's metadata and data LVs in turn.
+    fn raid_images_from_textmap(map: &LvmTextMap) -> Option<Vec<RaidImage>> {
+        let list = map.list_from_textmap("raids")?;
+        let device_count = map.i64_from_textmap("device_count")? as usize;
+        if list.len() != device_count * 2 {
+            return None;
+        }
+
+        let mut images = Vec::new();
+        for pair in list.chunks(2) {
+            match (&pair[0], &pair[1]) {
+                (Entry::String(ref meta), Entry::String(ref data)) => images.push(RaidImage {
+                    meta: meta.clone(),
+                    data: data.clone(),
+                }),
+                _ => return None,
+            }
+        }
+        Some(images)
+    }
+
+    fn raid_to_textmap(
+        type_name: &str,
+        start_extent: u64,
+        extent_count: u64,
+        stripe_size: u64,
+        region_size: Option<u64>,
+        images: &[RaidImage],
+    ) -> LvmTextMap {
+        let mut map = LvmTextMap::new();
+
+        map.insert(
+            "start_extent".to_string(),
+            Entry::Number(start_extent as i64),
+        );
+        map.insert(
+            "extent_count".to_string(),
+            Entry::Number(extent_count as i64),
+        );
+        map.insert("type".to_string(), Entry::String(type_name.to_string()));
+        map.insert(
+            "device_count".to_string(),
+            Entry::Number(images.len() as i64),
+        );
+        map.insert("stripe_size".to_string(), Entry::Number(stripe_size as i64));
+        if let Some(region_size) = region_size {
+            map.insert("region_size".to_string(), Entry::Number(region_size as i64));
+        }
+        map.insert(
+            "raids".to_string(),
+            Entry::List(
+                images
+                    .iter()
+                    .flat_map(|image| {
+                        vec![
+                            Entry::String(image.meta.clone()),
+                            Entry::String(image.data.clone()),
+                        ]
+                    })
+                    .collect(),
+            ),
+        );
+        map
+    }
+
+    fn raid_lv_dependencies(images: &[RaidImage]) -> Vec<String> {
+        images
+            .iter()
+            .flat_map(|image| vec![image.meta.clone(), image.data.clone()])
+            .collect()
+    }
+
+    // The sub-LVs must be active.
+    fn raid_dm_params(
+        vg: &VG,
+        type_name: &str,
+        stripe_size: u64,
+        region_size: Option<u64>,
+        images: &[RaidImage],
+    ) -> Result<String> {
+        let mut params = match region_size {
+            Some(region_size) => format!(
+                "{} 3 {} region_size {}",
+                type_name, stripe_size, region_size
+            ),
+            None => format!("{} 1 {}", type_name, stripe_size),
+        };
+        params.push_str(&format!(" {}", images.len()));
+        for image in images {
+            params.push_str(&format!(
+                " {} {}",
+                active_device(vg, &image.meta)?,
+                active_device(vg, &image.data)?
+            ));
+        }
+        Ok(params)
+    }
+
+    /// Where parity is placed in a RAID5 LV.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Raid5Layout {
+        /// Rotating parity, starting on the last device, with data
+        /// restarting on the first device in each stripe.
+        LeftAsymmetric,
+        /// Rotating parity, starting on the last device, with data
+        /// continuing after the parity in each stripe. The default.
+        LeftSymmetric,
+        /// Rotating parity, starting on the first device, with data
+        /// restarting on the first device in each stripe.
+        RightAsymmetric,
+        /// Rotating parity, starting on the first device, with data
+        /// continuing after the parity in each stripe.
+        RightSymmetric,
+        /// Parity always on the last device.
+        ParityN,
+    }
+
+    impl Raid5Layout {
+        /// The layout named by an LVM2 segment type.
+        pub fn from_type(type_name: &str) -> Option<Raid5Layout> {
+            match type_name {
+                "raid5_la" => Some(Raid5Layout::LeftAsymmetric),
+                "raid5" | "raid5_ls" => Some(Raid5Layout::LeftSymmetric),
+                "raid5_ra" => Some(Raid5Layout::RightAsymmetric),
+                "raid5_rs" => Some(Raid5Layout::RightSymmetric),
+                "raid5_n" => Some(Raid5Layout::ParityN),
+                _ => None,
+            }
+        }
+
+        /// The LVM2 segment type, which is also the dm-raid RAID type.
+        pub fn type_name(self) -> &'static str {
+            match self {
+                Raid5Layout::LeftAsymmetric => "raid5_la",
+                Raid5Layout::LeftSymmetric => "raid5_ls",
+                Raid5Layout::RightAsymmetric => "raid5_ra",
+                Raid5Layout::RightSymmetric => "raid5_rs",
+                Raid5Layout::ParityN => "raid5_n",
+            }
+        }
+    }
+
+    /// Where the two parity blocks are placed in a RAID6 LV.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Raid6Layout {
+        /// Rotating parity, with data restarting after the parity. The
+        /// default.
+        ZeroRestart,
+        /// Rotating parity, with data restarting at the first device.
+        NRestart,
+        /// Rotating parity, with data continuing after the parity.
+        NContinue,
+    }
+
+    impl Raid6Layout {
+        /// The layout named by an LVM2 segment type.
+        pub fn from_type(type_name: &str) -> Option<Raid6Layout> {
+            match type_name {
+                "raid6" | "raid6_zr" => Some(Raid6Layout::ZeroRestart),
+                "raid6_nr" => Some(Raid6Layout::NRestart),
+                "raid6_nc" => Some(Raid6Layout::NContinue),
+                _ => None,
+            }
+        }
+
+        /// The LVM2 segment type, which is also the dm-raid RAID type.
+        pub fn type_name(self) -> &'static str {
+            match self {
+                Raid6Layout::ZeroRestart => "raid6_zr",
+                Raid6Layout::NRestart => "raid6_nr",
+                Raid6Layout::NContinue => "raid6_nc",
+            }
+        }
+    }
+
+    /// A RAID5 LV segment: data striped across devices, with one parity
+    /// block per stripe.
+    #[derive(Debug, PartialEq)]
+    pub struct Raid5Segment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises
+        pub extent_count: u64,
+        /// Where parity is placed.
+        pub layout: Raid5Layout,
+        /// How many 512-byte sectors per stripe
+        pub stripe_size: u64,
+        /// How many 512-byte sectors each bit of the write-intent bitmap
+        /// covers.
+        pub region_size: Option<u64>,
+        /// The sub-LVs of each device, data and parity alike.
+        pub images: Vec<RaidImage>,
+    }
+
+    impl Raid5Segment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "raid5 segment textmap parsing error");
+
+            let layout = map
+                .string_from_textmap("type")
+                .and_then(Raid5Layout::from_type)
+                .ok_or_else(err)?;
+
+            Ok(Box::new(Raid5Segment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                layout,
+                stripe_size: map.i64_from_textmap("stripe_size").ok_or_else(err)? as u64,
+                // optional
+                region_size: map.i64_from_textmap("region_size").map(|x| x as u64),
+                images: raid_images_from_textmap(map).ok_or_else(err)?,
+            }))
+        }
+
+        /// The number of stripes holding data, not counting parity.
+        pub fn stripes(&self) -> usize {
+            self.images.len().saturating_sub(1)
+        }
+    }
+
+    impl Segment for Raid5Segment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            raid_to_textmap(
+                self.layout.type_name(),
+                self.start_extent,
+                self.extent_count,
+                self.stripe_size,
+                self.region_size,
+                &self.images,
+            )
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        fn lv_dependencies(&self) -> Vec<String> {
+            raid_lv_dependencies(&self.images)
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "raid"
+        }
+
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            raid_dm_params(
+                vg,
+                self.layout.type_name(),
+                self.stripe_size,
+                self.region_size,
+                &self.images,
+            )
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A RAID6 LV segment: data striped across devices, with two parity
+    /// blocks per stripe.
+    #[derive(Debug, PartialEq)]
+    pub struct Raid6Segment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises
+        pub extent_count: u64,
+        /// Where parity is placed.
+        pub layout: Raid6Layout,
+        /// How many 512-byte sectors per stripe
+        pub stripe_size: u64,
+        /// How many 512-byte sectors each bit of the write-intent bitmap
+        /// covers.
+        pub region_size: Option<u64>,
+        /// The sub-LVs of each device, data and parity alike.
+        pub images: Vec<RaidImage>,
+    }
+
+    impl Raid6Segment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "raid6 segment textmap parsing error");
+
+            let layout = map
+                .string_from_textmap("type")
+                .and_then(Raid6Layout::from_type)
+                .ok_or_else(err)?;
+
+            Ok(Box::new(Raid6Segment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                layout,
+                stripe_size: map.i64_from_textmap("stripe_size").ok_or_else(err)? as u64,
+                // optional
+                region_size: map.i64_from_textmap("region_size").map(|x| x as u64),
+                images: raid_images_from_textmap(map).ok_or_else(err)?,
+            }))
+        }
+
+        /// The number of stripes holding data, not counting parity.
+        pub fn stripes(&self) -> usize {
+            self.images.len().saturating_sub(2)
+        }
+    }
+
+    impl Segment for Raid6Segment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            raid_to_textmap(
+                self.layout.type_name(),
+                self.start_extent,
+                self.extent_count,
+                self.stripe_size,
+                self.region_size,
+                &self.images,
+            )
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        fn lv_dependencies(&self) -> Vec<String> {
+            raid_lv_dependencies(&self.images)
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "raid"
+        }
+
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            raid_dm_params(
+                vg,
+                self.layout.type_name(),
+                self.stripe_size,
+                self.region_size,
+                &self.images,
+            )
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// How writes to a cached LV are handled.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum CacheMode {
+        /// Writes go to the origin and the cache before completing.
+        Writethrough,
+        /// Writes complete once they are in the cache, and are written to
+        /// the origin later.
+        Writeback,
+        /// Reads and writes bypass the cache, which is invalidated by
+        /// writes.
+        Passthrough,
+    }
+
+    impl CacheMode {
+        /// The mode named in LVM2 metadata and the dm-cache table.
+        pub fn from_name(name: &str) -> Option<CacheMode> {
+            match name {
+                "writethrough" => Some(CacheMode::Writethrough),
+                "writeback" => Some(CacheMode::Writeback),
+                "passthrough" => Some(CacheMode::Passthrough),
+                _ => None,
+            }
+        }
+
+        /// The name used in LVM2 metadata and the dm-cache table.
+        pub fn name(self) -> &'static str {
+            match self {
+                CacheMode::Writethrough => "writethrough",
+                CacheMode::Writeback => "writeback",
+                CacheMode::Passthrough => "passthrough",
+            }
+        }
+    }
+
+    /// A cache pool, tying together the hidden LVs that hold cached data
+    /// and the cache's metadata. It has no DM device of its own; it is
+    /// used by the cache segment of the LV it caches.
+    #[derive(Debug, PartialEq)]
+    pub struct CachePoolSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. The same as the data LV.
+        pub extent_count: u64,
+        /// The name of the LV holding cached data.
+        pub data: String,
+        /// The name of the LV holding the cache's metadata.
+        pub metadata: String,
+        /// How many 512-byte sectors per cache block.
+        pub chunk_size: u64,
+        /// How writes are handled.
+        pub cache_mode: CacheMode,
+        /// The policy deciding what is cached, e.g. "smq".
+        pub policy: String,
+    }
+
+    impl CachePoolSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "cache-pool segment textmap parsing error");
+
+            Ok(Box::new(CachePoolSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                data: map.string_from_textmap("data").ok_or_else(err)?.to_string(),
+                metadata: map
+                    .string_from_textmap("metadata")
+                    .ok_or_else(err)?
+                    .to_string(),
+                chunk_size: map.i64_from_textmap("chunk_size").ok_or_else(err)? as u64,
+                // optional
+                cache_mode: map
+                    .string_from_textmap("cache_mode")
+                    .map_or(Some(CacheMode::Writethrough), CacheMode::from_name)
+                    .ok_or_else(err)?,
+                policy: map
+                    .string_from_textmap("policy")
+                    .unwrap_or("smq")
+                    .to_string(),
+            }))
+        }
+    }
+
+    impl Segment for CachePoolSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("cache-pool".to_string()));
+            map.insert("data".to_string(), Entry::String(self.data.clone()));
+            map.insert("metadata".to_string(), Entry::String(self.metadata.clone()));
+            map.insert(
+                "chunk_size".to_string(),
+                Entry::Number(self.chunk_size as i64),
+            );
+            map.insert(
+                "cache_mode".to_string(),
+                Entry::String(self.cache_mode.name().to_string()),
+            );
+            map.insert("policy".to_string(), Entry::String(self.policy.clone()));
+            map.insert("metadata_format".to_string(), Entry::Number(2));
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Always the data LV, then the metadata LV.
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.data.clone(), self.metadata.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "cache-pool"
+        }
+
+        // Not a DM target: the cache segment of the cached LV generates
+        // the table.
+        fn dm_params(&self, _vg: &VG) -> Result<String> {
+            Ok(String::new())
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A cached LV. Its original segments are moved to a hidden origin
+    /// LV, which is cached by a cache pool.
+    #[derive(Debug, PartialEq)]
+    pub struct CacheSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. The same as the origin.
+        pub extent_count: u64,
+        /// The name of the cache pool LV.
+        pub cache_pool: String,
+        /// The name of the hidden LV with the original segments.
+        pub origin: String,
+    }
+
+    impl CacheSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "cache segment textmap parsing error");
+
+            Ok(Box::new(CacheSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                cache_pool: map
+                    .string_from_textmap("cache_pool")
+                    .ok_or_else(err)?
+                    .to_string(),
+                origin: map
+                    .string_from_textmap("origin")
+                    .ok_or_else(err)?
+                    .to_string(),
+            }))
+        }
+
+        // The parameters of the cache target, optionally with a different
+        // policy than the pool's, e.g. "cleaner" to flush dirty blocks.
+        pub(crate) fn params_with_policy(&self, vg: &VG, policy: Option<&str>) -> Result<String> {
+            let pool = vg
+                .lv_get(&self.cache_pool)
+                .and_then(|lv| {
+                    lv.segments
+                        .iter()
+                        .find_map(|seg| seg.as_any().downcast_ref::<CachePoolSegment>())
+                })
+                .ok_or_else(|| {
+                    Error::new(Other, format!("Cache pool {} not found", self.cache_pool))
+                })?;
+
+            Ok(format!(
+                "{} {} {} {} 1 {} {} 0",
+                active_device(vg, &pool.metadata)?,
+                active_device(vg, &pool.data)?,
+                active_device(vg, &self.origin)?,
+                pool.chunk_size,
+                pool.cache_mode.name(),
+                policy.unwrap_or(&pool.policy)
+            ))
+        }
+    }
+
+    impl Segment for CacheSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("cache".to_string()));
+            map.insert(
+                "cache_pool".to_string(),
+                Entry::String(self.cache_pool.clone()),
+            );
+            map.insert("origin".to_string(), Entry::String(self.origin.clone()));
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Always the cache pool, then the origin.
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.cache_pool.clone(), self.origin.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "cache"
+        }
+
+        // The pool's data and metadata LVs and the origin must be active.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            self.params_with_policy(vg, None)
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A segment that buffers writes to an origin LV on a fast LV, using
+    /// dm-writecache.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct WritecacheSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises. The same as the origin.
+        pub extent_count: u64,
+        /// The name of the hidden LV with the original segments.
+        pub origin: String,
+        /// The name of the hidden LV writes are buffered on.
+        pub writecache: String,
+        /// The cache block size in bytes, either 512 or 4096.
+        pub block_size: u64,
+    }
+
+    impl WritecacheSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "writecache segment textmap parsing error");
+
+            Ok(Box::new(WritecacheSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                origin: map
+                    .string_from_textmap("origin")
+                    .ok_or_else(err)?
+                    .to_string(),
+                writecache: map
+                    .string_from_textmap("writecache")
+                    .ok_or_else(err)?
+                    .to_string(),
+                block_size: map
+                    .i64_from_textmap("writecache_block_size")
+                    .ok_or_else(err)? as u64,
+            }))
+        }
+    }
+
+    impl Segment for WritecacheSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("writecache".to_string()));
+            map.insert("origin".to_string(), Entry::String(self.origin.clone()));
+            map.insert(
+                "writecache".to_string(),
+                Entry::String(self.writecache.clone()),
+            );
+            map.insert(
+                "writecache_block_size".to_string(),
+                Entry::Number(self.block_size as i64),
+            );
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Always the origin, then the cache LV.
+        fn lv_dependencies(&self) -> Vec<String> {
+            vec![self.origin.clone(), self.writecache.clone()]
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "writecache"
+        }
+
+        // The origin and cache LVs must be active. "s" selects SSD rather
+        // than persistent memory mode.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            Ok(format!(
+                "s {} {} {} 0",
+                active_device(vg, &self.origin)?,
+                active_device(vg, &self.writecache)?,
+                self.block_size
+            ))
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A legacy dm-mirror segment, as created by older LVM2 versions.
+    /// Such LVs can be activated, but only read-only.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct MirrorSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises
+        pub extent_count: u64,
+        /// How many 512-byte sectors each bit of the mirror log covers.
+        pub region_size: u64,
+        /// The name of the LV holding the mirror log, or None if the log
+        /// is kept in memory.
+        pub mirror_log: Option<String>,
+        /// The name of each mirror image LV, with the extent within it
+        /// where this segment starts.
+        pub mirrors: Vec<(String, u64)>,
+    }
+
+    impl MirrorSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "mirror segment textmap parsing error");
+
+            let list = map.list_from_textmap("mirrors").ok_or_else(err)?;
+            let mirror_count = map.i64_from_textmap("mirror_count").ok_or_else(err)? as usize;
+            if list.len() != mirror_count * 2 {
+                return Err(err());
+            }
+
+            let mut mirrors = Vec::new();
+            for pair in list.chunks(2) {
+                match (&pair[0], &pair[1]) {
+                    (Entry::String(ref name), &Entry::Number(start)) => {
+                        mirrors.push((name.clone(), start as u64))
+                    }
+                    _ => return Err(err()),
+                }
+            }
+
+            Ok(Box::new(MirrorSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                region_size: map.i64_from_textmap("region_size").ok_or_else(err)? as u64,
+                // optional
+                mirror_log: map.string_from_textmap("mirror_log").map(|x| x.to_string()),
+                mirrors,
+            }))
+        }
+    }
+
+    impl Segment for MirrorSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = LvmTextMap::new();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map.insert("type".to_string(), Entry::String("mirror".to_string()));
+            map.insert(
+                "mirror_count".to_string(),
+                Entry::Number(self.mirrors.len() as i64),
+            );
+            if let Some(ref log) = self.mirror_log {
+                map.insert("mirror_log".to_string(), Entry::String(log.clone()));
+            }
+            map.insert(
+                "region_size".to_string(),
+                Entry::Number(self.region_size as i64),
+            );
+            map.insert(
+                "mirrors".to_string(),
+                Entry::List(
+                    self.mirrors
+                        .iter()
+                        .flat_map(|(name, start)| {
+                            vec![Entry::String(name.clone()), Entry::Number(*start as i64)]
+                        })
+                        .collect(),
+                ),
+            );
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // The images, then the log, if any.
+        fn lv_dependencies(&self) -> Vec<String> {
+            self.mirrors
+                .iter()
+                .map(|(name, _)| name.clone())
+                .chain(self.mirror_log.clone())
+                .collect()
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "mirror"
+        }
+
+        // Use an in-memory log marked in sync, so that nothing is
+        // written to the images or to the on-disk log.
+        fn dm_params(&self, vg: &VG) -> Result<String> {
+            let mut params = format!("core 2 {} nosync {}", self.region_size, self.mirrors.len());
+            for (name, start) in &self.mirrors {
+                params.push_str(&format!(
+                    " {} {}",
+                    active_device(vg, name)?,
+                    start * vg.extent_size()
+                ));
+            }
+            Ok(params)
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn read_only(&self) -> bool {
+            true
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// A segment of a type Melvin does not support, such as "vdo" or
+    /// "integrity". It is kept as it was read, so that the rest of the VG
+    /// can be used and the metadata written back unchanged, but LVs with
+    /// such segments cannot be activated.
+    ///
+    /// References to PVs within it are not translated, so they are only
+    /// correct as long as the VG's PVs do not change.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct OpaqueSegment {
+        /// The first extent within the LV this segment comprises.
+        pub start_extent: u64,
+        /// How many extents this segment comprises
+        pub extent_count: u64,
+        /// The segment as it was read.
+        pub map: LvmTextMap,
+    }
+
+    impl OpaqueSegment {
+        pub fn from_textmap(map: &LvmTextMap) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "segment textmap parsing error");
+
+            map.string_from_textmap("type").ok_or_else(err)?;
+
+            Ok(Box::new(OpaqueSegment {
+                start_extent: map.i64_from_textmap("start_extent").ok_or_else(err)? as u64,
+                extent_count: map.i64_from_textmap("extent_count").ok_or_else(err)? as u64,
+                map: map.clone(),
+            }))
+        }
+
+        /// The segment's type, as recorded in the metadata.
+        pub fn type_name(&self) -> &str {
+            self.map.string_from_textmap("type").unwrap_or_default()
+        }
+    }
+
+    impl Segment for OpaqueSegment {
+        fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
+            let mut map = self.map.clone();
+
+            map.insert(
+                "start_extent".to_string(),
+                Entry::Number(self.start_extent as i64),
+            );
+            map.insert(
+                "extent_count".to_string(),
+                Entry::Number(self.extent_count as i64),
+            );
+            map
+        }
+
+        fn start_extent(&self) -> u64 {
+            self.start_extent
+        }
+
+        fn extent_count(&self) -> u64 {
+            self.extent_count
+        }
+
+        fn pv_dependencies(&self) -> Vec<Device> {
+            Vec::new()
+        }
+
+        // Without knowing the format, any string value may name an LV.
+        // Callers only consider names of LVs that exist.
+        fn lv_dependencies(&self) -> Vec<String> {
+            self.map
+                .iter()
+                .filter(|(k, _)| *k != "type")
+                .flat_map(|(_, v)| match v {
+                    Entry::String(ref x) => vec![x.clone()],
+                    Entry::List(ref list) => list
+                        .iter()
+                        .filter_map(|item| match item {
+                            Entry::String(ref x) => Some(x.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                })
+                .collect()
+        }
+
+        fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            Vec::new()
+        }
+
+        fn dm_type(&self) -> &'static str {
+            "error"
+        }
+
+        fn dm_params(&self, _vg: &VG) -> Result<String> {
+            Ok(String::new())
+        }
+
+        fn extent_map(&self, _vg: &VG) -> Option<Vec<PhysicalRange>> {
+            None
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::buf_to_textmap;
+
+    fn lv_text(stripes: &str) -> LvmTextMap {
+        let text = format!(
+            "data {{
+id = \"hM2Nx4-2dJb-v8tS-0Bop-Pj3o-eN1m-Qw9RtU\"
+status = [\"READ\", \"WRITE\", \"VISIBLE\"]
+flags = []
+creation_host = \"host\"
+creation_time = 1600000000
+segment_count = 2
+segment1 {{
+start_extent = 0
+extent_count = 10
+type = \"striped\"
+stripe_count = 1
+stripes = [\"pv0\", 0]
+}}
+segment2 {{
+start_extent = 10
+extent_count = 10
+type = \"striped\"
+stripe_count = 1
+stripes = [{}]
+}}
+}}
+",
+            stripes
+        );
+        let map = buf_to_textmap(text.as_bytes()).unwrap();
+        map.textmap_from_textmap("data").unwrap().clone()
+    }
+
+    fn pvs() -> BTreeMap<String, PV> {
+        let pv = PV {
+            id: "Bd2Q1V-3sWk-pQ3v-XTbZ-fmSr-fE5G-2rDq0v".to_string(),
+            device: Device::from(2049),
+            status: vec!["ALLOCATABLE".to_string()],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            dev_size: 1 << 21,
+            pe_start: 2048,
+            pe_count: 255,
+            ba_start: 0,
+            ba_size: 0,
+        };
+        let mut pvs = BTreeMap::new();
+        pvs.insert("pv0".to_string(), pv);
+        pvs
+    }
+
+    #[test]
+    fn bad_segment_fails_lv() {
+        let lv = from_textmap("data", &lv_text("\"pv0\", 10"), &pvs()).unwrap();
+        assert_eq!(lv.segments.len(), 2);
+        assert_eq!(used_areas(&lv).len(), 2);
+
+        // A stripe on a PV the VG does not have
+        match from_textmap("data", &lv_text("\"pv1\", 10"), &pvs()) {
+            Err(Error::Metadata(msg)) => assert!(msg.starts_with("LV data segment2:")),
+            other => panic!("read {:?}", other.map(|lv| lv.segments.len())),
+        }
+    }
+
+    fn striped_segment(fields: &str) -> std::io::Result<Box<dyn segment::Segment>> {
+        let text = format!(
+            "start_extent = 0
+extent_count = 10
+type = \"striped\"
+{}
+",
+            fields
+        );
+        segment::from_textmap(&buf_to_textmap(text.as_bytes()).unwrap(), &pvs())
+    }
+
+    #[test]
+    fn striped_segment_needs_stripes_and_size() {
+        assert!(striped_segment("stripes = [\"pv0\", 0]").is_ok());
+        assert!(striped_segment("stripe_size = 128\nstripes = [\"pv0\", 0, \"pv0\", 5]").is_ok());
+
+        assert!(striped_segment("stripes = []").is_err());
+        assert!(striped_segment("stripes = [\"pv0\"]").is_err());
+        assert!(striped_segment("stripe_size = 0\nstripes = [\"pv0\", 0]").is_err());
+        assert!(striped_segment("stripes = [\"pv0\", 0, \"pv0\", 5]").is_err());
+    }
+
+    #[test]
+    fn locate_in_striped_range() {
+        let (a, b) = (Device::from(2049), Device::from(2065));
+        let range = segment::PhysicalRange {
+            lv_start: 0,
+            length: 64,
+            stripes: vec![(a, 1000), (b, 2000)],
+            stripe_size: 8,
+        };
+
+        assert_eq!(range.locate(0), (a, 1000, 8));
+        assert_eq!(range.locate(9), (b, 2001, 7));
+        assert_eq!(range.locate(17), (a, 1009, 7));
+        assert_eq!(range.locate(60), (b, 2028, 4));
+
+        let linear = segment::PhysicalRange {
+            stripes: vec![(a, 1000)],
+            stripe_size: 0,
+            ..range
+        };
+        assert_eq!(linear.locate(5), (a, 1005, 59));
+    }
 }
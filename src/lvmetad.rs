@@ -0,0 +1,199 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the state cached by lvmetad.
+//!
+//! lvmetad answers requests in LVM's text format. `dump` and `pv_list`
+//! responses contain maps keyed by UUIDs and device numbers; this module
+//! converts them into typed structs, mostly as a debugging aid.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::Path;
+
+use crate::daemon::transport::{Transport, DEFAULT_TIMEOUT};
+use crate::device::Device;
+use crate::parser::{Entry, LvmTextMap, TextMapOps};
+use crate::retry::RetryPolicy;
+use crate::{Error, Result};
+
+const LVMETAD_SOCKET: &str = "/run/lvm/lvmetad.socket";
+
+/// A PV as known to lvmetad.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachedPv {
+    /// The PV's UUID.
+    pub pvid: String,
+    /// The device the PV was last seen on.
+    pub device: Device,
+    /// The size of the device in bytes.
+    pub dev_size: u64,
+    /// The metadata format, usually "lvm2".
+    pub format: String,
+    /// The sector the PV label was found in.
+    pub label_sector: u64,
+    /// The UUID of the VG the PV belongs to, if any.
+    pub vgid: Option<String>,
+}
+
+/// A VG as known to lvmetad.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachedVg {
+    /// The VG's UUID.
+    pub vgid: String,
+    /// The VG's name.
+    pub name: Option<String>,
+    /// The VG's metadata, if lvmetad has it.
+    pub metadata: Option<LvmTextMap>,
+}
+
+/// The full contents of lvmetad's cache, as returned by `dump`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CacheDump {
+    /// VGs by UUID.
+    pub vgs: BTreeMap<String, CachedVg>,
+    /// PVs by UUID.
+    pub pvs: BTreeMap<String, CachedPv>,
+    /// The UUID of the PV on each device.
+    pub device_to_pvid: BTreeMap<Device, String>,
+}
+
+fn parse_err(what: &str) -> Error {
+    Error::Io(io::Error::new(
+        Other,
+        format!("lvmetad response parsing error: {}", what),
+    ))
+}
+
+impl CachedPv {
+    /// Construct a `CachedPv` from the textmap lvmetad keeps for a PV.
+    pub fn from_textmap(pvid: &str, map: &LvmTextMap) -> Result<CachedPv> {
+        let device = map
+            .i64_from_textmap("device")
+            .ok_or_else(|| parse_err("pv device"))?;
+        let dev_size = map
+            .i64_from_textmap("dev_size")
+            .ok_or_else(|| parse_err("pv dev_size"))?;
+        let format = map
+            .string_from_textmap("format")
+            .ok_or_else(|| parse_err("pv format"))?;
+        let label_sector = map.i64_from_textmap("label_sector").unwrap_or(1);
+
+        Ok(CachedPv {
+            pvid: pvid.to_string(),
+            device: Device::from(device as u64),
+            dev_size: dev_size as u64,
+            format: format.to_string(),
+            label_sector: label_sector as u64,
+            vgid: map.string_from_textmap("vgid").map(|x| x.to_string()),
+        })
+    }
+}
+
+// Convert a map of UUID to PV textmap.
+fn pvs_from_textmap(map: &LvmTextMap) -> Result<BTreeMap<String, CachedPv>> {
+    map.iter()
+        .filter_map(|(pvid, entry)| match entry {
+            Entry::TextMap(ref x) => Some((pvid, x)),
+            _ => None,
+        })
+        .map(|(pvid, x)| Ok((pvid.clone(), CachedPv::from_textmap(pvid, x)?)))
+        .collect()
+}
+
+// Convert a map of keys to string values.
+fn strings_from_textmap(map: &LvmTextMap) -> BTreeMap<String, String> {
+    map.iter()
+        .filter_map(|(k, v)| match v {
+            Entry::String(ref x) => Some((k.clone(), x.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+impl CacheDump {
+    /// Construct a `CacheDump` from the response to a `dump` request.
+    pub fn from_textmap(map: &LvmTextMap) -> Result<CacheDump> {
+        let empty = LvmTextMap::new();
+        let section = |name| map.textmap_from_textmap(name).unwrap_or(&empty);
+
+        let mut pvs = pvs_from_textmap(section("pvid_to_pvmeta"))?;
+        for (pvid, vgid) in strings_from_textmap(section("pvid_to_vgid")) {
+            if let Some(pv) = pvs.get_mut(&pvid) {
+                pv.vgid = Some(vgid);
+            }
+        }
+
+        let names = strings_from_textmap(section("vgid_to_vgname"));
+        let mut vgs: BTreeMap<_, _> = names
+            .iter()
+            .map(|(vgid, name)| {
+                (
+                    vgid.clone(),
+                    CachedVg {
+                        vgid: vgid.clone(),
+                        name: Some(name.clone()),
+                        metadata: None,
+                    },
+                )
+            })
+            .collect();
+        for (vgid, entry) in section("vgid_to_metadata") {
+            if let Entry::TextMap(ref x) = entry {
+                vgs.entry(vgid.clone())
+                    .or_insert_with(|| CachedVg {
+                        vgid: vgid.clone(),
+                        name: None,
+                        metadata: None,
+                    })
+                    .metadata = Some(*x.clone());
+            }
+        }
+
+        let device_to_pvid = strings_from_textmap(section("device_to_pvid"))
+            .into_iter()
+            .map(|(dev, pvid)| {
+                let dev = dev
+                    .parse::<u64>()
+                    .map_err(|_| parse_err("device_to_pvid key"))?;
+                Ok((Device::from(dev), pvid))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(CacheDump {
+            vgs,
+            pvs,
+            device_to_pvid,
+        })
+    }
+}
+
+/// Send a request to lvmetad and return its response. Connecting is
+/// retried as `retry` says; if lvmetad isn't running, so that its socket
+/// doesn't exist, this fails at once with a `NotFound` error.
+pub fn request(name: &str, retry: &RetryPolicy) -> Result<LvmTextMap> {
+    Transport::connect(
+        "lvmetad",
+        Path::new(LVMETAD_SOCKET),
+        Some(DEFAULT_TIMEOUT),
+        retry,
+    )?
+    .request(name, &LvmTextMap::new())
+}
+
+/// Get the full contents of lvmetad's cache.
+pub fn dump(retry: &RetryPolicy) -> Result<CacheDump> {
+    CacheDump::from_textmap(&request("dump", retry)?)
+}
+
+/// Get the PVs known to lvmetad.
+pub fn pv_list(retry: &RetryPolicy) -> Result<BTreeMap<String, CachedPv>> {
+    let map = request("pv_list", retry)?;
+    let pvs = map
+        .textmap_from_textmap("physical_volumes")
+        .ok_or_else(|| parse_err("no physical_volumes"))?;
+
+    pvs_from_textmap(pvs)
+}
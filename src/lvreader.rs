@@ -0,0 +1,129 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the contents of an LV without activating it.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::ErrorKind::{InvalidInput, Other};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use crate::device::Device;
+use crate::lv::segment::PhysicalRange;
+use crate::pvlabel::SECTOR_SIZE;
+use crate::{Error, Result, LV, VG};
+
+/// Reads an LV's data directly from its PVs, translating LV offsets
+/// to PV offsets in user space. This works without device-mapper, for
+/// example in a container or rescue environment, but the LV must not be
+/// written to while it is being read.
+#[derive(Debug)]
+pub struct LvReader {
+    ranges: Vec<PhysicalRange>,
+    files: BTreeMap<Device, File>,
+    // in bytes
+    len: u64,
+    pos: u64,
+}
+
+impl LvReader {
+    /// Open the PVs holding an LV's data for reading.
+    pub fn new(lv: &LV, vg: &VG) -> Result<LvReader> {
+        let ranges = lv.extent_map(vg)?;
+
+        let mut files = BTreeMap::new();
+        for &(device, _) in ranges.iter().flat_map(|range| &range.stripes) {
+            if files.contains_key(&device) {
+                continue;
+            }
+
+            let path = vg.pv_get(device).and_then(|pv| pv.path()).ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    Other,
+                    format!("Could not find PV device {}", device),
+                ))
+            })?;
+            files.insert(device, File::open(path)?);
+        }
+
+        let len = ranges.iter().map(|r| r.length).sum::<u64>() * SECTOR_SIZE as u64;
+
+        Ok(LvReader {
+            ranges,
+            files,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// The size of the LV in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the LV has no data.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for LvReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let sector = self.pos / SECTOR_SIZE as u64;
+        let idx = match self.ranges.binary_search_by_key(&sector, |r| r.lv_start) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        };
+        let range = &self.ranges[idx];
+
+        // Read no further than the PV sectors that follow on from this
+        // one, up to the end of a stripe chunk or of the range.
+        let (device, pv_sector, sectors) = range.locate(sector - range.lv_start);
+        let within = self.pos % SECTOR_SIZE as u64;
+        let run = sectors * SECTOR_SIZE as u64 - within;
+        // The run may not fit in a usize on 32-bit systems.
+        let to_read = usize::try_from(run).map_or(buf.len(), |n| buf.len().min(n));
+
+        let file = &self.files[&device];
+        let count = file.read_at(&mut buf[..to_read], pv_sector * SECTOR_SIZE as u64 + within)?;
+
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for LvReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => offset(self.len, x),
+            SeekFrom::Current(x) => offset(self.pos, x),
+        };
+
+        match new_pos {
+            Some(x) => {
+                self.pos = x;
+                Ok(x)
+            }
+            None => Err(io::Error::new(
+                InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, off: i64) -> Option<u64> {
+    if off >= 0 {
+        base.checked_add(off as u64)
+    } else {
+        base.checked_sub(off.wrapping_neg() as u64)
+    }
+}
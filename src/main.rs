@@ -2,104 +2,183 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-#![allow(dead_code)]
+//! mlv, Melvin's command-line tool. Its `pvs`, `vgs` and `lvs` commands
+//! report on the system's PVs, VGs and LVs as LVM2's tools of the same
+//! names do, with the same columns and options for their layout.
 
+use std::env;
 use std::io::{self, ErrorKind::Other};
-use std::path;
-//use std::path::Path;
+use std::process;
+
+#[cfg(feature = "daemon-clients")]
+use melvin::lvmetad;
+use melvin::parser::Entry;
+use melvin::report::{self, ReportOptions};
+use melvin::{Error, Melvin, PvHeader, Result, VG};
+
+fn usage() -> String {
+    let mut lines = vec![
+        "usage: mlv <command> [args]",
+        "",
+        "commands:",
+        "  pvs [options] [VG...]   report on PVs",
+        "  vgs [options] [VG...]   report on VGs",
+        "  lvs [options] [VG...]   report on LVs",
+    ];
+    #[cfg(feature = "spec")]
+    lines.push("  apply <spec.json>       make the system match a spec");
+    #[cfg(feature = "daemon-clients")]
+    lines.push("  dump-cache              print what lvmetad has cached");
+    lines.extend(&[
+        "",
+        "report options:",
+        "  --noheadings            leave out the line of column headings",
+        "  --separator <sep>       join fields with <sep> instead of aligning them",
+    ]);
+
+    lines.join("\n")
+}
 
-use melvin::parser;
-use melvin::{pvheader_scan, PvHeader};
-use melvin::{Error, Result};
-use melvin::{Flock, LockScope};
+fn usage_err() -> Error {
+    Error::Io(io::Error::new(Other, usage()))
+}
 
-fn print_pvheaders() -> Result<()> {
-    let dirs = vec![path::Path::new("/dev")];
+#[cfg(feature = "daemon-clients")]
+fn dump_cache() -> Result<()> {
+    let dump = lvmetad::dump(&melvin::RetryPolicy::default())?;
 
-    for pvheader in pvheader_scan(&dirs)? {
-        println!("pvheader {:#?}", pvheader);
-        println!("Hdr {:#?}", PvHeader::find_in_dev(&pvheader)?);
+    for (vgid, vg) in &dump.vgs {
+        println!("vg {} name {:?}", vgid, vg.name);
+    }
+    for (pvid, pv) in &dump.pvs {
+        println!("pv {} {:#?}", pvid, pv);
+    }
+    for (device, pvid) in &dump.device_to_pvid {
+        println!("device {} pvid {}", device, pvid);
     }
 
     Ok(())
 }
 
-fn get_first_vg_meta() -> Result<(String, parser::LvmTextMap)> {
-    let dirs = vec![path::Path::new("/dev")];
+#[cfg(feature = "spec")]
+fn apply(spec_path: &str) -> Result<()> {
+    let text = std::fs::read_to_string(spec_path)?;
+    let spec = melvin::spec::Spec::from_json(&text)?;
 
-    let _lock = Flock::lock_exclusive(LockScope::Global)?;
+    for change in Melvin::new().apply(&spec, true)? {
+        println!("{}", change);
+    }
 
-    for pv_path in pvheader_scan(&dirs)? {
-        let pvheader = PvHeader::find_in_dev(&pv_path)?;
-        let map = pvheader.read_metadata()?;
+    Ok(())
+}
 
-        // Find the textmap for the vg, among all the other stuff.
-        // (It's the only textmap.)
-        for (key, value) in map {
-            if let parser::Entry::TextMap(x) = value {
-                return Ok((key, *x));
-            }
+// The layout options and VG names given to a report command.
+fn report_args(args: &[String]) -> Result<(ReportOptions, Vec<String>)> {
+    let mut options = ReportOptions::default();
+    let mut names = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--noheadings" => options.headings = false,
+            "--separator" => options.separator = Some(args.next().ok_or_else(usage_err)?.clone()),
+            opt if opt.starts_with('-') => return Err(usage_err()),
+            name => names.push(name.to_string()),
         }
     }
 
-    Err(Error::Io(io::Error::new(Other, "dude")))
+    Ok((options, names))
 }
 
-fn get_conf() -> Result<parser::LvmTextMap> {
-    use std::fs;
-    use std::io::Read;
+// Read the VGs named, or every VG if none are. Unnamed VGs that cannot
+// be read are skipped with a warning, so one damaged VG does not hide
+// the others.
+fn read_vgs(melvin: &Melvin, names: &[String]) -> Result<Vec<VG>> {
+    if !names.is_empty() {
+        return names.iter().map(|name| melvin.vg_open(name)).collect();
+    }
+
+    let mut vgs = Vec::new();
+    for name in melvin.vg_names()? {
+        match melvin.vg_open(&name) {
+            Ok(vg) => vgs.push(vg),
+            Err(e) => eprintln!("  Skipping VG {}: {}", name, e),
+        }
+    }
 
-    let mut f = fs::File::open("/etc/lvm/lvm.conf")?;
+    Ok(vgs)
+}
 
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf)?;
+// The PVs that are in no VG. A PV with metadata for a VG that could not
+// be read is not one.
+fn orphan_pvs(melvin: &Melvin, vgs: &[VG]) -> Result<Vec<PvHeader>> {
+    let in_vgs: Vec<_> = vgs.iter().flat_map(|vg| vg.pv_list()).collect();
+
+    let mut orphans = Vec::new();
+    for path in melvin.pv_paths()? {
+        let pvh = match PvHeader::find_in_dev(&path) {
+            Ok(pvh) => pvh,
+            Err(_) => continue,
+        };
+        if pvh.device().is_ok_and(|dev| in_vgs.contains(&dev)) {
+            continue;
+        }
+        let has_vg = pvh
+            .read_metadata()
+            .is_ok_and(|map| map.values().any(|value| matches!(value, Entry::TextMap(_))));
+        if !has_vg {
+            orphans.push(pvh);
+        }
+    }
 
-    parser::buf_to_textmap(&buf)
+    Ok(orphans)
 }
 
-fn main() -> Result<()> {
-    // println!("{:?}", PvHeader::initialize(Path::new("/dev/vdc1")));
-    print_pvheaders()?;
-    let (name, map) = get_first_vg_meta().unwrap();
-    println!("name {} map {:#?}", name, map);
-    //    let vg = parser::vg_from_textmap(&name, &map).expect("didn't get vg!");
-
-    // let path1 = Path::new("/dev/vdc1");
-    // let path2 = Path::new("/dev/vdc2");
-
-    //    let pvh1 = PvHeader::find_in_dev(Path::new("/dev/vdc1")).expect("pvheader not found");
-
-    // let _vg = VG::create("vg-dopey", vec![path1, path2]).expect("vgcreate failed yo");
-    // vg.add_pv(&pvh1).unwrap();
-    // vg.add_pv(&pvh2).unwrap();
-
-    // match vg.new_linear_lv("grover125", 2021) {
-    //     Ok(_) => {},
-    //     Err(x) => {
-    //         println!("err {:?}", x);
-    //         return;
-    //     }
-    // };
-
-    // match vg.lv_remove("grover125") {
-    //     Ok(_) => {println!("yay")},
-    //     Err(x) => {
-    //         println!("err {:?}", x);
-    //         return;
-    //     }
-    // };
-
-    // for (lvname, lv) in &vg.lvs {
-    //     println!("lv2 {:?}", lv);
-    // }
-
-    // let tm = get_conf().expect("could not read lvm.conf");
-    // let locking_type = tm.textmap_from_textmap("global")
-    //     .and_then(|g| g.i64_from_textmap("locking_type")).unwrap();
-
-    // println!("locking_type = {}", locking_type);
-
-    // let vgtm = vg.into();
-    // let s = parser::textmap_to_buf(&vgtm);
+fn report(command: &str, args: &[String]) -> Result<()> {
+    let (options, names) = report_args(args)?;
+    let melvin = Melvin::new();
+    let vgs = read_vgs(&melvin, &names)?;
+    let vg_refs: Vec<&VG> = vgs.iter().collect();
+
+    let out = match command {
+        "pvs" => {
+            // As with LVM2, orphans are only listed when every PV is.
+            let orphans = match names.is_empty() {
+                true => orphan_pvs(&melvin, &vgs)?,
+                false => Vec::new(),
+            };
+            let orphan_refs: Vec<&PvHeader> = orphans.iter().collect();
+            report::pvs_with_orphans(&vg_refs, &orphan_refs, &options)
+        }
+        "vgs" => report::vgs(&vg_refs, &options),
+        _ => report::lvs(&vg_refs, &options),
+    };
+    print!("{}", out);
+
     Ok(())
 }
+
+fn run(args: &[String]) -> Result<()> {
+    let (command, args) = args.split_first().ok_or_else(usage_err)?;
+
+    match command.as_str() {
+        "pvs" | "vgs" | "lvs" => report(command, args),
+        #[cfg(feature = "spec")]
+        "apply" => match args {
+            [spec_path] => apply(spec_path),
+            _ => Err(usage_err()),
+        },
+        #[cfg(feature = "daemon-clients")]
+        "dump-cache" => dump_cache(),
+        _ => Err(usage_err()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("mlv: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Versioning of Melvin's additions to the VG metadata format.
+//
+// Melvin records the version of its extensions in the VG textmap. When
+// metadata from an older version is loaded, the upgrade hook for each
+// later version that has one is applied in turn. Metadata from a newer
+// version may contain things this version does not understand, so it
+// may be read but must not be written back.
+//
+// Going the other way, each version has a downgrade hook that removes
+// what it added. A version can't know what later ones added, so it is
+// the newer version that downgrades: `VG::downgrade_metadata` has it
+// write the VG as an older version, for deployments of that version to
+// change, not only read.
+
+use crate::parser::{Entry, LvmTextMap, TextMapOps};
+use crate::{Error, Result};
+
+/// The version of Melvin's metadata extensions that this version of
+/// Melvin writes.
+pub const METADATA_VERSION: u64 = 4;
+
+const VERSION_KEY: &str = "melvin_version";
+
+struct Migration {
+    // The version the migration upgrades to.
+    version: u64,
+    // Converts a VG textmap from `version - 1` to `version`, if anything
+    // in it has to change.
+    upgrade: Option<fn(&mut LvmTextMap) -> Result<()>>,
+    // Converts a VG textmap from `version` back to `version - 1`, by
+    // removing what `version` added.
+    downgrade: Option<fn(&mut LvmTextMap)>,
+}
+
+// Each version so far has only added keys that are absent when not
+// set, so older metadata needs no changes, and newer metadata only
+// loses those keys.
+const MIGRATIONS: &[Migration] = &[
+    // The version key itself.
+    Migration {
+        version: 1,
+        upgrade: None,
+        downgrade: Some(|map| remove_keys(map, &[VERSION_KEY])),
+    },
+    // "melvin_metadata_tags"
+    Migration {
+        version: 2,
+        upgrade: None,
+        downgrade: Some(|map| remove_keys(map, &["melvin_metadata_tags"])),
+    },
+    // "melvin_modified_time" and "melvin_description"
+    Migration {
+        version: 3,
+        upgrade: None,
+        downgrade: Some(|map| remove_keys(map, &["melvin_modified_time", "melvin_description"])),
+    },
+    // The per-LV "melvin_snapshot_interval", "melvin_snapshot_retention"
+    // and "melvin_schedule_*" keys.
+    Migration {
+        version: 4,
+        upgrade: None,
+        downgrade: Some(remove_lv_schedules),
+    },
+];
+
+fn remove_keys(map: &mut LvmTextMap, keys: &[&str]) {
+    for key in keys {
+        map.remove(*key);
+    }
+}
+
+fn remove_lv_schedules(map: &mut LvmTextMap) {
+    if let Some(Entry::TextMap(lvs)) = map.get_mut("logical_volumes") {
+        for entry in lvs.values_mut() {
+            if let Entry::TextMap(lv) = entry {
+                lv.retain(|key, _| {
+                    key != "melvin_snapshot_interval"
+                        && key != "melvin_snapshot_retention"
+                        && !key.starts_with("melvin_schedule_")
+                });
+            }
+        }
+    }
+}
+
+/// The version of Melvin's extensions a VG textmap uses. Metadata
+/// written by LVM2 is version 0.
+pub fn metadata_version(map: &LvmTextMap) -> Result<u64> {
+    match map.i64_from_textmap(VERSION_KEY) {
+        None => Ok(0),
+        Some(x) if x >= 0 => Ok(x as u64),
+        Some(x) => Err(Error::Metadata(format!("Invalid {} {}", VERSION_KEY, x))),
+    }
+}
+
+/// Upgrade a VG textmap from an older version to the current one.
+/// Returns the version the textmap was originally at. Textmaps from a
+/// newer version are left alone.
+pub fn upgrade(map: &mut LvmTextMap) -> Result<u64> {
+    let version = metadata_version(map)?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        if let Some(upgrade) = migration.upgrade {
+            upgrade(map)?;
+        }
+    }
+
+    Ok(version)
+}
+
+/// Record the version in a VG textmap being written out by this
+/// version, after removing what versions after it added. `version` must
+/// not be newer than `METADATA_VERSION`.
+pub fn set_version(map: &mut LvmTextMap, version: u64) {
+    map.insert(VERSION_KEY.to_string(), Entry::Number(version as i64));
+
+    for migration in MIGRATIONS.iter().rev().filter(|m| m.version > version) {
+        if let Some(downgrade) = migration.downgrade {
+            downgrade(map);
+        }
+    }
+}
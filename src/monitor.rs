@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Whether LVs are monitored, as dmeventd monitors them for LVM2: watched
+//! for events such as a thin pool or snapshot filling up, so that they
+//! can be extended before they run out of space.
+//!
+//! Like LVM2's, this is not part of the VG's metadata. It is kept in a
+//! file per VG under `Config::monitor_dir`, named by the VG's UUID and
+//! listing LVs by UUID, so that it follows renames. A VG's LVs are
+//! monitored unless it says otherwise.
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, remove_file, rename, write};
+use std::io::ErrorKind::NotFound;
+use std::path::Path;
+
+use crate::parser::{buf_to_textmap, textmap_to_buf, Entry, LvmTextMap, TextMapOps};
+use crate::{Error, Result};
+
+/// Where monitoring state is kept, unless configured otherwise.
+pub(crate) const DEFAULT_MONITOR_DIR: &str = "/run/melvin/monitor";
+
+// The DM targets with events worth watching for, as with dmeventd's
+// plugins.
+const MONITORED_TARGETS: &[&str] = &["thin-pool", "snapshot", "mirror"];
+
+/// Whether LVs using a DM target type can be monitored.
+pub(crate) fn is_monitorable(target: &str) -> bool {
+    MONITORED_TARGETS.contains(&target) || target.starts_with("raid")
+}
+
+/// Whether a VG's LVs are monitored.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct MonitorState {
+    /// Whether LVs are monitored unless `lvs` says otherwise.
+    pub monitor: bool,
+    /// Overrides of `monitor`, by LV UUID.
+    pub lvs: BTreeMap<String, bool>,
+}
+
+impl Default for MonitorState {
+    fn default() -> MonitorState {
+        MonitorState {
+            monitor: true,
+            lvs: BTreeMap::new(),
+        }
+    }
+}
+
+impl MonitorState {
+    /// Whether the LV with the given UUID is monitored.
+    pub fn lv_monitored(&self, lv_id: &str) -> bool {
+        self.lvs.get(lv_id).copied().unwrap_or(self.monitor)
+    }
+}
+
+fn id_list(state: &MonitorState, monitor: bool) -> Entry {
+    Entry::List(
+        state
+            .lvs
+            .iter()
+            .filter(|(_, m)| **m == monitor)
+            .map(|(id, _)| Entry::String(id.clone()))
+            .collect(),
+    )
+}
+
+/// Read the monitoring state of the VG with the given UUID. A VG with
+/// none recorded has all its LVs monitored.
+pub(crate) fn load(dir: &Path, vg_id: &str) -> Result<MonitorState> {
+    let buf = match read(dir.join(vg_id)) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == NotFound => return Ok(MonitorState::default()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let map = buf_to_textmap(&buf)?;
+
+    let mut state = MonitorState {
+        monitor: map.i64_from_textmap("monitor").unwrap_or(1) != 0,
+        lvs: BTreeMap::new(),
+    };
+    for (key, monitor) in &[("enabled", true), ("disabled", false)] {
+        for entry in map.list_from_textmap(key).into_iter().flatten() {
+            if let Entry::String(id) = entry {
+                state.lvs.insert(id.clone(), *monitor);
+            }
+        }
+    }
+
+    Ok(state)
+}
+
+/// Record the monitoring state of the VG with the given UUID.
+pub(crate) fn save(dir: &Path, vg_id: &str, state: &MonitorState) -> Result<()> {
+    let mut map = LvmTextMap::new();
+    map.insert("monitor".to_string(), Entry::Number(state.monitor as i64));
+    map.insert("enabled".to_string(), id_list(state, true));
+    map.insert("disabled".to_string(), id_list(state, false));
+
+    create_dir_all(dir)?;
+    let path = dir.join(vg_id);
+    let tmp_path = path.with_extension("tmp");
+    write(&tmp_path, textmap_to_buf(&map))?;
+    rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Forget the monitoring state of the VG with the given UUID.
+pub(crate) fn remove(dir: &Path, vg_id: &str) -> Result<()> {
+    match remove_file(dir.join(vg_id)) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == NotFound => Ok(()),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
@@ -28,10 +28,10 @@
 
 //! Parsing LVM's text-based configuration format.
 
-use std::io;
-use std::io::ErrorKind::Other;
-
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::fmt;
+use std::str;
 
 use crate::{Error, Result};
 
@@ -52,19 +52,25 @@ enum Token<'a> {
     /// `,`
     Comma,
 
-    /// A string , like `"foo"`
+    /// A string , like `"foo"`, as written, with any escapes
     String(&'a [u8]),
 
     Ident(&'a [u8]),
 
-    /// An unsigned integer number
-    Number(i64),
+    /// An integer number, and its text
+    Number(i64, &'a [u8]),
 
     Comment(&'a [u8]),
 
     /// The type of the token could not be identified.
     /// Should be removed if this lexer is ever to be feature complete
     Invalid(u8),
+
+    /// Digits that don't fit in an i64, or a lone `-`.
+    BadNumber(&'a [u8]),
+
+    /// A string with no closing quote before the end of the text.
+    Unterminated,
 }
 
 impl<'a> AsRef<str> for Token<'a> {
@@ -87,7 +93,6 @@ struct Lexer<'a> {
     chars: &'a [u8],
     next_byte: Option<u8>,
     cursor: usize,
-    next_is_ident: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -97,7 +102,6 @@ impl<'a> Lexer<'a> {
             chars,
             next_byte: None,
             cursor: 0,
-            next_is_ident: false,
         }
     }
 
@@ -124,6 +128,14 @@ impl<'a> Lexer<'a> {
             }
         }
     }
+
+    fn number(&self, first: usize) -> Token<'a> {
+        let digits = &self.chars[first..self.cursor];
+        match String::from_utf8_lossy(digits).parse() {
+            Ok(x) => Token::Number(x, digits),
+            Err(_) => Token::BadNumber(digits),
+        }
+    }
 }
 
 // Identifies the state of the lexer
@@ -138,62 +150,67 @@ enum Mode {
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Token<'a>;
+    /// Each token, with the offset of its first byte.
+    type Item = (usize, Token<'a>);
 
     /// Lex the underlying byte stream to generate tokens
-    fn next(&mut self) -> Option<Token<'a>> {
+    fn next(&mut self) -> Option<(usize, Token<'a>)> {
         let mut state = Mode::Main;
 
         while let Some(c) = self.next_byte() {
+            let offset = self.cursor - 1;
             match state {
                 Mode::Main => {
                     match c {
                         b'{' => {
-                            self.next_is_ident = true;
-                            return Some(Token::CurlyOpen);
+                            return Some((offset, Token::CurlyOpen));
                         }
                         b'}' => {
-                            return Some(Token::CurlyClose);
+                            return Some((offset, Token::CurlyClose));
                         }
                         b'"' => {
-                            state = Mode::String(self.cursor - 1);
+                            state = Mode::String(offset);
                         }
                         b'a'..=b'z' | b'A'..=b'Z' | b'_' | b'.' => {
-                            state = Mode::Ident(self.cursor - 1);
+                            state = Mode::Ident(offset);
                         }
                         b'0'..=b'9' | b'-' => {
-                            if self.next_is_ident {
-                                state = Mode::Ident(self.cursor - 1);
-                            } else {
-                                state = Mode::Number(self.cursor - 1);
-                            }
+                            state = Mode::Number(offset);
                         }
                         b'#' => {
-                            state = Mode::Comment(self.cursor - 1);
+                            state = Mode::Comment(offset);
                         }
                         b'[' => {
-                            return Some(Token::BracketOpen);
+                            return Some((offset, Token::BracketOpen));
                         }
                         b']' => {
-                            return Some(Token::BracketClose);
+                            return Some((offset, Token::BracketClose));
                         }
                         b'=' => {
-                            return Some(Token::Equals);
+                            return Some((offset, Token::Equals));
                         }
                         b',' => {
-                            return Some(Token::Comma);
+                            return Some((offset, Token::Comma));
                         }
-                        b' ' | b'\n' | b'\t' | b'\0' => {
+                        b' ' | b'\n' | b'\t' | b'\r' | b'\0' => {
                             // ignore whitespace
                         }
                         _ => {
-                            return Some(Token::Invalid(c));
+                            return Some((offset, Token::Invalid(c)));
                         }
                     }
                 }
                 Mode::String(first) => match c {
+                    // An escaped quote or backslash
+                    b'\\' => {
+                        self.next_byte();
+                        continue;
+                    }
                     b'"' => {
-                        return Some(Token::String(&self.chars[first + 1..self.cursor - 1]));
+                        return Some((
+                            first,
+                            Token::String(&self.chars[first + 1..self.cursor - 1]),
+                        ));
                     }
                     _ => {
                         continue;
@@ -205,25 +222,27 @@ impl<'a> Iterator for Lexer<'a> {
                     }
                     _ => {
                         self.put_back(c);
-                        self.next_is_ident = false;
-                        return Some(Token::Ident(&self.chars[first..self.cursor]));
+                        return Some((first, Token::Ident(&self.chars[first..self.cursor])));
                     }
                 },
                 Mode::Number(first) => match c {
                     b'0'..=b'9' => {
                         continue;
                     }
+                    // Identifiers in lvmetad responses, such as PV UUIDs,
+                    // may start with a digit.
+                    b'a'..=b'z' | b'A'..=b'Z' | b'_' | b'.' | b'-' => {
+                        state = Mode::Ident(first);
+                    }
                     _ => {
                         self.put_back(c);
-                        let s =
-                            String::from_utf8_lossy(&self.chars[first..self.cursor]).into_owned();
-                        return Some(Token::Number(s.parse().unwrap()));
+                        return Some((first, self.number(first)));
                     }
                 },
                 Mode::Comment(first) => match c {
                     b'\n' => {
                         self.put_back(c);
-                        return Some(Token::Comment(&self.chars[first..self.cursor]));
+                        return Some((first, Token::Comment(&self.chars[first..self.cursor])));
                     }
                     _ => {
                         continue;
@@ -232,10 +251,89 @@ impl<'a> Iterator for Lexer<'a> {
             }
         }
 
-        None
+        // The text may end in the middle of a token.
+        match state {
+            Mode::Main => None,
+            Mode::String(first) => Some((first, Token::Unterminated)),
+            Mode::Ident(first) => Some((first, Token::Ident(&self.chars[first..]))),
+            Mode::Number(first) => Some((first, self.number(first))),
+            Mode::Comment(first) => Some((first, Token::Comment(&self.chars[first..]))),
+        }
     }
 }
 
+/// An error in LVM configuration text, with where in the text it is.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    /// What is wrong.
+    pub message: String,
+    /// The line it is on, counting from 1.
+    pub line: usize,
+    /// The column, in characters counting from 1.
+    pub column: usize,
+    /// The text of the line, or of up to `SNIPPET_LEN` characters of it
+    /// around the column if it is long. Tabs are replaced by spaces, so
+    /// that a caret can be lined up under the column.
+    pub snippet: String,
+    /// The column within `snippet`, counting from 1.
+    pub snippet_column: usize,
+}
+
+/// The most characters of a line that a `ParseError` keeps.
+pub const SNIPPET_LEN: usize = 80;
+
+impl ParseError {
+    fn new(buf: &[u8], offset: usize, message: String) -> ParseError {
+        let offset = offset.min(buf.len());
+        let line_start = buf[..offset]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |pos| pos + 1);
+        let line_end = buf[offset..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(buf.len(), |pos| offset + pos);
+        let line_text = String::from_utf8_lossy(&buf[line_start..line_end]);
+        let column = String::from_utf8_lossy(&buf[line_start..offset])
+            .chars()
+            .count()
+            + 1;
+
+        let skip = column.saturating_sub(SNIPPET_LEN / 2 + 1);
+        let snippet: String = line_text
+            .trim_end()
+            .chars()
+            .skip(skip)
+            .take(SNIPPET_LEN)
+            .map(|c| if c == '\t' { ' ' } else { c })
+            .collect();
+
+        ParseError {
+            message,
+            line: buf[..line_start].iter().filter(|c| **c == b'\n').count() + 1,
+            column,
+            snippet,
+            snippet_column: column - skip,
+        }
+    }
+}
+
+/// Shown as the message and position, then the snippet, with a caret
+/// under the column.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )?;
+        writeln!(f, "  {}", self.snippet)?;
+        write!(f, "  {:>width$}", "^", width = self.snippet_column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 /// A Map that represents LVM metadata.
 ///
 /// This is an intermediate representation between LVM's textual metadata format
@@ -300,144 +398,647 @@ impl TextMapOps for LvmTextMap {
     }
 }
 
-fn find_matching_token<'a, 'b>(
-    tokens: &'b [Token<'a>],
-    begin: &Token<'a>,
-    end: &Token<'a>,
-) -> Result<&'b [Token<'a>]> {
-    let mut brace_count = 0;
+/// Like `LvmTextMap`, but with keys and strings borrowed from the text
+/// it was parsed from, by `buf_to_textmap_borrowed`. Only strings with
+/// escapes in them are copied.
+pub type LvmTextMapRef<'a> = BTreeMap<&'a str, EntryRef<'a>>;
 
-    for (i, x) in tokens.iter().enumerate() {
-        match x {
-            x if x == begin => {
-                brace_count += 1;
-            }
-            x if x == end => {
-                brace_count -= 1;
-                if brace_count == 0 {
-                    return Ok(&tokens[..i + 1]);
-                }
+/// Each value in an LvmTextMapRef is an EntryRef.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EntryRef<'a> {
+    /// An integral numeric value
+    Number(i64),
+    /// A text string
+    String(Cow<'a, str>),
+    /// An ordered list of strings and numbers, possibly both
+    List(Vec<EntryRef<'a>>),
+    /// A nested LvmTextMapRef
+    TextMap(Box<LvmTextMapRef<'a>>),
+}
+
+impl<'a> EntryRef<'a> {
+    /// Copy the value into an `Entry`.
+    pub fn to_owned_entry(&self) -> Entry {
+        match *self {
+            EntryRef::Number(x) => Entry::Number(x),
+            EntryRef::String(ref x) => Entry::String(x.to_string()),
+            EntryRef::List(ref x) => Entry::List(x.iter().map(|e| e.to_owned_entry()).collect()),
+            EntryRef::TextMap(ref x) => Entry::TextMap(Box::new(textmap_ref_to_owned(x))),
+        }
+    }
+}
+
+/// The same operations as `TextMapOps`, for an `LvmTextMapRef`.
+pub trait TextMapRefOps<'a> {
+    /// Get an i64 value from a LvmTextMapRef.
+    fn i64_from_textmap(&self, name: &str) -> Option<i64>;
+    /// Get a reference to a string in an LvmTextMapRef.
+    fn string_from_textmap(&self, name: &str) -> Option<&str>;
+    /// Get a reference to a List within an LvmTextMapRef.
+    fn list_from_textmap(&self, name: &str) -> Option<&Vec<EntryRef<'a>>>;
+    /// Get a reference to a nested LvmTextMapRef within an LvmTextMapRef.
+    fn textmap_from_textmap(&self, name: &str) -> Option<&LvmTextMapRef<'a>>;
+}
+
+impl<'a> TextMapRefOps<'a> for LvmTextMapRef<'a> {
+    fn i64_from_textmap(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(EntryRef::Number(x)) => Some(*x),
+            _ => None,
+        }
+    }
+    fn string_from_textmap(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(EntryRef::String(x)) => Some(x),
+            _ => None,
+        }
+    }
+    fn textmap_from_textmap(&self, name: &str) -> Option<&LvmTextMapRef<'a>> {
+        match self.get(name) {
+            Some(EntryRef::TextMap(x)) => Some(x),
+            _ => None,
+        }
+    }
+    fn list_from_textmap(&self, name: &str) -> Option<&Vec<EntryRef<'a>>> {
+        match self.get(name) {
+            Some(EntryRef::List(x)) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+// A token and the offset in the text it starts at.
+type Spanned<'a> = (usize, Token<'a>);
+
+// What went wrong, and the offset in the text where.
+type ParseResult<T> = std::result::Result<T, (usize, String)>;
+
+fn describe(tok: &Token) -> String {
+    match *tok {
+        Token::CurlyOpen => "'{'".to_string(),
+        Token::CurlyClose => "'}'".to_string(),
+        Token::BracketOpen => "'['".to_string(),
+        Token::BracketClose => "']'".to_string(),
+        Token::Equals => "'='".to_string(),
+        Token::Comma => "','".to_string(),
+        Token::String(x) => format!("string \"{}\"", String::from_utf8_lossy(x)),
+        Token::Ident(x) => format!("identifier {}", String::from_utf8_lossy(x)),
+        Token::Number(x, _) => format!("number {}", x),
+        Token::Comment(_) => "comment".to_string(),
+        Token::Invalid(c) => format!("character {:?}", c as char),
+        Token::BadNumber(x) => format!("invalid number {}", String::from_utf8_lossy(x)),
+        Token::Unterminated => "string with no closing quote".to_string(),
+    }
+}
+
+// The token at `cur`, or an error saying what was expected if the text
+// ended, pointing at `open`, the offset of the bracket or brace being
+// parsed, if there is one.
+fn token_at<'a, 'b>(
+    tokens: &'b [Spanned<'a>],
+    cur: usize,
+    end: usize,
+    open: Option<(usize, &str)>,
+) -> ParseResult<&'b Spanned<'a>> {
+    tokens.get(cur).ok_or_else(|| match open {
+        Some((offset, what)) => (offset, format!("{} is never closed", what)),
+        None => (end, "unexpected end of text".to_string()),
+    })
+}
+
+// The text of a token the lexer only makes of ASCII.
+fn ascii(x: &[u8]) -> &str {
+    str::from_utf8(x).expect("identifiers and numbers are ASCII")
+}
+
+// A string's value, which must be UTF-8 to be borrowed as a str. As in
+// LVM2, a backslash escapes a quote or another backslash, and is kept
+// before any other character.
+fn string_value(offset: usize, x: &[u8]) -> ParseResult<Cow<'_, str>> {
+    let x = str::from_utf8(x).map_err(|_| (offset, "string is not valid UTF-8".to_string()))?;
+    if !x.contains('\\') {
+        return Ok(Cow::Borrowed(x));
+    }
+
+    let mut value = String::with_capacity(x.len());
+    let mut chars = x.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next)) if next == '"' || next == '\\' => {
+                value.push(next);
+                chars.next();
             }
-            _ => {}
+            _ => value.push(c),
         }
     }
-    Err(Error::Io(io::Error::new(Other, "token mismatch")))
+    Ok(Cow::Owned(value))
+}
+
+// A string as written in LVM configuration text: in quotes, with quotes
+// and backslashes in it escaped.
+fn quote(x: &str) -> String {
+    let mut quoted = String::with_capacity(x.len() + 2);
+    quoted.push('"');
+    for c in x.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
 }
 
 // lists can only contain strings and numbers, yay
-fn get_list<'a>(tokens: &[Token<'a>]) -> Result<Vec<Entry>> {
+fn get_list<'a>(
+    tokens: &[Spanned<'a>],
+    cur: &mut usize,
+    end: usize,
+) -> ParseResult<Vec<EntryRef<'a>>> {
     let mut v = Vec::new();
 
-    assert_eq!(*tokens.first().unwrap(), Token::BracketOpen);
-    assert_eq!(*tokens.last().unwrap(), Token::BracketClose);
+    let open = tokens[*cur].0;
+    *cur += 1;
 
-    // Omit enclosing brackets
-    for tok in &tokens[1..tokens.len() - 1] {
+    loop {
+        let (offset, ref tok) = *token_at(tokens, *cur, end, Some((open, "'['")))?;
+        *cur += 1;
         match *tok {
-            Token::Number(x) => v.push(Entry::Number(x)),
-            Token::String(x) => v.push(Entry::String(String::from_utf8_lossy(x).into_owned())),
+            Token::Number(x, _) => v.push(EntryRef::Number(x)),
+            Token::String(x) => v.push(EntryRef::String(string_value(offset, x)?)),
             Token::Comma => {}
-            _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?}", *tok),
-                )))
-            }
+            // Only kept outside of lists, by buf_to_commented_textmap
+            Token::Comment(_) => {}
+            Token::BracketClose => return Ok(v),
+            _ => return Err((offset, format!("unexpected {} in a list", describe(tok)))),
         }
     }
+}
+
+// The key of the next value or section, or None if the next token is
+// the '}' closing the section.
+fn get_key<'a>(
+    tokens: &[Spanned<'a>],
+    cur: &mut usize,
+    end: usize,
+    open: Option<(usize, &str)>,
+) -> ParseResult<Option<&'a str>> {
+    let (offset, ref tok) = *token_at(tokens, *cur, end, open)?;
+    *cur += 1;
 
-    Ok(v)
+    match *tok {
+        Token::Ident(x) => Ok(Some(ascii(x))),
+        // lvmetad keys some maps by device number
+        Token::Number(_, x) => Ok(Some(ascii(x))),
+        Token::CurlyClose if open.is_some() => Ok(None),
+        _ => Err((
+            offset,
+            format!("unexpected {} where a key was expected", describe(tok)),
+        )),
+    }
 }
 
-// TODO: More appropriate error type than Result
-fn get_textmap<'a>(tokens: &[Token<'a>]) -> Result<LvmTextMap> {
-    let mut ret: LvmTextMap = BTreeMap::new();
+// What follows a key.
+enum AfterKey<'a> {
+    Value(EntryRef<'a>),
+    // A section, with the offset of its '{'.
+    Section(usize),
+}
 
-    assert_eq!(*tokens.first().unwrap(), Token::CurlyOpen);
-    assert_eq!(*tokens.last().unwrap(), Token::CurlyClose);
+// Parse the '=' and value after the key `ident`, or the '{' opening a
+// section, whose entries are left to be parsed.
+fn get_after_key<'a>(
+    tokens: &[Spanned<'a>],
+    cur: &mut usize,
+    end: usize,
+    open: Option<(usize, &str)>,
+    ident: &str,
+) -> ParseResult<AfterKey<'a>> {
+    let (offset, ref tok) = *token_at(tokens, *cur, end, open)?;
+    match *tok {
+        Token::Equals => {
+            *cur += 1;
+            let (offset, ref tok) = *token_at(tokens, *cur, end, open)?;
+            match *tok {
+                Token::Number(x, _) => {
+                    *cur += 1;
+                    Ok(AfterKey::Value(EntryRef::Number(x)))
+                }
+                Token::String(x) => {
+                    *cur += 1;
+                    Ok(AfterKey::Value(EntryRef::String(string_value(offset, x)?)))
+                }
+                Token::BracketOpen => {
+                    Ok(AfterKey::Value(EntryRef::List(get_list(tokens, cur, end)?)))
+                }
+                _ => Err((
+                    offset,
+                    format!("unexpected {} as the value of {}", describe(tok), ident),
+                )),
+            }
+        }
+        Token::CurlyOpen => {
+            *cur += 1;
+            Ok(AfterKey::Section(offset))
+        }
+        _ => Err((
+            offset,
+            format!(
+                "unexpected {} after {}, expected '=' or '{{'",
+                describe(tok),
+                ident
+            ),
+        )),
+    }
+}
 
-    let mut cur = 1;
+// Parse entries up to the '}' closing a section that opens at `open`,
+// or to the end of the text if None.
+fn get_textmap<'a>(
+    tokens: &[Spanned<'a>],
+    cur: &mut usize,
+    end: usize,
+    open: Option<usize>,
+) -> ParseResult<LvmTextMapRef<'a>> {
+    let mut ret = LvmTextMapRef::new();
+    let open_brace = open.map(|offset| (offset, "'{'"));
 
-    while tokens[cur] != Token::CurlyClose {
-        let ident = match tokens[cur] {
-            Token::Ident(x) => String::from_utf8_lossy(x).into_owned(),
-            Token::Comment(_) => {
-                cur += 1;
-                continue;
+    loop {
+        if *cur == tokens.len() && open.is_none() {
+            return Ok(ret);
+        }
+        let ident = match get_key(tokens, cur, end, open_brace)? {
+            Some(ident) => ident,
+            None => return Ok(ret),
+        };
+
+        match get_after_key(tokens, cur, end, open_brace, ident)? {
+            AfterKey::Value(value) => {
+                ret.insert(ident, value);
+            }
+            AfterKey::Section(offset) => {
+                let map = get_textmap(tokens, cur, end, Some(offset))?;
+                ret.insert(ident, EntryRef::TextMap(Box::new(map)));
             }
+        }
+    }
+}
+
+/// Generate an `LvmTextMap` from a textual LVM configuration string.
+///
+/// LVM uses the same configuration file format for it's on-disk metadata,
+/// as well as for the lvm.conf configuration file. Errors in the text
+/// are returned as `Error::Parse`, saying where they are. Strings that
+/// are not valid UTF-8 have the invalid parts replaced.
+pub fn buf_to_textmap(buf: &[u8]) -> Result<LvmTextMap> {
+    let text = String::from_utf8_lossy(buf);
+    buf_to_textmap_borrowed(text.as_bytes()).map(|map| textmap_ref_to_owned(&map))
+}
+
+/// Like `buf_to_textmap`, but keys and strings are borrowed from `buf`
+/// rather than copied, which saves allocating them when only a few
+/// values are wanted, as when reading the metadata of many PVs. Strings
+/// that are not valid UTF-8 are an error.
+pub fn buf_to_textmap_borrowed(buf: &[u8]) -> Result<LvmTextMapRef<'_>> {
+    let tokens: Vec<Spanned> = Lexer::new(buf)
+        .filter(|(_, tok)| !matches!(tok, Token::Comment(_)))
+        .collect();
+
+    // LVM vsn1 is implicitly a map at the top level
+    let mut cur = 0;
+    get_textmap(&tokens, &mut cur, buf.len(), None)
+        .map_err(|(offset, message)| Error::Parse(ParseError::new(buf, offset, message)))
+}
+
+/// Copy an `LvmTextMapRef` into an `LvmTextMap` that owns its keys and
+/// strings.
+pub fn textmap_ref_to_owned(map: &LvmTextMapRef) -> LvmTextMap {
+    map.iter()
+        .map(|(k, v)| (k.to_string(), v.to_owned_entry()))
+        .collect()
+}
+
+/// LVM configuration text as parsed by `buf_to_commented_textmap`,
+/// keeping its comments, its blank lines and the order of its values,
+/// so that a file like lvm.conf can have a value changed and be written
+/// back by `commented_textmap_to_buf` with its documentation intact.
+///
+/// Values are found by a path of section names and the value's name
+/// joined by '/', such as "devices/filter".
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CommentedTextMap {
+    /// The lines and sections, in order.
+    pub items: Vec<CommentedItem>,
+}
+
+/// An item of a `CommentedTextMap`. Comments directly before a value or
+/// section, with no blank line between, are taken to be about it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CommentedItem {
+    /// A comment on a line of its own, from its `#`.
+    Comment(String),
+    /// A blank line.
+    Blank,
+    /// A value other than a section, and the comment after it on the
+    /// same line, if any.
+    Value(String, Entry, Option<String>),
+    /// A section.
+    Section(String, CommentedTextMap),
+}
+
+// A path's section names, and the name of the value.
+fn split_path(path: &str) -> (Vec<&str>, &str) {
+    let mut names: Vec<_> = path.split('/').filter(|name| !name.is_empty()).collect();
+    let name = names.pop().unwrap_or("");
+    (names, name)
+}
+
+impl CommentedTextMap {
+    // The position of the last value or section named `name`, which is
+    // the one that counts.
+    fn position(&self, name: &str) -> Option<usize> {
+        self.items.iter().rposition(|item| match item {
+            CommentedItem::Value(k, _, _) | CommentedItem::Section(k, _) => k == name,
+            _ => false,
+        })
+    }
+
+    fn section(&self, name: &str) -> Option<&CommentedTextMap> {
+        match self.position(name).map(|pos| &self.items[pos]) {
+            Some(CommentedItem::Section(_, map)) => Some(map),
+            _ => None,
+        }
+    }
+
+    // The section named `name`, which is added at the end if there is
+    // none, or if a value has the name.
+    fn section_or_insert(&mut self, name: &str) -> &mut CommentedTextMap {
+        let pos = match self.position(name) {
+            Some(pos) if matches!(self.items[pos], CommentedItem::Section(_, _)) => pos,
             _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?} when seeking ident", tokens[cur]),
-                )))
+                self.items.push(CommentedItem::Section(
+                    name.to_string(),
+                    CommentedTextMap::default(),
+                ));
+                self.items.len() - 1
             }
         };
+        match self.items[pos] {
+            CommentedItem::Section(_, ref mut map) => map,
+            _ => unreachable!("found or added a section"),
+        }
+    }
 
-        cur += 1;
-        match tokens[cur] {
-            Token::Equals => {
-                cur += 1;
-                match tokens[cur] {
-                    Token::Number(x) => {
-                        cur += 1;
-                        ret.insert(ident, Entry::Number(x));
-                    }
-                    Token::String(x) => {
-                        cur += 1;
-                        ret.insert(
-                            ident,
-                            Entry::String(String::from_utf8_lossy(x).into_owned()),
-                        );
-                    }
-                    Token::BracketOpen => {
-                        let slc = find_matching_token(
-                            &tokens[cur..],
-                            &Token::BracketOpen,
-                            &Token::BracketClose,
-                        )?;
-                        ret.insert(ident, Entry::List(get_list(&slc)?));
-                        cur += slc.len();
-                    }
-                    _ => {
-                        return Err(Error::Io(io::Error::new(
-                            Other,
-                            format!("Unexpected {:?} as rvalue", tokens[cur]),
-                        )))
-                    }
+    /// The value at `path`. A section is not returned; use
+    /// `to_textmap` for that.
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        let (sections, name) = split_path(path);
+        let mut map = self;
+        for section in sections {
+            map = map.section(section)?;
+        }
+
+        match map.position(name).map(|pos| &map.items[pos]) {
+            Some(CommentedItem::Value(_, value, _)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Set the value at `path`. A value already there is replaced in
+    /// place, keeping its comments. Otherwise it is added at the end of
+    /// its section, which is added too if missing. An `Entry::TextMap`
+    /// is set as a section, in sorted order and without comments.
+    pub fn set(&mut self, path: &str, value: Entry) {
+        let (sections, name) = split_path(path);
+        let mut map = self;
+        for section in sections {
+            map = map.section_or_insert(section);
+        }
+
+        let item = match value {
+            Entry::TextMap(x) => CommentedItem::Section(name.to_string(), x.as_ref().into()),
+            value => CommentedItem::Value(name.to_string(), value, None),
+        };
+        match map.position(name) {
+            Some(pos) => match (&mut map.items[pos], item) {
+                (CommentedItem::Value(_, old, _), CommentedItem::Value(_, new, _)) => *old = new,
+                (old, item) => *old = item,
+            },
+            None => map.items.push(item),
+        }
+    }
+
+    /// Remove the value or section at `path`, with the comments about
+    /// it, and return it.
+    pub fn remove(&mut self, path: &str) -> Option<Entry> {
+        let (sections, name) = split_path(path);
+        let mut map = self;
+        for section in sections {
+            let pos = map.position(section)?;
+            map = match map.items[pos] {
+                CommentedItem::Section(_, ref mut map) => map,
+                _ => return None,
+            };
+        }
+
+        let pos = map.position(name)?;
+        let first = map.items[..pos]
+            .iter()
+            .rposition(|item| !matches!(item, CommentedItem::Comment(_)))
+            .map_or(0, |before| before + 1);
+        let entry = match map.items.drain(first..=pos).next_back() {
+            Some(CommentedItem::Value(_, value, _)) => value,
+            Some(CommentedItem::Section(_, x)) => Entry::TextMap(Box::new(x.to_textmap())),
+            _ => unreachable!("position finds values and sections"),
+        };
+
+        Some(entry)
+    }
+
+    /// The values without their comments or order, as `buf_to_textmap`
+    /// would give them.
+    pub fn to_textmap(&self) -> LvmTextMap {
+        let mut ret = LvmTextMap::new();
+        for item in &self.items {
+            match item {
+                CommentedItem::Value(k, v, _) => {
+                    ret.insert(k.clone(), v.clone());
                 }
+                CommentedItem::Section(k, x) => {
+                    ret.insert(k.clone(), Entry::TextMap(Box::new(x.to_textmap())));
+                }
+                CommentedItem::Comment(_) | CommentedItem::Blank => {}
             }
-            Token::CurlyOpen => {
-                let slc =
-                    find_matching_token(&tokens[cur..], &Token::CurlyOpen, &Token::CurlyClose)?;
-                ret.insert(ident, Entry::TextMap(Box::new(get_textmap(&slc)?)));
-                cur += slc.len();
+        }
+        ret
+    }
+}
+
+/// The values of an `LvmTextMap` in sorted order, with no comments.
+impl From<&LvmTextMap> for CommentedTextMap {
+    fn from(map: &LvmTextMap) -> CommentedTextMap {
+        CommentedTextMap {
+            items: map
+                .iter()
+                .map(|(k, v)| match v {
+                    Entry::TextMap(x) => CommentedItem::Section(k.clone(), x.as_ref().into()),
+                    v => CommentedItem::Value(k.clone(), v.clone(), None),
+                })
+                .collect(),
+        }
+    }
+}
+
+// The offset just past the end of a token.
+fn token_end(buf: &[u8], &(offset, ref tok): &Spanned) -> usize {
+    offset
+        + match *tok {
+            Token::String(x) => x.len() + 2,
+            Token::Ident(x) | Token::Number(_, x) | Token::Comment(x) | Token::BadNumber(x) => {
+                x.len()
             }
-            _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?} after an ident", tokens[cur]),
-                )))
+            Token::Unterminated => buf.len() - offset,
+            _ => 1,
+        }
+}
+
+// The number of line breaks between the token before `cur` and the
+// token at `cur`, or the end of the text.
+fn newlines_before(buf: &[u8], tokens: &[Spanned], cur: usize) -> usize {
+    let start = match cur {
+        0 => 0,
+        _ => token_end(buf, &tokens[cur - 1]),
+    };
+    let end = tokens.get(cur).map_or(buf.len(), |(offset, _)| *offset);
+    buf[start..end].iter().filter(|c| **c == b'\n').count()
+}
+
+// Like get_textmap, keeping comments and blank lines.
+fn get_commented_textmap(
+    buf: &[u8],
+    tokens: &[Spanned],
+    cur: &mut usize,
+    open: Option<usize>,
+) -> ParseResult<CommentedTextMap> {
+    let mut ret = CommentedTextMap::default();
+    let open_brace = open.map(|offset| (offset, "'{'"));
+
+    loop {
+        // A line break ends the line before; any more are blank lines.
+        let newlines = newlines_before(buf, tokens, *cur);
+        let blanks = match *cur {
+            0 => newlines,
+            _ => newlines.saturating_sub(1),
+        };
+        ret.items
+            .extend(std::iter::repeat_n(CommentedItem::Blank, blanks));
+
+        if *cur == tokens.len() && open.is_none() {
+            return Ok(ret);
+        }
+        if let Some((_, Token::Comment(x))) = tokens.get(*cur) {
+            *cur += 1;
+            let text = String::from_utf8_lossy(x).trim_end().to_string();
+            match ret.items.last_mut() {
+                Some(CommentedItem::Value(_, _, comment)) if comment.is_none() && newlines == 0 => {
+                    *comment = Some(text)
+                }
+                _ => ret.items.push(CommentedItem::Comment(text)),
             }
+            continue;
+        }
+
+        let ident = match get_key(tokens, cur, buf.len(), open_brace)? {
+            Some(ident) => ident,
+            None => return Ok(ret),
         };
+
+        match get_after_key(tokens, cur, buf.len(), open_brace, ident)? {
+            AfterKey::Value(value) => ret.items.push(CommentedItem::Value(
+                ident.to_string(),
+                value.to_owned_entry(),
+                None,
+            )),
+            AfterKey::Section(offset) => {
+                let map = get_commented_textmap(buf, tokens, cur, Some(offset))?;
+                ret.items
+                    .push(CommentedItem::Section(ident.to_string(), map));
+            }
+        }
     }
+}
+
+/// Like `buf_to_textmap`, but keeping the text's comments, blank lines
+/// and order, for a program that changes a file like lvm.conf and
+/// writes it back. Comments within lists are not kept.
+pub fn buf_to_commented_textmap(buf: &[u8]) -> Result<CommentedTextMap> {
+    let text = String::from_utf8_lossy(buf);
+    let buf = text.as_bytes();
+    let tokens: Vec<Spanned> = Lexer::new(buf).collect();
 
-    Ok(ret)
+    let mut cur = 0;
+    get_commented_textmap(buf, &tokens, &mut cur, None)
+        .map_err(|(offset, message)| Error::Parse(ParseError::new(buf, offset, message)))
 }
 
-/// Generate an `LvmTextMap` from a textual LVM configuration string.
-///
-/// LVM uses the same configuration file format for it's on-disk metadata,
-/// as well as for the lvm.conf configuration file.
-pub fn buf_to_textmap(buf: &[u8]) -> Result<LvmTextMap> {
-    let mut tokens: Vec<Token> = Vec::new();
+/// Generate LVM configuration text from a `CommentedTextMap`, with its
+/// comments and blank lines where they were. Sections are indented by a
+/// tab per level, as in lvm.conf, and each list is on one line.
+pub fn commented_textmap_to_buf(map: &CommentedTextMap) -> Vec<u8> {
+    let mut vec = Vec::new();
+    write_commented(map, 0, &mut vec);
+    vec
+}
+
+fn write_commented(map: &CommentedTextMap, depth: usize, vec: &mut Vec<u8>) {
+    let indent = "\t".repeat(depth);
 
-    // LVM vsn1 is implicitly a map at the top level, so add
-    // the appropriate tokens
-    tokens.push(Token::CurlyOpen);
-    tokens.extend(&mut Lexer::new(&buf));
-    tokens.push(Token::CurlyClose);
+    for item in &map.items {
+        match item {
+            CommentedItem::Blank => vec.push(b'\n'),
+            CommentedItem::Comment(x) => vec.extend(format!("{}{}\n", indent, x).as_bytes()),
+            CommentedItem::Value(k, v, comment) => {
+                vec.extend(indent.as_bytes());
+                vec.extend(k.as_bytes());
+                match v {
+                    Entry::String(ref x) => vec.extend(format!(" = {}", quote(x)).as_bytes()),
+                    Entry::Number(ref x) => vec.extend(format!(" = {}", x).as_bytes()),
+                    Entry::List(ref x) => {
+                        vec.extend(format!(" = {}", list_to_string(x)).as_bytes())
+                    }
+                    Entry::TextMap(ref x) => {
+                        vec.extend(b" {\n");
+                        write_canonical(x, depth + 1, vec);
+                        vec.extend(indent.as_bytes());
+                        vec.push(b'}');
+                    }
+                }
+                if let Some(comment) = comment {
+                    vec.extend(format!(" {}", comment).as_bytes());
+                }
+                vec.push(b'\n');
+            }
+            CommentedItem::Section(k, x) => {
+                vec.extend(format!("{}{} {{\n", indent, k).as_bytes());
+                write_commented(x, depth + 1, vec);
+                vec.extend(indent.as_bytes());
+                vec.extend(b"}\n");
+            }
+        }
+    }
+}
 
-    get_textmap(&tokens)
+/// Merge `over` into `base`. Sections present in both are merged
+/// recursively; any other value in `over` replaces the one in `base`.
+pub fn merge_textmap(base: &mut LvmTextMap, over: &LvmTextMap) {
+    for (k, v) in over {
+        match (base.get_mut(k), v) {
+            (Some(Entry::TextMap(ref mut b)), Entry::TextMap(o)) => merge_textmap(b, o),
+            _ => {
+                base.insert(k.clone(), v.clone());
+            }
+        }
+    }
 }
 
 /// Status may be either a string or a list of strings. Convert either
@@ -453,10 +1054,9 @@ pub fn status_from_textmap(map: &LvmTextMap) -> Result<Vec<String>> {
                 })
                 .collect()
         }),
-        _ => Err(Error::Io(io::Error::new(
-            Other,
-            "status textmap parsing error",
-        ))),
+        _ => Err(Error::Metadata(
+            "status is not a string or list".to_string(),
+        )),
     }
 }
 
@@ -468,9 +1068,9 @@ pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
         match v {
             Entry::String(ref x) => {
                 vec.extend(k.as_bytes());
-                vec.extend(b" = \"");
-                vec.extend(x.as_bytes());
-                vec.extend(b"\"\n");
+                vec.extend(b" = ");
+                vec.extend(quote(x).as_bytes());
+                vec.extend(b"\n");
             }
             &Entry::Number(ref x) => {
                 vec.extend(k.as_bytes());
@@ -479,17 +1079,9 @@ pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
             }
             &Entry::List(ref x) => {
                 vec.extend(k.as_bytes());
-                vec.extend(b" = [");
-                let z: Vec<_> = x
-                    .iter()
-                    .map(|x| match x {
-                        Entry::String(ref x) => format!("\"{}\"", x),
-                        Entry::Number(ref x) => format!("{}", x),
-                        _ => panic!("should not be in lists"),
-                    })
-                    .collect();
-                vec.extend(z.join(", ").as_bytes());
-                vec.extend(b"]\n");
+                vec.extend(b" = ");
+                vec.extend(list_to_string(x).as_bytes());
+                vec.extend(b"\n");
             }
             &Entry::TextMap(ref x) => {
                 vec.extend(k.as_bytes());
@@ -502,3 +1094,400 @@ pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
 
     vec
 }
+
+fn list_items(list: &[Entry]) -> Vec<String> {
+    list.iter()
+        .map(|x| match x {
+            Entry::String(ref x) => quote(x),
+            Entry::Number(ref x) => format!("{}", x),
+            _ => panic!("should not be in lists"),
+        })
+        .collect()
+}
+
+fn list_to_string(list: &[Entry]) -> String {
+    format!("[{}]", list_items(list).join(", "))
+}
+
+/// Generate LVM configuration text from an LvmTextMap in a canonical
+/// form, so that equal maps always give the same text and a change to
+/// one value changes one line: keys in sorted order, one per line,
+/// sections indented by a tab per level, and each list on one line.
+pub fn textmap_to_canonical_buf(tm: &LvmTextMap) -> Vec<u8> {
+    let mut vec = Vec::new();
+    write_canonical(tm, 0, &mut vec);
+    vec
+}
+
+fn write_canonical(tm: &LvmTextMap, depth: usize, vec: &mut Vec<u8>) {
+    let indent = "\t".repeat(depth);
+
+    for (k, v) in tm {
+        vec.extend(indent.as_bytes());
+        vec.extend(k.as_bytes());
+        match v {
+            Entry::String(ref x) => vec.extend(format!(" = {}\n", quote(x)).as_bytes()),
+            Entry::Number(ref x) => vec.extend(format!(" = {}\n", x).as_bytes()),
+            Entry::List(ref x) => vec.extend(format!(" = {}\n", list_to_string(x)).as_bytes()),
+            Entry::TextMap(ref x) => {
+                vec.extend(b" {\n");
+                write_canonical(x, depth + 1, vec);
+                vec.extend(indent.as_bytes());
+                vec.extend(b"}\n");
+            }
+        }
+    }
+}
+
+// The order LVM2 writes the values of a section in, other than at the
+// top level. Values not listed come after, in sorted order.
+const PRETTY_ORDER: &[&str] = &[
+    "id",
+    "seqno",
+    "format",
+    "device",
+    "status",
+    "flags",
+    "tags",
+    "creation_time",
+    "creation_host",
+    "extent_size",
+    "max_lv",
+    "max_pv",
+    "metadata_copies",
+    "allocation_policy",
+    "profile",
+    "dev_size",
+    "pe_start",
+    "pe_count",
+    "ba_start",
+    "ba_size",
+    "segment_count",
+    "start_extent",
+    "extent_count",
+    "type",
+    "stripe_count",
+    "stripe_size",
+    "region_size",
+    "mirror_count",
+    "mirror_log",
+    "thin_pool",
+    "origin",
+    "cow_store",
+    "chunk_size",
+    "metadata",
+    "pool",
+    "transaction_id",
+    "device_id",
+    "discards",
+    "zero_new_blocks",
+    "stripes",
+    "mirrors",
+    "raids",
+];
+
+// The order of values at the top level, outside of the VG's section.
+const PRETTY_TOP_ORDER: &[&str] = &[
+    "contents",
+    "version",
+    "description",
+    "creation_host",
+    "creation_time",
+];
+
+// The order of a VG's sections. Others, such as each PV, LV and
+// segment, come in order of name, with numbers compared as numbers.
+const PRETTY_SECTION_ORDER: &[&str] = &[
+    "physical_volumes",
+    "logical_volumes",
+    "historical_logical_volumes",
+];
+
+// Values with a blank line before them.
+const PRETTY_BLANK_BEFORE: &[&str] = &["type", "stripes", "mirrors", "raids"];
+const PRETTY_TOP_BLANK_BEFORE: &[&str] = &["description", "creation_host"];
+
+// Split a name like "segment10" into "segment" and 10, so that it sorts
+// after "segment9".
+fn natural_key(name: &str) -> (&str, Option<u64>) {
+    let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    (prefix, name[prefix.len()..].parse().ok())
+}
+
+fn pretty_rank<'a>(order: &[&str], name: &'a str) -> (usize, (&'a str, Option<u64>)) {
+    match order.iter().position(|key| *key == name) {
+        Some(pos) => (pos, ("", None)),
+        None => (order.len(), natural_key(name)),
+    }
+}
+
+/// Generate LVM configuration text from an LvmTextMap laid out as LVM2
+/// writes metadata, so that it can be compared with LVM2's backups:
+/// values in LVM2's order, sections indented by a tab per level, and
+/// blank lines between sections and groups of values.
+pub fn textmap_to_pretty_buf(tm: &LvmTextMap) -> Vec<u8> {
+    let mut vec = Vec::new();
+    write_pretty(tm, 0, &mut vec);
+    vec
+}
+
+fn write_pretty(tm: &LvmTextMap, depth: usize, vec: &mut Vec<u8>) {
+    let indent = "\t".repeat(depth);
+    let (order, blank_before) = match depth {
+        0 => (PRETTY_TOP_ORDER, PRETTY_TOP_BLANK_BEFORE),
+        _ => (PRETTY_ORDER, PRETTY_BLANK_BEFORE),
+    };
+
+    let (mut sections, mut values): (Vec<_>, Vec<_>) =
+        tm.iter().partition(|(_, v)| matches!(v, Entry::TextMap(_)));
+    values.sort_by_key(|(k, _)| pretty_rank(order, k));
+    sections.sort_by_key(|(k, _)| pretty_rank(PRETTY_SECTION_ORDER, k));
+
+    // A PV's status is set apart from its id and device.
+    let is_pv = tm.contains_key("dev_size");
+
+    for (k, v) in values {
+        if blank_before.contains(&k.as_str()) || (is_pv && k == "status") {
+            vec.push(b'\n');
+        }
+        vec.extend(indent.as_bytes());
+        vec.extend(k.as_bytes());
+        match v {
+            Entry::String(ref x) => vec.extend(format!(" = {}\n", quote(x)).as_bytes()),
+            Entry::Number(ref x) => vec.extend(format!(" = {}\n", x).as_bytes()),
+            // Each stripe is on a line of its own, as a PV or LV and
+            // the extent it starts at.
+            Entry::List(ref x) if k == "stripes" && !x.is_empty() => {
+                let lines: Vec<_> = x
+                    .chunks(2)
+                    .map(|stripe| format!("{}\t{}", indent, list_items(stripe).join(", ")))
+                    .collect();
+                vec.extend(format!(" = [\n{}\n{}]\n", lines.join(",\n"), indent).as_bytes());
+            }
+            Entry::List(ref x) => vec.extend(format!(" = {}\n", list_to_string(x)).as_bytes()),
+            Entry::TextMap(_) => unreachable!("sections are written below"),
+        }
+    }
+
+    for (k, v) in sections {
+        if let Entry::TextMap(ref x) = v {
+            vec.push(b'\n');
+            vec.extend(indent.as_bytes());
+            vec.extend(k.as_bytes());
+            vec.extend(b" {\n");
+            write_pretty(x, depth + 1, vec);
+            // LVM2 ends the VG's section with a blank line.
+            if depth == 0 {
+                vec.push(b'\n');
+            }
+            vec.extend(indent.as_bytes());
+            vec.extend(b"}\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse `text` both ways, checking that they agree.
+    fn parse(text: &str) -> LvmTextMap {
+        let owned = buf_to_textmap(text.as_bytes()).unwrap();
+        let borrowed = buf_to_textmap_borrowed(text.as_bytes()).unwrap();
+        assert_eq!(textmap_ref_to_owned(&borrowed), owned);
+        owned
+    }
+
+    fn string(map: &LvmTextMap, key: &str) -> String {
+        match map.get(key) {
+            Some(Entry::String(x)) => x.clone(),
+            other => panic!("{} is {:?}", key, other),
+        }
+    }
+
+    #[test]
+    fn escaped_strings() {
+        let map = parse(
+            r#"quote = "say \"hi\""
+backslash = "a\\b"
+trailing = "a\\"
+other = "a\nb"
+list = ["\"", "\\"]
+"#,
+        );
+        assert_eq!(string(&map, "quote"), r#"say "hi""#);
+        assert_eq!(string(&map, "backslash"), r"a\b");
+        assert_eq!(string(&map, "trailing"), r"a\");
+        // A backslash before any other character is kept, as in LVM2.
+        assert_eq!(string(&map, "other"), r"a\nb");
+        assert_eq!(
+            map.get("list"),
+            Some(&Entry::List(vec![
+                Entry::String(r#"""#.to_string()),
+                Entry::String(r"\".to_string()),
+            ]))
+        );
+
+        // Written back, each string reads as the same value.
+        assert_eq!(parse(str::from_utf8(&textmap_to_buf(&map)).unwrap()), map);
+        assert_eq!(
+            parse(str::from_utf8(&textmap_to_canonical_buf(&map)).unwrap()),
+            map
+        );
+        assert_eq!(quote(r#"a\"b"#), r#""a\\\"b""#);
+    }
+
+    #[test]
+    fn unescaped_strings_are_borrowed() {
+        let map = buf_to_textmap_borrowed(br#"a = "plain" b = "es\\caped""#).unwrap();
+        assert!(matches!(
+            map.get("a"),
+            Some(EntryRef::String(Cow::Borrowed("plain")))
+        ));
+        assert!(matches!(
+            map.get("b"),
+            Some(EntryRef::String(Cow::Owned(_)))
+        ));
+    }
+
+    #[test]
+    fn backslash_before_closing_quote_escapes_it() {
+        for text in [&br#"a = "x\""#[..], &b"a = \"x\\"[..]] {
+            match buf_to_textmap(text) {
+                Err(Error::Parse(_)) => (),
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+            assert!(buf_to_textmap_borrowed(text).is_err());
+        }
+    }
+
+    const LVM_CONF: &str = "# This is an example configuration file.
+
+# Configuration section devices.
+devices {
+\t# Where to look for block devices.
+\tdir = \"/dev\"
+\tscan = [\"/dev\"]
+
+\t# Accept every block device.
+\tfilter = [\"a|.*|\"] # the default
+\tsysfs_scan = 1
+}
+
+global {
+\tumask = 63
+}
+";
+
+    #[test]
+    fn commented_text_is_written_back_as_it_was() {
+        let mut map = buf_to_commented_textmap(LVM_CONF.as_bytes()).unwrap();
+        assert_eq!(commented_textmap_to_buf(&map), LVM_CONF.as_bytes());
+        assert_eq!(
+            map.to_textmap(),
+            buf_to_textmap(LVM_CONF.as_bytes()).unwrap()
+        );
+
+        map.set(
+            "devices/filter",
+            Entry::List(vec![
+                Entry::String("a|/dev/sd.*|".to_string()),
+                Entry::String("r|.*|".to_string()),
+            ]),
+        );
+        assert_eq!(map.get("devices/sysfs_scan"), Some(&Entry::Number(1)));
+        let expected = LVM_CONF.replace(
+            "filter = [\"a|.*|\"]",
+            "filter = [\"a|/dev/sd.*|\", \"r|.*|\"]",
+        );
+        assert_eq!(
+            str::from_utf8(&commented_textmap_to_buf(&map)).unwrap(),
+            expected
+        );
+    }
+
+    fn parse_error(text: &str) -> ParseError {
+        let owned = match buf_to_textmap(text.as_bytes()) {
+            Err(Error::Parse(err)) => err,
+            other => panic!("{:?} parsed as {:?}", text, other),
+        };
+        match buf_to_textmap_borrowed(text.as_bytes()) {
+            Err(Error::Parse(err)) => assert_eq!(err, owned),
+            other => panic!("{:?} parsed as {:?}", text, other),
+        }
+        owned
+    }
+
+    #[test]
+    fn unterminated_string_error() {
+        let err = parse_error("a = 1\nb = \"abc\n");
+        assert_eq!(
+            err.message,
+            "unexpected string with no closing quote as the value of b"
+        );
+        assert_eq!((err.line, err.column), (2, 5));
+        assert_eq!((err.snippet.as_str(), err.snippet_column), ("b = \"abc", 5));
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 5: unexpected string with no closing quote as the value of b\n  \
+             b = \"abc\n      ^"
+        );
+    }
+
+    #[test]
+    fn unclosed_section_error() {
+        // The error points at the '{' rather than at the end of the text.
+        let err = parse_error("vg {\n\ta = 1\n");
+        assert!(err.message.ends_with("is never closed"), "{}", err.message);
+        assert_eq!((err.line, err.column), (1, 4));
+        assert_eq!((err.snippet.as_str(), err.snippet_column), ("vg {", 4));
+    }
+
+    #[test]
+    fn tabs_in_snippet_are_spaces() {
+        let err = parse_error("s {\n\t\tb = ]\n}\n");
+        // A tab is one column, and one space in the snippet.
+        assert_eq!((err.line, err.column), (2, 7));
+        assert_eq!((err.snippet.as_str(), err.snippet_column), ("  b = ]", 7));
+        assert!(err.to_string().ends_with("\n    b = ]\n        ^"));
+    }
+
+    #[test]
+    fn long_line_snippet_is_around_the_column() {
+        let text = format!("long = \"{}\" ] {}\n", "a".repeat(100), "z ".repeat(60));
+        let err = parse_error(&text);
+        assert_eq!(err.message, "unexpected ']' where a key was expected");
+        assert_eq!((err.line, err.column), (1, 111));
+
+        // The snippet starts far enough in to have the column at its
+        // middle, and is cut off at SNIPPET_LEN characters.
+        assert_eq!(err.snippet.chars().count(), SNIPPET_LEN);
+        assert_eq!(err.snippet_column, SNIPPET_LEN / 2 + 1);
+        assert_eq!(err.snippet.chars().nth(err.snippet_column - 1), Some(']'));
+        assert!(err.snippet.starts_with("aaaa"));
+
+        // Near the start of a long line, nothing is skipped.
+        let err = parse_error(&format!("= {}\n", "a".repeat(200)));
+        assert_eq!((err.column, err.snippet_column), (1, 1));
+        assert_eq!(err.snippet.chars().count(), SNIPPET_LEN);
+    }
+
+    #[test]
+    fn end_of_text_errors() {
+        let err = parse_error("a =");
+        assert_eq!(err.message, "unexpected end of text");
+        assert_eq!((err.line, err.column), (1, 4));
+        assert_eq!(err.snippet, "a =");
+
+        // After a final newline, the error is at the start of an empty
+        // last line.
+        let err = parse_error("a =\n");
+        assert_eq!((err.line, err.column), (2, 1));
+        assert_eq!((err.snippet.as_str(), err.snippet_column), ("", 1));
+
+        // Offsets past the end are taken to be at it.
+        let err = ParseError::new(b"ab\ncd", 100, "past the end".to_string());
+        assert_eq!((err.line, err.column), (2, 3));
+        assert_eq!(err.snippet, "cd");
+    }
+}
@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Metadata profiles, as LVM2 keeps in /etc/lvm/profile.
+//!
+//! A profile is a file in lvm.conf's format named "<name>.profile".
+//! A VG or LV names one in its metadata with `profile = "<name>"`, and
+//! the settings in it then apply to that VG or LV in place of the
+//! defaults. An LV's profile takes precedence over its VG's.
+//!
+//! Melvin uses the settings that affect how thin pools are created:
+//! `allocation/thin_pool_chunk_size`, `allocation/thin_pool_zero` and
+//! `allocation/thin_pool_discards`. Others are ignored.
+
+use std::fs::read;
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::Path;
+
+use crate::parser::{buf_to_textmap, LvmTextMap, TextMapOps};
+use crate::{Error, Result};
+
+/// Where profiles are found, unless configured otherwise.
+pub(crate) const DEFAULT_PROFILE_DIR: &str = "/etc/lvm/profile";
+
+// The kernel's limits on a thin pool's chunk size, in sectors. It must
+// also be a multiple of the minimum.
+const MIN_THIN_CHUNK_SIZE: u64 = 128; // 64KiB
+const MAX_THIN_CHUNK_SIZE: u64 = 2 * 1024 * 1024; // 1GiB
+
+fn profile_err(name: &str, what: &str) -> Error {
+    Error::Io(io::Error::new(Other, format!("Profile {}: {}", name, what)))
+}
+
+/// The settings of a profile that Melvin uses. Those the profile does
+/// not give are None.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Profile {
+    /// The profile's name.
+    pub name: String,
+    /// `allocation/thin_pool_chunk_size`: the chunk size of new thin
+    /// pools, in 512-byte sectors. The file gives it in KiB.
+    pub thin_pool_chunk_size: Option<u64>,
+    /// `allocation/thin_pool_zero`: whether new thin pools zero newly
+    /// provisioned chunks.
+    pub thin_pool_zero: Option<bool>,
+    /// `allocation/thin_pool_discards`: how new thin pools handle
+    /// discards, "ignore", "nopassdown" or "passdown".
+    pub thin_pool_discards: Option<String>,
+}
+
+impl Profile {
+    /// Read the profile `name` from `dir`, such as
+    /// `Config::profile_dir`.
+    pub fn load(dir: &Path, name: &str) -> Result<Profile> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(profile_err(name, "invalid name"));
+        }
+
+        let buf = read(dir.join(format!("{}.profile", name)))?;
+        Profile::from_textmap(name, &buf_to_textmap(&buf)?)
+    }
+
+    /// Get the settings from a profile's contents, checking that they
+    /// are usable.
+    pub fn from_textmap(name: &str, map: &LvmTextMap) -> Result<Profile> {
+        let mut profile = Profile {
+            name: name.to_string(),
+            ..Profile::default()
+        };
+        let allocation = match map.textmap_from_textmap("allocation") {
+            Some(allocation) => allocation,
+            None => return Ok(profile),
+        };
+
+        if let Some(kib) = allocation.i64_from_textmap("thin_pool_chunk_size") {
+            let sectors = (kib as u64).saturating_mul(2);
+            if kib < 0
+                || !(MIN_THIN_CHUNK_SIZE..=MAX_THIN_CHUNK_SIZE).contains(&sectors)
+                || !sectors.is_multiple_of(MIN_THIN_CHUNK_SIZE)
+            {
+                return Err(profile_err(
+                    name,
+                    &format!("invalid thin_pool_chunk_size {}", kib),
+                ));
+            }
+            profile.thin_pool_chunk_size = Some(sectors);
+        }
+        profile.thin_pool_zero = allocation
+            .i64_from_textmap("thin_pool_zero")
+            .map(|zero| zero != 0);
+        if let Some(discards) = allocation.string_from_textmap("thin_pool_discards") {
+            if !["ignore", "nopassdown", "passdown"].contains(&discards) {
+                return Err(profile_err(
+                    name,
+                    &format!("invalid thin_pool_discards {}", discards),
+                ));
+            }
+            profile.thin_pool_discards = Some(discards.to_string());
+        }
+
+        Ok(profile)
+    }
+
+    /// The settings of this profile, with those it does not give taken
+    /// from `fallback`.
+    pub fn or(self, fallback: Profile) -> Profile {
+        Profile {
+            name: self.name,
+            thin_pool_chunk_size: self.thin_pool_chunk_size.or(fallback.thin_pool_chunk_size),
+            thin_pool_zero: self.thin_pool_zero.or(fallback.thin_pool_zero),
+            thin_pool_discards: self.thin_pool_discards.or(fallback.thin_pool_discards),
+        }
+    }
+}
@@ -5,30 +5,27 @@
 //! Physical Volumes
 
 use std::fs::File;
-use std::io;
-use std::io::ErrorKind::Other;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
-use devicemapper::Device;
 use nix::sys::stat;
 
+use crate::device::Device;
 use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
 use crate::{Error, Result};
 
 pub fn dev_from_textmap(map: &LvmTextMap) -> Result<Device> {
     let entry = map
         .get("device")
-        .ok_or_else(|| Error::Io(io::Error::new(Other, "device textmap parsing error")))?;
+        .ok_or_else(|| Error::Metadata("PV has no device".to_string()))?;
 
     let val = match entry {
         Entry::String(s) => stat::stat(&**s)?.st_rdev as i64,
         &Entry::Number(x) => x,
         _ => {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                "device textmap parsing error",
-            )))
+            return Err(Error::Metadata(
+                "PV device is not a path or number".to_string(),
+            ))
         }
     };
 
@@ -36,7 +33,7 @@ pub fn dev_from_textmap(map: &LvmTextMap) -> Result<Device> {
 }
 
 /// A Physical Volume that is part of a Volume Group.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PV {
     /// Its UUID
     pub id: String,
@@ -46,15 +43,40 @@ pub struct PV {
     pub status: Vec<String>,
     /// Flags
     pub flags: Vec<String>,
+    /// Tags, used to pick which PVs hold metadata
+    pub tags: Vec<String>,
     /// The device's size, in sectors
     pub dev_size: u64,
     /// The offset in sectors of where the first extent starts
     pub pe_start: u64,
     /// The number of extents in the PV
     pub pe_count: u64,
+    /// The offset in sectors of the bootloader area
+    pub ba_start: u64,
+    /// The size in sectors of the bootloader area, 0 if there is none
+    pub ba_size: u64,
 }
 
 impl PV {
+    // The extents that overlap the bootloader area, as (start, len), if
+    // any do.
+    pub(crate) fn ba_extents(&self, extent_size: u64) -> Option<(u64, u64)> {
+        let ba_end = self.ba_start + self.ba_size;
+        if self.ba_size == 0 || ba_end <= self.pe_start {
+            return None;
+        }
+
+        let first = self.ba_start.saturating_sub(self.pe_start) / extent_size;
+        let last = (ba_end - self.pe_start)
+            .div_ceil(extent_size)
+            .min(self.pe_count);
+        if first >= last {
+            return None;
+        }
+
+        Some((first, last - first))
+    }
+
     pub fn path(&self) -> Option<PathBuf> {
         let f = File::open("/proc/partitions").expect("Could not open /proc/partitions");
 
@@ -77,13 +99,17 @@ impl PV {
 
 /// Construct a PV from an LvmTextMap.
 pub fn from_textmap(map: &LvmTextMap) -> Result<PV> {
-    let err = || Error::Io(io::Error::new(Other, "pv textmap parsing error"));
+    let err = || Error::Metadata("PV is missing a required value".to_string());
 
     let id = map.string_from_textmap("id").ok_or_else(err)?;
     let device = dev_from_textmap(map)?;
     let dev_size = map.i64_from_textmap("dev_size").ok_or_else(err)?;
     let pe_start = map.i64_from_textmap("pe_start").ok_or_else(err)?;
     let pe_count = map.i64_from_textmap("pe_count").ok_or_else(err)?;
+    // "ba_start" and "ba_size" are only present if there is a
+    // bootloader area
+    let ba_start = map.i64_from_textmap("ba_start").unwrap_or(0);
+    let ba_size = map.i64_from_textmap("ba_size").unwrap_or(0);
 
     let status = status_from_textmap(map)?;
 
@@ -97,14 +123,30 @@ pub fn from_textmap(map: &LvmTextMap) -> Result<PV> {
         })
         .collect();
 
+    // "tags" may be absent
+    let tags: Vec<_> = map
+        .list_from_textmap("tags")
+        .map(|list| {
+            list.iter()
+                .filter_map(|item| match item {
+                    Entry::String(ref x) => Some(x.clone()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
     Ok(PV {
         id: id.to_string(),
         device,
         status,
         flags,
+        tags,
         dev_size: dev_size as u64,
         pe_start: pe_start as u64,
         pe_count: pe_count as u64,
+        ba_start: ba_start as u64,
+        ba_size: ba_size as u64,
     })
 }
 
@@ -125,9 +167,85 @@ pub fn to_textmap(pv: &PV) -> LvmTextMap {
         Entry::List(pv.flags.iter().map(|x| Entry::String(x.clone())).collect()),
     );
 
+    if !pv.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(pv.tags.iter().map(|x| Entry::String(x.clone())).collect()),
+        );
+    }
+
     map.insert("dev_size".to_string(), Entry::Number(pv.dev_size as i64));
     map.insert("pe_start".to_string(), Entry::Number(pv.pe_start as i64));
     map.insert("pe_count".to_string(), Entry::Number(pv.pe_count as i64));
 
+    if pv.ba_size != 0 {
+        map.insert("ba_start".to_string(), Entry::Number(pv.ba_start as i64));
+        map.insert("ba_size".to_string(), Entry::Number(pv.ba_size as i64));
+    }
+
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 MiB, in sectors
+    const EXTENT_SIZE: u64 = 8192;
+    // 2^32 sectors, past which sector offsets don't fit in 32 bits
+    const SECTORS_2TIB: u64 = 1 << 32;
+    // 100 TiB, in sectors
+    const SECTORS_100TIB: u64 = 100 << 31;
+
+    fn pv_with_ba(dev_size: u64, ba_start: u64, ba_size: u64) -> PV {
+        let pe_start = 2048;
+
+        PV {
+            id: "Bd2Q1V-3sWk-pQ3v-XTbZ-fmSr-fE5G-2rDq0v".to_string(),
+            device: Device::from(2049),
+            status: vec!["ALLOCATABLE".to_string()],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            dev_size,
+            pe_start,
+            pe_count: (dev_size - pe_start) / EXTENT_SIZE,
+            ba_start,
+            ba_size,
+        }
+    }
+
+    #[test]
+    fn ba_extents_past_32_bits() {
+        // Straddling an extent boundary just past 2^32 sectors
+        let pv = pv_with_ba(SECTORS_100TIB, SECTORS_2TIB + 100, EXTENT_SIZE);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), Some((524_287, 2)));
+
+        // Taking up the last sector of the last extent
+        let end = pv.pe_start + pv.pe_count * EXTENT_SIZE;
+        let pv = pv_with_ba(SECTORS_100TIB, end - 1, 1);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), Some((pv.pe_count - 1, 1)));
+        assert_eq!(pv.pe_count, 26_214_399);
+    }
+
+    #[test]
+    fn ba_extents_outside_extents() {
+        // No BA
+        let pv = pv_with_ba(SECTORS_100TIB, 0, 0);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), None);
+
+        // Before the first extent
+        let pv = pv_with_ba(SECTORS_100TIB, 1024, 1024);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), None);
+
+        // After the last, in the space too small to be an extent
+        let pv = pv_with_ba(SECTORS_2TIB + 4096, SECTORS_2TIB + 2048, 1024);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), None);
+    }
+
+    #[test]
+    fn ba_extents_clamped_to_pv() {
+        // Running on past the last extent
+        let pv = pv_with_ba(SECTORS_2TIB + 4096, SECTORS_2TIB - 1024, 4096);
+        assert_eq!(pv.ba_extents(EXTENT_SIZE), Some((pv.pe_count - 1, 1)));
+    }
+}
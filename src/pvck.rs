@@ -0,0 +1,466 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Checking and repairing the on-disk structures of a PV, as `pvck`
+//! does for LVM2.
+//!
+//! `PvHeader::verify` reports everything wrong with a PV's label, its
+//! pvheader and its metadata area headers, where reading the PV would
+//! stop at the first problem. `PvHeader::repair` also fixes what it
+//! can from a good copy of the VG's metadata: a damaged metadata area
+//! is rewritten from another, and a damaged label and pvheader are
+//! rebuilt from the PV's entry in the metadata.
+
+use std::fs::{canonicalize, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::blockdev;
+use crate::device::{Device, DeviceExt};
+use crate::parser::{buf_to_textmap, Entry, LvmTextMap, TextMapOps};
+use crate::pv::dev_from_textmap;
+use crate::pvlabel::{
+    PvArea, PvHeader, RawLocn, DEFAULT_MDA_SIZE, EXTENSION_VERSION, LABEL_SCAN_SECTORS,
+    LABEL_SECTOR, LABEL_SIZE, MDA_HEADER_SIZE, MDA_MAGIC, SECTOR_SIZE,
+};
+use crate::util::crc32_calc;
+use crate::Result;
+
+/// A problem with a PV found by `PvHeader::verify`. Metadata areas are
+/// numbered in the order the pvheader lists them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PvckProblem {
+    /// None of the sectors a label may be in has one.
+    NoLabel,
+    /// The label is in this sector rather than sector 1, where LVM2
+    /// writes it. It is still found there, so this is not repaired.
+    LabelMisplaced(u64),
+    /// A sector after the one the label was found in has another,
+    /// left behind by something else.
+    ExtraLabel(u64),
+    /// The sector the label records being in, which is not the one it
+    /// is in.
+    LabelSectorMismatch(u64),
+    /// The label's CRC does not match its contents.
+    LabelCrc,
+    /// The label's type is not "LVM2 001".
+    LabelType(String),
+    /// The pvheader does not follow the label directly.
+    PvHeaderOffset(u32),
+    /// An area the pvheader lists goes past the end of the device.
+    AreaOutsideDevice(PvArea),
+    /// A metadata area header's CRC does not match its contents.
+    MdaHeaderCrc(usize),
+    /// A metadata area header does not start with the magic number.
+    MdaMagic(usize),
+    /// A metadata area header has a version other than 1.
+    MdaVersion(usize, u32),
+    /// A metadata area header records a different start or size for
+    /// its area than the pvheader does.
+    MdaLocation(usize),
+    /// A metadata area header points to text outside of its area.
+    RlocnOutOfRange(usize, RawLocn),
+    /// The text a metadata area header points to does not match its
+    /// checksum.
+    MdaTextCrc(usize),
+}
+
+/// The results of checking a PV.
+#[derive(Debug, Default, PartialEq)]
+pub struct PvckReport {
+    /// What was found wrong. After a repair, what was found before it.
+    pub problems: Vec<PvckProblem>,
+    /// Whether anything was rewritten.
+    pub repaired: bool,
+}
+
+impl PvckReport {
+    /// Whether the PV passed every check.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl PvHeader {
+    /// Check the label, pvheader and metadata area headers of the PV on
+    /// `path`, reporting every problem found. Nothing is written.
+    pub fn verify(path: &Path) -> Result<PvckReport> {
+        check(path, false)
+    }
+
+    /// Like `verify`, but rewrite what is damaged from a good copy of
+    /// the metadata, if there is one. Problems that can't be repaired
+    /// that way are still reported, with nothing written for them.
+    pub fn repair(path: &Path) -> Result<PvckReport> {
+        check(path, true)
+    }
+}
+
+fn check(path: &Path, repair: bool) -> Result<PvckReport> {
+    let path = canonicalize(path)?;
+    let mut f = OpenOptions::new().read(true).write(repair).open(&path)?;
+    let dev_size = blockdev::size(&f)?;
+
+    let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+    f.read_exact(&mut buf)?;
+
+    let mut report = PvckReport::default();
+    let (pvheader, labels) = check_label(&buf, &path, &mut report.problems);
+
+    // Other labels are only removed once there is a good one: the first,
+    // or after rebuilding, the one in sector 1.
+    let stale_labels: Vec<_> = match pvheader {
+        Some(pvh) => {
+            check_areas(&pvh, dev_size, &mut report.problems);
+            let good_map = check_mdas(&pvh, &mut f, &mut report.problems)?;
+
+            if repair {
+                report.repaired = repair_mdas(&pvh, &mut f, good_map, &report.problems)?;
+            }
+            labels.into_iter().skip(1).collect()
+        }
+        None if repair && rebuild_label(&path, &mut f, dev_size)? => {
+            report.repaired = true;
+            labels
+                .into_iter()
+                .filter(|x| *x != LABEL_SECTOR as u64)
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    if repair {
+        for sector in stale_labels {
+            f.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+            f.write_all(&[0u8; 8])?;
+            report.repaired = true;
+        }
+    }
+    if report.repaired {
+        f.sync_all()?;
+    }
+
+    Ok(report)
+}
+
+// Check the label, returning the pvheader if it can be trusted, and the
+// sectors with labels.
+fn check_label(
+    buf: &[u8],
+    path: &Path,
+    problems: &mut Vec<PvckProblem>,
+) -> (Option<PvHeader>, Vec<u64>) {
+    let labels: Vec<_> = (0..LABEL_SCAN_SECTORS)
+        .filter(|x| &buf[x * SECTOR_SIZE..x * SECTOR_SIZE + 8] == b"LABELONE")
+        .collect();
+
+    let sector = match labels.first() {
+        Some(&sector) => sector,
+        None => {
+            problems.push(PvckProblem::NoLabel);
+            return (None, Vec::new());
+        }
+    };
+    let labels: Vec<_> = labels.into_iter().map(|x| x as u64).collect();
+    problems.extend(labels[1..].iter().map(|x| PvckProblem::ExtraLabel(*x)));
+    if sector != LABEL_SECTOR {
+        problems.push(PvckProblem::LabelMisplaced(sector as u64));
+    }
+
+    let sec_buf = &buf[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE];
+    let mut trusted = true;
+
+    let recorded = LittleEndian::read_u64(&sec_buf[8..16]);
+    if recorded != sector as u64 {
+        problems.push(PvckProblem::LabelSectorMismatch(recorded));
+        trusted = false;
+    }
+    if LittleEndian::read_u32(&sec_buf[16..20]) != crc32_calc(&sec_buf[20..]) {
+        problems.push(PvckProblem::LabelCrc);
+        trusted = false;
+    }
+    let offset = LittleEndian::read_u32(&sec_buf[20..24]);
+    if offset as usize != LABEL_SIZE {
+        problems.push(PvckProblem::PvHeaderOffset(offset));
+        trusted = false;
+    }
+    let label_type = String::from_utf8_lossy(&sec_buf[24..32]).into_owned();
+    if label_type != "LVM2 001" {
+        problems.push(PvckProblem::LabelType(label_type));
+        trusted = false;
+    }
+
+    if !trusted {
+        return (None, labels);
+    }
+
+    let pvh = PvHeader::from_buf(&buf[sector * SECTOR_SIZE + LABEL_SIZE..], path).ok();
+    (pvh, labels)
+}
+
+fn check_areas(pvh: &PvHeader, dev_size: u64, problems: &mut Vec<PvckProblem>) {
+    let areas = pvh
+        .data_areas
+        .iter()
+        .chain(&pvh.metadata_areas)
+        .chain(&pvh.bootloader_areas);
+    for area in areas {
+        if area.offset + area.size > dev_size {
+            problems.push(PvckProblem::AreaOutsideDevice(*area));
+        }
+    }
+}
+
+// Check each MDA header, and the text its rlocns point to. Returns the
+// current metadata from the first MDA where it is good, if any.
+fn check_mdas(
+    pvh: &PvHeader,
+    f: &mut File,
+    problems: &mut Vec<PvckProblem>,
+) -> Result<Option<LvmTextMap>> {
+    let mut good_map = None;
+
+    for (idx, area) in pvh.metadata_areas.iter().enumerate() {
+        let rlocns = match check_mda_header(idx, area, f, problems)? {
+            Some(rlocns) => rlocns,
+            None => continue,
+        };
+
+        for (slot, rl) in rlocns.iter().enumerate() {
+            let rl = match rl {
+                Some(rl) => rl,
+                None => continue,
+            };
+            let text_space = area.size - MDA_HEADER_SIZE as u64;
+            if rl.offset < MDA_HEADER_SIZE as u64 || rl.offset >= area.size || rl.size > text_space
+            {
+                problems.push(PvckProblem::RlocnOutOfRange(idx, *rl));
+                continue;
+            }
+
+            match PvHeader::read_mda_text(area, f, rl, &pvh.dev_path) {
+                Ok(text) => {
+                    if slot == 0 && good_map.is_none() {
+                        good_map = buf_to_textmap(&text).ok();
+                    }
+                }
+                Err(_) => problems.push(PvckProblem::MdaTextCrc(idx)),
+            }
+        }
+    }
+
+    Ok(good_map)
+}
+
+// Check an MDA header, returning its current and precommitted rlocns
+// if it is good.
+fn check_mda_header(
+    idx: usize,
+    area: &PvArea,
+    f: &mut File,
+    problems: &mut Vec<PvckProblem>,
+) -> Result<Option<[Option<RawLocn>; 2]>> {
+    let mut hdr = [0u8; MDA_HEADER_SIZE];
+    f.seek(SeekFrom::Start(area.offset))?;
+    f.read_exact(&mut hdr)?;
+
+    if LittleEndian::read_u32(&hdr[..4]) != crc32_calc(&hdr[4..]) {
+        problems.push(PvckProblem::MdaHeaderCrc(idx));
+        return Ok(None);
+    }
+    if &hdr[4..20] != MDA_MAGIC {
+        problems.push(PvckProblem::MdaMagic(idx));
+        return Ok(None);
+    }
+    let version = LittleEndian::read_u32(&hdr[20..24]);
+    if version != 1 {
+        problems.push(PvckProblem::MdaVersion(idx, version));
+        return Ok(None);
+    }
+    if LittleEndian::read_u64(&hdr[24..32]) != area.offset
+        || LittleEndian::read_u64(&hdr[32..40]) != area.size
+    {
+        problems.push(PvckProblem::MdaLocation(idx));
+        return Ok(None);
+    }
+
+    Ok(Some([read_rlocn(&hdr[40..64]), read_rlocn(&hdr[64..88])]))
+}
+
+// An rlocn with offset 0 is unset.
+fn read_rlocn(buf: &[u8]) -> Option<RawLocn> {
+    match LittleEndian::read_u64(&buf[..8]) {
+        0 => None,
+        offset => Some(RawLocn {
+            offset,
+            size: LittleEndian::read_u64(&buf[8..16]),
+            checksum: LittleEndian::read_u32(&buf[16..20]),
+            ignored: LittleEndian::read_u32(&buf[20..24]) & 1 > 0,
+        }),
+    }
+}
+
+// Rewrite each damaged MDA with a fresh header, then write the good
+// metadata to them all. Without good metadata there is nothing to write
+// them from, so nothing is done.
+fn repair_mdas(
+    pvh: &PvHeader,
+    f: &mut File,
+    good_map: Option<LvmTextMap>,
+    problems: &[PvckProblem],
+) -> Result<bool> {
+    let good_map = match good_map {
+        Some(map) => map,
+        None => return Ok(false),
+    };
+
+    let mut damaged: Vec<_> = problems
+        .iter()
+        .filter_map(|problem| match *problem {
+            PvckProblem::MdaHeaderCrc(idx)
+            | PvckProblem::MdaMagic(idx)
+            | PvckProblem::MdaVersion(idx, _)
+            | PvckProblem::MdaLocation(idx)
+            | PvckProblem::RlocnOutOfRange(idx, _)
+            | PvckProblem::MdaTextCrc(idx) => Some(idx),
+            _ => None,
+        })
+        .collect();
+    damaged.sort_unstable();
+    damaged.dedup();
+    if damaged.is_empty() {
+        return Ok(false);
+    }
+
+    let empty = RawLocn {
+        offset: 0,
+        size: 0,
+        checksum: 0,
+        ignored: false,
+    };
+    for idx in damaged {
+        PvHeader::write_mda_header(&pvh.metadata_areas[idx], f, &empty)?;
+    }
+    f.sync_all()?;
+    let precommitted = pvh.precommit_metadata(&good_map)?;
+    pvh.commit_precommitted(&precommitted)?;
+
+    Ok(true)
+}
+
+// Read the current metadata from an MDA that may be at `offset` in
+// `f`, the file of `path`, the header giving its size.
+fn read_mda_at(f: &mut File, path: &Path, offset: u64) -> Result<Option<(PvArea, LvmTextMap)>> {
+    let mut hdr = [0u8; MDA_HEADER_SIZE];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut hdr)?;
+
+    if LittleEndian::read_u32(&hdr[..4]) != crc32_calc(&hdr[4..])
+        || &hdr[4..20] != MDA_MAGIC
+        || LittleEndian::read_u64(&hdr[24..32]) != offset
+    {
+        return Ok(None);
+    }
+    let area = PvArea {
+        offset,
+        size: LittleEndian::read_u64(&hdr[32..40]),
+    };
+    if area.size <= MDA_HEADER_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let map = match read_rlocn(&hdr[40..64]) {
+        Some(rl) => PvHeader::read_mda_text(&area, f, &rl, path)
+            .and_then(|text| buf_to_textmap(&text))
+            .ok(),
+        None => None,
+    };
+
+    Ok(map.map(|map| (area, map)))
+}
+
+// Rebuild the label and pvheader of a PV whose label is damaged, from
+// its entry in the metadata in an MDA where Melvin and LVM2 put them by
+// default. The PV's entry is found by its device number, or as the only
+// PV in the VG.
+fn rebuild_label(path: &Path, f: &mut File, dev_size: u64) -> Result<bool> {
+    let mut mdas = Vec::new();
+    let mut map = None;
+    let offsets = [
+        (8 * SECTOR_SIZE) as u64,
+        dev_size.saturating_sub(DEFAULT_MDA_SIZE),
+    ];
+    for &offset in &offsets {
+        if let Some((area, mda_map)) = read_mda_at(f, path, offset)? {
+            mdas.push(area);
+            map.get_or_insert(mda_map);
+        }
+    }
+    let map = match map {
+        Some(map) => map,
+        None => return Ok(false),
+    };
+
+    let pvs: Vec<&LvmTextMap> = map
+        .values()
+        .find_map(|entry| match entry {
+            Entry::TextMap(vg) => vg.textmap_from_textmap("physical_volumes"),
+            _ => None,
+        })
+        .map_or_else(Vec::new, |pvs| {
+            pvs.values()
+                .filter_map(|entry| match entry {
+                    Entry::TextMap(pv) => Some(&**pv),
+                    _ => None,
+                })
+                .collect()
+        });
+    let dev = Device::from_path(path)?;
+    let pv = match pvs
+        .iter()
+        .find(|pv| dev_from_textmap(pv).is_ok_and(|d| d == dev))
+    {
+        Some(pv) => pv,
+        None if pvs.len() == 1 => pvs[0],
+        None => return Ok(false),
+    };
+
+    let (id, pe_start) = match (
+        pv.string_from_textmap("id"),
+        pv.i64_from_textmap("pe_start"),
+    ) {
+        (Some(id), Some(pe_start)) => (id, pe_start as u64),
+        _ => return Ok(false),
+    };
+    let bootloader_areas = match (
+        pv.i64_from_textmap("ba_start"),
+        pv.i64_from_textmap("ba_size"),
+    ) {
+        (Some(start), Some(size)) if size > 0 => vec![PvArea {
+            offset: start as u64 * SECTOR_SIZE as u64,
+            size: size as u64 * SECTOR_SIZE as u64,
+        }],
+        _ => Vec::new(),
+    };
+
+    let pvh = PvHeader {
+        uuid: id.to_string(),
+        size: pv
+            .i64_from_textmap("dev_size")
+            .map_or(dev_size, |size| size as u64 * SECTOR_SIZE as u64),
+        ext_version: EXTENSION_VERSION,
+        ext_flags: 0,
+        data_areas: vec![PvArea {
+            offset: pe_start * SECTOR_SIZE as u64,
+            size: 0,
+        }],
+        metadata_areas: mdas,
+        bootloader_areas,
+        dev_path: path.to_owned(),
+    };
+    pvh.write_label(f)?;
+
+    Ok(true)
+}
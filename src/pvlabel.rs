@@ -12,7 +12,9 @@
 //   large text area
 // mda header has 40b of stuff, then rlocns[].
 // rlocns point into mda text area. rlocn 0 used for text metadata, rlocn 1
-//   points to precommitted data (not currently supported by Melvin)
+//   points to precommitted data: metadata written to every PV in the VG
+//   before any PV's rlocn 0 is moved to it, so that a commit interrupted
+//   partway can be finished or undone
 // text metadata written aligned to sector-size; text area treated as circular
 //   and text may wrap across end to beginning
 // text metadata contains vg metadata in lvm config text format. Each write
@@ -20,29 +22,40 @@
 //
 
 use std::cmp::min;
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::ErrorKind::Other;
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::os::unix::io::AsRawFd;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{canonicalize, read_dir, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 use byteorder::{ByteOrder, LittleEndian};
-use nix::ioctl_read;
-use nix::sys::stat;
-
-use crate::parser::{buf_to_textmap, textmap_to_buf, LvmTextMap};
-use crate::util::{align_to, crc32_calc, hyphenate_uuid, make_uuid};
+use nix::sys::stat::{self, SFlag};
+
+use crate::blockdev;
+use crate::device::{Device, DeviceExt};
+use crate::filter::DeviceFilter;
+use crate::parser::{buf_to_textmap, textmap_to_buf, Entry, LvmTextMap, TextMapOps};
+use crate::signature;
+use crate::util::{align_to, crc32_calc, hyphenate_uuid, make_uuid, to_usize};
 use crate::{Error, Result};
 
-const LABEL_SCAN_SECTORS: usize = 4;
+pub(crate) const LABEL_SCAN_SECTORS: usize = 4;
 const ID_LEN: usize = 32;
-const MDA_MAGIC: &[u8] = b"\x20\x4c\x56\x4d\x32\x20\x78\x5b\x35\x41\x25\x72\x30\x4e\x2a\x3e";
-const LABEL_SIZE: usize = 32;
-const LABEL_SECTOR: usize = 1;
+pub(crate) const MDA_MAGIC: &[u8] =
+    b"\x20\x4c\x56\x4d\x32\x20\x78\x5b\x35\x41\x25\x72\x30\x4e\x2a\x3e";
+pub(crate) const LABEL_SIZE: usize = 32;
+pub(crate) const LABEL_SECTOR: usize = 1;
 pub const SECTOR_SIZE: usize = 512;
-const MDA_HEADER_SIZE: usize = 512;
-const DEFAULT_MDA_SIZE: u64 = 1024 * 1024;
-const EXTENSION_VERSION: u32 = 1;
+pub(crate) const MDA_HEADER_SIZE: usize = 512;
+pub(crate) const DEFAULT_MDA_SIZE: u64 = 1024 * 1024;
+pub(crate) const EXTENSION_VERSION: u32 = 1;
+
+// An rlocn with offset 0 marks the end of the list.
+const NO_RLOCN: RawLocn = RawLocn {
+    offset: 0,
+    size: 0,
+    checksum: 0,
+    ignored: false,
+};
 
 #[derive(Debug)]
 struct LabelHeader {
@@ -54,24 +67,28 @@ struct LabelHeader {
 }
 
 impl LabelHeader {
-    fn from_buf(buf: &[u8]) -> Result<LabelHeader> {
+    // Returns None if there is no label, or an error if there is a
+    // label but it is damaged.
+    fn from_buf(buf: &[u8], path: &Path) -> Result<Option<LabelHeader>> {
         for x in 0..LABEL_SCAN_SECTORS {
             let sec_buf = &buf[x * SECTOR_SIZE..x * SECTOR_SIZE + SECTOR_SIZE];
             if &sec_buf[..8] == b"LABELONE" {
                 let crc = LittleEndian::read_u32(&sec_buf[16..20]);
                 if crc != crc32_calc(&sec_buf[20..SECTOR_SIZE]) {
-                    return Err(Error::Io(io::Error::new(Other, "Label CRC error")));
+                    return Err(Error::ChecksumMismatch {
+                        path: path.to_owned(),
+                        what: "label",
+                    });
                 }
 
                 let sector = LittleEndian::read_u64(&sec_buf[8..16]);
                 if sector != x as u64 {
-                    return Err(Error::Io(io::Error::new(
-                        Other,
-                        "Sector field should equal sector count",
-                    )));
+                    return Err(Error::Metadata(
+                        "Sector field should equal sector count".to_string(),
+                    ));
                 }
 
-                return Ok(LabelHeader {
+                return Ok(Some(LabelHeader {
                     id: String::from_utf8_lossy(&sec_buf[..8]).into_owned(),
                     sector,
                     crc,
@@ -79,11 +96,11 @@ impl LabelHeader {
                     offset: LittleEndian::read_u32(&sec_buf[20..24])
                         + (x * SECTOR_SIZE as usize) as u32,
                     label: String::from_utf8_lossy(&sec_buf[24..32]).into_owned(),
-                });
+                }));
             }
         }
 
-        Err(Error::Io(io::Error::new(Other, "Label not found")))
+        Ok(None)
     }
 
     /// Initialize a device with a label header.
@@ -131,12 +148,42 @@ impl<'a> Iterator for PvAreaIter<'a> {
     }
 }
 
+/// The location of metadata text within a metadata area.
 #[derive(Debug, PartialEq, Clone, Copy)]
-struct RawLocn {
-    offset: u64,
-    size: u64,
-    checksum: u32,
-    ignored: bool,
+pub struct RawLocn {
+    /// The offset of the text from the start of the MDA, in bytes
+    pub offset: u64,
+    /// The size of the text in bytes
+    pub size: u64,
+    /// The CRC32 of the text
+    pub checksum: u32,
+    /// Whether the MDA is ignored, and should not hold metadata
+    pub ignored: bool,
+}
+
+/// A metadata area and the location of the metadata text within it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MdaInfo {
+    /// The metadata area.
+    pub area: PvArea,
+    /// Where the current metadata text is, or None if the MDA has never
+    /// been written to.
+    pub rlocn: Option<RawLocn>,
+}
+
+/// A copy of VG metadata found in a metadata area by
+/// `PvHeader::scan_metadata_history`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MetadataGeneration {
+    /// The VG's seqno in this copy.
+    pub seqno: u64,
+    /// The metadata area it was found in.
+    pub area: PvArea,
+    /// Where the text is, as the MDA header would record it if this
+    /// copy were current.
+    pub rlocn: RawLocn,
+    /// The metadata.
+    pub map: LvmTextMap,
 }
 
 #[derive(Debug)]
@@ -171,6 +218,28 @@ impl<'a> Iterator for RawLocnIter<'a> {
     }
 }
 
+/// How much of a metadata area's text space is in use.
+///
+/// The text area is a ring buffer, and a new copy of the metadata is
+/// written before the old one is released, so writes will fail once the
+/// current text uses more than half of the capacity.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MdaUsage {
+    /// The metadata area.
+    pub area: PvArea,
+    /// How many bytes are available for metadata text.
+    pub capacity: u64,
+    /// How many bytes the current metadata text uses.
+    pub used: u64,
+}
+
+impl MdaUsage {
+    /// The percentage of the capacity in use.
+    pub fn percent_used(&self) -> u64 {
+        self.used * 100 / self.capacity
+    }
+}
+
 /// A block device that has been initialized to be a LVM Physical
 /// Volume, but that may not be part of a VG yet.
 #[derive(Debug, PartialEq, Clone)]
@@ -181,9 +250,9 @@ pub struct PvHeader {
     pub size: u64,
     /// Extension version. If 1, we look for an extension header that may contain a reference
     /// to a bootloader area.
-    ext_version: u32,
+    pub(crate) ext_version: u32,
     /// Extension flags, of which there are none.
-    ext_flags: u32,
+    pub(crate) ext_flags: u32,
     /// A list of the data areas.
     pub data_areas: Vec<PvArea>,
     /// A list of the metadata areas.
@@ -209,7 +278,7 @@ impl PvHeader {
     //
     // Parse a buf containing the on-disk pvheader and create a struct
     // representing it.
-    fn from_buf(buf: &[u8], path: &Path) -> Result<PvHeader> {
+    pub(crate) fn from_buf(buf: &[u8], path: &Path) -> Result<PvHeader> {
         let mut da_buf = &buf[ID_LEN + 8..];
 
         let da_vec: Vec<_> = iter_pv_area(da_buf).collect();
@@ -246,20 +315,44 @@ impl PvHeader {
         })
     }
 
-    /// Find the PvHeader struct in a given device.
+    /// Find the PvHeader struct in a given device. Symlinks to the
+    /// device, such as those in /dev/disk/by-id, are resolved, so the
+    /// PvHeader is the same whichever path was used.
     pub fn find_in_dev(path: &Path) -> Result<PvHeader> {
+        let path = &canonicalize(path)?;
         let mut f = File::open(path)?;
 
         let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
 
         f.read_exact(&mut buf)?;
 
-        let label_header = LabelHeader::from_buf(&buf)?;
+        let label_header =
+            LabelHeader::from_buf(&buf, path)?.ok_or_else(|| Error::NotAPv(path.to_owned()))?;
         let pvheader = Self::from_buf(&buf[label_header.offset as usize..], path)?;
 
         Ok(pvheader)
     }
 
+    /// The areas of the device that extents must not be allocated from:
+    /// the sectors the label may be in, the metadata areas, and the
+    /// bootloader areas.
+    pub fn reserved_areas(&self) -> Vec<PvArea> {
+        let label = PvArea {
+            offset: 0,
+            size: (LABEL_SCAN_SECTORS * SECTOR_SIZE) as u64,
+        };
+
+        let mut v = vec![label];
+        v.extend(&self.metadata_areas);
+        v.extend(&self.bootloader_areas);
+        v
+    }
+
+    /// The device number of the device the pvheader is within.
+    pub fn device(&self) -> Result<Device> {
+        Device::from_path(&self.dev_path)
+    }
+
     /// Initialize a device as a PV with reasonable defaults: two metadata
     /// areas, no bootsector area, and size based on the device's size.
     pub fn initialize(path: &Path) -> Result<PvHeader> {
@@ -270,10 +363,13 @@ impl PvHeader {
         // mda0's length is reduced a little by the header length,
         // maybe to keep the data area aligned to 1MB?
         let mda0_length = DEFAULT_MDA_SIZE - mda0_offset;
-        let dev_size = blkdev_size(&f)?;
+        let dev_size = blockdev::size(&f)?;
 
         if dev_size < ((DEFAULT_MDA_SIZE * 2) + mda0_offset) {
-            return Err(Error::Io(io::Error::new(Other, "Device too small")));
+            return Err(Error::InvalidArgument(format!(
+                "{} is too small to be a PV",
+                path.display()
+            )));
         }
 
         let pvh = PvHeader {
@@ -364,63 +460,130 @@ impl PvHeader {
         Ok(pvh)
     }
 
-    // For the moment, the only important thing in the MDA header is rlocn0,
-    // so we don't need separate functions that return anything in it except
-    // rlocn0.
-    fn read_mda_header(area: &PvArea, file: &mut File) -> Result<Option<RawLocn>> {
+    // Write the label, in sector LABEL_SECTOR, and the pvheader after it,
+    // as they describe this PvHeader. Only the label's own sector is
+    // written, so a label in another sector must be dealt with apart.
+    pub(crate) fn write_label(&self, file: &mut File) -> Result<()> {
+        let uuid = self.uuid.replace("-", "");
+        // The fixed part, each list of areas with its blank entry, and
+        // the extension header.
+        let len = LABEL_SIZE
+            + ID_LEN
+            + 8
+            + (self.data_areas.len() + self.metadata_areas.len() + 2) * 16
+            + 8
+            + (self.bootloader_areas.len() + 1) * 16;
+        if uuid.len() != ID_LEN || len > SECTOR_SIZE {
+            return Err(Error::Metadata(
+                "PV header does not fit in the label sector".to_string(),
+            ));
+        }
+
+        let mut sec_buf = [0u8; SECTOR_SIZE];
+        {
+            let slc = &mut sec_buf[LABEL_SIZE..];
+            slc[..ID_LEN].copy_from_slice(uuid.as_bytes());
+            let mut slc = &mut slc[ID_LEN..];
+
+            LittleEndian::write_u64(slc, self.size);
+            slc = &mut slc[8..];
+
+            for areas in &[&self.data_areas, &self.metadata_areas] {
+                for area in areas.iter() {
+                    LittleEndian::write_u64(slc, area.offset);
+                    LittleEndian::write_u64(&mut slc[8..], area.size);
+                    slc = &mut slc[16..];
+                }
+                slc = &mut slc[16..];
+            }
+
+            LittleEndian::write_u32(slc, self.ext_version);
+            LittleEndian::write_u32(&mut slc[4..], self.ext_flags);
+            slc = &mut slc[8..];
+
+            if self.ext_version != 0 {
+                for area in &self.bootloader_areas {
+                    LittleEndian::write_u64(slc, area.offset);
+                    LittleEndian::write_u64(&mut slc[8..], area.size);
+                    slc = &mut slc[16..];
+                }
+            }
+        }
+
+        LabelHeader::initialize(&mut sec_buf);
+
+        file.seek(SeekFrom::Start((LABEL_SECTOR * SECTOR_SIZE) as u64))?;
+        file.write_all(&sec_buf)?;
+        Ok(())
+    }
+
+    // Most callers only care about rlocn0, the current metadata.
+    fn read_mda_header(area: &PvArea, file: &mut File, path: &Path) -> Result<Option<RawLocn>> {
+        let [current, _] = Self::read_mda_rlocns(area, file, path)?;
+        Ok(current)
+    }
+
+    // Read rlocn0 and rlocn1, the current and precommitted metadata.
+    fn read_mda_rlocns(
+        area: &PvArea,
+        file: &mut File,
+        path: &Path,
+    ) -> Result<[Option<RawLocn>; 2]> {
         assert!(area.size as usize > MDA_HEADER_SIZE);
         file.seek(SeekFrom::Start(area.offset))?;
         let mut hdr = [0u8; MDA_HEADER_SIZE];
         file.read_exact(&mut hdr)?;
 
         if LittleEndian::read_u32(&hdr[..4]) != crc32_calc(&hdr[4..MDA_HEADER_SIZE]) {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                "MDA header checksum failure",
-            )));
+            return Err(Error::ChecksumMismatch {
+                path: path.to_owned(),
+                what: "MDA header",
+            });
         }
 
         if &hdr[4..20] != MDA_MAGIC {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                format!(
-                    "'{}' doesn't match MDA_MAGIC",
-                    String::from_utf8_lossy(&hdr[4..20])
-                ),
+            return Err(Error::Metadata(format!(
+                "'{}' doesn't match MDA_MAGIC",
+                String::from_utf8_lossy(&hdr[4..20])
             )));
         }
 
         let ver = LittleEndian::read_u32(&hdr[20..24]);
         if ver != 1 {
-            return Err(Error::Io(io::Error::new(Other, "Bad version, expected 1")));
+            return Err(Error::Metadata("Bad version, expected 1".to_string()));
         }
 
         let start = LittleEndian::read_u64(&hdr[24..32]);
         if start != area.offset {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                format!(
-                    "mdah start {} does not equal pvarea start {}",
-                    start, area.offset
-                ),
+            return Err(Error::Metadata(format!(
+                "mdah start {} does not equal pvarea start {}",
+                start, area.offset
             )));
         }
 
         let size = LittleEndian::read_u64(&hdr[32..40]);
         if size != area.size {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                format!(
-                    "mdah size {} does not equal pvarea size {}",
-                    size, area.size
-                ),
+            return Err(Error::Metadata(format!(
+                "mdah size {} does not equal pvarea size {}",
+                size, area.size
             )));
         }
 
-        Ok(iter_raw_locn(&hdr[40..]).next())
+        // rlocn1 may be set when rlocn0 is not, so it is read on its own
+        // rather than by continuing the iteration.
+        Ok([
+            iter_raw_locn(&hdr[40..]).next(),
+            iter_raw_locn(&hdr[64..]).next(),
+        ])
+    }
+
+    // Write an MDA header with only rlocn0 set, dropping any precommitted
+    // metadata.
+    pub(crate) fn write_mda_header(area: &PvArea, file: &mut File, rl: &RawLocn) -> Result<()> {
+        Self::write_mda_rlocns(area, file, &[*rl])
     }
 
-    fn write_mda_header(area: &PvArea, file: &mut File, rl: &RawLocn) -> Result<()> {
+    fn write_mda_rlocns(area: &PvArea, file: &mut File, rlocns: &[RawLocn]) -> Result<()> {
         let mut hdr = [0u8; MDA_HEADER_SIZE];
 
         hdr[4..20].copy_from_slice(MDA_MAGIC);
@@ -428,8 +591,8 @@ impl PvHeader {
         LittleEndian::write_u64(&mut hdr[24..32], area.offset);
         LittleEndian::write_u64(&mut hdr[32..40], area.size);
 
-        {
-            let raw_locn = &mut hdr[40..];
+        for (idx, rl) in rlocns.iter().enumerate() {
+            let raw_locn = &mut hdr[40 + idx * 24..];
 
             LittleEndian::write_u64(&mut raw_locn[..8], rl.offset);
             LittleEndian::write_u64(&mut raw_locn[8..16], rl.size);
@@ -447,14 +610,272 @@ impl PvHeader {
         Ok(())
     }
 
+    // Read the text a RawLocn points to. The text area is circular, so
+    // if the text runs past the end of the area, the remainder is at the
+    // start of the text area, just after the MDA header. `path` is the
+    // file's, for errors.
+    pub(crate) fn read_mda_text(
+        area: &PvArea,
+        file: &mut File,
+        rl: &RawLocn,
+        path: &Path,
+    ) -> Result<Vec<u8>> {
+        let text_start = MDA_HEADER_SIZE as u64;
+
+        if rl.offset < text_start || rl.offset >= area.size || rl.size > area.size - text_start {
+            return Err(Error::Metadata(format!(
+                "rlocn offset {} size {} outside of MDA text area",
+                rl.offset, rl.size
+            )));
+        }
+
+        let mut text = vec![0; to_usize(rl.size)?];
+        let first_read = min(area.size - rl.offset, rl.size) as usize;
+
+        file.seek(SeekFrom::Start(area.offset + rl.offset))?;
+        file.read_exact(&mut text[..first_read])?;
+
+        if first_read != text.len() {
+            file.seek(SeekFrom::Start(area.offset + text_start))?;
+            file.read_exact(&mut text[first_read..])?;
+        }
+
+        if rl.checksum != crc32_calc(&text) {
+            return Err(Error::ChecksumMismatch {
+                path: path.to_owned(),
+                what: "metadata text",
+            });
+        }
+
+        Ok(text)
+    }
+
+    /// Report where the metadata text is in each metadata area.
+    pub fn mda_info(&self) -> Result<Vec<MdaInfo>> {
+        let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
+
+        let mut v = Vec::new();
+        for pvarea in &self.metadata_areas {
+            v.push(MdaInfo {
+                area: *pvarea,
+                rlocn: Self::read_mda_header(pvarea, &mut f, &self.dev_path)?,
+            });
+        }
+
+        Ok(v)
+    }
+
+    /// Report how much of each metadata area is in use.
+    pub fn mda_usage(&self) -> Result<Vec<MdaUsage>> {
+        Ok(self
+            .mda_info()?
+            .into_iter()
+            .map(|info| MdaUsage {
+                area: info.area,
+                capacity: info.area.size - MDA_HEADER_SIZE as u64,
+                used: info.rlocn.map_or(0, |rl| rl.size),
+            })
+            .collect())
+    }
+
+    /// Set whether a metadata area is ignored. Metadata is neither read
+    /// from nor written to ignored MDAs.
+    pub fn set_mda_ignored(&self, idx: usize, ignored: bool) -> Result<()> {
+        let pvarea = self
+            .metadata_areas
+            .get(idx)
+            .ok_or_else(|| Error::InvalidArgument("No such metadata area".to_string()))?;
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        let rl = match Self::read_mda_header(pvarea, &mut f, &self.dev_path)? {
+            None => RawLocn {
+                offset: MDA_HEADER_SIZE as u64,
+                size: 0,
+                checksum: 0,
+                ignored,
+            },
+            Some(x) => RawLocn { ignored, ..x },
+        };
+
+        Self::write_mda_header(pvarea, &mut f, &rl)
+    }
+
+    /// Forget the metadata in every metadata area, leaving the PV as an
+    /// orphan that is not part of any VG.
+    pub fn clear_metadata(&self) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        for pvarea in &self.metadata_areas {
+            Self::write_mda_header(pvarea, &mut f, &NO_RLOCN)?;
+        }
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Overwrite the PV's label with zeroes, so the device is no longer
+    /// recognized as a PV.
+    pub fn wipe_label(&self) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+        f.read_exact(&mut buf)?;
+
+        let label_header = LabelHeader::from_buf(&buf, &self.dev_path)?
+            .ok_or_else(|| Error::NotAPv(self.dev_path.clone()))?;
+
+        f.seek(SeekFrom::Start(label_header.sector * SECTOR_SIZE as u64))?;
+        f.write_all(&[0u8; SECTOR_SIZE])?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Remove the PV from a device, like LVM2's pvremove: the label
+    /// sector and the header of each metadata area are zeroed, so the
+    /// device is no longer recognized as a PV. A PV that still holds
+    /// metadata for a VG is refused unless `force` is set.
+    pub fn wipe(path: &Path, force: bool) -> Result<()> {
+        let pvh = Self::find_in_dev(path)?;
+
+        if !force {
+            if let Ok(metadata) = pvh.read_metadata() {
+                let vg_name = metadata
+                    .iter()
+                    .find(|(_, value)| matches!(value, Entry::TextMap(_)))
+                    .map(|(key, _)| key.as_str())
+                    .unwrap_or("<unknown>");
+                return Err(Error::PvInUse {
+                    path: path.to_owned(),
+                    vg: Some(vg_name.to_string()),
+                    lv: None,
+                });
+            }
+        }
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&pvh.dev_path)?;
+        for pvarea in &pvh.metadata_areas {
+            f.seek(SeekFrom::Start(pvarea.offset))?;
+            f.write_all(&[0u8; MDA_HEADER_SIZE])?;
+        }
+        f.sync_all()?;
+
+        pvh.wipe_label()
+    }
+
+    /// Update the PV's size to match its device, after the device has
+    /// grown. A metadata area at the end of the old device is moved to
+    /// the new end. Shrinking is not supported.
+    pub fn update_size(&mut self) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        let new_size = blockdev::size(&f)?;
+        if new_size < self.size {
+            return Err(Error::Unsupported(
+                "Device is smaller than the PV, shrinking is not supported".to_string(),
+            ));
+        }
+        if new_size == self.size {
+            return Ok(());
+        }
+
+        // Copy the trailing MDA to the new end first. Until the label
+        // is rewritten, the copy at the old end is still the one used.
+        let mut mdas = self.metadata_areas.clone();
+        for area in &mut mdas {
+            if area.offset + area.size != self.size {
+                continue;
+            }
+
+            let rl = Self::read_mda_header(area, &mut f, &self.dev_path)?.unwrap_or(RawLocn {
+                offset: 0,
+                size: 0,
+                checksum: 0,
+                ignored: false,
+            });
+
+            let mut buf = vec![0; to_usize(area.size)?];
+            f.seek(SeekFrom::Start(area.offset))?;
+            f.read_exact(&mut buf)?;
+
+            let new_area = PvArea {
+                offset: new_size - area.size,
+                size: area.size,
+            };
+            f.seek(SeekFrom::Start(new_area.offset))?;
+            f.write_all(&buf)?;
+            // rlocn offsets are relative to the area, so only the
+            // header's own start changes.
+            Self::write_mda_header(&new_area, &mut f, &rl)?;
+
+            *area = new_area;
+        }
+        f.sync_all()?;
+
+        let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut buf)?;
+
+        let label_header = LabelHeader::from_buf(&buf, &self.dev_path)?
+            .ok_or_else(|| Error::NotAPv(self.dev_path.clone()))?;
+
+        {
+            let pvh_buf = &mut buf[label_header.offset as usize..];
+            LittleEndian::write_u64(&mut pvh_buf[ID_LEN..ID_LEN + 8], new_size);
+
+            // MDA list follows the data area list and its blank entry
+            let mda_list = ID_LEN + 8 + (self.data_areas.len() + 1) * 16;
+            for (i, area) in mdas.iter().enumerate() {
+                let pos = mda_list + i * 16;
+                LittleEndian::write_u64(&mut pvh_buf[pos..pos + 8], area.offset);
+            }
+        }
+
+        let sector = label_header.sector as usize;
+        let sec_buf = &mut buf[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE];
+        let crc_val = crc32_calc(&sec_buf[20..]);
+        LittleEndian::write_u32(&mut sec_buf[16..20], crc_val);
+
+        f.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64))?;
+        f.write_all(sec_buf)?;
+        f.sync_all()?;
+
+        self.size = new_size;
+        self.metadata_areas = mdas;
+
+        Ok(())
+    }
+
     /// Read the metadata contained in the metadata area.
     /// In the case of multiple metadata areas, return the information
     /// from the first valid one.
     pub fn read_metadata(&self) -> Result<LvmTextMap> {
+        buf_to_textmap(&self.read_metadata_text()?)
+    }
+
+    /// Like `read_metadata`, but return the text without parsing it,
+    /// e.g. to parse it with `buf_to_textmap_borrowed`.
+    pub fn read_metadata_text(&self) -> Result<Vec<u8>> {
         let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
 
         for pvarea in &self.metadata_areas {
-            let rl = match Self::read_mda_header(&pvarea, &mut f)? {
+            let rl = match Self::read_mda_header(pvarea, &mut f, &self.dev_path)? {
                 None => continue,
                 Some(x) => x,
             };
@@ -463,32 +884,131 @@ impl PvHeader {
                 continue;
             }
 
-            let mut text = vec![0; rl.size as usize];
-            let first_read = min(pvarea.size - rl.offset, rl.size) as usize;
+            return Self::read_mda_text(pvarea, &mut f, &rl, &self.dev_path);
+        }
 
-            f.seek(SeekFrom::Start(pvarea.offset + rl.offset))?;
-            f.read_exact(&mut text[..first_read])?;
+        Err(Error::Metadata("No valid metadata found".to_string()))
+    }
+
+    /// Find every copy of VG metadata still readable in the metadata
+    /// areas, oldest first by seqno.
+    ///
+    /// The text area of each MDA is a ring buffer, and each commit writes
+    /// its text after the last, so earlier generations stay there until
+    /// they are written over. Only the current one is recorded in the MDA
+    /// header; the others are found by trying each sector as the start
+    /// of a text, which ends at a NUL, and keeping those that parse as a
+    /// VG. A text found in more than one MDA is returned once.
+    pub fn scan_metadata_history(&self) -> Result<Vec<MetadataGeneration>> {
+        let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
 
-            if first_read != rl.size as usize {
-                f.seek(SeekFrom::Start(pvarea.offset + MDA_HEADER_SIZE as u64))?;
-                f.read_exact(&mut text[rl.size as usize - first_read..])?;
+        let mut found = BTreeMap::new();
+        for pvarea in &self.metadata_areas {
+            if Self::read_mda_header(pvarea, &mut f, &self.dev_path)?.is_none() {
+                continue;
             }
 
-            if rl.checksum != crc32_calc(&text) {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    "MDA text checksum failure",
-                )));
+            let text_start = MDA_HEADER_SIZE as u64;
+            let mut ring = vec![0; to_usize(pvarea.size - text_start)?];
+            f.seek(SeekFrom::Start(pvarea.offset + text_start))?;
+            f.read_exact(&mut ring)?;
+
+            for start in (0..ring.len()).step_by(SECTOR_SIZE) {
+                // Quickly pass over sectors that can't start a text.
+                if !(ring[start].is_ascii_alphanumeric() || ring[start] == b'#') {
+                    continue;
+                }
+
+                let text: Vec<u8> = ring[start..]
+                    .iter()
+                    .chain(&ring[..start])
+                    .take_while(|b| **b != 0)
+                    .copied()
+                    .collect();
+                if text.len() == ring.len() {
+                    continue;
+                }
+                let map = match buf_to_textmap(&text) {
+                    Ok(map) => map,
+                    Err(_) => continue,
+                };
+                let seqno = map.values().find_map(|entry| match entry {
+                    Entry::TextMap(vg) if vg.string_from_textmap("id").is_some() => {
+                        vg.i64_from_textmap("seqno")
+                    }
+                    _ => None,
+                });
+
+                // Like the texts Melvin writes, the size includes the NUL.
+                let mut checked = text.clone();
+                checked.push(b'\0');
+                let checksum = crc32_calc(&checked);
+                if let Some(seqno) = seqno {
+                    found
+                        .entry((seqno as u64, checksum))
+                        .or_insert(MetadataGeneration {
+                            seqno: seqno as u64,
+                            area: *pvarea,
+                            rlocn: RawLocn {
+                                offset: text_start + start as u64,
+                                size: checked.len() as u64,
+                                checksum,
+                                ignored: false,
+                            },
+                            map,
+                        });
+                }
             }
+        }
+
+        Ok(found.into_values().collect())
+    }
+
+    /// Read the precommitted metadata, from the first metadata area that
+    /// has any. Returns None if no commit is in progress.
+    pub fn read_precommitted_metadata(&self) -> Result<Option<LvmTextMap>> {
+        let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
 
-            return buf_to_textmap(&text);
+        for pvarea in &self.metadata_areas {
+            let rl = match Self::read_mda_rlocns(pvarea, &mut f, &self.dev_path)? {
+                [_, None] => continue,
+                [_, Some(x)] => x,
+            };
+
+            let text = Self::read_mda_text(pvarea, &mut f, &rl, &self.dev_path)?;
+
+            return buf_to_textmap(&text).map(Some);
         }
 
-        Err(Error::Io(io::Error::new(Other, "No valid metadata found")))
+        Ok(None)
     }
 
     /// Write the given metadata to all active metadata areas in the PV.
     pub fn write_metadata(&mut self, map: &LvmTextMap) -> Result<()> {
+        let precommitted = self.precommit_metadata(map)?;
+        self.commit_precommitted(&precommitted)
+    }
+
+    /// Where precommitted metadata is in each metadata area, or None
+    /// for areas without any.
+    pub fn precommitted_rlocns(&self) -> Result<Vec<Option<RawLocn>>> {
+        let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
+
+        let mut v = Vec::new();
+        for pvarea in &self.metadata_areas {
+            let [_, precommitted] = Self::read_mda_rlocns(pvarea, &mut f, &self.dev_path)?;
+            v.push(precommitted);
+        }
+
+        Ok(v)
+    }
+
+    /// Write the given metadata to all active metadata areas in the PV
+    /// as precommitted metadata, the first step of a commit. The current
+    /// metadata is still what is read until `commit_precommitted` is
+    /// called. Returns where it was written in each metadata area, or
+    /// None for ignored ones, to be passed to `commit_precommitted`.
+    pub fn precommit_metadata(&self, map: &LvmTextMap) -> Result<Vec<Option<RawLocn>>> {
         let mut f = OpenOptions::new()
             .read(true)
             .write(true)
@@ -498,82 +1018,550 @@ impl PvHeader {
         // Ends with one null
         text.push(b'\0');
 
+        let mut precommitted = Vec::new();
         for pvarea in &self.metadata_areas {
+            let [current, _] = Self::read_mda_rlocns(pvarea, &mut f, &self.dev_path)?;
+
             // If this is the first write, supply an initial RawLocn template
-            let rl = match Self::read_mda_header(&pvarea, &mut f)? {
-                None => RawLocn {
-                    offset: MDA_HEADER_SIZE as u64,
-                    size: 0,
-                    checksum: 0,
-                    ignored: false,
-                },
-                Some(x) => x,
-            };
+            let rl = current.unwrap_or(RawLocn {
+                offset: MDA_HEADER_SIZE as u64,
+                size: 0,
+                checksum: 0,
+                ignored: false,
+            });
 
             if rl.ignored {
+                precommitted.push(None);
                 continue;
             }
 
-            // start at next sector in loop, but skip 0th sector
-            let start_off = min(
-                MDA_HEADER_SIZE as u64,
-                (align_to((rl.offset + rl.size) as usize, SECTOR_SIZE) % pvarea.size as usize)
-                    as u64,
-            );
-            let tail_space = pvarea.size as u64 - start_off;
-
-            assert_eq!(start_off % SECTOR_SIZE as u64, 0);
-            assert_eq!(tail_space % SECTOR_SIZE as u64, 0);
-
-            let written = if tail_space != 0 {
-                f.seek(SeekFrom::Start(pvarea.offset + start_off))?;
-                f.write_all(&text[..min(tail_space as usize, text.len())])?;
-                min(tail_space as usize, text.len())
-            } else {
-                0
-            };
+            let new_rl = Self::write_mda_text(pvarea, &mut f, &rl, &text)?;
+            Self::write_mda_rlocns(pvarea, &mut f, &[current.unwrap_or(NO_RLOCN), new_rl])?;
+            precommitted.push(Some(new_rl));
+        }
+        f.sync_all()?;
+
+        Ok(precommitted)
+    }
 
-            if written != text.len() {
-                f.seek(SeekFrom::Start(pvarea.offset + MDA_HEADER_SIZE as u64))?;
-                f.write_all(&text[written as usize..])?;
+    /// Make the precommitted metadata current, the second step of a
+    /// commit. `precommitted` is what `precommit_metadata` returned, or
+    /// what `precommitted_rlocns` found; metadata areas it has None for
+    /// are left alone. If any other area no longer has that metadata
+    /// precommitted, as when it was discarded or replaced by another
+    /// commit, nothing is committed.
+    pub fn commit_precommitted(&self, precommitted: &[Option<RawLocn>]) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        if precommitted.len() != self.metadata_areas.len() {
+            return Err(Error::InvalidArgument(format!(
+                "Expected precommitted metadata for {} metadata areas on {}, got {}",
+                self.metadata_areas.len(),
+                self.dev_path.display(),
+                precommitted.len()
+            )));
+        }
+
+        let areas: Vec<_> = self
+            .metadata_areas
+            .iter()
+            .zip(precommitted)
+            .filter_map(|(pvarea, rl)| rl.map(|rl| (pvarea, rl)))
+            .collect();
+
+        // Every area is checked before any is changed, so that a commit
+        // is not made current on some of a PV's areas but not others.
+        for (pvarea, rl) in &areas {
+            let [_, found] = Self::read_mda_rlocns(pvarea, &mut f, &self.dev_path)?;
+            if found != Some(*rl) {
+                return Err(Error::Metadata(format!(
+                    "Precommitted metadata on {} was discarded or replaced",
+                    self.dev_path.display()
+                )));
             }
+        }
+        for (pvarea, rl) in &areas {
+            Self::write_mda_header(pvarea, &mut f, rl)?;
+        }
+        f.sync_all()?;
 
-            let new_rl = RawLocn {
-                offset: start_off,
-                size: text.len() as u64,
-                checksum: crc32_calc(&text),
-                ignored: rl.ignored,
-            };
-            Self::write_mda_header(&pvarea, &mut f, &new_rl)?;
+        Ok(())
+    }
+
+    /// Forget any precommitted metadata, leaving the current metadata
+    /// as it was.
+    pub fn discard_precommitted(&self) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.dev_path)?;
+
+        for pvarea in &self.metadata_areas {
+            if let [current, Some(_)] = Self::read_mda_rlocns(pvarea, &mut f, &self.dev_path)? {
+                Self::write_mda_rlocns(pvarea, &mut f, &[current.unwrap_or(NO_RLOCN)])?;
+            }
         }
+        f.sync_all()?;
 
         Ok(())
     }
+
+    // Write text into an MDA's text area after the text `rl` points to,
+    // and return where it was written.
+    fn write_mda_text(
+        area: &PvArea,
+        file: &mut File,
+        rl: &RawLocn,
+        text: &[u8],
+    ) -> Result<RawLocn> {
+        // The new text can't overwrite the current copy, since that
+        // is still what the header points to until we are done.
+        if text.len() as u64 + rl.size > area.size - MDA_HEADER_SIZE as u64 {
+            return Err(Error::Metadata(
+                "Metadata too large for metadata area".to_string(),
+            ));
+        }
+
+        // Start at the next sector after the current text, which
+        // may itself have wrapped around to the start of the text
+        // area. Never start within the MDA header.
+        let mut end_off = rl.offset + rl.size;
+        if end_off > area.size {
+            end_off = end_off - area.size + MDA_HEADER_SIZE as u64;
+        }
+        let mut start_off = align_to(end_off, SECTOR_SIZE as u64);
+        if start_off >= area.size {
+            start_off = MDA_HEADER_SIZE as u64;
+        }
+        let tail_space = area.size as u64 - start_off;
+
+        assert_eq!(start_off % SECTOR_SIZE as u64, 0);
+        assert_eq!(tail_space % SECTOR_SIZE as u64, 0);
+
+        let written = if tail_space != 0 {
+            file.seek(SeekFrom::Start(area.offset + start_off))?;
+            let len = min(tail_space, text.len() as u64) as usize;
+            file.write_all(&text[..len])?;
+            len
+        } else {
+            0
+        };
+
+        if written != text.len() {
+            file.seek(SeekFrom::Start(area.offset + MDA_HEADER_SIZE as u64))?;
+            file.write_all(&text[written..])?;
+        }
+
+        Ok(RawLocn {
+            offset: start_off,
+            size: text.len() as u64,
+            checksum: crc32_calc(text),
+            ignored: rl.ignored,
+        })
+    }
+}
+
+// The seqno of the named VG in a metadata text, if it is for that VG.
+fn vg_seqno(map: &LvmTextMap, vg_name: &str) -> Option<i64> {
+    map.textmap_from_textmap(vg_name)?.i64_from_textmap("seqno")
+}
+
+/// Finish or undo a commit to the named VG's metadata that was
+/// interrupted, such as by a crash, given the devices found by
+/// `pvheader_scan`. Returns whether anything was changed.
+///
+/// The VG must be locked exclusively, as a commit in progress in another
+/// process looks the same as an interrupted one.
+///
+/// A commit first writes the new metadata to every PV as precommitted
+/// metadata, and only then makes it current on each. If every PV has the
+/// new metadata, current or precommitted, the commit got as far as the
+/// second step and is finished. Otherwise it is undone, as it never
+/// completed.
+pub fn recover_commit(vg_name: &str, paths: &[PathBuf]) -> Result<bool> {
+    // Each PV with the VG's metadata, with the seqno of its current
+    // metadata and, if it has precommitted metadata, the seqno of that.
+    // Precommitted metadata that can't be read is discarded either way.
+    let mut pvs = Vec::new();
+    for path in paths {
+        let pvh = match PvHeader::find_in_dev(path) {
+            Ok(pvh) => pvh,
+            Err(_) => continue,
+        };
+        let current = pvh
+            .read_metadata()
+            .ok()
+            .and_then(|map| vg_seqno(&map, vg_name));
+        let precommitted = match pvh.read_precommitted_metadata() {
+            Ok(None) => None,
+            Ok(Some(map)) => Some(vg_seqno(&map, vg_name)),
+            Err(_) => Some(None),
+        };
+
+        match (current, precommitted) {
+            (None, None) | (None, Some(None)) => continue,
+            _ => pvs.push((pvh, current, precommitted)),
+        }
+    }
+
+    let seqno = pvs.iter().filter_map(|(_, _, pre)| pre.flatten()).max();
+    let complete = seqno.is_some()
+        && pvs
+            .iter()
+            .all(|(_, current, pre)| *current == seqno || *pre == Some(seqno));
+
+    let mut changed = false;
+    for (pvh, _, pre) in &pvs {
+        if complete && *pre == Some(seqno) {
+            pvh.commit_precommitted(&pvh.precommitted_rlocns()?)?;
+        } else if pre.is_some() {
+            pvh.discard_precommitted()?;
+        } else {
+            continue;
+        }
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+/// A problem found with a device while scanning for PVs.
+#[derive(Debug)]
+pub enum ScanProblem {
+    /// The device could not be examined.
+    Io(Error),
+    /// The device has an LVM label, but the label or pvheader is damaged.
+    BadLabel(Error),
+    /// The PV has the same UUID as a PV found earlier in the scan, on the
+    /// given device.
+    DuplicateUuid(String, PathBuf),
+    /// The device filter rejected the device, so it was not read.
+    Filtered,
+}
+
+/// What a device without a PV label holds, as far as a scan can tell.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeviceClass {
+    /// Nothing known, and it is not mounted.
+    Unused,
+    /// Swap space.
+    Swap,
+    /// A LUKS encrypted volume.
+    Luks,
+    /// A member of an MD RAID array.
+    MdRaidMember,
+    /// A mounted filesystem, and where it is mounted.
+    MountedFs(PathBuf),
+    /// Some other signature, named as by blkid, such as that of a
+    /// filesystem that is not mounted.
+    Other(&'static str),
+}
+
+impl DeviceClass {
+    /// Whether the device can be made a PV without losing anything.
+    pub fn is_usable(&self) -> bool {
+        *self == DeviceClass::Unused
+    }
 }
 
-ioctl_read!(blkgetsize64, 0x12, 114, u64);
+/// The results of a scan for PVs, including the devices that were
+/// passed over and why.
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Devices containing valid PV labels.
+    pub pvs: Vec<PathBuf>,
+    /// Devices that may be PVs but were not included in `pvs`.
+    pub skipped: Vec<(PathBuf, ScanProblem)>,
+    /// Devices without PV labels, and what they hold.
+    pub others: Vec<(PathBuf, DeviceClass)>,
+}
+
+impl ScanReport {
+    /// The devices without PV labels that hold nothing, so that they
+    /// could be made PVs.
+    pub fn usable(&self) -> Vec<&Path> {
+        self.others
+            .iter()
+            .filter(|(_, class)| class.is_usable())
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+}
+
+// Like PvHeader::find_in_dev(), but keeps I/O errors, damaged labels,
+// and devices without labels apart.
+fn probe_dev(path: &Path) -> std::result::Result<Option<PvHeader>, ScanProblem> {
+    let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .map_err(|e| ScanProblem::Io(Error::Io(e)))?;
+
+    match LabelHeader::from_buf(&buf, path) {
+        Ok(None) => Ok(None),
+        Ok(Some(label_header)) => PvHeader::from_buf(&buf[label_header.offset as usize..], path)
+            .map(Some)
+            .map_err(ScanProblem::BadLabel),
+        Err(e) => Err(ScanProblem::BadLabel(e)),
+    }
+}
+
+// Work out what a device without a PV label holds. Being mounted comes
+// first, as not every filesystem's signature is known.
+fn classify_dev(path: &Path, dev: Device) -> std::result::Result<DeviceClass, ScanProblem> {
+    if let Some(mount_point) = blockdev::mount_point(dev).map_err(ScanProblem::Io)? {
+        return Ok(DeviceClass::MountedFs(mount_point));
+    }
+
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|f| f.take(signature::SCAN_LEN).read_to_end(&mut buf))
+        .map_err(|e| ScanProblem::Io(Error::Io(e)))?;
+
+    Ok(match signature::find(&buf).first().map(|sig| sig.name) {
+        None => DeviceClass::Unused,
+        Some("swap") => DeviceClass::Swap,
+        Some("crypto_LUKS") => DeviceClass::Luks,
+        Some("linux_raid_member") => DeviceClass::MdRaidMember,
+        Some(name) => DeviceClass::Other(name),
+    })
+}
+
+/// Scan a list of directories for block devices containing LVM PV
+/// labels, and report on devices that could not be used.
+pub fn pvheader_scan_report(dirs: &[&Path]) -> Result<ScanReport> {
+    pvheader_scan_report_with_filter(dirs, &DeviceFilter::default())
+}
+
+/// Like `pvheader_scan_report`, but only reading devices `filter`
+/// accepts.
+pub fn pvheader_scan_report_with_filter(
+    dirs: &[&Path],
+    filter: &DeviceFilter,
+) -> Result<ScanReport> {
+    let mut report = ScanReport::default();
+    let mut uuids: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut devices = BTreeSet::new();
+
+    for dir in dirs {
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+
+            let dev = match stat::stat(&path) {
+                Ok(st)
+                    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFBLK =>
+                {
+                    // Another name for a device already scanned is not a
+                    // duplicate PV.
+                    let dev = Device {
+                        major: stat::major(st.st_rdev) as u32,
+                        minor: stat::minor(st.st_rdev) as u32,
+                    };
+                    if !devices.insert(dev) {
+                        continue;
+                    }
+                    dev
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    report.skipped.push((path, ScanProblem::Io(Error::Nix(e))));
+                    continue;
+                }
+            };
 
-pub fn blkdev_size(file: &File) -> Result<u64> {
-    let mut val: u64 = 0;
+            if !filter.accepts(&path) {
+                report.skipped.push((path, ScanProblem::Filtered));
+                continue;
+            }
 
-    match unsafe { blkgetsize64(file.as_raw_fd(), &mut val) } {
-        Err(x) => Err(Error::Nix(x)),
-        Ok(_) => Ok(val),
+            match probe_dev(&path) {
+                Ok(None) => match classify_dev(&path, dev) {
+                    Ok(class) => report.others.push((path, class)),
+                    Err(problem) => report.skipped.push((path, problem)),
+                },
+                Ok(Some(pvh)) => match uuids.get(&pvh.uuid) {
+                    Some(other) => {
+                        let problem = ScanProblem::DuplicateUuid(pvh.uuid, other.clone());
+                        report.skipped.push((path, problem));
+                    }
+                    None => {
+                        uuids.insert(pvh.uuid, path.clone());
+                        report.pvs.push(path);
+                    }
+                },
+                Err(problem) => report.skipped.push((path, problem)),
+            }
+        }
     }
+
+    Ok(report)
 }
 
 /// Scan a list of directories for block devices containing LVM PV labels.
 pub fn pvheader_scan(dirs: &[&Path]) -> Result<Vec<PathBuf>> {
-    let mut ret_vec = Vec::new();
+    Ok(pvheader_scan_report(dirs)?.pvs)
+}
 
-    for dir in dirs {
-        ret_vec.extend(
-            read_dir(dir)?
-                .map(|res| res.unwrap().path())
-                .filter(|path| (stat::stat(path).unwrap().st_mode & 0x6000) == 0x6000) // S_IFBLK
-                .filter(|path| PvHeader::find_in_dev(path).is_ok()),
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: PvArea = PvArea {
+        offset: 4096,
+        size: 8192,
+    };
+
+    // Metadata text as LVM2 writes it to an MDA, ending with a null.
+    fn lvm2_text() -> Vec<u8> {
+        let mut text = include_bytes!("../tests/fixtures/lvm2-vg0.txt").to_vec();
+        text.push(b'\0');
+        text
+    }
+
+    // A file holding the MDA `AREA`, with `text` written `offset` bytes
+    // into it, and what did not fit before the end of the area written
+    // just after the MDA header, as LVM2 wraps it. The rest of the file,
+    // the header included, is filled with bytes that are not text, so
+    // that reading any of it is noticed.
+    fn mda_image(name: &str, offset: u64, text: &[u8]) -> (PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("melvin-{}-{}", name, std::process::id()));
+
+        let start = (AREA.offset + offset) as usize;
+        let end = (AREA.offset + AREA.size) as usize;
+        let wrap_start = AREA.offset as usize + MDA_HEADER_SIZE;
+        let first = min(end - start, text.len());
+
+        let mut image = vec![0xa5; end];
+        image[start..start + first].copy_from_slice(&text[..first]);
+        image[wrap_start..wrap_start + text.len() - first].copy_from_slice(&text[first..]);
+        std::fs::write(&path, &image).unwrap();
+
+        let file = File::open(&path).unwrap();
+        (path, file)
+    }
+
+    // Read the fixture's text from an MDA image with it `offset` bytes
+    // into the area, and check it comes back whole and parses.
+    fn check_read_at(name: &str, offset: u64) {
+        let text = lvm2_text();
+        let (path, mut file) = mda_image(name, offset, &text);
+        let rl = RawLocn {
+            offset,
+            size: text.len() as u64,
+            checksum: crc32_calc(&text),
+            ignored: false,
+        };
+
+        let read = PvHeader::read_mda_text(&AREA, &mut file, &rl, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let read = read.unwrap();
+        assert_eq!(read, text);
+        let map = buf_to_textmap(&read).unwrap();
+        let vg = map.textmap_from_textmap("vg0").unwrap();
+        assert_eq!(vg.i64_from_textmap("seqno"), Some(7));
+    }
+
+    #[test]
+    fn read_text_ending_at_area_end() {
+        check_read_at("end", AREA.size - lvm2_text().len() as u64);
     }
 
-    Ok(ret_vec)
+    #[test]
+    fn read_text_wrapped_by_one_byte() {
+        check_read_at("one-byte", AREA.size - lvm2_text().len() as u64 + 1);
+    }
+
+    #[test]
+    fn read_text_wrapped_past_header() {
+        // As much fits before the end as the header takes at the start.
+        check_read_at("header-gap", AREA.size - MDA_HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn read_text_not_wrapped() {
+        check_read_at("unwrapped", MDA_HEADER_SIZE as u64);
+    }
+
+    // A PV with the fixture's text as its current metadata, starting in
+    // the last sector of its MDA and wrapped to just after the MDA
+    // header. It was laid out byte by byte from LVM2's on-disk format,
+    // not written by Melvin, so it doesn't share the offset arithmetic
+    // under test.
+    #[test]
+    fn read_wrapped_pv_image() {
+        let path = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/lvm2-pv-wrapped.img"
+        ));
+        let pvh = PvHeader::find_in_dev(path).unwrap();
+        assert_eq!(pvh.metadata_areas, vec![AREA]);
+
+        let rl = pvh.mda_info().unwrap()[0].rlocn.unwrap();
+        assert!(rl.offset + rl.size > AREA.size);
+
+        assert_eq!(pvh.read_metadata_text().unwrap(), lvm2_text());
+        let map = pvh.read_metadata().unwrap();
+        let vg = map.textmap_from_textmap("vg0").unwrap();
+        assert_eq!(vg.i64_from_textmap("seqno"), Some(7));
+    }
+
+    // Write the fixture's text after the text `current` points to, and
+    // check it lands `offset` bytes into the area, wrapped as LVM2 would
+    // wrap it, without touching anything else.
+    fn check_write_after(name: &str, current: RawLocn, offset: u64) {
+        let text = lvm2_text();
+        let (path, _) = mda_image(name, 0, &[]);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let written = PvHeader::write_mda_text(&AREA, &mut file, &current, &text);
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The file stays open, so what was written can still be read.
+        let rl = written.unwrap();
+        assert_eq!(rl.offset, offset);
+        assert_eq!(rl.size, text.len() as u64);
+        let read = PvHeader::read_mda_text(&AREA, &mut file, &rl, &path).unwrap();
+        assert_eq!(read, text);
+
+        let (expected_path, _) = mda_image(&format!("{}-expected", name), offset, &text);
+        let expected = std::fs::read(&expected_path).unwrap();
+        std::fs::remove_file(&expected_path).unwrap();
+        assert!(image == expected);
+    }
+
+    fn rlocn(offset: u64, size: u64) -> RawLocn {
+        RawLocn {
+            offset,
+            size,
+            checksum: 0,
+            ignored: false,
+        }
+    }
+
+    #[test]
+    fn write_text_wrapping_past_area_end() {
+        // Only two sectors are left after the current text.
+        check_write_after("write-wrap", rlocn(6144, 1000), 7168);
+    }
+
+    #[test]
+    fn write_text_after_wrapped_text() {
+        // The current text wraps, so the next starts after its tail.
+        let current = rlocn(AREA.size - SECTOR_SIZE as u64, lvm2_text().len() as u64);
+        check_write_after("write-after-wrap", current, 1536);
+    }
+
+    #[test]
+    fn write_text_after_text_ending_at_area_end() {
+        check_write_after("write-at-end", rlocn(7168, 1024), MDA_HEADER_SIZE as u64);
+    }
 }
@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Python bindings, so that tools written in Python can manage VGs
+//! without running a command-line tool and parsing its output.
+//!
+//! They are built with the "python" feature, as an extension module
+//! named `melvin`:
+//!
+//! ```text
+//! cargo rustc --release --lib --features python,pyo3/extension-module --crate-type cdylib
+//! ```
+//!
+//! and the resulting `libmelvin.so` installed as `melvin.so`. Sizes are
+//! given as strings, as `Size` parses them, e.g. "10G" or "100%FREE".
+//! Errors are raised as `OSError`, or as its subclasses
+//! `FileExistsError` for an LV that already exists and
+//! `FileNotFoundError` for a VG, LV or PV that does not.
+
+// pyo3's macros convert the errors of methods to PyErr, even when they
+// already are one.
+#![allow(clippy::useless_conversion)]
+
+use std::path::{Path, PathBuf};
+
+use pyo3::exceptions::{PyFileExistsError, PyFileNotFoundError, PyOSError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::context::{recover_vg, vg_metadata, DEV_DIR};
+use crate::pvlabel::pvheader_scan;
+use crate::state::LvState;
+use crate::{Config, Error, Flock, LockScope, Size, PV, VG};
+
+fn to_py_err(e: Error) -> PyErr {
+    match e {
+        Error::Io(e) => e.into(),
+        e @ Error::LvExists { .. } => PyFileExistsError::new_err(e.to_string()),
+        e @ (Error::VgNotFound(_) | Error::LvNotFound { .. } | Error::PvNotFound { .. }) => {
+            PyFileNotFoundError::new_err(e.to_string())
+        }
+        e @ Error::InvalidArgument(_) => PyValueError::new_err(e.to_string()),
+        e => PyOSError::new_err(e.to_string()),
+    }
+}
+
+fn parse_size(size: &str) -> PyResult<Size> {
+    size.parse().map_err(to_py_err)
+}
+
+/// A PV, as of when it was read.
+#[pyclass(name = "PV", module = "melvin")]
+pub struct PyPv {
+    /// Its UUID.
+    #[pyo3(get)]
+    id: String,
+    /// Its device number, as "major:minor".
+    #[pyo3(get)]
+    device: String,
+    /// The path of its device, if it could be found.
+    #[pyo3(get)]
+    path: Option<PathBuf>,
+    /// The device's size, in sectors.
+    #[pyo3(get)]
+    dev_size: u64,
+    /// The number of extents.
+    #[pyo3(get)]
+    pe_count: u64,
+}
+
+impl From<&PV> for PyPv {
+    fn from(pv: &PV) -> PyPv {
+        PyPv {
+            id: pv.id.clone(),
+            device: pv.device.to_string(),
+            path: pv.path(),
+            dev_size: pv.dev_size,
+            pe_count: pv.pe_count,
+        }
+    }
+}
+
+/// An LV, as of when it was read.
+#[pyclass(name = "LV", module = "melvin")]
+pub struct PyLv {
+    /// The name.
+    #[pyo3(get)]
+    name: String,
+    /// The UUID.
+    #[pyo3(get)]
+    id: String,
+    /// The size, in extents.
+    #[pyo3(get)]
+    extents: u64,
+    /// The DM target type of each segment.
+    #[pyo3(get)]
+    segment_types: Vec<String>,
+    /// Whether it has a DM device.
+    #[pyo3(get)]
+    active: bool,
+    /// Tags.
+    #[pyo3(get)]
+    tags: Vec<String>,
+}
+
+impl From<&LvState> for PyLv {
+    fn from(lv: &LvState) -> PyLv {
+        PyLv {
+            name: lv.name.clone(),
+            id: lv.id.clone(),
+            extents: lv.extents,
+            segment_types: lv.segment_types.iter().map(|t| t.to_string()).collect(),
+            active: lv.device.is_some(),
+            tags: lv.tags.clone(),
+        }
+    }
+}
+
+/// A VG. Changes are written to the PVs as each method returns. Once
+/// `remove` has been called, every method fails.
+#[pyclass(name = "VG", module = "melvin", unsendable)]
+pub struct PyVg {
+    vg: Option<VG>,
+}
+
+impl PyVg {
+    fn vg(&self) -> PyResult<&VG> {
+        self.vg
+            .as_ref()
+            .ok_or_else(|| PyOSError::new_err("VG has been removed"))
+    }
+
+    fn vg_mut(&mut self) -> PyResult<&mut VG> {
+        self.vg
+            .as_mut()
+            .ok_or_else(|| PyOSError::new_err("VG has been removed"))
+    }
+}
+
+#[pymethods]
+impl PyVg {
+    /// Read the metadata of an existing VG.
+    #[staticmethod]
+    fn open(name: &str) -> PyResult<PyVg> {
+        let config = Config::default();
+        recover_vg(name, &config).map_err(to_py_err)?;
+        let _lock = Flock::lock_shared(LockScope::VG(name.to_string())).map_err(to_py_err)?;
+        let map = vg_metadata(name, &config).map_err(to_py_err)?;
+        let vg = VG::from_textmap(name, &map).map_err(to_py_err)?;
+
+        Ok(PyVg { vg: Some(vg) })
+    }
+
+    /// Create a VG from devices that are already PVs.
+    #[staticmethod]
+    fn create(name: &str, pv_paths: Vec<PathBuf>) -> PyResult<PyVg> {
+        let paths = pv_paths.iter().map(|p| p.as_path()).collect();
+        let vg = VG::create(name, paths).map_err(to_py_err)?;
+
+        Ok(PyVg { vg: Some(vg) })
+    }
+
+    #[getter]
+    fn name(&self) -> PyResult<String> {
+        Ok(self.vg()?.name().to_string())
+    }
+
+    #[getter]
+    fn id(&self) -> PyResult<String> {
+        Ok(self.vg()?.id().to_string())
+    }
+
+    #[getter]
+    fn seqno(&self) -> PyResult<u64> {
+        Ok(self.vg()?.seqno())
+    }
+
+    /// The size of each extent, in sectors.
+    #[getter]
+    fn extent_size(&self) -> PyResult<u64> {
+        Ok(self.vg()?.extent_size())
+    }
+
+    #[getter]
+    fn extents(&self) -> PyResult<u64> {
+        Ok(self.vg()?.extents())
+    }
+
+    #[getter]
+    fn extents_free(&self) -> PyResult<u64> {
+        Ok(self.vg()?.extents_free())
+    }
+
+    /// The VG's PVs.
+    fn pvs(&self) -> PyResult<Vec<PyPv>> {
+        let vg = self.vg()?;
+        Ok(vg
+            .pv_list()
+            .into_iter()
+            .filter_map(|dev| vg.pv_get(dev))
+            .map(PyPv::from)
+            .collect())
+    }
+
+    /// The VG's LVs, hidden ones included.
+    fn lvs(&self) -> PyResult<Vec<PyLv>> {
+        let state = self.vg()?.clone_state();
+        Ok(state
+            .lv_list()
+            .iter()
+            .filter_map(|name| state.lv_get(name))
+            .map(PyLv::from)
+            .collect())
+    }
+
+    /// Warnings from operations that went ahead despite a possible
+    /// problem, which are cleared.
+    fn take_warnings(&mut self) -> PyResult<Vec<String>> {
+        Ok(self.vg_mut()?.take_warnings())
+    }
+
+    /// Add a device that is already a PV to the VG.
+    fn pv_add(&mut self, path: PathBuf) -> PyResult<()> {
+        self.vg_mut()?.pv_add(&path).map_err(to_py_err)
+    }
+
+    /// Create a linear LV.
+    fn lv_create_linear(&mut self, name: &str, size: &str) -> PyResult<()> {
+        let size = parse_size(size)?;
+        self.vg_mut()?
+            .lv_create_linear(name, size)
+            .map_err(to_py_err)
+    }
+
+    /// Create a thin pool.
+    fn lv_create_thinpool(&mut self, name: &str, size: &str) -> PyResult<()> {
+        let size = parse_size(size)?;
+        self.vg_mut()?
+            .lv_create_thinpool(name, size)
+            .map_err(to_py_err)
+    }
+
+    /// Create a thin LV in an active thin pool.
+    fn lv_create_thin(&mut self, pool_name: &str, name: &str, size: &str) -> PyResult<()> {
+        let size = parse_size(size)?;
+        self.vg_mut()?
+            .lv_create_thin(pool_name, name, size)
+            .map_err(to_py_err)
+    }
+
+    /// Grow an LV by `size`.
+    fn lv_extend(&mut self, name: &str, size: &str) -> PyResult<()> {
+        let size = parse_size(size)?;
+        self.vg_mut()?.lv_extend(name, size).map_err(to_py_err)
+    }
+
+    /// Remove an LV.
+    fn lv_remove(&mut self, name: &str) -> PyResult<()> {
+        self.vg_mut()?.lv_remove(name).map_err(to_py_err)
+    }
+
+    /// Activate an LV.
+    fn lv_activate(&mut self, name: &str) -> PyResult<()> {
+        self.vg_mut()?.lv_activate(name).map_err(to_py_err)
+    }
+
+    /// Deactivate an LV.
+    fn lv_deactivate(&mut self, name: &str) -> PyResult<()> {
+        self.vg_mut()?.lv_deactivate(name).map_err(to_py_err)
+    }
+
+    /// Remove the VG, leaving its PVs as orphans, or wiping their
+    /// labels too if `wipe_labels` is set. The object cannot be used
+    /// afterwards even if this fails; open the VG again instead.
+    #[pyo3(signature = (wipe_labels=false))]
+    fn remove(&mut self, wipe_labels: bool) -> PyResult<()> {
+        let vg = self
+            .vg
+            .take()
+            .ok_or_else(|| PyOSError::new_err("VG has been removed"))?;
+        vg.remove(wipe_labels).map_err(to_py_err)
+    }
+}
+
+/// Find the devices under /dev with PV labels.
+#[pyfunction]
+fn scan() -> PyResult<Vec<PathBuf>> {
+    pvheader_scan(&[Path::new(DEV_DIR)]).map_err(to_py_err)
+}
+
+#[pymodule]
+fn melvin(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(scan, m)?)?;
+    m.add_class::<PyPv>()?;
+    m.add_class::<PyLv>()?;
+    m.add_class::<PyVg>()?;
+
+    Ok(())
+}
@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reports on PVs, VGs and LVs, laid out like those of LVM2's `pvs`,
+//! `vgs` and `lvs`.
+//!
+//! The default columns, attribute strings and size formatting follow
+//! LVM2's defaults, as do the `--noheadings` and `--separator` layouts,
+//! so that scripts written to parse LVM2's output can parse these too.
+//! Columns that need the status of active DM devices, such as Data%,
+//! are left empty.
+
+use crate::dm;
+use crate::lv::segment::{SnapshotSegment, ThinPoolSegment, ThinSegment};
+use crate::{PvHeader, LV, VG};
+
+/// How to lay out a report.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReportOptions {
+    /// Whether to print a line of column headings first. Like LVM2's
+    /// `--noheadings` when not set.
+    pub headings: bool,
+    /// If set, fields are joined with this instead of being aligned in
+    /// columns, like LVM2's `--separator`.
+    pub separator: Option<String>,
+}
+
+impl Default for ReportOptions {
+    fn default() -> ReportOptions {
+        ReportOptions {
+            headings: true,
+            separator: None,
+        }
+    }
+}
+
+// A column's heading, and whether its values are aligned to the right.
+type Column = (&'static str, bool);
+
+const PVS_COLUMNS: &[Column] = &[
+    ("PV", false),
+    ("VG", false),
+    ("Fmt", false),
+    ("Attr", false),
+    ("PSize", true),
+    ("PFree", true),
+];
+
+const VGS_COLUMNS: &[Column] = &[
+    ("VG", false),
+    ("#PV", true),
+    ("#LV", true),
+    ("#SN", true),
+    ("Attr", false),
+    ("VSize", true),
+    ("VFree", true),
+];
+
+const LVS_COLUMNS: &[Column] = &[
+    ("LV", false),
+    ("VG", false),
+    ("Attr", false),
+    ("LSize", true),
+    ("Pool", false),
+    ("Origin", false),
+    ("Data%", true),
+    ("Meta%", true),
+    ("Move", false),
+    ("Log", false),
+    ("Cpy%Sync", true),
+    ("Convert", false),
+];
+
+/// Format a size in bytes as LVM2 does by default: two decimal places
+/// in the largest unit that keeps the number at least 1, with "<" in
+/// front if the rounded number is larger than the size. Zero is "0 ".
+pub fn format_size(bytes: u64) -> String {
+    if bytes == 0 {
+        return "0 ".to_string();
+    }
+
+    let units = ['b', 'k', 'm', 'g', 't', 'p', 'e'];
+    let mut idx = 0;
+    while idx + 1 < units.len() && bytes >= 1u64 << (10 * (idx + 1)) {
+        idx += 1;
+    }
+    let unit = 1u128 << (10 * idx);
+
+    // In hundredths of the unit, rounded to the nearest.
+    let hundredths = (u128::from(bytes) * 100 + unit / 2) / unit;
+    let prefix = if hundredths * unit > u128::from(bytes) * 100 {
+        "<"
+    } else {
+        ""
+    };
+
+    format!(
+        "{}{}.{:02}{}",
+        prefix,
+        hundredths / 100,
+        hundredths % 100,
+        units[idx]
+    )
+}
+
+fn extents_to_bytes(vg: &VG, extents: u64) -> u64 {
+    extents * vg.extent_size() * 512
+}
+
+// Lay out rows under the given columns. Every line starts with two
+// spaces, as LVM2's do, even with a separator.
+fn render(columns: &[Column], rows: Vec<Vec<String>>, options: &ReportOptions) -> String {
+    let mut lines = Vec::new();
+    if options.headings {
+        lines.push(columns.iter().map(|(h, _)| h.to_string()).collect());
+    }
+    lines.extend(rows);
+
+    let mut out = String::new();
+    match options.separator {
+        Some(ref sep) => {
+            for line in lines {
+                out.push_str("  ");
+                out.push_str(&line.join(sep));
+                out.push('\n');
+            }
+        }
+        None => {
+            let widths: Vec<usize> = (0..columns.len())
+                .map(|col| {
+                    lines
+                        .iter()
+                        .map(|line| line[col].chars().count())
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+            for line in lines {
+                let fields: Vec<String> = line
+                    .iter()
+                    .zip(columns.iter().zip(&widths))
+                    .map(|(field, ((_, right), width))| {
+                        if *right {
+                            format!("{:>1$}", field, width)
+                        } else {
+                            format!("{:<1$}", field, width)
+                        }
+                    })
+                    .collect();
+                out.push_str("  ");
+                out.push_str(fields.join(" ").trim_end());
+                out.push('\n');
+            }
+        }
+    }
+
+    out
+}
+
+fn pv_attr(status: &[String], missing: bool) -> String {
+    let has = |s: &str| status.iter().any(|x| x == s);
+    [
+        if has("ALLOCATABLE") { 'a' } else { '-' },
+        if has("EXPORTED") { 'x' } else { '-' },
+        if missing { 'm' } else { '-' },
+    ]
+    .iter()
+    .collect()
+}
+
+/// A report of the PVs in the given VGs, with LVM2's default `pvs`
+/// columns: PV, VG, Fmt, Attr, PSize and PFree.
+pub fn pvs(vgs: &[&VG], options: &ReportOptions) -> String {
+    pvs_with_orphans(vgs, &[], options)
+}
+
+/// Like `pvs`, followed by the PVs in `orphans`, which are in no VG.
+/// As in LVM2, their VG is empty and all of their space is free.
+pub fn pvs_with_orphans(vgs: &[&VG], orphans: &[&PvHeader], options: &ReportOptions) -> String {
+    let mut rows = Vec::new();
+
+    for vg in vgs {
+        for dev in vg.pv_list() {
+            let pv = vg.pv_get(dev).expect("listed");
+            let path = pv.path();
+            let name = match path {
+                Some(ref path) => path.display().to_string(),
+                None => "[unknown]".to_string(),
+            };
+            let free = pv.pe_count - vg.pv_extents_in_use(dev);
+
+            rows.push(vec![
+                name,
+                vg.name().to_string(),
+                "lvm2".to_string(),
+                pv_attr(&pv.status, path.is_none()),
+                format_size(extents_to_bytes(vg, pv.pe_count)),
+                format_size(extents_to_bytes(vg, free)),
+            ]);
+        }
+    }
+    for pvh in orphans {
+        rows.push(vec![
+            pvh.dev_path.display().to_string(),
+            String::new(),
+            "lvm2".to_string(),
+            "---".to_string(),
+            format_size(pvh.size),
+            format_size(pvh.size),
+        ]);
+    }
+
+    render(PVS_COLUMNS, rows, options)
+}
+
+fn vg_attr(vg: &VG) -> String {
+    let has = |s: &str| vg.status().iter().any(|x| x == s);
+    let partial = vg
+        .pv_list()
+        .into_iter()
+        .any(|dev| vg.pv_get(dev).and_then(|pv| pv.path()).is_none());
+    [
+        if has("WRITE") { 'w' } else { 'r' },
+        if has("RESIZEABLE") { 'z' } else { '-' },
+        if has("EXPORTED") { 'x' } else { '-' },
+        if partial { 'p' } else { '-' },
+        'n',
+        '-',
+    ]
+    .iter()
+    .collect()
+}
+
+fn is_visible(lv: &LV) -> bool {
+    lv.status.iter().any(|s| s == "VISIBLE")
+}
+
+// The snapshots in a VG, as (origin, COW store).
+fn snapshots(vg: &VG) -> Vec<(String, String)> {
+    vg.lv_list()
+        .iter()
+        .filter_map(|name| vg.lv_get(name))
+        .flat_map(|lv| lv.segments.iter())
+        .filter_map(|seg| seg.as_any().downcast_ref::<SnapshotSegment>())
+        .map(|snap| (snap.origin.clone(), snap.cow_store.clone()))
+        .collect()
+}
+
+/// A report of the given VGs, with LVM2's default `vgs` columns: VG,
+/// #PV, #LV, #SN, Attr, VSize and VFree.
+pub fn vgs(vgs: &[&VG], options: &ReportOptions) -> String {
+    let rows = vgs
+        .iter()
+        .map(|vg| {
+            let snaps = snapshots(vg).len();
+            let lvs = vg
+                .lv_list()
+                .iter()
+                .filter_map(|name| vg.lv_get(name))
+                .filter(|lv| is_visible(lv))
+                .count();
+            vec![
+                vg.name().to_string(),
+                vg.pv_list().len().to_string(),
+                lvs.to_string(),
+                snaps.to_string(),
+                vg_attr(vg),
+                format_size(extents_to_bytes(vg, vg.extents())),
+                format_size(extents_to_bytes(vg, vg.extents_free())),
+            ]
+        })
+        .collect();
+
+    render(VGS_COLUMNS, rows, options)
+}
+
+fn lv_attr(vg: &VG, lv: &LV, snaps: &[(String, String)]) -> String {
+    let dm_type = lv.segments.first().map(|seg| seg.dm_type()).unwrap_or("");
+    let is_cow = snaps.iter().any(|(_, cow)| *cow == lv.name);
+    let is_origin = snaps.iter().any(|(origin, _)| *origin == lv.name);
+    let active = lv.device.is_some() || matches!(dm::find_device(&vg.lv_dm_name(lv)), Ok(Some(_)));
+    let zero = lv
+        .segments
+        .iter()
+        .filter_map(|seg| seg.as_any().downcast_ref::<ThinPoolSegment>())
+        .any(|pool| pool.zero_new_blocks);
+
+    let volume_type = match dm_type {
+        "thin-pool" => 't',
+        "thin" => 'V',
+        "cache" | "writecache" => 'C',
+        "mirror" => 'm',
+        t if t.starts_with("raid") => 'r',
+        _ if is_cow => 's',
+        _ if is_origin => 'o',
+        _ => '-',
+    };
+    let target_type = match dm_type {
+        "thin-pool" | "thin" => 't',
+        "cache" | "writecache" => 'C',
+        "mirror" => 'm',
+        t if t.starts_with("raid") => 'r',
+        _ if is_cow || is_origin => 's',
+        _ => '-',
+    };
+
+    [
+        volume_type,
+        if lv.status.iter().any(|s| s == "WRITE") {
+            'w'
+        } else {
+            'r'
+        },
+        'i',
+        '-',
+        if active { 'a' } else { '-' },
+        '-',
+        target_type,
+        if zero { 'z' } else { '-' },
+        '-',
+        if lv.activation_skip() { 'k' } else { '-' },
+    ]
+    .iter()
+    .collect()
+}
+
+/// A report of the visible LVs in the given VGs, with LVM2's default
+/// `lvs` columns: LV, VG, Attr, LSize, Pool, Origin, Data%, Meta%,
+/// Move, Log, Cpy%Sync and Convert.
+pub fn lvs(vgs: &[&VG], options: &ReportOptions) -> String {
+    dm::cached(|| lvs_uncached(vgs, options))
+}
+
+fn lvs_uncached(vgs: &[&VG], options: &ReportOptions) -> String {
+    let mut rows = Vec::new();
+
+    for vg in vgs {
+        let snaps = snapshots(vg);
+        for name in vg.lv_list() {
+            let lv = vg.lv_get(&name).expect("listed");
+            if !is_visible(lv) {
+                continue;
+            }
+
+            let thin = lv
+                .segments
+                .iter()
+                .find_map(|seg| seg.as_any().downcast_ref::<ThinSegment>());
+            let pool = thin.map(|thin| thin.thin_pool.clone()).unwrap_or_default();
+            let origin = snaps
+                .iter()
+                .find(|(_, cow)| *cow == name)
+                .map(|(origin, _)| origin.clone())
+                .or_else(|| thin.and_then(|thin| thin.origin.clone()))
+                .unwrap_or_default();
+
+            rows.push(vec![
+                name.clone(),
+                vg.name().to_string(),
+                lv_attr(vg, lv, &snaps),
+                format_size(extents_to_bytes(vg, lv.used_extents())),
+                pool,
+                origin,
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+    }
+
+    render(LVS_COLUMNS, rows, options)
+}
@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Retrying operations that can fail transiently.
+
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "devicemapper")]
+use devicemapper::{CoreError, DmError};
+use nix::errno::Errno;
+
+use crate::{Error, Result};
+
+/// How to retry operations that fail with a transient error, such as
+/// EBUSY because udev is still processing a device that was just
+/// created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first. 1 disables
+    /// retrying.
+    pub max_attempts: u32,
+    /// The delay before the first retry. It doubles with each further
+    /// retry.
+    pub initial_delay: Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Call `f` until it succeeds, it fails with an error that is not
+    /// transient, or the maximum number of attempts is reached.
+    pub fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        self.run_with(is_transient, f)
+    }
+
+    // Like `run`, but with `is_transient` deciding which errors are
+    // worth retrying.
+    pub(crate) fn run_with<T, F, P>(&self, is_transient: P, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+        P: Fn(&Error) -> bool,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+
+        loop {
+            match f() {
+                Err(ref e) if attempt < self.max_attempts && is_transient(e) => {
+                    sleep(jitter(delay));
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// Scale the delay to between 50% and 100%, so that multiple processes
+// that failed at the same time do not retry in lockstep.
+fn jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+
+    delay / 2 + (delay / 2) * (nanos % 1000) / 1000
+}
+
+fn is_transient_errno(errno: Errno) -> bool {
+    errno == Errno::EBUSY || errno == Errno::EAGAIN
+}
+
+// The errno a system call failed with, if that is what the error is.
+// The rest come from what is on disk or asked for, which retrying does
+// not change.
+fn errno(err: &Error) -> Option<Errno> {
+    match err {
+        Error::Io(e) => e.raw_os_error().map(Errno::from_i32),
+        Error::Nix(e) => e.as_errno(),
+        // The devicemapper crate's nix may not be ours, so its Errno is
+        // converted by value.
+        #[cfg(feature = "devicemapper")]
+        Error::Dm(DmError::Core(CoreError::Ioctl(_, _, _, e))) => {
+            e.as_errno().map(|errno| Errno::from_i32(errno as i32))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an error is one that may go away if the operation is retried.
+pub fn is_transient(err: &Error) -> bool {
+    errno(err).is_some_and(is_transient_errno)
+}
+
+// Whether an error connecting to a daemon's socket may go away if
+// retried. As well as the errors `is_transient` covers, the daemon may
+// be starting, and not listening on its socket yet. A missing socket
+// means the daemon isn't running, which retrying won't change.
+#[cfg(feature = "daemon-clients")]
+pub(crate) fn is_transient_connect(err: &Error) -> bool {
+    match errno(err) {
+        Some(Errno::ECONNREFUSED) => true,
+        Some(errno) => is_transient_errno(errno),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    fn os_err(errno: Errno) -> Error {
+        Error::Io(io::Error::from_raw_os_error(errno as i32))
+    }
+
+    fn quick(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn transient_by_errno() {
+        assert!(is_transient(&os_err(Errno::EBUSY)));
+        assert!(is_transient(&Error::Nix(nix::Error::Sys(Errno::EAGAIN))));
+        assert!(!is_transient(&os_err(Errno::ENOENT)));
+        assert!(!is_transient(&Error::Metadata("EBUSY".to_string())));
+
+        let mut attempts = 0;
+        let res: Result<()> = quick(3).run(|| {
+            attempts += 1;
+            Err(os_err(Errno::EBUSY))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[cfg(feature = "daemon-clients")]
+    #[test]
+    fn connect_retries_until_daemon_listens() {
+        assert!(is_transient_connect(&os_err(Errno::ECONNREFUSED)));
+        assert!(!is_transient_connect(&os_err(Errno::ENOENT)));
+        assert!(is_transient_connect(&os_err(Errno::EAGAIN)));
+        assert!(!is_transient_connect(&os_err(Errno::EACCES)));
+
+        let mut attempts = 0;
+        let res: Result<()> = quick(4).run_with(is_transient_connect, || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(os_err(Errno::ECONNREFUSED)),
+                _ => Ok(()),
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(attempts, 3);
+
+        // A missing socket means the daemon isn't running at all.
+        let mut attempts = 0;
+        let res: Result<()> = quick(4).run_with(is_transient_connect, || {
+            attempts += 1;
+            Err(os_err(Errno::ENOENT))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,54 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Finding known on-disk signatures, such as PV labels and filesystem
+//! superblocks, so that they can be wiped.
+
+// What each signature marks, its offset in bytes from the start of the
+// device, and its magic bytes. An LVM2 label may be in any of the first
+// four sectors.
+const SIGNATURES: &[(&str, u64, &[u8])] = &[
+    ("LVM2_member", 0, b"LABELONE"),
+    ("LVM2_member", 512, b"LABELONE"),
+    ("LVM2_member", 1024, b"LABELONE"),
+    ("LVM2_member", 1536, b"LABELONE"),
+    ("crypto_LUKS", 0, b"LUKS\xba\xbe"),
+    ("xfs", 0, b"XFSB"),
+    ("ext4", 0x438, b"\x53\xef"),
+    ("linux_raid_member", 0x1000, b"\xfc\x4e\x2b\xa9"),
+    ("swap", 0xff6, b"SWAPSPACE2"),
+    ("btrfs", 0x10040, b"_BHRfS_M"),
+];
+
+/// How many bytes from the start of a device must be read to find any
+/// known signature.
+pub const SCAN_LEN: u64 = 0x10048;
+
+/// A signature found on a device.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Signature {
+    /// What the signature marks, named as by blkid.
+    pub name: &'static str,
+    /// The offset of the magic bytes from the start of the device.
+    pub offset: u64,
+    /// How many magic bytes there are.
+    pub len: u64,
+}
+
+/// Find the known signatures within `buf`, which holds the start of a
+/// device. Signatures beyond the end of `buf` are not found.
+pub fn find(buf: &[u8]) -> Vec<Signature> {
+    SIGNATURES
+        .iter()
+        .filter(|(_, offset, magic)| {
+            let start = *offset as usize;
+            buf.get(start..start + magic.len()) == Some(magic)
+        })
+        .map(|(name, offset, magic)| Signature {
+            name,
+            offset: *offset,
+            len: magic.len() as u64,
+        })
+        .collect()
+}
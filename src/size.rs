@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Sizes of LVs, in the units callers think in.
+//!
+//! LV creation and extension take a `Size`, which the VG turns into a
+//! number of extents. Byte sizes are rounded up to whole extents, and
+//! percentages are rounded down, so that 100% of the free space is
+//! never more than is free. A plain `u64` is a number of extents.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+/// The size of an LV, or of an addition to one.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Size {
+    /// A number of extents.
+    Extents(u64),
+    /// A number of bytes, rounded up to whole extents.
+    Bytes(u64),
+    /// A percentage of the VG's free extents.
+    PercentFree(u8),
+    /// A percentage of all of the VG's extents.
+    PercentVg(u8),
+}
+
+impl From<u64> for Size {
+    fn from(extents: u64) -> Size {
+        Size::Extents(extents)
+    }
+}
+
+fn size_err(s: &str) -> Error {
+    Error::Io(io::Error::new(Other, format!("Invalid size {}", s)))
+}
+
+fn parse_percent(s: &str, full: &str) -> Result<u8> {
+    match s.parse::<u8>() {
+        Ok(pct) if pct <= 100 => Ok(pct),
+        _ => Err(size_err(full)),
+    }
+}
+
+/// Parse a size like LVM2's `-L` and `-l` options take it: a number with
+/// an optional fraction and unit, e.g. "10G", "1.5t" or "512M", or a
+/// percentage, "50%FREE" or "100%VG". Units are powers of 1024, and are
+/// "b" for bytes, "s" for sectors, or "k", "m", "g", "t", "p" and "e",
+/// in either case. A number without a unit is in MiB.
+impl FromStr for Size {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Size> {
+        let s = s.trim();
+
+        if let Some(idx) = s.find('%') {
+            let (pct, of) = (&s[..idx], &s[idx + 1..]);
+            return match of.to_uppercase().as_str() {
+                "FREE" => Ok(Size::PercentFree(parse_percent(pct, s)?)),
+                "VG" => Ok(Size::PercentVg(parse_percent(pct, s)?)),
+                _ => Err(size_err(s)),
+            };
+        }
+
+        let (num, unit) = match s.char_indices().last() {
+            Some((idx, c)) if c.is_ascii_alphabetic() => (&s[..idx], c.to_ascii_lowercase()),
+            _ => (s, 'm'),
+        };
+        let multiplier: u64 = match unit {
+            'b' => 1,
+            's' => 512,
+            'k' => 1 << 10,
+            'm' => 1 << 20,
+            'g' => 1 << 30,
+            't' => 1 << 40,
+            'p' => 1 << 50,
+            'e' => 1 << 60,
+            _ => return Err(size_err(s)),
+        };
+
+        let (whole, frac) = match num.find('.') {
+            Some(idx) => (&num[..idx], &num[idx + 1..]),
+            None => (num, ""),
+        };
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(size_err(s));
+        }
+        if !frac.bytes().all(|b| b.is_ascii_digit()) || frac.len() > 9 {
+            return Err(size_err(s));
+        }
+
+        let mut bytes = whole
+            .parse::<u64>()
+            .ok()
+            .and_then(|whole| whole.checked_mul(multiplier))
+            .ok_or_else(|| size_err(s))?;
+        if !frac.is_empty() {
+            // Fractions of a byte are rounded up
+            let denom = 10u128.pow(frac.len() as u32);
+            let frac_bytes =
+                (frac.parse::<u128>().expect("digits") * multiplier as u128).div_ceil(denom);
+            bytes = bytes
+                .checked_add(frac_bytes as u64)
+                .ok_or_else(|| size_err(s))?;
+        }
+
+        Ok(Size::Bytes(bytes))
+    }
+}
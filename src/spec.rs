@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Declarative descriptions of a VG, its PVs and its LVs.
+//!
+//! A `Spec` says what should exist, rather than how to create it.
+//! `Melvin::apply` creates whatever is missing, so applying the same
+//! spec again changes nothing. A spec is usually read from JSON:
+//!
+//! ```json
+//! {
+//!     "vg": "vg0",
+//!     "pvs": ["/dev/sdb", "/dev/sdc"],
+//!     "lvs": [
+//!         { "name": "root", "size": 10737418240 },
+//!         { "name": "pool", "size": 53687091200, "type": "thin-pool" },
+//!         { "name": "data", "size": 107374182400, "type": "thin", "pool": "pool" }
+//!     ]
+//! }
+//! ```
+//!
+//! Sizes are in bytes, and are rounded up to whole extents.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+/// The kind of LV to create.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LvType {
+    /// A linear LV.
+    Linear,
+    /// A thin pool.
+    ThinPool,
+    /// A thin LV in the named pool.
+    Thin(String),
+}
+
+/// A desired LV.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LvSpec {
+    /// The name.
+    pub name: String,
+    /// The size in bytes.
+    pub size: u64,
+    /// What kind of LV it is.
+    pub lv_type: LvType,
+}
+
+/// A desired VG, with its PVs and LVs.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Spec {
+    /// The name of the VG.
+    pub vg: String,
+    /// The devices that should be PVs in the VG.
+    pub pvs: Vec<PathBuf>,
+    /// The LVs that should exist, in the order to create them.
+    pub lvs: Vec<LvSpec>,
+}
+
+fn spec_err(msg: &str) -> Error {
+    Error::Io(io::Error::new(Other, format!("spec: {}", msg)))
+}
+
+impl LvSpec {
+    fn from_value(value: &Value) -> Result<LvSpec> {
+        let name = value
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| spec_err("LV without a name"))?;
+        let size = value
+            .get("size")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| spec_err(&format!("LV {} without a size", name)))?;
+
+        let lv_type = match value.get("type").and_then(Value::as_str) {
+            None | Some("linear") => LvType::Linear,
+            Some("thin-pool") => LvType::ThinPool,
+            Some("thin") => {
+                let pool = value
+                    .get("pool")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| spec_err(&format!("thin LV {} without a pool", name)))?;
+                LvType::Thin(pool.to_string())
+            }
+            Some(x) => return Err(spec_err(&format!("unknown LV type {}", x))),
+        };
+
+        Ok(LvSpec {
+            name: name.to_string(),
+            size,
+            lv_type,
+        })
+    }
+}
+
+impl Spec {
+    /// Read a spec from JSON text.
+    pub fn from_json(text: &str) -> Result<Spec> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|e| spec_err(&format!("invalid JSON: {}", e)))?;
+
+        let vg = value
+            .get("vg")
+            .and_then(Value::as_str)
+            .ok_or_else(|| spec_err("no VG name"))?;
+
+        let pvs = match value.get("pvs") {
+            Some(Value::Array(list)) => list
+                .iter()
+                .map(|pv| {
+                    pv.as_str()
+                        .map(PathBuf::from)
+                        .ok_or_else(|| spec_err("PVs must be paths"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            _ => return Err(spec_err("no PVs")),
+        };
+
+        // "lvs" may be absent
+        let lvs = match value.get("lvs") {
+            Some(Value::Array(list)) => list
+                .iter()
+                .map(LvSpec::from_value)
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+            _ => return Err(spec_err("LVs must be a list")),
+        };
+
+        Ok(Spec {
+            vg: vg.to_string(),
+            pvs,
+            lvs,
+        })
+    }
+}
@@ -0,0 +1,246 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Read-only views of a VG, for use by other threads.
+//!
+//! A `VgState` is taken from a `VG` at a point in time and does not
+//! change afterwards. It is cheap to clone and can be sent to threads
+//! that report on the VG while another thread holds the `VG` and
+//! modifies it.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::device::Device;
+use crate::lv::segment::Segment;
+use crate::lv::LV;
+use crate::parser::TextMapOps;
+use crate::{PV, VG};
+
+/// A segment of an LV as of when its `VgState` was taken, with the
+/// fields `lvs --segments` reports.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SegmentState {
+    /// The first extent of the segment within the LV.
+    pub seg_start: u64,
+    /// The size of the segment in extents.
+    pub seg_size: u64,
+    /// The segment type, as LVM2 names it, e.g. "linear" or "thin-pool".
+    pub seg_type: String,
+    /// Where the segment's data is, e.g. "/dev/sdb1(0)" for a PV and
+    /// its starting extent, or "lv_rimage_0(0)" for a sub-LV.
+    pub devices: String,
+}
+
+impl SegmentState {
+    fn new(seg: &dyn Segment, dev_to_idx: &BTreeMap<Device, usize>, vg: &VG) -> SegmentState {
+        let seg_type = match seg.dm_type() {
+            "linear" => "linear".to_string(),
+            _ => seg
+                .to_textmap(dev_to_idx)
+                .string_from_textmap("type")
+                .unwrap_or_default()
+                .to_string(),
+        };
+
+        let areas = seg.used_areas();
+        let devices: Vec<_> = if areas.is_empty() {
+            seg.lv_dependencies()
+                .into_iter()
+                .filter(|name| vg.lv_get(name).is_some())
+                .map(|name| format!("{}(0)", name))
+                .collect()
+        } else {
+            areas
+                .into_iter()
+                .map(|(dev, start, _)| {
+                    let path = vg
+                        .pv_get(dev)
+                        .and_then(|pv| pv.path())
+                        .map(|path| path.display().to_string())
+                        .unwrap_or_else(|| dev.to_string());
+                    format!("{}({})", path, start)
+                })
+                .collect()
+        };
+
+        SegmentState {
+            seg_start: seg.start_extent(),
+            seg_size: seg.extent_count(),
+            seg_type,
+            devices: devices.join(","),
+        }
+    }
+}
+
+/// An LV as of when its `VgState` was taken.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LvState {
+    /// The name.
+    pub name: String,
+    /// The UUID.
+    pub id: String,
+    /// The status.
+    pub status: Vec<String>,
+    /// Flags.
+    pub flags: Vec<String>,
+    /// Tags.
+    pub tags: Vec<String>,
+    /// Created by this host.
+    pub creation_host: String,
+    /// Created at this Unix time.
+    pub creation_time: i64,
+    /// The size of the LV in extents.
+    pub extents: u64,
+    /// The segment type of each segment, by DM target name.
+    pub segment_types: Vec<&'static str>,
+    /// Each segment, in LV order.
+    pub segments: Vec<SegmentState>,
+    /// The major/minor number of the LV's DM device, if it is active.
+    pub device: Option<Device>,
+}
+
+impl LvState {
+    fn new(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>, vg: &VG) -> LvState {
+        LvState {
+            name: lv.name.clone(),
+            id: lv.id.clone(),
+            status: lv.status.clone(),
+            flags: lv.flags.clone(),
+            tags: lv.tags.clone(),
+            creation_host: lv.creation_host.clone(),
+            creation_time: lv.creation_time,
+            extents: lv.used_extents(),
+            segment_types: lv.segments.iter().map(|seg| seg.dm_type()).collect(),
+            segments: lv
+                .segments
+                .iter()
+                .map(|seg| SegmentState::new(&**seg, dev_to_idx, vg))
+                .collect(),
+            device: lv.device,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Inner {
+    name: String,
+    id: String,
+    seqno: u64,
+    status: Vec<String>,
+    flags: Vec<String>,
+    extent_size: u64,
+    extents: u64,
+    extents_in_use: u64,
+    pvs: BTreeMap<Device, PV>,
+    lvs: BTreeMap<String, LvState>,
+}
+
+/// An immutable view of a VG as of when it was taken.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VgState {
+    inner: Arc<Inner>,
+}
+
+impl VgState {
+    pub(crate) fn new(vg: &VG) -> VgState {
+        let dev_to_idx = vg
+            .pv_list()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, dev)| (dev, idx))
+            .collect();
+
+        VgState {
+            inner: Arc::new(Inner {
+                name: vg.name().to_string(),
+                id: vg.id().to_string(),
+                seqno: vg.seqno(),
+                status: vg.status().to_vec(),
+                flags: vg.flags().to_vec(),
+                extent_size: vg.extent_size(),
+                extents: vg.extents(),
+                extents_in_use: vg.extents_in_use(),
+                pvs: vg
+                    .pv_list()
+                    .into_iter()
+                    .filter_map(|dev| vg.pv_get(dev).map(|pv| (dev, pv.clone())))
+                    .collect(),
+                lvs: vg
+                    .lv_list()
+                    .into_iter()
+                    .filter_map(|name| {
+                        vg.lv_get(&name)
+                            .map(|lv| (name, LvState::new(lv, &dev_to_idx, vg)))
+                    })
+                    .collect(),
+            }),
+        }
+    }
+
+    /// Returns the name of the VG.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Returns the UUID of the VG.
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    /// Returns the generation of metadata the view was taken from.
+    pub fn seqno(&self) -> u64 {
+        self.inner.seqno
+    }
+
+    /// Returns the VG's status.
+    pub fn status(&self) -> &[String] {
+        &self.inner.status
+    }
+
+    /// Returns the VG's flags.
+    pub fn flags(&self) -> &[String] {
+        &self.inner.flags
+    }
+
+    /// Returns how many 512-byte sectors make up each extent in the VG.
+    pub fn extent_size(&self) -> u64 {
+        self.inner.extent_size
+    }
+
+    /// The total number of extents in the volume group.
+    pub fn extents(&self) -> u64 {
+        self.inner.extents
+    }
+
+    /// The total number of extents in use in the volume group.
+    pub fn extents_in_use(&self) -> u64 {
+        self.inner.extents_in_use
+    }
+
+    /// The total number of free extents in the volume group.
+    pub fn extents_free(&self) -> u64 {
+        self.inner.extents - self.inner.extents_in_use
+    }
+
+    /// Returns a list of PV Devices that make up the VG.
+    pub fn pv_list(&self) -> Vec<Device> {
+        self.inner.pvs.keys().copied().collect()
+    }
+
+    /// Returns a reference to the PV matching the Device.
+    pub fn pv_get(&self, dev: Device) -> Option<&PV> {
+        self.inner.pvs.get(&dev)
+    }
+
+    /// Returns a list of the names of LVs in the VG.
+    pub fn lv_list(&self) -> Vec<String> {
+        self.inner.lvs.keys().cloned().collect()
+    }
+
+    /// Returns a reference to the LV matching the name.
+    pub fn lv_get(&self, name: &str) -> Option<&LvState> {
+        self.inner.lvs.get(name)
+    }
+}
@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parsers for the status strings of DM targets.
+//!
+//! The kernel reports the state of a target, such as how full a thin
+//! pool is or how far a RAID array has synced, as a line of text in a
+//! format particular to the target type. `VG::lv_status` gets these
+//! lines for an LV; the types here pick them apart. Fields the kernel
+//! added in later versions are optional.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+fn status_err(target: &str, s: &str) -> Error {
+    Error::Io(io::Error::new(
+        Other,
+        format!("Could not parse {} status \"{}\"", target, s),
+    ))
+}
+
+// "<used>/<total>"
+fn parse_ratio(s: &str) -> Option<(u64, u64)> {
+    let mut parts = s.splitn(2, '/');
+    let used = parts.next()?.parse().ok()?;
+    let total = parts.next()?.parse().ok()?;
+    Some((used, total))
+}
+
+/// The mode a thin pool is running in.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ThinPoolMode {
+    /// Working normally.
+    ReadWrite,
+    /// Out of data space. Writes that need new blocks queue or fail,
+    /// depending on error_if_no_space.
+    OutOfDataSpace,
+    /// The metadata could not be changed, so the pool is read only.
+    ReadOnly,
+}
+
+/// The status of a thin-pool target.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ThinPoolStatus {
+    /// The pool's transaction id.
+    pub transaction_id: u64,
+    /// Metadata blocks in use.
+    pub used_metadata_blocks: u64,
+    /// Metadata blocks in all.
+    pub total_metadata_blocks: u64,
+    /// Data blocks in use. Each is the pool's chunk size.
+    pub used_data_blocks: u64,
+    /// Data blocks in all.
+    pub total_data_blocks: u64,
+    /// The metadata root held for userspace to read, if any.
+    pub held_metadata_root: Option<u64>,
+    /// The mode the pool is in.
+    pub mode: ThinPoolMode,
+    /// Whether discards are passed down to the data device.
+    pub discard_passdown: bool,
+    /// Whether writes fail, rather than queue, when out of data space.
+    pub error_if_no_space: bool,
+    /// Whether the metadata has been flagged as needing `thin_check`.
+    pub needs_check: bool,
+}
+
+impl ThinPoolStatus {
+    /// The fraction of data blocks in use, from 0 to 1.
+    pub fn data_ratio(&self) -> f64 {
+        ratio(self.used_data_blocks, self.total_data_blocks)
+    }
+
+    /// The fraction of metadata blocks in use, from 0 to 1.
+    pub fn metadata_ratio(&self) -> f64 {
+        ratio(self.used_metadata_blocks, self.total_metadata_blocks)
+    }
+}
+
+fn ratio(used: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        used as f64 / total as f64
+    }
+}
+
+/// Parse a thin-pool status line: "<transaction id> <used>/<total
+/// metadata blocks> <used>/<total data blocks> <held root> rw|ro|
+/// out_of_data_space [no_]discard_passdown error|queue_if_no_space
+/// needs_check|-". A failed pool reports only "Fail", which is an error
+/// here.
+impl FromStr for ThinPoolStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ThinPoolStatus> {
+        let err = || status_err("thin-pool", s);
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 5 {
+            return Err(err());
+        }
+
+        let transaction_id = fields[0].parse().map_err(|_| err())?;
+        let (used_metadata_blocks, total_metadata_blocks) =
+            parse_ratio(fields[1]).ok_or_else(err)?;
+        let (used_data_blocks, total_data_blocks) = parse_ratio(fields[2]).ok_or_else(err)?;
+        let held_metadata_root = match fields[3] {
+            "-" => None,
+            root => Some(root.parse().map_err(|_| err())?),
+        };
+        let mode = match fields[4] {
+            "rw" => ThinPoolMode::ReadWrite,
+            "out_of_data_space" => ThinPoolMode::OutOfDataSpace,
+            "ro" => ThinPoolMode::ReadOnly,
+            _ => return Err(err()),
+        };
+
+        Ok(ThinPoolStatus {
+            transaction_id,
+            used_metadata_blocks,
+            total_metadata_blocks,
+            used_data_blocks,
+            total_data_blocks,
+            held_metadata_root,
+            mode,
+            discard_passdown: fields.get(5) != Some(&"no_discard_passdown"),
+            error_if_no_space: fields.get(6) == Some(&"error_if_no_space"),
+            needs_check: fields.get(7) == Some(&"needs_check"),
+        })
+    }
+}
+
+/// The health of one device in a RAID array.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RaidDeviceHealth {
+    /// In sync.
+    Alive,
+    /// Not yet in sync, being recovered or reshaped.
+    Syncing,
+    /// Failed.
+    Dead,
+}
+
+/// The status of a raid target.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RaidStatus {
+    /// The RAID type, e.g. "raid1" or "raid5_ls".
+    pub raid_type: String,
+    /// The health of each device, in table order.
+    pub devices: Vec<RaidDeviceHealth>,
+    /// Sectors synced so far.
+    pub sync_current: u64,
+    /// Sectors to sync in all.
+    pub sync_total: u64,
+    /// What the array is doing, e.g. "idle", "resync" or "recover".
+    pub sync_action: Option<String>,
+    /// Discrepancies found by the last "check" or "repair".
+    pub mismatch_count: Option<u64>,
+}
+
+impl RaidStatus {
+    /// The fraction of the array that is in sync, from 0 to 1.
+    pub fn sync_ratio(&self) -> f64 {
+        if self.sync_total == 0 {
+            1.0
+        } else {
+            self.sync_current as f64 / self.sync_total as f64
+        }
+    }
+
+    /// Whether any device has failed.
+    pub fn is_degraded(&self) -> bool {
+        self.devices.contains(&RaidDeviceHealth::Dead)
+    }
+}
+
+/// Parse a raid status line: "<raid type> <#devices> <health chars>
+/// <synced>/<total sectors> [<sync action> <mismatch count> ...]".
+impl FromStr for RaidStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RaidStatus> {
+        let err = || status_err("raid", s);
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(err());
+        }
+
+        let count: usize = fields[1].parse().map_err(|_| err())?;
+        let devices = fields[2]
+            .chars()
+            .map(|c| match c {
+                'A' => Ok(RaidDeviceHealth::Alive),
+                'a' => Ok(RaidDeviceHealth::Syncing),
+                'D' => Ok(RaidDeviceHealth::Dead),
+                _ => Err(err()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if devices.len() != count {
+            return Err(err());
+        }
+        let (sync_current, sync_total) = parse_ratio(fields[3]).ok_or_else(err)?;
+
+        Ok(RaidStatus {
+            raid_type: fields[0].to_string(),
+            devices,
+            sync_current,
+            sync_total,
+            sync_action: fields.get(4).map(|s| s.to_string()),
+            mismatch_count: match fields.get(5) {
+                Some(count) => Some(count.parse().map_err(|_| err())?),
+                None => None,
+            },
+        })
+    }
+}
@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Limits on the I/O of background operations.
+//!
+//! Wiping and discarding can keep a disk busy enough to starve the
+//! applications using it. A `Throttle` caps an operation's rate, and can
+//! lower the I/O priority it runs at, as `ionice` does. Each kind of
+//! operation has its own throttle in `Config::throttles`.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::libc::{c_int, syscall, SYS_ioprio_get, SYS_ioprio_set};
+
+use crate::Result;
+
+const IOPRIO_WHO_PROCESS: c_int = 1;
+const IOPRIO_CLASS_SHIFT: c_int = 13;
+const IOPRIO_CLASS_BE: c_int = 2;
+const IOPRIO_CLASS_IDLE: c_int = 3;
+
+// The most I/O done between checks of the rate, in bytes.
+const MAX_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// An I/O scheduling class and level, as `ionice` sets them.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IoPriority {
+    /// Best effort, at a level from 0, the highest, to 7.
+    BestEffort(u8),
+    /// Only when the disk is otherwise idle.
+    Idle,
+}
+
+impl IoPriority {
+    fn to_raw(self) -> c_int {
+        match self {
+            IoPriority::BestEffort(level) => {
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | c_int::from(level.min(7))
+            }
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        }
+    }
+}
+
+/// Limits on an operation's I/O.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Throttle {
+    /// The most bytes to read, write or discard per second. None, or 0,
+    /// means no limit.
+    pub bytes_per_sec: Option<u64>,
+    /// The I/O priority to run the operation at. If None, it is not
+    /// changed.
+    pub priority: Option<IoPriority>,
+}
+
+/// A `Throttle` for each kind of background operation.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Throttles {
+    /// Wiping signatures and zeroing the start of LVs.
+    pub wipe: Throttle,
+    /// Discarding the unused space of thin LVs.
+    pub discard: Throttle,
+}
+
+// Applies a `Throttle` to an operation while it exists. The calling
+// thread's I/O priority is changed, and restored when it is dropped.
+pub(crate) struct Limiter {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    done: u64,
+    old_priority: Option<c_int>,
+}
+
+impl Limiter {
+    pub(crate) fn new(throttle: &Throttle) -> Result<Limiter> {
+        let old_priority = match throttle.priority {
+            Some(prio) => {
+                let old = Errno::result(unsafe { syscall(SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) })?;
+                Errno::result(unsafe {
+                    syscall(SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio.to_raw())
+                })?;
+                Some(old as c_int)
+            }
+            None => None,
+        };
+
+        Ok(Limiter {
+            bytes_per_sec: throttle.bytes_per_sec.filter(|rate| *rate != 0),
+            start: Instant::now(),
+            done: 0,
+            old_priority,
+        })
+    }
+
+    // How many bytes to do at a time, between calls to `account`.
+    pub(crate) fn chunk_size(&self) -> u64 {
+        match self.bytes_per_sec {
+            Some(rate) => rate.min(MAX_CHUNK),
+            None => u64::MAX,
+        }
+    }
+
+    // Record that `bytes` more were done, and sleep for as long as it
+    // takes to get back under the rate.
+    pub(crate) fn account(&mut self, bytes: u64) {
+        self.done += bytes;
+
+        if let Some(rate) = self.bytes_per_sec {
+            let due = Duration::from_secs_f64(self.done as f64 / rate as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Some(old) = self.old_priority {
+            // Nothing can be done about an error here.
+            unsafe { syscall(SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, old) };
+        }
+    }
+}
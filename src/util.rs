@@ -2,18 +2,38 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::convert::TryFrom;
+use std::io;
+use std::io::ErrorKind::Other;
+
 use crc::crc32;
 use uuid::Uuid;
 
+use crate::{Error, Result};
+
 const INITIAL_CRC: u32 = 0xf597a6cf;
 const CRC_SEED: u32 = 0xedb88320;
 
-pub fn align_to(num: usize, align_to: usize) -> usize {
+// Round `num` up to a multiple of `align_to`, which must be a power of
+// two. Sector and byte offsets on large devices do not fit in a usize
+// on 32-bit systems, so this works in u64.
+pub fn align_to(num: u64, align_to: u64) -> u64 {
     let agn = align_to - 1;
 
     (num + agn) & !agn
 }
 
+// Convert a size or offset in bytes to a usize, to size a buffer or
+// index into one. Fails rather than truncating on 32-bit systems.
+pub fn to_usize(num: u64) -> Result<usize> {
+    usize::try_from(num).map_err(|_| {
+        Error::Io(io::Error::new(
+            Other,
+            format!("{} bytes is too large to hold in memory", num),
+        ))
+    })
+}
+
 pub fn crc32_calc(buf: &[u8]) -> u32 {
     let table = crc32::make_table(CRC_SEED);
 
@@ -42,3 +62,43 @@ pub fn hyphenate_uuid(uuid: &[u8]) -> String {
         String::from_utf8_lossy(&uuid[26..32])
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIB: u64 = 1 << 40;
+
+    #[test]
+    fn align_past_32_bits() {
+        assert_eq!(align_to(0, 8), 0);
+        assert_eq!(align_to(1 << 32, 8), 1 << 32);
+        assert_eq!(align_to((1 << 32) + 1, 8), (1 << 32) + 8);
+        assert_eq!(align_to(100 * TIB - 1, 1 << 20), 100 * TIB);
+        assert_eq!(align_to(100 * TIB + 1, 1 << 20), 100 * TIB + (1 << 20));
+    }
+
+    #[test]
+    fn to_usize_fits() {
+        assert_eq!(to_usize(0).unwrap(), 0);
+        assert_eq!(to_usize(u32::MAX as u64).unwrap(), u32::MAX as usize);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn to_usize_past_32_bits() {
+        assert_eq!(to_usize(1 << 32).unwrap(), 1 << 32);
+        assert_eq!(to_usize(100 * TIB).unwrap(), 100 * TIB as usize);
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn to_usize_past_32_bits() {
+        for num in &[1 << 32, 100 * TIB, u64::MAX] {
+            match to_usize(*num) {
+                Err(Error::Io(e)) => assert!(e.to_string().starts_with(&num.to_string())),
+                other => panic!("{} converted to {:?}", num, other),
+            }
+        }
+    }
+}
@@ -5,30 +5,272 @@
 //! Volume Groups
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
-use std::io;
-use std::io::ErrorKind::Other;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
-use std::str::FromStr;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
-use devicemapper::{
-    DevId, Device, DmFlags, DmName, DmOptions, LinearDev, LinearDevTargetParams,
-    LinearTargetParams, Sectors, TargetLine, DM,
-};
-use nix::sys::utsname::uname;
 use time::now;
 
+use crate::backup;
+use crate::blockdev;
+use crate::capabilities;
+use crate::capabilities::Capabilities;
+use crate::config::Config;
+use crate::device::{Device, DeviceExt};
+use crate::diff::VgDiff;
+use crate::dm;
+use crate::dm::TableLine;
 use crate::lv;
 use crate::lv::segment;
-use crate::lv::LV;
-use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
+use crate::lv::{ActivationName, ScheduleHints, LV};
+use crate::migrate;
+#[cfg(feature = "monitor")]
+use crate::monitor;
+use crate::parser::{
+    buf_to_textmap, status_from_textmap, textmap_to_buf, textmap_to_canonical_buf, Entry,
+    LvmTextMap, TextMapOps,
+};
+use crate::profile::Profile;
 use crate::pv;
 use crate::pv::PV;
-use crate::pvlabel::{PvHeader, SECTOR_SIZE};
-use crate::util::{align_to, make_uuid};
-use crate::{Error, Result};
+use crate::pvlabel::{MdaUsage, PvArea, PvHeader, DEFAULT_MDA_SIZE, SECTOR_SIZE};
+use crate::signature;
+use crate::size::Size;
+use crate::state::VgState;
+use crate::status::ThinPoolStatus;
+use crate::throttle::Limiter;
+use crate::util::{align_to, make_uuid, to_usize};
+use crate::{Error, Flock, LockScope, LvReader, Result};
 
 const DEFAULT_EXTENT_SIZE: u64 = 8192; // 4MiB
+const DEFAULT_CHUNK_SIZE: u64 = 8; // 4KiB
+const DEFAULT_THIN_CHUNK_SIZE: u64 = 128; // 64KiB
+const MIN_THIN_METADATA_SIZE: u64 = 4096; // 2MiB
+
+// The hidden LV kept free to repair thin pool metadata into, as in LVM2.
+const THIN_METADATA_SPARE: &str = "lvol0_pmspare";
+const DEFAULT_CACHE_CHUNK_SIZE: u64 = 128; // 64KiB
+const CACHE_FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Percentage of the smallest MDA that new metadata may use before commit
+// drops optional content.
+const METADATA_COMPACT_THRESHOLD: u64 = 40;
+// Rough sizes in bytes of the metadata text for an LV, and for each
+// further segment, used when there are no LVs to measure.
+const LV_TEXT_SIZE_ESTIMATE: u64 = 512;
+const SEGMENT_TEXT_SIZE_ESTIMATE: u64 = 192;
+
+/// Where new extents may be placed, as with LVM2's `--alloc` option.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AllocPolicy {
+    /// All new extents in one free area. When extending, the area must
+    /// follow on directly from the LV's last segment.
+    Contiguous,
+    /// When extending, only on PVs the LV already uses. For a new LV,
+    /// the same as `Normal`.
+    Cling,
+    /// Anywhere free, in a single area if one is big enough. The
+    /// default.
+    Normal,
+    /// Anywhere free. LVM2 only treats this differently from `Normal`
+    /// for parallel areas, such as mirror images, which Melvin does not
+    /// allocate, so for now the two are the same.
+    Anywhere,
+}
+
+/// Where the extents for a new or extended LV may come from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AllocOptions {
+    /// How the extents are placed.
+    pub policy: AllocPolicy,
+    /// If not empty, only these PVs are used, like naming PVs on the
+    /// `lvcreate` command line.
+    pub allowed_pvs: Vec<Device>,
+}
+
+impl Default for AllocOptions {
+    fn default() -> AllocOptions {
+        AllocOptions {
+            policy: AllocPolicy::Normal,
+            allowed_pvs: Vec::new(),
+        }
+    }
+}
+
+/// A prospective allocation, for `VG::plan_allocation`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AllocRequest {
+    /// Creating a linear LV, as `lv_create_linear_with_options` would.
+    Create {
+        /// The new LV's name.
+        name: String,
+        /// Its size.
+        size: Size,
+        /// Where its extents may come from.
+        options: AllocOptions,
+    },
+    /// Extending an LV, as `lv_extend_with_options` would.
+    Extend {
+        /// The LV's name.
+        name: String,
+        /// How much to add.
+        size: Size,
+        /// Where the new extents may come from.
+        options: AllocOptions,
+    },
+}
+
+/// Where the extents for an `AllocRequest` would be placed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AllocPlan {
+    /// The name of the LV that would be created or extended.
+    pub name: String,
+    /// How many extents would be allocated.
+    pub extents: u64,
+    /// The areas they would come from, in LV order, as (PV, first
+    /// extent, number of extents). Each would become a segment.
+    pub areas: Vec<(Device, u64, u64)>,
+}
+
+/// A run of extents on a PV.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct ExtentRange {
+    /// The first extent.
+    pub start: u64,
+    /// How many extents there are.
+    pub len: u64,
+}
+
+impl ExtentRange {
+    /// The extent just past the end of the range.
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Which extents of each PV in a VG are used and which are free, from
+/// `VG::extent_usage`. Ranges are in order and never overlap or touch:
+/// neighbouring ranges are merged, even when used by different LVs.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExtentUsage {
+    /// The extents holding LV data, by PV. PVs with none are left out.
+    pub used: BTreeMap<Device, Vec<ExtentRange>>,
+    /// The extents that can be allocated, by PV. Every PV is present.
+    /// Extents overlapping a bootloader area are neither used nor free.
+    pub free: BTreeMap<Device, Vec<ExtentRange>>,
+}
+
+// Convert a map of areas, {start: len}, as from `VG::used_areas`, into
+// ranges, merging those that overlap or touch.
+fn merge_areas(areas: &BTreeMap<u64, u64>) -> Vec<ExtentRange> {
+    let mut ranges: Vec<ExtentRange> = Vec::new();
+    for (&start, &len) in areas {
+        match ranges.last_mut() {
+            Some(last) if start <= last.end() => {
+                last.len = last.len.max(start + len - last.start);
+            }
+            _ => ranges.push(ExtentRange { start, len }),
+        }
+    }
+    ranges
+}
+
+/// How the DM devices of an LV are removed, when it is deactivated or
+/// removed.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct DeactivateOptions {
+    /// If set, devices that are still open are removed when they are
+    /// last closed, like `dmsetup remove --deferred`, instead of the
+    /// removal failing with EBUSY. They are not suspended first.
+    pub deferred: bool,
+}
+
+/// Settings for a new thin pool.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ThinPoolOptions {
+    /// If set, writes to thin LVs fail when the pool runs out of data
+    /// space. Otherwise they are queued until the pool is extended.
+    pub error_if_no_space: bool,
+    /// If set, the VG's spare metadata LV, used by `thinpool_repair`, is
+    /// not created or grown to match the new pool.
+    pub skip_metadata_spare: bool,
+    /// The profile to record for the pool. Its settings, and then those
+    /// of the VG's profile, are used in place of the defaults for the
+    /// chunk size, zeroing and discards.
+    pub profile: Option<String>,
+}
+
+/// A function called after each commit with the metadata text that was
+/// written and its seqno, e.g. to copy the metadata to another node.
+pub type CommitHook = Box<dyn Fn(&[u8], u64) -> Result<()> + Send>;
+
+// The registered commit hooks. They are not part of the VG's state, so
+// they are ignored when comparing VGs.
+#[derive(Default)]
+struct CommitHooks(Vec<CommitHook>);
+
+impl fmt::Debug for CommitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommitHooks({})", self.0.len())
+    }
+}
+
+impl PartialEq for CommitHooks {
+    fn eq(&self, _other: &CommitHooks) -> bool {
+        true
+    }
+}
+
+/// The result of discarding unused space in thin LVs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TrimReport {
+    /// How many bytes were discarded.
+    pub trimmed: u64,
+    /// How many bytes of the thin pool's data space were freed.
+    pub reclaimed: u64,
+}
+
+/// An estimate of how much more a VG's metadata can grow before it no
+/// longer fits in its metadata areas.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MetadataHeadroom {
+    /// The size of the smallest metadata area's text area, in bytes.
+    pub capacity: u64,
+    /// The size of the current metadata text, in bytes.
+    pub text_size: u64,
+    /// How many bytes the metadata text can grow by. Each write needs
+    /// room for the previous copy as well as the new one.
+    pub available: u64,
+    /// Roughly how many more LVs fit.
+    pub lvs: u64,
+    /// Roughly how many more segments fit, e.g. when extending LVs.
+    pub segments: u64,
+}
+
+// The state of a VG as of its last write, so that changes made in
+// deferred mode can be undone.
+#[derive(Debug, PartialEq)]
+struct Checkpoint {
+    seqno: u64,
+    status: Vec<String>,
+    flags: Vec<String>,
+    tags: Vec<String>,
+    profile: Option<String>,
+    metadata_copies: u64,
+    metadata_tags: Vec<String>,
+    historical_lvs: Option<LvmTextMap>,
+    write_version: u64,
+    pvs: BTreeMap<Device, PV>,
+    lvs: BTreeMap<String, LV>,
+    // LVs removed since the checkpoint, with how to remove their DM
+    // devices, which is not done until the changes are flushed.
+    removed_lvs: Vec<(LV, DeactivateOptions)>,
+}
 
 /// A Volume Group allows multiple Physical Volumes to be treated as a
 /// storage pool that can then be used to allocate Logical Volumes.
@@ -46,28 +288,71 @@ pub struct VG {
     status: Vec<String>,
     /// Flags.
     flags: Vec<String>,
+    /// Tags.
+    tags: Vec<String>,
     /// Size of each extent, in 512-byte sectors.
     extent_size: u64,
     /// Maximum number of LVs, 0 means no limit.
     max_lv: u64,
     /// Maximum number of PVs, 0 means no limit.
     max_pv: u64,
-    /// How many metadata copies (?)
+    /// How many metadata areas should hold metadata, 0 means all.
     metadata_copies: u64,
+    /// PVs with any of these tags are preferred to hold metadata.
+    metadata_tags: Vec<String>,
+    /// The name of the VG's profile.
+    profile: Option<String>,
     /// Physical Volumes within this volume group.
     pvs: BTreeMap<Device, PV>,
     /// Logical Volumes within this volume group.
     lvs: BTreeMap<String, LV>,
+    /// The version of Melvin's metadata extensions the VG was loaded
+    /// with.
+    metadata_version: u64,
+    /// The version of Melvin's metadata extensions the VG is written as.
+    write_version: u64,
+    /// When the metadata was last written, in seconds since the epoch,
+    /// or 0 if not known.
+    modified_time: i64,
+    /// What the operations that produced the current seqno did.
+    description: String,
+    /// Descriptions of operations whose changes have not been written.
+    pending_ops: Vec<String>,
+    /// Records of removed LVs kept by LVM2, preserved verbatim.
+    historical_lvs: Option<LvmTextMap>,
+    /// Runtime settings.
+    config: Config,
+    /// If set, commits only mark the VG as changed.
+    defer_commit: bool,
+    /// The VG has changes that have not been written to the PVs.
+    dirty: bool,
+    /// Present when changes are being deferred until an explicit flush.
+    checkpoint: Option<Checkpoint>,
+    /// Warnings from operations that went ahead anyway.
+    warnings: Vec<String>,
+    /// PVs whose devices were read-only when the VG was loaded. If there
+    /// are any, the metadata is never written.
+    read_only_pvs: Vec<Device>,
+    /// The DM targets the kernel has, once an activation has needed to
+    /// know.
+    capabilities: Option<Capabilities>,
+    /// Called after each commit.
+    commit_hooks: CommitHooks,
 }
 
 impl VG {
     /// Create a Volume Group from one or more PVs.
     pub fn create(name: &str, pv_paths: Vec<&Path>) -> Result<VG> {
+        Self::create_with_config(name, pv_paths, Config::default())
+    }
+
+    /// Create a Volume Group from one or more PVs, using the given
+    /// runtime settings.
+    pub fn create_with_config(name: &str, pv_paths: Vec<&Path>, config: Config) -> Result<VG> {
         if pv_paths.is_empty() {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                "One or more paths to PVs required",
-            )));
+            return Err(Error::InvalidArgument(
+                "One or more paths to PVs required".to_string(),
+            ));
         }
 
         let pvhs = {
@@ -80,10 +365,9 @@ impl VG {
 
         let metadata_areas = pvhs.iter().map(|x| x.metadata_areas.len()).sum::<usize>();
         if metadata_areas == 0 {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                "PVs must have at least one metadata area",
-            )));
+            return Err(Error::InvalidArgument(
+                "PVs must have at least one metadata area".to_string(),
+            ));
         }
 
         let mut vg = VG {
@@ -97,12 +381,29 @@ impl VG {
                 "RESIZEABLE".to_string(),
             ],
             flags: Vec::new(),
+            tags: Vec::new(),
             extent_size: DEFAULT_EXTENT_SIZE,
             max_lv: 0,
             max_pv: 0,
             metadata_copies: 0,
+            metadata_tags: Vec::new(),
+            profile: None,
             pvs: BTreeMap::new(),
             lvs: BTreeMap::new(),
+            metadata_version: migrate::METADATA_VERSION,
+            write_version: migrate::METADATA_VERSION,
+            modified_time: 0,
+            description: String::new(),
+            pending_ops: Vec::new(),
+            historical_lvs: None,
+            config,
+            defer_commit: false,
+            dirty: false,
+            checkpoint: None,
+            warnings: Vec::new(),
+            read_only_pvs: Vec::new(),
+            capabilities: None,
+            commit_hooks: CommitHooks::default(),
         };
 
         for path in &pv_paths {
@@ -112,9 +413,226 @@ impl VG {
         Ok(vg)
     }
 
-    /// Construct a `VG` from its name and an `LvmTextMap`.
+    /// Convert a device holding data, such as a filesystem, into a new
+    /// VG `name` with the device as its only PV, and an LV `lv_name`
+    /// holding the first `data_size` of the data, without reformatting.
+    ///
+    /// The data must already end short of the end of the device, as
+    /// after shrinking the filesystem: the PV label and first metadata
+    /// area take the place of the start of the data, so the first
+    /// extent's worth is copied to the space after the data first, and
+    /// the LV maps it from there. The rest stays where it is. There must
+    /// be room after the data for that extent and the second metadata
+    /// area.
+    pub fn convert_device(
+        name: &str,
+        lv_name: &str,
+        path: &Path,
+        data_size: impl Into<Size>,
+    ) -> Result<VG> {
+        Self::convert_device_with_config(name, lv_name, path, data_size, Config::default())
+    }
+
+    /// Like `convert_device`, but using the given runtime settings.
+    pub fn convert_device_with_config(
+        name: &str,
+        lv_name: &str,
+        path: &Path,
+        data_size: impl Into<Size>,
+        config: Config,
+    ) -> Result<VG> {
+        if PvHeader::find_in_dev(path).is_ok() {
+            return Err(Error::PvInUse {
+                path: path.to_owned(),
+                vg: None,
+                lv: None,
+            });
+        }
+
+        let extent_bytes = DEFAULT_EXTENT_SIZE * SECTOR_SIZE as u64;
+        let data_extents = match data_size.into() {
+            Size::Bytes(bytes) => bytes.div_ceil(extent_bytes),
+            Size::Extents(extents) => extents,
+            _ => {
+                return Err(Error::InvalidArgument(
+                    "Data size must be in bytes or extents".to_string(),
+                ))
+            }
+        };
+        if data_extents == 0 {
+            return Err(Error::InvalidArgument("No data to convert".to_string()));
+        }
+
+        // The data area starts one extent in, so that every extent after
+        // the first lines up with where its data already is. The first
+        // extent goes in the extent just after the data.
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        let head_offset = data_extents * extent_bytes;
+        if head_offset + extent_bytes + DEFAULT_MDA_SIZE > blockdev::size(&f)? {
+            return Err(Error::InvalidArgument(
+                "Not enough free space after the data".to_string(),
+            ));
+        }
+
+        let mut head = vec![0; to_usize(extent_bytes)?];
+        f.read_exact_at(&mut head, 0)?;
+        f.write_all_at(&head, head_offset)?;
+        f.sync_all()?;
+
+        let mut copy = vec![0; head.len()];
+        f.read_exact_at(&mut copy, head_offset)?;
+        if copy != head {
+            return Err(Error::DataMismatch {
+                path: path.to_owned(),
+                what: "copy of the start of the data",
+            });
+        }
+
+        // Only the copy should be recognized as, say, a filesystem.
+        for sig in signature::find(&head) {
+            f.write_all_at(&vec![0; sig.len as usize], sig.offset)?;
+        }
+        f.sync_all()?;
+        drop(f);
+
+        PvHeader::initialize(path)?;
+        let mut vg = VG::create_with_config(name, vec![path], config)?;
+
+        let dev = Device::from_path(path)?;
+        let pv = &vg.pvs[&dev];
+        if pv.pe_start != DEFAULT_EXTENT_SIZE || pv.pe_count < data_extents {
+            return Err(Error::InvalidArgument(
+                "PV layout does not fit the data".to_string(),
+            ));
+        }
+
+        let mut segments: Vec<Box<dyn segment::Segment>> =
+            vec![Box::new(segment::StripedSegment {
+                start_extent: 0,
+                extent_count: 1,
+                stripes: vec![(dev, data_extents - 1)],
+                stripe_size: None,
+            })];
+        if data_extents > 1 {
+            segments.push(Box::new(segment::StripedSegment {
+                start_extent: 1,
+                extent_count: data_extents - 1,
+                stripes: vec![(dev, 0)],
+                stripe_size: None,
+            }));
+        }
+        let lv = vg.new_lv(lv_name, segments);
+        vg.lvs.insert(lv_name.to_string(), lv);
+
+        vg.commit_op(&format!("convert {} into LV {}", path.display(), lv_name))?;
+
+        Ok(vg)
+    }
+
+    /// Rebuild a VG's metadata from a backup or archive file, like
+    /// LVM2's `vgcfgrestore`, and write it to every metadata area of the
+    /// PVs at `pv_paths`, replacing whatever is there.
+    ///
+    /// Every PV in the file must be among `pv_paths`, and every device
+    /// must be one of them, as told by the UUID in its PV label. The
+    /// devices may have different numbers than the file records.
+    pub fn restore_from_file(path: &Path, pv_paths: Vec<&Path>) -> Result<VG> {
+        Self::restore_from_file_with_config(path, pv_paths, Config::default())
+    }
+
+    /// Like `restore_from_file`, but using the given runtime settings.
+    pub fn restore_from_file_with_config(
+        path: &Path,
+        pv_paths: Vec<&Path>,
+        config: Config,
+    ) -> Result<VG> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let mut map = buf_to_textmap(&buf)?;
+
+        let name = map
+            .iter()
+            .find(|(_, value)| matches!(value, Entry::TextMap(_)))
+            .map(|(key, _)| key.clone())
+            .ok_or_else(|| Error::Metadata(format!("No VG found in {}", path.display())))?;
+
+        let mut pvhs = BTreeMap::new();
+        for pv_path in &pv_paths {
+            let pvh = PvHeader::find_in_dev(pv_path)?;
+            pvhs.insert(pvh.uuid.clone(), pvh);
+        }
+
+        let extent_size = map
+            .textmap_from_textmap(&name)
+            .and_then(|vg| vg.i64_from_textmap("extent_size"))
+            .ok_or_else(|| Error::Metadata(format!("VG {} has no extent size", name)))?
+            as u64;
+        let pv_maps = match map.get_mut(&name) {
+            Some(Entry::TextMap(vg)) => match vg.get_mut("physical_volumes") {
+                Some(Entry::TextMap(pvs)) => pvs,
+                _ => return Err(Error::Metadata(format!("VG {} has no PVs", name))),
+            },
+            _ => unreachable!("found above"),
+        };
+
+        // Point each PV at the device now carrying its label.
+        let mut found = 0;
+        for pv_map in pv_maps.values_mut() {
+            let pv_map = match pv_map {
+                Entry::TextMap(pv_map) => pv_map,
+                _ => continue,
+            };
+            let id = pv_map.string_from_textmap("id").unwrap_or_default();
+            let pvh = pvhs.get(id).ok_or_else(|| {
+                Error::InvalidArgument(format!("PV {} not found among the given devices", id))
+            })?;
+
+            let pe_start = pv_map.i64_from_textmap("pe_start").unwrap_or(0) as u64;
+            let pe_count = pv_map.i64_from_textmap("pe_count").unwrap_or(0) as u64;
+            if (pe_start + pe_count * extent_size) * SECTOR_SIZE as u64 > pvh.size {
+                return Err(Error::InvalidArgument(format!(
+                    "PV {} is smaller than the file records",
+                    id
+                )));
+            }
+
+            let dev = pvh.device()?;
+            pv_map.insert("device".to_string(), Entry::Number(u64::from(dev) as i64));
+            found += 1;
+        }
+        if found != pvhs.len() {
+            return Err(Error::InvalidArgument(
+                "Not every device given is a PV of the VG".to_string(),
+            ));
+        }
+
+        let vg_map = map.textmap_from_textmap(&name).expect("found above");
+        let mut vg = VG::from_textmap_with_config(&name, vg_map, config)?;
+
+        // The metadata areas may be damaged, so they are started afresh.
+        for pvh in pvhs.values() {
+            pvh.clear_metadata()?;
+        }
+        vg.commit_op(&format!("restore from {}", path.display()))?;
+
+        Ok(vg)
+    }
+
+    /// Construct a `VG` from its name and an `LvmTextMap`. The kernel is
+    /// not touched, so all LVs start out inactive, even those with DM
+    /// devices already. Use `lv_activate` to activate them, or to take
+    /// over their existing devices.
     pub fn from_textmap(name: &str, map: &LvmTextMap) -> Result<VG> {
-        let err = || Error::Io(io::Error::new(Other, "vg textmap parsing error"));
+        Self::from_textmap_with_config(name, map, Config::default())
+    }
+
+    /// Like `from_textmap`, but using the given runtime settings.
+    pub fn from_textmap_with_config(name: &str, map: &LvmTextMap, config: Config) -> Result<VG> {
+        let err = || Error::Metadata(format!("VG {} is missing a required value", name));
+
+        let mut map = map.clone();
+        let metadata_version = migrate::upgrade(&mut map)?;
+        let map = &map;
 
         let id = map.string_from_textmap("id").ok_or_else(err)?;
         let seqno = map.i64_from_textmap("seqno").ok_or_else(err)?;
@@ -136,6 +654,32 @@ impl VG {
             })
             .collect();
 
+        // "tags" may be absent
+        let tags: Vec<_> = map
+            .list_from_textmap("tags")
+            .map(|list| {
+                list.iter()
+                    .filter_map(|item| match item {
+                        Entry::String(ref x) => Some(x.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // "melvin_metadata_tags" may be absent
+        let metadata_tags: Vec<_> = map
+            .list_from_textmap("melvin_metadata_tags")
+            .map(|list| {
+                list.iter()
+                    .filter_map(|item| match item {
+                        Entry::String(ref x) => Some(x.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
         // While the textmap uses "pv0"-style names to link physical
         // volume definitions with LV segment stripes, we do not want to
         // use these internally, because what if "pv0" is unused and is
@@ -158,7 +702,12 @@ impl VG {
                         Entry::TextMap(ref pv_dict) => {
                             ret_map.insert(key.to_string(), pv::from_textmap(pv_dict)?);
                         }
-                        _ => return Err(Error::Io(io::Error::new(Other, "expected PV textmap"))),
+                        _ => {
+                            return Err(Error::Metadata(format!(
+                                "PV {} of VG {} is not a section",
+                                key, name
+                            )))
+                        }
                     };
                 }
 
@@ -175,10 +724,15 @@ impl VG {
                         Entry::TextMap(ref lv_dict) => {
                             ret_map.insert(
                                 key.to_string(),
-                                lv::from_textmap(key, name, lv_dict, &str_to_pv)?,
+                                lv::from_textmap(key, lv_dict, &str_to_pv)?,
                             );
                         }
-                        _ => return Err(Error::Io(io::Error::new(Other, "expected LV textmap"))),
+                        _ => {
+                            return Err(Error::Metadata(format!(
+                                "LV {} of VG {} is not a section",
+                                key, name
+                            )))
+                        }
                     }
                 }
 
@@ -192,30 +746,337 @@ impl VG {
             .map(|(_, pv)| (pv.device, pv))
             .collect();
 
-        Ok(VG {
+        let mut vg = VG {
             name: name.to_string(),
             id: id.to_string(),
             seqno: seqno as u64,
             format: format.to_string(),
             status,
             flags,
+            tags,
             extent_size: extent_size as u64,
             max_lv: max_lv as u64,
             max_pv: max_pv as u64,
             metadata_copies: metadata_copies as u64,
+            metadata_tags,
+            profile: map.string_from_textmap("profile").map(|p| p.to_string()),
             pvs,
             lvs,
-        })
+            metadata_version,
+            write_version: migrate::METADATA_VERSION,
+            modified_time: map.i64_from_textmap("melvin_modified_time").unwrap_or(0),
+            description: map
+                .string_from_textmap("melvin_description")
+                .unwrap_or("")
+                .to_string(),
+            pending_ops: Vec::new(),
+            historical_lvs: map
+                .textmap_from_textmap("historical_logical_volumes")
+                .cloned(),
+            config,
+            defer_commit: false,
+            dirty: false,
+            checkpoint: None,
+            warnings: Vec::new(),
+            read_only_pvs: Vec::new(),
+            capabilities: None,
+            commit_hooks: CommitHooks::default(),
+        };
+
+        vg.read_only_pvs = vg
+            .pvs
+            .values()
+            .filter(|pv| {
+                pv.path()
+                    .and_then(|path| File::open(path).ok())
+                    .and_then(|f| blockdev::is_read_only(&f).ok())
+                    .unwrap_or(false)
+            })
+            .map(|pv| pv.device)
+            .collect();
+        if !vg.read_only_pvs.is_empty() {
+            let devs: Vec<_> = vg.read_only_pvs.iter().map(|dev| dev.to_string()).collect();
+            vg.warnings.push(format!(
+                "PVs {} are read-only, so changes to VG {} cannot be written",
+                devs.join(", "),
+                name
+            ));
+        }
+
+        Ok(vg)
+    }
+
+    /// Like `from_textmap_with_config`, but every LV is then activated,
+    /// as by `lv_activate_all`. LVs that fail to activate do not cause an
+    /// error. Instead, each is returned alongside the VG, with why it
+    /// failed.
+    pub fn from_textmap_with_failures(
+        name: &str,
+        map: &LvmTextMap,
+        config: Config,
+    ) -> Result<(VG, Vec<(String, Error)>)> {
+        let mut vg = Self::from_textmap_with_config(name, map, config)?;
+        let failures = vg.lv_activate_all();
+
+        Ok((vg, failures))
+    }
+
+    /// Activate every LV that is not already active, except those with
+    /// segment types Melvin does not support, those with the
+    /// ACTIVATION_SKIP flag, and the spare thin pool metadata LV. LVs
+    /// that fail to activate do not stop the
+    /// others from being tried, and those that succeed are left active.
+    /// Returns each LV that failed, with why.
+    pub fn lv_activate_all(&mut self) -> Vec<(String, Error)> {
+        self.activate_all(false)
+    }
+
+    /// Like `lv_activate_all`, but if `auto_only` is set, LVs that have
+    /// autoactivation turned off are left out as well, as is wanted when
+    /// activating at boot. LVs skipped this way are still activated if
+    /// an LV that is activated is built on them.
+    pub fn activate_all(&mut self, auto_only: bool) -> Vec<(String, Error)> {
+        dm::cached(|| self.activate_all_uncached(auto_only))
+    }
+
+    fn activate_all_uncached(&mut self, auto_only: bool) -> Vec<(String, Error)> {
+        let mut failures = Vec::new();
+
+        for name in self.lv_list() {
+            let lv = &self.lvs[&name];
+            if opaque_segment(lv).is_some()
+                || lv.name == THIN_METADATA_SPARE
+                || lv.activation_skip()
+                || (auto_only && !lv.autoactivate())
+            {
+                continue;
+            }
+            if let Err(e) = self.lv_activate(&name) {
+                failures.push((name, e));
+            }
+        }
+
+        failures
+    }
+
+    /// Load the DM tables of every active LV again from the metadata,
+    /// like `vgchange --refresh`, so that the devices match it after it
+    /// has changed underneath them, such as after a metadata repair or
+    /// once data has been moved. Each device is only suspended while its
+    /// new table is swapped in, so LVs can stay in use throughout. LVs
+    /// are refreshed after the LVs they are built on, and those that fail
+    /// do not stop the others from being tried. Returns each LV that
+    /// failed, with why.
+    pub fn refresh_activations(&self) -> Vec<(String, Error)> {
+        let mut refreshed = BTreeSet::new();
+        let mut failures = Vec::new();
+
+        for name in self.lvs.keys() {
+            self.lv_refresh_with_deps(name, &mut refreshed, &mut failures);
+        }
+
+        failures
+    }
+
+    fn lv_refresh_with_deps(
+        &self,
+        name: &str,
+        refreshed: &mut BTreeSet<String>,
+        failures: &mut Vec<(String, Error)>,
+    ) {
+        if !refreshed.insert(name.to_string()) {
+            return;
+        }
+        let lv = match self.lvs.get(name) {
+            Some(lv) => lv,
+            None => return,
+        };
+
+        for dep in lv.segments.iter().flat_map(|seg| seg.lv_dependencies()) {
+            self.lv_refresh_with_deps(&dep, refreshed, failures);
+        }
+
+        if lv.device.is_some() || !lv.layers.is_empty() {
+            if let Err(e) = self.lv_refresh(name) {
+                failures.push((name.to_string(), e));
+            }
+        }
+    }
+
+    // Load an active LV's tables again, lowest layer first. See
+    // `lv_activate` for what the layers of each kind of LV are.
+    pub(crate) fn lv_refresh(&self, name: &str) -> Result<()> {
+        let lv = &self.lvs[name];
+        let dm_name = self.lv_dm_name(lv);
+        let retry = self.config.retry;
+        let reload = |dm_name: &str, table: &[TableLine]| -> Result<()> {
+            dm::load_table(dm_name, table, &retry)?;
+            dm::suspend_device(dm_name, &retry)?;
+            dm::resume_device(dm_name, &retry)
+        };
+
+        if let Some(tpool) = lv.layers.get("tpool") {
+            reload(&self.lv_dm_layer_name(lv, "tpool"), &self.lv_table(lv)?)?;
+            let table = vec![(
+                0,
+                self.lv_len(name),
+                "linear".to_string(),
+                format!("{} 0", tpool),
+            )];
+            reload(&dm_name, &table)
+        } else if let Some(real) = lv.layers.get("real") {
+            reload(&self.lv_dm_layer_name(lv, "real"), &self.lv_table(lv)?)?;
+            let table = vec![(
+                0,
+                self.lv_len(name),
+                "snapshot-origin".to_string(),
+                real.to_string(),
+            )];
+            reload(&dm_name, &table)
+        } else if lv.layers.contains_key("cow") {
+            reload(&self.lv_dm_layer_name(lv, "cow"), &self.lv_table(lv)?)?;
+            match self.snapshots().into_iter().find(|(_, _, cow)| cow == name) {
+                Some((snap, _, _)) => reload(&dm_name, &self.lv_table(&self.lvs[&snap])?),
+                None => Ok(()),
+            }
+        } else {
+            self.lv_reload(name)
+        }
+    }
+
+    /// Rename the VG, writing the metadata under the new name and
+    /// renaming the DM devices of active LVs to match.
+    pub fn rename(&mut self, new_name: &str) -> Result<()> {
+        if new_name.is_empty() || new_name.contains('/') {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid VG name {}",
+                new_name
+            )));
+        }
+        if self.checkpoint.is_some() {
+            return Err(Error::NotPermitted(
+                "Cannot rename a VG while changes are deferred".to_string(),
+            ));
+        }
+        if new_name == self.name {
+            return Ok(());
+        }
+
+        // Every DM device of every active LV, as (old name, new name).
+        // LVs activated under other names keep them.
+        let mut renames = Vec::new();
+        for lv in self.lvs.values() {
+            if lv.activation_name.is_some() {
+                continue;
+            }
+            if lv.device.is_some() {
+                renames.push((
+                    lv::dm_name(&self.name, &lv.name),
+                    lv::dm_name(new_name, &lv.name),
+                ));
+            }
+            for suffix in lv.layers.keys() {
+                renames.push((
+                    lv::dm_layer_name(&self.name, &lv.name, suffix),
+                    lv::dm_layer_name(new_name, &lv.name, suffix),
+                ));
+            }
+        }
+
+        let retry = self.config.retry;
+        for (idx, (old, new)) in renames.iter().enumerate() {
+            if let Err(e) = dm::rename_device(old, new, &retry) {
+                // Put back the devices already renamed.
+                for (old, new) in renames[..idx].iter().rev() {
+                    let _ = dm::rename_device(new, old, &retry);
+                }
+                return Err(e);
+            }
+        }
+
+        let old_name = std::mem::replace(&mut self.name, new_name.to_string());
+        if let Err(e) = self.commit_op(&format!("rename VG to {}", new_name)) {
+            for (old, new) in renames.iter().rev() {
+                let _ = dm::rename_device(new, old, &retry);
+            }
+            self.name = old_name;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Destroy the VG, by forgetting its metadata on all of its PVs. They
+    /// are left as orphan PVs, unless `wipe_labels` is set, in which case
+    /// their labels are wiped too. Fails if any LV is active.
+    pub fn remove(self, wipe_labels: bool) -> Result<()> {
+        self.check_writable()?;
+        if let Some(lv) = self
+            .lvs
+            .values()
+            .find(|lv| lv.device.is_some() || !lv.layers.is_empty())
+        {
+            return Err(self.lv_active(&lv.name));
+        }
+
+        let mut pvheaders = Vec::new();
+        for pv in self.pvs.values() {
+            let path = pv.path().ok_or(Error::DeviceNotFound(pv.device))?;
+            pvheaders.push(PvHeader::find_in_dev(&path)?);
+        }
+
+        if self.config.wipe_signatures_on_remove {
+            for lv in self.lvs.values() {
+                self.lv_wipe_signatures(lv)?;
+            }
+        }
+
+        for pvheader in &pvheaders {
+            pvheader.clear_metadata()?;
+            if wipe_labels {
+                pvheader.wipe_label()?;
+            }
+        }
+
+        if self.config.backup.backup {
+            backup::remove_backup(&self.config.backup, &self.name)?;
+        }
+        #[cfg(feature = "monitor")]
+        monitor::remove(&self.config.monitor_dir(), &self.id)?;
+
+        Ok(())
     }
 
     /// Add a non-affiliated PV to this VG.
     pub fn pv_add(&mut self, path: &Path) -> Result<()> {
+        self.check_resizeable()?;
+        self.check_writable()?;
+        if !self.config.filter.accepts(path) {
+            return Err(Error::NotPermitted(format!(
+                "{} is rejected by the device filter",
+                path.display()
+            )));
+        }
         let pvh = PvHeader::find_in_dev(path)?;
+        if blockdev::is_read_only(&File::open(path)?)? {
+            return Err(Error::NotPermitted(format!(
+                "{} is read-only",
+                path.display()
+            )));
+        }
+
+        // The size in the pvheader was recorded at initialization time,
+        // make sure the device hasn't shrunk out from under it.
+        if pvh.size > blockdev::size(&File::open(path)?)? {
+            return Err(Error::InvalidArgument(
+                "PV size exceeds block device size".to_string(),
+            ));
+        }
 
         // Check pv is not on an LV from the vg:
         // 1) is pv's major a devicemapper major?
         // 2) Walk dm deps (equiv. of LVM2 dev_manager_device_uses_vg)
-        let dev = Device::from_str(&path.to_string_lossy())?;
+        let dev = Device::from_path(path)?;
         // let dm_majors = dm::dev_majors();
         // if dm_majors.contains(&dev.major) {
         //     let dm = DM::new()?;
@@ -227,7 +1088,11 @@ impl VG {
         // Check to ensure device is not already in VG as this could happen
         // if PV has no MDAs
         if self.pvs.contains_key(&dev) {
-            return Err(Error::Io(io::Error::new(Other, "PV already in VG")));
+            return Err(Error::PvInUse {
+                path: path.to_owned(),
+                vg: Some(self.name.clone()),
+                lv: None,
+            });
         }
 
         // check pv is not already in the VG or another VG
@@ -243,30 +1108,30 @@ impl VG {
                 }
             }
 
-            return Err(Error::Io(io::Error::new(
-                Other,
-                format!("PV already in VG {}", vg_name),
-            )));
+            return Err(Error::PvInUse {
+                path: path.to_owned(),
+                vg: Some(vg_name.into_owned()),
+                lv: None,
+            });
         }
 
         let da = pvh
             .data_areas
             .get(0)
-            .ok_or_else(|| Error::Io(io::Error::new(Other, "Could not find data area in PV")))?;
+            .ok_or_else(|| Error::Metadata("Could not find data area in PV".to_string()))?;
 
-        // figure out how many extents fit in the PV's data area
-        // pe_start aligned to extent size
         let dev_size_sectors = pvh.size / SECTOR_SIZE as u64;
-        let pe_start_sectors = align_to(
-            (da.offset / SECTOR_SIZE as u64) as usize,
-            self.extent_size as usize,
-        ) as u64;
-        let mda1_size_sectors = match pvh.metadata_areas.get(1) {
-            Some(pvarea) => pvarea.size / SECTOR_SIZE as u64,
-            None => 0,
-        };
-        let area_size_sectors = dev_size_sectors - pe_start_sectors - mda1_size_sectors;
-        let pe_count = area_size_sectors / self.extent_size;
+        let (pe_start_sectors, pe_count) = pv_extents(&pvh, da, self.extent_size);
+        let ba = pvh
+            .bootloader_areas
+            .first()
+            .copied()
+            .unwrap_or(PvArea { offset: 0, size: 0 });
+        if pe_count == 0 {
+            return Err(Error::InvalidArgument(
+                "PV too small for any extents".to_string(),
+            ));
+        }
 
         self.pvs.insert(
             dev,
@@ -275,27 +1140,64 @@ impl VG {
                 device: dev,
                 status: vec!["ALLOCATABLE".to_string()],
                 flags: Vec::new(),
+                tags: Vec::new(),
                 dev_size: dev_size_sectors,
                 pe_start: pe_start_sectors,
                 pe_count,
+                ba_start: ba.offset / SECTOR_SIZE as u64,
+                ba_size: ba.size / SECTOR_SIZE as u64,
             },
         );
 
-        self.commit()
+        self.commit_op(&format!("add PV {}", path.display()))
+    }
+
+    /// Grow a PV to use all of its device, after the device has been
+    /// grown. The pvheader is updated on disk, and the new space is
+    /// added to the PV's extents.
+    pub fn pv_resize(&mut self, dev: Device) -> Result<()> {
+        self.check_resizeable()?;
+        let pv = self.pvs.get(&dev).ok_or_else(|| self.pv_not_found(dev))?;
+        let path = pv.path().ok_or(Error::DeviceNotFound(pv.device))?;
+        let pe_start = pv.pe_start;
+
+        let mut pvh = PvHeader::find_in_dev(&path)?;
+        pvh.update_size()?;
+
+        let pe_count = extents_after(&pvh, pe_start, self.extent_size);
+        let used_end = self
+            .used_areas()
+            .get(&dev)
+            .and_then(|areas| areas.iter().map(|(start, len)| start + len).max())
+            .unwrap_or(0);
+        if pe_count < used_end {
+            return Err(Error::InvalidArgument(format!(
+                "PV {} would be smaller than the extents in use",
+                dev
+            )));
+        }
+
+        let pv = self.pvs.get_mut(&dev).expect("PV checked above");
+        pv.dev_size = pvh.size / SECTOR_SIZE as u64;
+        pv.pe_count = pe_count;
+
+        self.commit_op(&format!("resize PV {}", dev))
     }
 
     /// Remove a PV. It must be unused by any LVs.
     pub fn pv_remove(&mut self, pvh: &PvHeader) -> Result<()> {
-        let dev = Device::from_str(&pvh.dev_path.to_string_lossy())?;
+        self.check_resizeable()?;
+        let dev = pvh.device()?;
 
         for (lvname, lv) in &self.lvs {
             for seg in &lv.segments {
                 for seg_dev in seg.pv_dependencies() {
                     if seg_dev == dev {
-                        return Err(Error::Io(io::Error::new(
-                            Other,
-                            format!("PV in use by LV {}", lvname),
-                        )));
+                        return Err(Error::PvInUse {
+                            path: pvh.dev_path.clone(),
+                            vg: Some(self.name.clone()),
+                            lv: Some(lvname.clone()),
+                        });
                     }
                 }
             }
@@ -303,108 +1205,2584 @@ impl VG {
 
         self.pvs
             .remove(&dev)
-            .ok_or_else(|| Error::Io(io::Error::new(Other, "Could not remove PV")))?;
+            .ok_or_else(|| self.pv_not_found(dev))?;
 
-        self.commit()
+        self.commit_op(&format!("remove PV {}", dev))
     }
 
-    /// Create a new linear logical volume in the volume group.
-    pub fn lv_create_linear(&mut self, name: &str, extent_size: u64) -> Result<()> {
-        if self.lvs.contains_key(name) {
-            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+    // Find a free contiguous area for a new linear segment.
+    fn alloc_linear(&self, extent_size: u64) -> Result<segment::StripedSegment> {
+        let mut contig_area = None;
+        for (dev, areas) in self.free_areas() {
+            for (start, len) in areas {
+                if len >= extent_size {
+                    contig_area = Some((dev, start));
+                    break;
+                }
+            }
+        }
+
+        match contig_area {
+            Some((dev, area_start)) => {
+                self.check_not_reserved(dev, area_start, extent_size)?;
+                Ok(segment::StripedSegment {
+                    start_extent: 0,
+                    extent_count: extent_size,
+                    stripes: vec![(dev, area_start)],
+                    stripe_size: None,
+                })
+            }
+            None => Err(self.alloc_err(extent_size, true)),
+        }
+    }
+
+    // Find areas within `free_areas` adding up to `extents`, as (device,
+    // start, length). A single area is used if one is big enough.
+    fn alloc_areas_from(
+        &self,
+        free_areas: BTreeMap<Device, BTreeMap<u64, u64>>,
+        extents: u64,
+    ) -> Result<Vec<(Device, u64, u64)>> {
+        let mut found = Vec::new();
+        for (dev, areas) in &free_areas {
+            for (start, len) in areas {
+                if *len >= extents {
+                    found.push((*dev, *start, extents));
+                    break;
+                }
+            }
+            if !found.is_empty() {
+                break;
+            }
         }
 
-        let (dev, area_start, len) = {
-            let mut contig_area = None;
-            for (dev, areas) in self.free_areas() {
+        if found.is_empty() {
+            let mut needed = extents;
+            for (dev, areas) in free_areas {
                 for (start, len) in areas {
-                    if len >= extent_size {
-                        contig_area = Some((dev, start, len));
+                    if needed == 0 {
                         break;
                     }
+                    let len = len.min(needed);
+                    found.push((dev, start, len));
+                    needed -= len;
                 }
             }
 
-            if let Some(contig) = contig_area {
-                contig
-            } else {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    "no contiguous area for new LV",
-                )));
+            if needed != 0 {
+                return Err(self.alloc_err(extents, false));
             }
-        };
+        }
 
-        let segment = Box::new(segment::StripedSegment {
-            start_extent: 0,
-            extent_count: extent_size,
-            stripes: vec![(dev, area_start)],
-            stripe_size: None,
-        });
+        Ok(found)
+    }
 
-        let lv_name = format!(
-            "{}-{}",
-            self.name.replace("-", "--"),
-            name.replace("-", "--")
-        );
+    // Find free areas adding up to `extents` for the LV `name`, or for a
+    // new LV if it is None, placed according to `options`.
+    fn alloc_with_options(
+        &self,
+        name: Option<&str>,
+        extents: u64,
+        options: &AllocOptions,
+    ) -> Result<Vec<(Device, u64, u64)>> {
+        let used = name
+            .and_then(|name| self.lvs.get(name))
+            .map(lv::used_areas)
+            .unwrap_or_default();
 
-        let params = LinearTargetParams::new(Device::from(u64::from(dev)), Sectors(area_start));
-        let table = vec![TargetLine::new(
-            Sectors(0),
-            Sectors(len),
-            LinearDevTargetParams::Linear(params),
-        )];
+        let areas = self.alloc_from(self.free_areas(), &used, extents, options)?;
+        self.check_areas_not_reserved(&areas)?;
+        Ok(areas)
+    }
 
-        // poke dm and tell it about a new device
-        let dm = DM::new()?;
-        let new_linear = LinearDev::setup(
-            &dm,
-            DmName::new(&lv_name).expect("valid format"),
-            None,
-            table,
-        )?;
+    // Find areas within `free_areas` adding up to `extents`, for an LV
+    // that already has the areas `used`, placed according to `options`.
+    // The areas are only as free of the PVs' reserved areas as
+    // `free_areas` is; `check_areas_not_reserved` checks them on disk.
+    fn alloc_from(
+        &self,
+        free_areas: BTreeMap<Device, BTreeMap<u64, u64>>,
+        used: &[(Device, u64, u64)],
+        extents: u64,
+        options: &AllocOptions,
+    ) -> Result<Vec<(Device, u64, u64)>> {
+        if let Some(dev) = options
+            .allowed_pvs
+            .iter()
+            .find(|dev| !self.pvs.contains_key(dev))
+        {
+            return Err(self.pv_not_found(*dev));
+        }
 
-        let lv = LV {
-            name: name.to_string(),
-            id: make_uuid(),
-            status: vec![
-                "READ".to_string(),
-                "WRITE".to_string(),
-                "VISIBLE".to_string(),
-            ],
-            flags: Vec::new(),
-            creation_host: uname().nodename().to_string(),
-            creation_time: now().to_timespec().sec,
-            segments: vec![segment],
-            device: new_linear,
-        };
+        let free_areas: BTreeMap<_, _> = free_areas
+            .into_iter()
+            .filter(|(dev, _)| options.allowed_pvs.is_empty() || options.allowed_pvs.contains(dev))
+            .collect();
+
+        match options.policy {
+            AllocPolicy::Normal | AllocPolicy::Anywhere => {
+                self.alloc_areas_from(free_areas, extents)
+            }
+            AllocPolicy::Cling if used.is_empty() => self.alloc_areas_from(free_areas, extents),
+            AllocPolicy::Cling => {
+                let devs: BTreeSet<_> = used.iter().map(|(dev, _, _)| *dev).collect();
+                let free_areas = free_areas
+                    .into_iter()
+                    .filter(|(dev, _)| devs.contains(dev))
+                    .collect();
+                self.alloc_areas_from(free_areas, extents)
+            }
+            AllocPolicy::Contiguous => {
+                let found = match used.last() {
+                    Some(&(dev, start, len)) => free_areas
+                        .get(&dev)
+                        .and_then(|areas| areas.get(&(start + len)))
+                        .filter(|free_len| **free_len >= extents)
+                        .map(|_| (dev, start + len)),
+                    None => free_areas.iter().find_map(|(dev, areas)| {
+                        areas
+                            .iter()
+                            .find(|(_, len)| **len >= extents)
+                            .map(|(start, _)| (*dev, *start))
+                    }),
+                };
+
+                let (dev, start) = found.ok_or_else(|| self.alloc_err(extents, true))?;
+                Ok(vec![(dev, start, extents)])
+            }
+        }
+    }
+
+    // Make sure a range of extents on a PV does not overlap the PV's
+    // label, metadata areas or bootloader areas. pv_add places extents
+    // so they never should, but metadata written by other tools may
+    // not have.
+    fn check_not_reserved(&self, dev: Device, start: u64, len: u64) -> Result<()> {
+        let pv = self.pvs.get(&dev).ok_or_else(|| self.pv_not_found(dev))?;
+        let path = pv.path().ok_or(Error::DeviceNotFound(pv.device))?;
+        let reserved = PvHeader::find_in_dev(&path)?.reserved_areas();
+
+        if overlaps_reserved(pv, &reserved, self.extent_size, start, len) {
+            return Err(Error::Metadata(format!(
+                "Extents {}..{} on PV {} overlap a reserved area",
+                start,
+                start + len,
+                dev
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Like `check_not_reserved`, for each of `areas`, as (device, start,
+    // length).
+    fn check_areas_not_reserved(&self, areas: &[(Device, u64, u64)]) -> Result<()> {
+        for (dev, start, len) in areas {
+            self.check_not_reserved(*dev, *start, *len)?;
+        }
+
+        Ok(())
+    }
+
+    fn new_lv(&self, name: &str, segments: Vec<Box<dyn segment::Segment>>) -> LV {
+        LV {
+            name: name.to_string(),
+            id: make_uuid(),
+            status: vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "VISIBLE".to_string(),
+            ],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: self.config.creation_host(),
+            creation_time: now().to_timespec().sec,
+            schedule: ScheduleHints::default(),
+            profile: None,
+            segments,
+            device: None,
+            layers: BTreeMap::new(),
+            activation_name: None,
+        }
+    }
+
+    /// Set the tags of a PV.
+    pub fn pv_set_tags(&mut self, dev: Device, tags: Vec<String>) -> Result<()> {
+        for tag in &tags {
+            check_tag(tag)?;
+        }
+        let pv = match self.pvs.get_mut(&dev) {
+            Some(pv) => pv,
+            None => return Err(self.pv_not_found(dev)),
+        };
+        pv.tags = tags;
+
+        self.commit_op(&format!("set tags on PV {}", dev))
+    }
+
+    /// Add a tag to a PV.
+    pub fn pv_add_tag(&mut self, dev: Device, tag: &str) -> Result<()> {
+        check_tag(tag)?;
+        let pv = match self.pvs.get_mut(&dev) {
+            Some(pv) => pv,
+            None => return Err(self.pv_not_found(dev)),
+        };
+        if add_tag(&mut pv.tags, tag) {
+            self.commit_op(&format!("add tag {} to PV {}", tag, dev))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a tag from a PV.
+    pub fn pv_del_tag(&mut self, dev: Device, tag: &str) -> Result<()> {
+        let pv = match self.pvs.get_mut(&dev) {
+            Some(pv) => pv,
+            None => return Err(self.pv_not_found(dev)),
+        };
+        if del_tag(&mut pv.tags, tag) {
+            self.commit_op(&format!("remove tag {} from PV {}", tag, dev))?;
+        }
+        Ok(())
+    }
+
+    /// Whether PVs may be added to or removed from the VG, and its LVs
+    /// resized.
+    pub fn is_resizeable(&self) -> bool {
+        self.status.iter().any(|s| s == "RESIZEABLE")
+    }
+
+    /// Allow or forbid adding and removing PVs and resizing LVs, like
+    /// `vgchange -x`.
+    pub fn set_resizeable(&mut self, resizeable: bool) -> Result<()> {
+        if self.is_resizeable() == resizeable {
+            return Ok(());
+        }
+
+        if resizeable {
+            // After READ and WRITE, where LVM2 puts it.
+            let idx = self
+                .status
+                .iter()
+                .rposition(|s| s == "READ" || s == "WRITE")
+                .map_or(0, |i| i + 1);
+            self.status.insert(idx, "RESIZEABLE".to_string());
+        } else {
+            self.status.retain(|s| s != "RESIZEABLE");
+        }
+
+        self.commit_op(if resizeable {
+            "make VG resizeable"
+        } else {
+            "make VG not resizeable"
+        })
+    }
+
+    /// The PVs whose devices were read-only when the VG was loaded.
+    pub fn read_only_pvs(&self) -> &[Device] {
+        &self.read_only_pvs
+    }
+
+    /// Whether the VG's metadata cannot be written, because some of its
+    /// PVs are read-only. LVs can still be activated, but every change
+    /// to the VG fails when it comes to write the metadata, before any
+    /// PV is written. The VG should then be reloaded, as it keeps the
+    /// change in memory.
+    pub fn is_read_only(&self) -> bool {
+        !self.read_only_pvs.is_empty()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only_pvs.is_empty() {
+            return Ok(());
+        }
+
+        let devs: Vec<_> = self
+            .read_only_pvs
+            .iter()
+            .map(|dev| dev.to_string())
+            .collect();
+        Err(Error::NotPermitted(format!(
+            "VG has read-only PVs: {}",
+            devs.join(", ")
+        )))
+    }
+
+    fn check_resizeable(&self) -> Result<()> {
+        if self.is_resizeable() {
+            Ok(())
+        } else {
+            Err(Error::NotPermitted("VG is not resizeable".to_string()))
+        }
+    }
+
+    fn lv_not_found(&self, name: &str) -> Error {
+        Error::LvNotFound {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+        }
+    }
+
+    fn lv_exists(&self, name: &str) -> Error {
+        Error::LvExists {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+        }
+    }
+
+    fn lv_active(&self, name: &str) -> Error {
+        Error::LvActive {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+        }
+    }
+
+    fn lv_not_active(&self, name: &str) -> Error {
+        Error::LvNotActive {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+        }
+    }
+
+    fn lv_in_use(&self, name: &str, user: &str) -> Error {
+        Error::LvInUse {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+            user: user.to_string(),
+        }
+    }
+
+    fn lv_type_err(&self, name: &str, expected: &'static str) -> Error {
+        Error::InvalidLvType {
+            vg: self.name.clone(),
+            lv: name.to_string(),
+            expected,
+        }
+    }
+
+    fn alloc_err(&self, extents: u64, contiguous: bool) -> Error {
+        Error::Alloc {
+            vg: self.name.clone(),
+            extents,
+            contiguous,
+        }
+    }
+
+    fn pv_not_found(&self, dev: Device) -> Error {
+        Error::PvNotFound {
+            vg: self.name.clone(),
+            device: dev,
+        }
+    }
+
+    /// Add a tag to the VG.
+    pub fn add_tag(&mut self, tag: &str) -> Result<()> {
+        check_tag(tag)?;
+        if add_tag(&mut self.tags, tag) {
+            self.commit_op(&format!("add tag {} to VG", tag))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a tag from the VG.
+    pub fn del_tag(&mut self, tag: &str) -> Result<()> {
+        if del_tag(&mut self.tags, tag) {
+            self.commit_op(&format!("remove tag {} from VG", tag))?;
+        }
+        Ok(())
+    }
+
+    /// Add a tag to an LV.
+    pub fn lv_add_tag(&mut self, name: &str, tag: &str) -> Result<()> {
+        check_tag(tag)?;
+        let lv = match self.lvs.get_mut(name) {
+            Some(lv) => lv,
+            None => return Err(self.lv_not_found(name)),
+        };
+        if add_tag(&mut lv.tags, tag) {
+            self.commit_op(&format!("add tag {} to LV {}", tag, name))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a tag from an LV.
+    pub fn lv_del_tag(&mut self, name: &str, tag: &str) -> Result<()> {
+        let lv = match self.lvs.get_mut(name) {
+            Some(lv) => lv,
+            None => return Err(self.lv_not_found(name)),
+        };
+        if del_tag(&mut lv.tags, tag) {
+            self.commit_op(&format!("remove tag {} from LV {}", tag, name))?;
+        }
+        Ok(())
+    }
+
+    /// Name the VG's profile, or with None, stop using one. The profile
+    /// must exist in `Config::profile_dir`. Its settings apply to
+    /// objects created afterwards.
+    pub fn set_profile(&mut self, profile: Option<&str>) -> Result<()> {
+        if let Some(name) = profile {
+            Profile::load(&self.config.profile_dir(), name)?;
+        }
+        if self.profile.as_deref() == profile {
+            return Ok(());
+        }
+        self.profile = profile.map(|p| p.to_string());
+
+        match profile {
+            Some(name) => self.commit_op(&format!("set profile {} for VG", name)),
+            None => self.commit_op("detach profile from VG"),
+        }
+    }
+
+    /// Name the profile of an LV, or with None, stop using one, as for
+    /// `set_profile`.
+    pub fn lv_set_profile(&mut self, name: &str, profile: Option<&str>) -> Result<()> {
+        if let Some(profile_name) = profile {
+            Profile::load(&self.config.profile_dir(), profile_name)?;
+        }
+        let lv = match self.lvs.get_mut(name) {
+            Some(lv) => lv,
+            None => return Err(self.lv_not_found(name)),
+        };
+        if lv.profile.as_deref() == profile {
+            return Ok(());
+        }
+        lv.profile = profile.map(|p| p.to_string());
+
+        match profile {
+            Some(profile_name) => {
+                self.commit_op(&format!("set profile {} for LV {}", profile_name, name))
+            }
+            None => self.commit_op(&format!("detach profile from LV {}", name)),
+        }
+    }
+
+    // The settings of the profile an LV names, falling back to those of
+    // the VG's profile.
+    fn profile_settings(&self, lv_profile: Option<&str>) -> Result<Profile> {
+        let dir = self.config.profile_dir();
+        let load = |name: Option<&str>| match name {
+            Some(name) => Profile::load(&dir, name),
+            None => Ok(Profile::default()),
+        };
+
+        Ok(load(lv_profile)?.or(load(self.profile.as_deref())?))
+    }
+
+    /// Set how many metadata areas across the VG's PVs hold metadata,
+    /// with 0 meaning all of them. The rest are ignored. MDAs on PVs with
+    /// any of `preferred_tags` are used first, for example to keep
+    /// metadata on SSDs. Which MDAs are used is revisited on every
+    /// commit, so it follows PVs being added, removed, or retagged.
+    pub fn set_metadata_copies(&mut self, copies: u64, preferred_tags: Vec<String>) -> Result<()> {
+        self.metadata_copies = copies;
+        self.metadata_tags = preferred_tags;
+
+        self.commit_op(&format!("set metadata copies to {}", copies))
+    }
+
+    // Ignore or use each MDA so that metadata_copies of them are in use.
+    // Preferred PVs come first, then the first MDA of each PV, so copies
+    // are spread across PVs.
+    fn balance_metadata(&self) -> Result<()> {
+        let mut mdas = Vec::new();
+        for pv in self.pvs.values() {
+            if let Some(path) = pv.path() {
+                let preferred = pv.tags.iter().any(|tag| self.metadata_tags.contains(tag));
+                let pvheader = PvHeader::find_in_dev(&path)?;
+                for (idx, info) in pvheader.mda_info()?.iter().enumerate() {
+                    let ignored = matches!(info.rlocn, Some(ref rl) if rl.ignored);
+                    mdas.push(((!preferred, idx, pv.device), path.clone(), ignored));
+                }
+            }
+        }
+        mdas.sort_by_key(|mda| mda.0);
+
+        let copies = match self.metadata_copies {
+            0 => mdas.len(),
+            x => x as usize,
+        };
+
+        for (num, ((_, idx, _), path, ignored)) in mdas.into_iter().enumerate() {
+            let ignore = num >= copies;
+            if ignore != ignored {
+                PvHeader::find_in_dev(&path)?.set_mda_ignored(idx, ignore)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // An LV that is only used as part of another, and so is not listed
+    // by LVM2.
+    fn new_hidden_lv(&self, name: &str, segments: Vec<Box<dyn segment::Segment>>) -> LV {
+        let mut lv = self.new_lv(name, segments);
+        lv.status = vec!["READ".to_string(), "WRITE".to_string()];
+        lv
+    }
+
+    /// Create a new linear logical volume in the volume group.
+    pub fn lv_create_linear(&mut self, name: &str, size: impl Into<Size>) -> Result<()> {
+        self.lv_create_linear_with_options(name, size, &AllocOptions::default())
+    }
+
+    /// Create a new linear logical volume in the volume group, with its
+    /// extents placed according to `options`.
+    pub fn lv_create_linear_with_options(
+        &mut self,
+        name: &str,
+        size: impl Into<Size>,
+        options: &AllocOptions,
+    ) -> Result<()> {
+        let extent_size = self.size_to_extents(size.into())?;
+        if self.lvs.contains_key(name) {
+            return Err(self.lv_exists(name));
+        }
+
+        let areas = self.alloc_with_options(None, extent_size, options)?;
+        self.check_metadata_space(1, (areas.len() as u64).saturating_sub(1))?;
+
+        let mut start_extent = 0;
+        let mut segments: Vec<Box<dyn segment::Segment>> = Vec::new();
+        for (dev, start, len) in areas {
+            segments.push(Box::new(segment::StripedSegment {
+                start_extent,
+                extent_count: len,
+                stripes: vec![(dev, start)],
+                stripe_size: None,
+            }));
+            start_extent += len;
+        }
+        let lv = self.new_lv(name, segments);
+        self.lvs.insert(name.to_string(), lv);
+
+        self.commit_op(&format!("create LV {}", name))
+    }
+
+    /// Create a snapshot of the active LV `origin`. `size` is allocated
+    /// for the LV `name`, to store the chunks that change
+    /// in either the origin or the snapshot after it is taken. Unlike
+    /// other new LVs, the snapshot is active, since it must be in place
+    /// from the moment it is taken.
+    pub fn lv_create_snapshot(
+        &mut self,
+        origin: &str,
+        name: &str,
+        size: impl Into<Size>,
+    ) -> Result<()> {
+        let extent_size = self.size_to_extents(size.into())?;
+        if self.lvs.contains_key(name) {
+            return Err(self.lv_exists(name));
+        }
+
+        let origin_extents = match self.lvs.get(origin) {
+            Some(lv) if lv.device.is_some() => lv.used_extents(),
+            Some(_) => return Err(self.lv_not_active(origin)),
+            None => return Err(self.lv_not_found(origin)),
+        };
+        if self
+            .snapshots()
+            .iter()
+            .any(|(snap, _, cow)| snap == origin || cow == origin)
+        {
+            return Err(Error::Unsupported(
+                "Snapshots of snapshots are not supported".to_string(),
+            ));
+        }
+
+        self.check_metadata_space(2, 0)?;
+
+        let cow_segment = self.alloc_linear(extent_size)?;
+        let cow_lv = self.new_lv(name, vec![Box::new(cow_segment)]);
+
+        let snap_name = (0..)
+            .map(|n| format!("snapshot{}", n))
+            .find(|n| !self.lvs.contains_key(n))
+            .expect("some name is free");
+        let snap_lv = self.new_hidden_lv(
+            &snap_name,
+            vec![Box::new(segment::SnapshotSegment {
+                start_extent: 0,
+                extent_count: origin_extents,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                origin: origin.to_string(),
+                cow_store: name.to_string(),
+            })],
+        );
+
+        self.lvs.insert(name.to_string(), cow_lv);
+        self.lvs.insert(snap_name.clone(), snap_lv);
+
+        // A stale header at the start of the COW store would be taken
+        // as an existing snapshot.
+        let mut res = self.lv_zero_start(name, DEFAULT_CHUNK_SIZE);
+
+        if res.is_ok() {
+            let origin_dm_name = self.lv_dm_name(&self.lvs[origin]);
+            let retry = self.config.retry;
+
+            res = dm::suspend_device(&origin_dm_name, &retry);
+            if res.is_ok() {
+                res = self
+                    .lv_stack_origin(origin)
+                    .and_then(|_| self.lv_activate(name));
+                let resumed = dm::resume_device(&origin_dm_name, &retry);
+                res = res.and(resumed);
+            }
+        }
+
+        if let Err(e) = res {
+            if let Some(lv) = self.lvs.remove(name) {
+                let _ = self.lv_teardown(&lv, &DeactivateOptions::default());
+            }
+            if let Some(lv) = self.lvs.remove(&snap_name) {
+                let _ = self.lv_teardown(&lv, &DeactivateOptions::default());
+            }
+            return Err(e);
+        }
+
+        self.commit_op(&format!("create snapshot {} of LV {}", name, origin))
+    }
+
+    /// Create a thin pool with `size` for data. Space for
+    /// the pool's metadata is allocated as well, in a hidden LV.
+    pub fn lv_create_thinpool(&mut self, name: &str, size: impl Into<Size>) -> Result<()> {
+        self.lv_create_thinpool_with_options(name, size, &ThinPoolOptions::default())
+    }
+
+    /// Create a thin pool with `size` for data, set up according to
+    /// `options`.
+    pub fn lv_create_thinpool_with_options(
+        &mut self,
+        name: &str,
+        size: impl Into<Size>,
+        options: &ThinPoolOptions,
+    ) -> Result<()> {
+        let extent_size = self.size_to_extents(size.into())?;
+        let tmeta = format!("{}_tmeta", name);
+        let tdata = format!("{}_tdata", name);
+        for lv_name in &[name, &tmeta, &tdata] {
+            if self.lvs.contains_key(*lv_name) {
+                return Err(self.lv_exists(lv_name));
+            }
+        }
+
+        let profile = self.profile_settings(options.profile.as_deref())?;
+        let chunk_size = profile
+            .thin_pool_chunk_size
+            .unwrap_or(DEFAULT_THIN_CHUNK_SIZE);
+
+        // The kernel needs 64 bytes of metadata per data chunk, but uses
+        // no more than its maximum.
+        let chunks = extent_size * self.extent_size / chunk_size;
+        let max_meta_sectors = capabilities::kernel_limits()
+            .unwrap_or_default()
+            .max_thin_metadata_sectors;
+        let meta_sectors = (chunks * 64 / SECTOR_SIZE as u64)
+            .max(MIN_THIN_METADATA_SIZE)
+            .min(max_meta_sectors);
+        let meta_extents = meta_sectors.div_ceil(self.extent_size);
+
+        self.check_metadata_space(4, 0)?;
+
+        let data_segment = self.alloc_linear(extent_size)?;
+        let data_lv = self.new_hidden_lv(&tdata, vec![Box::new(data_segment)]);
+        self.lvs.insert(tdata.clone(), data_lv);
+
+        let meta_segment = match self.alloc_linear(meta_extents) {
+            Ok(seg) => seg,
+            Err(e) => {
+                self.lvs.remove(&tdata);
+                return Err(e);
+            }
+        };
+        let meta_lv = self.new_hidden_lv(&tmeta, vec![Box::new(meta_segment)]);
+        self.lvs.insert(tmeta.clone(), meta_lv);
+
+        let mut pool_lv = self.new_lv(
+            name,
+            vec![Box::new(segment::ThinPoolSegment {
+                start_extent: 0,
+                extent_count: extent_size,
+                metadata: tmeta.clone(),
+                pool: tdata.clone(),
+                transaction_id: 0,
+                chunk_size,
+                discards: profile
+                    .thin_pool_discards
+                    .unwrap_or_else(|| "passdown".to_string()),
+                zero_new_blocks: profile.thin_pool_zero.unwrap_or(true),
+                error_if_no_space: options.error_if_no_space,
+            })],
+        );
+        pool_lv.profile = options.profile.clone();
+        self.lvs.insert(name.to_string(), pool_lv);
+
+        // The kernel formats the metadata when the pool is first
+        // activated, if its superblock is blank.
+        if let Err(e) = self.lv_zero_start(&tmeta, DEFAULT_CHUNK_SIZE) {
+            for lv_name in &[name, &tmeta, &tdata] {
+                self.lvs.remove(*lv_name);
+            }
+            return Err(e);
+        }
+
+        // Like LVM2, a pool without a spare is only warned about.
+        if !options.skip_metadata_spare {
+            if let Err(e) = self.ensure_thin_metadata_spare() {
+                self.warnings.push(format!(
+                    "could not create spare metadata LV for thin pool {}, so it \
+                     cannot be repaired automatically: {:?}",
+                    name, e
+                ));
+            }
+        }
+
+        self.commit_op(&format!("create thin pool {}", name))
+    }
+
+    // Make sure the spare metadata LV exists and is as large as the
+    // largest thin pool metadata LV in the VG. A spare that is too small
+    // holds nothing of value, so it is allocated again.
+    fn ensure_thin_metadata_spare(&mut self) -> Result<()> {
+        let needed = self
+            .lvs
+            .values()
+            .flat_map(|lv| lv.segments.iter())
+            .filter_map(|seg| seg.as_any().downcast_ref::<segment::ThinPoolSegment>())
+            .filter_map(|seg| self.lvs.get(&seg.metadata))
+            .map(|tmeta| tmeta.used_extents())
+            .max()
+            .unwrap_or(0);
+
+        let old = match self.lvs.get(THIN_METADATA_SPARE) {
+            Some(spare) if spare.used_extents() >= needed => return Ok(()),
+            _ => self.lvs.remove(THIN_METADATA_SPARE),
+        };
+        if needed == 0 {
+            return Ok(());
+        }
+
+        match self.alloc_linear(needed) {
+            Ok(seg) => {
+                let spare = self.new_hidden_lv(THIN_METADATA_SPARE, vec![Box::new(seg)]);
+                self.lvs.insert(THIN_METADATA_SPARE.to_string(), spare);
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(old) = old {
+                    self.lvs.insert(THIN_METADATA_SPARE.to_string(), old);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Create a thin LV of `size` in the thin pool
+    /// `pool_name`, which must be active. Extents are only allocated from
+    /// the pool as the thin LV is written.
+    pub fn lv_create_thin(
+        &mut self,
+        pool_name: &str,
+        name: &str,
+        size: impl Into<Size>,
+    ) -> Result<()> {
+        let extent_size = self.size_to_extents(size.into())?;
+        if self.lvs.contains_key(name) {
+            return Err(self.lv_exists(name));
+        }
+
+        match self.lvs.get(pool_name) {
+            Some(lv) if lv.device.is_some() => {}
+            Some(_) => return Err(self.lv_not_active(pool_name)),
+            None => return Err(self.lv_not_found(pool_name)),
+        }
+
+        self.check_metadata_space(1, 0)?;
+
+        let device_id = self.next_thin_device_id(pool_name);
+        self.thin_pool_message(pool_name, &format!("create_thin {}", device_id))?;
+        let transaction_id = self
+            .thin_pool_segment_mut(pool_name)
+            .expect("checked by thin_pool_message")
+            .transaction_id;
+
+        let lv = self.new_lv(
+            name,
+            vec![Box::new(segment::ThinSegment {
+                start_extent: 0,
+                extent_count: extent_size,
+                thin_pool: pool_name.to_string(),
+                transaction_id,
+                device_id,
+                origin: None,
+            })],
+        );
+        self.lvs.insert(name.to_string(), lv);
+
+        self.commit_op(&format!("create thin LV {} in pool {}", name, pool_name))
+    }
+
+    /// Create a thin LV in the thin pool `pool_name` that is a snapshot
+    /// of `origin`, a thin LV in the same pool. It starts out sharing
+    /// all of the origin's blocks, so takes no space from the pool until
+    /// one of them is written. The pool must be active. If the origin is
+    /// active, it is suspended while the snapshot is taken.
+    pub fn lv_create_thin_snapshot(
+        &mut self,
+        pool_name: &str,
+        origin: &str,
+        name: &str,
+    ) -> Result<()> {
+        if self.lvs.contains_key(name) {
+            return Err(self.lv_exists(name));
+        }
+
+        match self.lvs.get(pool_name) {
+            Some(lv) if lv.device.is_some() => {}
+            Some(_) => return Err(self.lv_not_active(pool_name)),
+            None => return Err(self.lv_not_found(pool_name)),
+        }
+
+        let origin_lv = self
+            .lvs
+            .get(origin)
+            .ok_or_else(|| self.lv_not_found(origin))?;
+        let origin_id = origin_lv
+            .segments
+            .iter()
+            .filter_map(|seg| seg.as_any().downcast_ref::<segment::ThinSegment>())
+            .find(|thin| thin.thin_pool == pool_name)
+            .map(|thin| thin.device_id)
+            .ok_or_else(|| self.lv_type_err(origin, "a thin LV in the thin pool"))?;
+        let extent_count = origin_lv.used_extents();
+        let origin_dm_name = origin_lv.device.map(|_| self.lv_dm_name(origin_lv));
+
+        self.check_metadata_space(1, 0)?;
+
+        // The origin must not be written to while its mappings are
+        // copied.
+        let retry = self.config.retry;
+        if let Some(ref dm_name) = origin_dm_name {
+            dm::suspend_device(dm_name, &retry)?;
+        }
+        let device_id = self.next_thin_device_id(pool_name);
+        let res = self.thin_pool_message(
+            pool_name,
+            &format!("create_snap {} {}", device_id, origin_id),
+        );
+        if let Some(ref dm_name) = origin_dm_name {
+            dm::resume_device(dm_name, &retry)?;
+        }
+        res?;
+
+        let transaction_id = self
+            .thin_pool_segment_mut(pool_name)
+            .expect("checked by thin_pool_message")
+            .transaction_id;
+
+        let lv = self.new_lv(
+            name,
+            vec![Box::new(segment::ThinSegment {
+                start_extent: 0,
+                extent_count,
+                thin_pool: pool_name.to_string(),
+                transaction_id,
+                device_id,
+                origin: Some(origin.to_string()),
+            })],
+        );
+        self.lvs.insert(name.to_string(), lv);
+
+        self.commit_op(&format!(
+            "create thin snapshot {} of {} in pool {}",
+            name, origin, pool_name
+        ))
+    }
+
+    // The id for a new thin device in a pool: one more than the highest
+    // in use.
+    fn next_thin_device_id(&self, pool_name: &str) -> u64 {
+        self.lvs
+            .values()
+            .flat_map(|lv| lv.segments.iter())
+            .filter_map(|seg| seg.as_any().downcast_ref::<segment::ThinSegment>())
+            .filter(|thin| thin.thin_pool == pool_name)
+            .map(|thin| thin.device_id)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+
+    // Whether an LV is active and is made up of only linear or striped
+    // segments, with no other LVs using it.
+    fn lv_is_plain(&self, name: &str) -> bool {
+        let lv = match self.lvs.get(name) {
+            Some(lv) => lv,
+            None => return false,
+        };
+
+        let used = self.lvs.values().any(|other| {
+            other
+                .segments
+                .iter()
+                .any(|seg| seg.lv_dependencies().iter().any(|dep| dep == name))
+        });
+
+        lv.device.is_some()
+            && !used
+            && lv
+                .segments
+                .iter()
+                .all(|seg| matches!(seg.dm_type(), "linear" | "striped"))
+    }
+
+    // Rename an LV and its DM devices. The devices of an LV activated
+    // under another name keep it.
+    fn lv_rename_active(&mut self, old: &str, new: &str) -> Result<()> {
+        let lv = &self.lvs[old];
+        if lv.activation_name.is_none() {
+            self.rename_dm_devices(lv, old, new)?;
+        }
+
+        let mut lv = self.lvs.remove(old).expect("exists");
+        lv.name = new.to_string();
+        self.lvs.insert(new.to_string(), lv);
+
+        Ok(())
+    }
+
+    // Rename the DM devices of the LV `lv` from those for the name `old`
+    // to those for `new`.
+    fn rename_dm_devices(&self, lv: &LV, old: &str, new: &str) -> Result<()> {
+        let retry = self.config.retry;
+
+        if lv.device.is_some() {
+            dm::rename_device(
+                &lv::dm_name(&self.name, old),
+                &lv::dm_name(&self.name, new),
+                &retry,
+            )?;
+        }
+        for suffix in lv.layers.keys() {
+            dm::rename_device(
+                &lv::dm_layer_name(&self.name, old, suffix),
+                &lv::dm_layer_name(&self.name, new, suffix),
+                &retry,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Load an active LV's table again, e.g. after its segments changed.
+    fn lv_reload(&self, name: &str) -> Result<()> {
+        let lv = &self.lvs[name];
+        let dm_name = self.lv_dm_name(lv);
+        let table = self.lv_table(lv)?;
+        let retry = self.config.retry;
+
+        if lv_read_only(lv) {
+            dm::load_read_only_table(&dm_name, &table, &retry)?;
+        } else {
+            dm::load_table(&dm_name, &table, &retry)?;
+        }
+        dm::suspend_device(&dm_name, &retry)?;
+        dm::resume_device(&dm_name, &retry)
+    }
+
+    /// Make an LV read-only, or writable again, like `lvchange -p`. An
+    /// active LV's table is reloaded so the change takes effect at once.
+    /// LVs whose devices are stacked on layers, such as thin pools and
+    /// snapshot origins, can only be changed while inactive.
+    pub fn lv_set_readonly(&mut self, name: &str, read_only: bool) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if !read_only && lv.segments.iter().any(|seg| seg.read_only()) {
+            return Err(Error::NotPermitted(
+                "LV has segments that are always read-only".to_string(),
+            ));
+        }
+        if lv.device.is_some() && !lv.layers.is_empty() {
+            return Err(self.lv_active(name));
+        }
+        if lv.status.iter().any(|s| s == "WRITE") != read_only {
+            return Ok(());
+        }
+
+        let active = lv.device.is_some();
+        let lv = self.lvs.get_mut(name).expect("checked above");
+        if read_only {
+            lv.status.retain(|s| s != "WRITE");
+        } else {
+            let idx = lv
+                .status
+                .iter()
+                .position(|s| s == "READ")
+                .map_or(0, |i| i + 1);
+            lv.status.insert(idx, "WRITE".to_string());
+        }
+
+        if active {
+            if let Err(e) = self.lv_reload(name) {
+                let lv = self.lvs.get_mut(name).expect("checked above");
+                if read_only {
+                    lv.status.push("WRITE".to_string());
+                } else {
+                    lv.status.retain(|s| s != "WRITE");
+                }
+                return Err(e);
+            }
+        }
+
+        self.commit_op(&format!(
+            "make LV {} {}",
+            name,
+            if read_only { "read-only" } else { "writable" }
+        ))
+    }
+
+    /// Set or clear the ACTIVATION_SKIP flag of the LV `name`, which
+    /// keeps it from being activated along with the rest of the VG. It
+    /// can still be activated by name.
+    pub fn lv_set_activation_skip(&mut self, name: &str, skip: bool) -> Result<()> {
+        if self.lv_set_flag(name, lv::ACTIVATION_SKIP, skip)? {
+            let op = if skip { "set" } else { "clear" };
+            self.commit_op(&format!("{} activation skip on LV {}", op, name))?;
+        }
+        Ok(())
+    }
+
+    /// Turn autoactivation of the LV `name` on or off. LVs with it off
+    /// are left out by `activate_all(true)`.
+    pub fn lv_set_autoactivation(&mut self, name: &str, enabled: bool) -> Result<()> {
+        if self.lv_set_flag(name, lv::NOAUTOACTIVATE, !enabled)? {
+            let op = if enabled { "enable" } else { "disable" };
+            self.commit_op(&format!("{} autoactivation of LV {}", op, name))?;
+        }
+        Ok(())
+    }
+
+    // Set or clear a flag of an LV. Returns whether it changed.
+    fn lv_set_flag(&mut self, name: &str, flag: &str, set: bool) -> Result<bool> {
+        let lv = match self.lvs.get_mut(name) {
+            Some(lv) => lv,
+            None => return Err(self.lv_not_found(name)),
+        };
+
+        if lv.flags.iter().any(|f| f == flag) == set {
+            return Ok(false);
+        }
+        if set {
+            lv.flags.push(flag.to_string());
+        } else {
+            lv.flags.retain(|f| f != flag);
+        }
+
+        Ok(true)
+    }
+
+    /// Set the hints for a snapshot scheduler stored with the LV `name`,
+    /// replacing any it had. Empty hints remove them.
+    pub fn lv_set_schedule(&mut self, name: &str, schedule: ScheduleHints) -> Result<()> {
+        schedule.check()?;
+        let lv = match self.lvs.get_mut(name) {
+            Some(lv) => lv,
+            None => return Err(self.lv_not_found(name)),
+        };
+        if lv.schedule == schedule {
+            return Ok(());
+        }
+        lv.schedule = schedule;
+
+        self.commit_op(&format!("set schedule of LV {}", name))
+    }
+
+    /// The LVs that have hints for a snapshot scheduler, with their
+    /// hints, in name order.
+    pub fn lv_schedules(&self) -> Vec<(String, ScheduleHints)> {
+        self.lvs
+            .values()
+            .filter(|lv| !lv.schedule.is_empty())
+            .map(|lv| (lv.name.clone(), lv.schedule.clone()))
+            .collect()
+    }
+
+    /// Grow the linear or striped LV `name` by `size`. New
+    /// linear segments are added, using free space anywhere in the VG,
+    /// and the LV's table is reloaded if it is active.
+    pub fn lv_extend(&mut self, name: &str, size: impl Into<Size>) -> Result<()> {
+        self.lv_extend_with_options(name, size, &AllocOptions::default())
+    }
+
+    /// Grow the linear or striped LV `name` by `size`, with
+    /// the new extents placed according to `options`.
+    pub fn lv_extend_with_options(
+        &mut self,
+        name: &str,
+        size: impl Into<Size>,
+        options: &AllocOptions,
+    ) -> Result<()> {
+        self.check_resizeable()?;
+        let extents = self.size_to_extents(size.into())?;
+        self.check_extendable(name)?;
+
+        if extents == 0 {
+            return Ok(());
+        }
+
+        let areas = self.alloc_with_options(Some(name), extents, options)?;
+        self.check_metadata_space(0, areas.len() as u64)?;
+        self.lv_append_areas(name, areas);
+
+        if self.lvs[name].device.is_some() {
+            self.lv_reload(name)?;
+        }
+
+        self.commit_op(&format!("extend LV {} by {} extents", name, extents))
+    }
+
+    // Make sure the LV `name` is one `lv_extend` can extend.
+    fn check_extendable(&self, name: &str) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        let used = self.lvs.values().any(|other| {
+            other
+                .segments
+                .iter()
+                .any(|seg| seg.lv_dependencies().iter().any(|dep| dep == name))
+        });
+        if used
+            || !lv.layers.is_empty()
+            || !lv
+                .segments
+                .iter()
+                .all(|seg| matches!(seg.dm_type(), "linear" | "striped"))
+        {
+            return Err(self.lv_type_err(
+                name,
+                "a linear or striped LV that is not in use by other LVs",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Work out where the extents for each of `requests` would be
+    /// placed, as if they were carried out in order, without changing
+    /// anything. Each request sees the space taken by those before it,
+    /// and can extend an LV an earlier one creates, so this answers
+    /// whether a set of LVs will fit, and shows exactly where they would
+    /// go. Percentages of free space are of what earlier requests leave.
+    /// Fails for the first request that would fail, such as for lack of
+    /// space; whether the metadata areas have room is not checked.
+    pub fn plan_allocation(&self, requests: &[AllocRequest]) -> Result<Vec<AllocPlan>> {
+        let mut free_areas = self.free_areas();
+        let mut plans: Vec<AllocPlan> = Vec::new();
+
+        for request in requests {
+            let (name, size, options) = match request {
+                AllocRequest::Create {
+                    name,
+                    size,
+                    options,
+                } => {
+                    if self.lvs.contains_key(name) || plans.iter().any(|plan| plan.name == *name) {
+                        return Err(self.lv_exists(name));
+                    }
+                    (name, *size, options)
+                }
+                AllocRequest::Extend {
+                    name,
+                    size,
+                    options,
+                } => {
+                    self.check_resizeable()?;
+                    if !plans.iter().any(|plan| plan.name == *name) {
+                        self.check_extendable(name)?;
+                    }
+                    (name, *size, options)
+                }
+            };
+
+            let extents = match size {
+                Size::PercentFree(pct) if pct <= 100 => {
+                    let free: u64 = free_areas.values().flat_map(|areas| areas.values()).sum();
+                    free * pct as u64 / 100
+                }
+                size => self.size_to_extents(size)?,
+            };
+
+            let mut used = self.lvs.get(name).map(lv::used_areas).unwrap_or_default();
+            used.extend(
+                plans
+                    .iter()
+                    .filter(|plan| plan.name == *name)
+                    .flat_map(|plan| plan.areas.iter().cloned()),
+            );
+
+            let areas = if extents == 0 {
+                Vec::new()
+            } else {
+                self.alloc_from(free_areas.clone(), &used, extents, options)?
+            };
+            self.check_areas_not_reserved(&areas)?;
+            for (dev, start, len) in &areas {
+                take_area(&mut free_areas, *dev, *start, *len);
+            }
+
+            plans.push(AllocPlan {
+                name: name.clone(),
+                extents,
+                areas,
+            });
+        }
+
+        Ok(plans)
+    }
+
+    // Take the last `extents` extents off an LV, undoing
+    // `lv_append_areas`.
+    fn lv_unappend_extents(&mut self, name: &str, mut extents: u64) {
+        let lv = self.lvs.get_mut(name).expect("checked by caller");
+        while extents != 0 {
+            let last = lv
+                .segments
+                .last_mut()
+                .and_then(|seg| seg.as_any_mut().downcast_mut::<segment::StripedSegment>())
+                .expect("appended as linear segments");
+            if last.extent_count > extents {
+                last.extent_count -= extents;
+                return;
+            }
+            extents -= last.extent_count;
+            lv.segments.pop();
+        }
+    }
+
+    // Add allocated areas to the end of an LV, as linear segments.
+    fn lv_append_areas(&mut self, name: &str, areas: Vec<(Device, u64, u64)>) {
+        let lv = self.lvs.get_mut(name).expect("checked by caller");
+        for (dev, start, len) in areas {
+            let start_extent = lv.used_extents();
+
+            // Grow the last segment if the area follows on from it.
+            if let Some(last) = lv
+                .segments
+                .last_mut()
+                .and_then(|seg| seg.as_any_mut().downcast_mut::<segment::StripedSegment>())
+            {
+                if last.stripes.len() == 1
+                    && last.stripes[0].0 == dev
+                    && last.stripes[0].1 + last.extent_count == start
+                {
+                    last.extent_count += len;
+                    continue;
+                }
+            }
+
+            lv.segments.push(Box::new(segment::StripedSegment {
+                start_extent,
+                extent_count: len,
+                stripes: vec![(dev, start)],
+                stripe_size: None,
+            }));
+        }
+    }
+
+    /// Grow the thin pool `name`: its data LV by `data_size`, and its
+    /// metadata LV by `meta_size`. Either may be zero. If the pool is
+    /// active, it is suspended while the grown LVs' tables are made live,
+    /// and then its own table is reloaded with the new size. If that
+    /// fails, the pool is resumed as it was and the extents allocated
+    /// are given back. The spare metadata LV, if there is one, is grown
+    /// to match.
+    pub fn thinpool_extend(
+        &mut self,
+        name: &str,
+        data_size: impl Into<Size>,
+        meta_size: impl Into<Size>,
+    ) -> Result<()> {
+        self.check_resizeable()?;
+        let data_extents = self.size_to_extents(data_size.into())?;
+        let meta_extents = self.size_to_extents(meta_size.into())?;
+        let (tmeta, tdata) = match self.thin_pool_segment_mut(name) {
+            Some(seg) => (seg.metadata.clone(), seg.pool.clone()),
+            None => return Err(self.lv_type_err(name, "a thin pool")),
+        };
+        if data_extents == 0 && meta_extents == 0 {
+            return Ok(());
+        }
+        let max_meta_sectors = capabilities::kernel_limits()
+            .unwrap_or_default()
+            .max_thin_metadata_sectors;
+        if meta_extents != 0
+            && (self.lvs[&tmeta].used_extents() + meta_extents) * self.extent_size
+                > max_meta_sectors
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Thin pool metadata would be larger than the kernel's limit of {} sectors",
+                max_meta_sectors
+            )));
+        }
+        // Both are allocated before either is added, so check up front
+        // that they fit together.
+        if data_extents + meta_extents > self.extents_free() {
+            return Err(self.alloc_err(data_extents + meta_extents, false));
+        }
+
+        let options = AllocOptions::default();
+        let mut appended = Vec::new();
+        let mut res = Ok(());
+        for (lv_name, extents) in &[(&tdata, data_extents), (&tmeta, meta_extents)] {
+            if *extents == 0 {
+                continue;
+            }
+            let areas = match self
+                .alloc_with_options(Some(lv_name), *extents, &options)
+                .and_then(|areas| {
+                    self.check_metadata_space(0, areas.len() as u64)?;
+                    Ok(areas)
+                }) {
+                Ok(areas) => areas,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            };
+            self.lv_append_areas(lv_name, areas);
+            appended.push((lv_name.to_string(), *extents));
+        }
+
+        if res.is_ok() {
+            let seg = self.thin_pool_segment_mut(name).expect("checked above");
+            seg.extent_count += data_extents;
+
+            if self.lvs[name].layers.contains_key("tpool") {
+                res = self.thin_pool_reload(name, &[&tmeta, &tdata]);
+                if res.is_err() {
+                    let seg = self.thin_pool_segment_mut(name).expect("checked above");
+                    seg.extent_count -= data_extents;
+                }
+            }
+        }
+
+        if let Err(e) = res {
+            for (lv_name, extents) in &appended {
+                self.lv_unappend_extents(lv_name, *extents);
+                if self.lvs[lv_name].device.is_some() {
+                    let _ = self.lv_reload(lv_name);
+                }
+            }
+            return Err(e);
+        }
+
+        if self.lvs[name].layers.contains_key("tpool") {
+            let len = self.lv_len(name);
+            let table = vec![(
+                0,
+                len,
+                "linear".to_string(),
+                format!("{} 0", self.lvs[name].layers["tpool"]),
+            )];
+            let dm_name = self.lv_dm_name(&self.lvs[name]);
+            let retry = self.config.retry;
+            dm::load_table(&dm_name, &table, &retry)?;
+            dm::suspend_device(&dm_name, &retry)?;
+            dm::resume_device(&dm_name, &retry)?;
+        }
+
+        if meta_extents != 0 && self.lvs.contains_key(THIN_METADATA_SPARE) {
+            if let Err(e) = self.ensure_thin_metadata_spare() {
+                self.warnings
+                    .push(format!("could not grow spare metadata LV: {:?}", e));
+            }
+        }
+
+        self.commit_op(&format!(
+            "extend thin pool {} by {} data and {} metadata extents",
+            name, data_extents, meta_extents
+        ))
+    }
+
+    /// Cache the LV `origin` with dm-cache. The unused LVs
+    /// `cache_data_lv` and `cache_meta_lv` become the data and metadata
+    /// of a new cache pool, named after `cache_data_lv`. As in LVM2, they
+    /// are hidden and given "_cdata" and "_cmeta" suffixes, and the
+    /// origin's own segments move to a hidden LV with a "_corig" suffix.
+    pub fn lv_cache_attach(
+        &mut self,
+        origin: &str,
+        cache_data_lv: &str,
+        cache_meta_lv: &str,
+        mode: segment::CacheMode,
+    ) -> Result<()> {
+        if origin == cache_data_lv || origin == cache_meta_lv || cache_data_lv == cache_meta_lv {
+            return Err(Error::InvalidArgument("LVs must be distinct".to_string()));
+        }
+        for lv_name in &[origin, cache_data_lv, cache_meta_lv] {
+            if !self.lv_is_plain(lv_name) {
+                return Err(self.lv_type_err(lv_name, "an active, unused linear or striped LV"));
+            }
+        }
+
+        let pool = cache_data_lv.to_string();
+        let cdata = format!("{}_cdata", pool);
+        let cmeta = format!("{}_cmeta", pool);
+        let corig = format!("{}_corig", origin);
+        for lv_name in &[&cdata, &cmeta, &corig] {
+            if self.lvs.contains_key(*lv_name) {
+                return Err(self.lv_exists(lv_name));
+            }
+        }
+
+        self.check_metadata_space(2, 0)?;
+
+        // The kernel formats the metadata if its superblock is blank.
+        self.lv_zero_start(cache_meta_lv, DEFAULT_CHUNK_SIZE)?;
+
+        self.lv_rename_active(cache_data_lv, &cdata)?;
+        self.lv_rename_active(cache_meta_lv, &cmeta)?;
+        for lv_name in &[&cdata, &cmeta] {
+            let lv = self.lvs.get_mut(*lv_name).expect("renamed above");
+            lv.status.retain(|s| s != "VISIBLE");
+        }
+
+        let pool_lv = self.new_hidden_lv(
+            &pool,
+            vec![Box::new(segment::CachePoolSegment {
+                start_extent: 0,
+                extent_count: self.lvs[&cdata].used_extents(),
+                data: cdata.clone(),
+                metadata: cmeta.clone(),
+                chunk_size: DEFAULT_CACHE_CHUNK_SIZE,
+                cache_mode: mode,
+                policy: "smq".to_string(),
+            })],
+        );
+        self.lvs.insert(pool.clone(), pool_lv);
+
+        let origin_lv = self.lvs.get_mut(origin).expect("checked above");
+        let extent_count = origin_lv.used_extents();
+        let segments = std::mem::replace(
+            &mut origin_lv.segments,
+            vec![Box::new(segment::CacheSegment {
+                start_extent: 0,
+                extent_count,
+                cache_pool: pool,
+                origin: corig.clone(),
+            })],
+        );
+        let corig_lv = self.new_hidden_lv(&corig, segments);
+        self.lvs.insert(corig.clone(), corig_lv);
+
+        self.lv_activate(&corig)?;
+        self.lv_reload(origin)?;
+
+        self.commit_op(&format!("attach cache {} to LV {}", cache_data_lv, origin))
+    }
+
+    /// Buffer writes to the LV `origin` on the unused LV `cache_lv` with
+    /// dm-writecache, in blocks of `block_size` bytes, which must be 512
+    /// or 4096. As in LVM2, the cache LV is hidden and given a "_cvol"
+    /// suffix, and the origin's own segments move to a hidden LV with a
+    /// "_wcorig" suffix.
+    pub fn lv_writecache_attach(
+        &mut self,
+        origin: &str,
+        cache_lv: &str,
+        block_size: u64,
+    ) -> Result<()> {
+        if block_size != 512 && block_size != 4096 {
+            return Err(Error::InvalidArgument(
+                "Writecache block size must be 512 or 4096".to_string(),
+            ));
+        }
+        if origin == cache_lv {
+            return Err(Error::InvalidArgument("LVs must be distinct".to_string()));
+        }
+        for lv_name in &[origin, cache_lv] {
+            if !self.lv_is_plain(lv_name) {
+                return Err(self.lv_type_err(lv_name, "an active, unused linear or striped LV"));
+            }
+        }
+
+        let cvol = format!("{}_cvol", cache_lv);
+        let wcorig = format!("{}_wcorig", origin);
+        for lv_name in &[&cvol, &wcorig] {
+            if self.lvs.contains_key(*lv_name) {
+                return Err(self.lv_exists(lv_name));
+            }
+        }
+
+        self.check_metadata_space(1, 0)?;
+
+        // The kernel formats the cache if its superblock is blank.
+        self.lv_zero_start(cache_lv, DEFAULT_CHUNK_SIZE)?;
+
+        self.lv_rename_active(cache_lv, &cvol)?;
+        let lv = self.lvs.get_mut(&cvol).expect("renamed above");
+        lv.status.retain(|s| s != "VISIBLE");
+
+        let origin_lv = self.lvs.get_mut(origin).expect("checked above");
+        let extent_count = origin_lv.used_extents();
+        let segments = std::mem::replace(
+            &mut origin_lv.segments,
+            vec![Box::new(segment::WritecacheSegment {
+                start_extent: 0,
+                extent_count,
+                origin: wcorig.clone(),
+                writecache: cvol,
+                block_size,
+            })],
+        );
+        let wcorig_lv = self.new_hidden_lv(&wcorig, segments);
+        self.lvs.insert(wcorig.clone(), wcorig_lv);
+
+        self.lv_activate(&wcorig)?;
+        self.lv_reload(origin)?;
+
+        self.commit_op(&format!("attach writecache {} to LV {}", cache_lv, origin))
+    }
+
+    fn cache_segment(&self, name: &str) -> Result<&segment::CacheSegment> {
+        self.lvs
+            .get(name)
+            .and_then(|lv| {
+                lv.segments
+                    .iter()
+                    .find_map(|seg| seg.as_any().downcast_ref::<segment::CacheSegment>())
+            })
+            .ok_or_else(|| self.lv_type_err(name, "cached"))
+    }
+
+    // Switch a cached LV to the cleaner policy, and wait until it has
+    // written all dirty blocks back to the origin.
+    fn lv_cache_clean(&self, name: &str) -> Result<()> {
+        let seg = self.cache_segment(name)?;
+        let dm_name = self.lv_dm_name(&self.lvs[name]);
+        let retry = self.config.retry;
+
+        let table = vec![(
+            0,
+            self.lv_len(name),
+            "cache".to_string(),
+            seg.params_with_policy(self, Some("cleaner"))?,
+        )];
+        dm::load_table(&dm_name, &table, &retry)?;
+        dm::suspend_device(&dm_name, &retry)?;
+        dm::resume_device(&dm_name, &retry)?;
+
+        loop {
+            let status = dm::table_status(&dm_name, &retry)?;
+            // The count of dirty blocks is the 11th field.
+            let dirty = status
+                .first()
+                .and_then(|(_, _, _, params)| params.split_whitespace().nth(10))
+                .and_then(|x| x.parse::<u64>().ok())
+                .ok_or_else(|| Error::DmStatus("Could not parse cache status".to_string()))?;
+            if dirty == 0 {
+                return Ok(());
+            }
+            thread::sleep(CACHE_FLUSH_POLL_INTERVAL);
+        }
+    }
+
+    /// Write all dirty blocks in the cache of the LV `name` back to the
+    /// origin, and wait until that is done.
+    pub fn lv_cache_flush(&mut self, name: &str) -> Result<()> {
+        self.lv_cache_clean(name)?;
+        self.lv_reload(name)
+    }
+
+    /// Stop caching the LV `name`, after flushing its cache. The cache
+    /// pool is kept, and becomes visible, so that it can be removed with
+    /// `lv_remove`.
+    pub fn lv_cache_detach(&mut self, name: &str) -> Result<()> {
+        let seg = self.cache_segment(name)?;
+        let (pool, corig) = (seg.cache_pool.clone(), seg.origin.clone());
+
+        self.lv_cache_clean(name)?;
+
+        let segments = std::mem::take(&mut self.lvs.get_mut(&corig).expect("exists").segments);
+        self.lvs.get_mut(name).expect("exists").segments = segments;
+        self.lv_reload(name)?;
+
+        let corig_lv = self.lvs.remove(&corig).expect("exists");
+        dm::remove_device(&self.lv_dm_name(&corig_lv), &self.config.retry)?;
+
+        if let Some(pool_lv) = self.lvs.get_mut(&pool) {
+            pool_lv.status.push("VISIBLE".to_string());
+        }
+
+        self.commit_op(&format!("detach cache from LV {}", name))
+    }
+
+    fn thin_pool_segment_mut(&mut self, name: &str) -> Option<&mut segment::ThinPoolSegment> {
+        self.lvs
+            .get_mut(name)?
+            .segments
+            .iter_mut()
+            .find_map(|seg| seg.as_any_mut().downcast_mut())
+    }
+
+    /// Change how the thin pool `name` handles discards: "ignore",
+    /// "nopassdown" or "passdown". If the pool is active, its table is
+    /// reloaded. The kernel cannot switch an active pool to or from
+    /// "ignore", so that requires the pool to be inactive.
+    pub fn lv_thinpool_set_discards(&mut self, name: &str, discards: &str) -> Result<()> {
+        if !matches!(discards, "ignore" | "nopassdown" | "passdown") {
+            return Err(Error::InvalidArgument(format!(
+                "Unknown discards policy {}",
+                discards
+            )));
+        }
+
+        let active = self
+            .lvs
+            .get(name)
+            .map(|lv| lv.layers.contains_key("tpool"))
+            .unwrap_or(false);
+        let seg = match self.thin_pool_segment_mut(name) {
+            Some(seg) => seg,
+            None => return Err(self.lv_type_err(name, "a thin pool")),
+        };
+        if seg.discards == discards {
+            return Ok(());
+        }
+        if active && (seg.discards == "ignore" || discards == "ignore") {
+            return Err(Error::NotPermitted(
+                "Cannot change discards to or from ignore while the pool is active".to_string(),
+            ));
+        }
+        seg.discards = discards.to_string();
+
+        if active {
+            self.thin_pool_reload(name, &[])?;
+        }
+
+        self.commit_op(&format!(
+            "set discards of thin pool {} to {}",
+            name, discards
+        ))
+    }
+
+    /// Choose what happens to writes to the thin LVs in the pool `name`
+    /// when it runs out of data space: if `error` is set they fail,
+    /// otherwise they are queued until the pool is extended. If the pool
+    /// is active, its table is reloaded.
+    pub fn lv_thinpool_set_error_if_no_space(&mut self, name: &str, error: bool) -> Result<()> {
+        let active = self
+            .lvs
+            .get(name)
+            .map(|lv| lv.layers.contains_key("tpool"))
+            .unwrap_or(false);
+        let seg = match self.thin_pool_segment_mut(name) {
+            Some(seg) => seg,
+            None => return Err(self.lv_type_err(name, "a thin pool")),
+        };
+        if seg.error_if_no_space == error {
+            return Ok(());
+        }
+        seg.error_if_no_space = error;
+
+        if active {
+            self.thin_pool_reload(name, &[])?;
+        }
+
+        let behaviour = if error { "error" } else { "queue" };
+        self.commit_op(&format!(
+            "set thin pool {} to {} when full",
+            name, behaviour
+        ))
+    }
+
+    /// Repair the metadata of the inactive thin pool `name` with
+    /// `thin_repair`, which must be installed. The repaired metadata is
+    /// written to the VG's spare metadata LV, which then becomes the
+    /// pool's metadata LV. The old metadata LV is kept as the visible LV
+    /// "<pool>_meta<N>", so it can be inspected or removed, and a new
+    /// spare is allocated if there is space.
+    pub fn thinpool_repair(&mut self, name: &str) -> Result<()> {
+        let pool = self.lvs.get(name).and_then(|lv| {
+            lv.segments
+                .iter()
+                .find_map(|seg| seg.as_any().downcast_ref::<segment::ThinPoolSegment>())
+        });
+        let tmeta = match pool {
+            Some(seg) => seg.metadata.clone(),
+            None => return Err(self.lv_type_err(name, "a thin pool")),
+        };
+        if self.lvs[name].device.is_some() || self.lvs[&tmeta].device.is_some() {
+            return Err(self.lv_active(name));
+        }
+        match self.lvs.get(THIN_METADATA_SPARE) {
+            Some(spare) if spare.used_extents() >= self.lvs[&tmeta].used_extents() => {}
+            _ => {
+                return Err(Error::NotPermitted(
+                    "VG has no spare metadata LV large enough to repair into".to_string(),
+                ))
+            }
+        }
+
+        self.lv_activate(&tmeta)?;
+        let res = self.lv_activate(THIN_METADATA_SPARE).and_then(|_| {
+            let input = self.lvs[&tmeta].device.expect("active").to_dev_path();
+            let output = self.lvs[THIN_METADATA_SPARE]
+                .device
+                .expect("active")
+                .to_dev_path();
+            let out = Command::new("thin_repair")
+                .arg("-i")
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .output()?;
+            if !out.status.success() {
+                return Err(Error::CommandFailed {
+                    command: "thin_repair".to_string(),
+                    detail: format!(
+                        "{}: {}",
+                        out.status,
+                        String::from_utf8_lossy(&out.stderr).trim()
+                    ),
+                });
+            }
+            Ok(())
+        });
+        let deactivated = self
+            .lv_deactivate(THIN_METADATA_SPARE)
+            .and_then(|_| self.lv_deactivate(&tmeta));
+        res.and(deactivated)?;
+
+        let old_name = (0..)
+            .map(|n| format!("{}_meta{}", name, n))
+            .find(|n| !self.lvs.contains_key(n))
+            .expect("some name is free");
+        let mut old = self.lvs.remove(&tmeta).expect("checked above");
+        old.name = old_name.clone();
+        old.status.push("VISIBLE".to_string());
+        self.lvs.insert(old_name.clone(), old);
+
+        let mut repaired = self.lvs.remove(THIN_METADATA_SPARE).expect("checked above");
+        repaired.name = tmeta.clone();
+        self.lvs.insert(tmeta, repaired);
+
+        if let Err(e) = self.ensure_thin_metadata_spare() {
+            self.warnings
+                .push(format!("could not create a new spare metadata LV: {:?}", e));
+        }
+
+        self.commit_op(&format!(
+            "repair thin pool {}, keeping old metadata as {}",
+            name, old_name
+        ))
+    }
+
+    // Load the table of an active thin pool's "-tpool" device again, and
+    // make it live, first reloading the active LVs in `grown`, such as
+    // its data LV after it was extended. The device is resumed even if
+    // a reload fails, so that I/O to the pool's thin LVs is not left
+    // blocked.
+    fn thin_pool_reload(&self, name: &str, grown: &[&str]) -> Result<()> {
+        let tpool = self.lv_dm_layer_name(&self.lvs[name], "tpool");
+        let retry = self.config.retry;
+
+        dm::suspend_device(&tpool, &retry)?;
+        let mut res = Ok(());
+        for lv_name in grown {
+            if res.is_ok() && self.lvs[*lv_name].device.is_some() {
+                res = self.lv_reload(lv_name);
+            }
+        }
+        if res.is_ok() {
+            res = self
+                .lv_table(&self.lvs[name])
+                .and_then(|table| dm::load_table(&tpool, &table, &retry));
+        }
+        let resumed = dm::resume_device(&tpool, &retry);
+        res.and(resumed)
+    }
+
+    // How many bytes of an active thin pool's data space are in use.
+    fn thin_pool_used_bytes(&self, pool_name: &str) -> Result<u64> {
+        let chunk_size = self
+            .lvs
+            .get(pool_name)
+            .and_then(|lv| {
+                lv.segments
+                    .iter()
+                    .find_map(|seg| seg.as_any().downcast_ref::<segment::ThinPoolSegment>())
+            })
+            .ok_or_else(|| self.lv_type_err(pool_name, "a thin pool"))?
+            .chunk_size;
+
+        let used = self.lv_thinpool_status(pool_name)?.used_data_blocks;
+
+        Ok(used * chunk_size * SECTOR_SIZE as u64)
+    }
+
+    /// Get the status of each target in the table of the active LV
+    /// `name`, as the kernel reports it. For an LV stacked on a layer,
+    /// such as a thin pool, this is the status of the layer, which has
+    /// the LV's own table.
+    pub fn lv_status(&self, name: &str) -> Result<Vec<TableLine>> {
+        dm::table_status(&self.lv_table_dm_name(name)?, &self.config.retry)
+    }
+
+    /// Get the table the kernel is running for the active LV `name`,
+    /// from the same device as `lv_status`. It differs from the table
+    /// the metadata gives if the metadata has changed since the LV was
+    /// activated or refreshed.
+    pub fn lv_loaded_table(&self, name: &str) -> Result<Vec<TableLine>> {
+        dm::table(&self.lv_table_dm_name(name)?, &self.config.retry)
+    }
+
+    /// Get the count of events the active LV `name` has had, from the
+    /// same device as `lv_status`.
+    pub fn lv_event_nr(&self, name: &str) -> Result<u32> {
+        dm::event_nr(&self.lv_table_dm_name(name)?, &self.config.retry)
+    }
+
+    /// Block until the active LV `name` has an event since its count of
+    /// events was `event_nr`, as from `lv_event_nr`, and return the new
+    /// count. Events are raised when a thin pool crosses its low water
+    /// mark or runs out of space, or a RAID array finishes syncing, so
+    /// this lets a caller react to them without polling `lv_status`.
+    /// Returns at once if the count has already moved on.
+    pub fn lv_wait_event(&self, name: &str, event_nr: u32) -> Result<u32> {
+        dm::wait_event(&self.lv_table_dm_name(name)?, event_nr, &self.config.retry)
+    }
+
+    // The DM device with an active LV's own table.
+    fn lv_table_dm_name(&self, name: &str) -> Result<String> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if lv.device.is_none() {
+            return Err(self.lv_not_active(name));
+        }
+
+        let dm_name = match ["tpool", "real", "cow"]
+            .iter()
+            .find(|layer| lv.layers.contains_key(**layer))
+        {
+            Some(layer) => self.lv_dm_layer_name(lv, layer),
+            None => self.lv_dm_name(lv),
+        };
+
+        Ok(dm_name)
+    }
+
+    /// Get the status of the active thin pool `name`: how many of its
+    /// data and metadata blocks are in use, and whether it is healthy.
+    pub fn lv_thinpool_status(&self, name: &str) -> Result<ThinPoolStatus> {
+        if self
+            .lvs
+            .get(name)
+            .and_then(|lv| {
+                lv.segments
+                    .iter()
+                    .find_map(|seg| seg.as_any().downcast_ref::<segment::ThinPoolSegment>())
+            })
+            .is_none()
+        {
+            return Err(self.lv_type_err(name, "a thin pool"));
+        }
+
+        match self.lv_status(name)?.first() {
+            Some((_, _, _, params)) => params.parse(),
+            None => Err(Error::DmStatus(
+                "Could not parse thin pool status".to_string(),
+            )),
+        }
+    }
+
+    /// Discard unused space in the thin LV `name` with `LV::trim`, and
+    /// report how much of its pool's space was freed.
+    pub fn lv_trim(&self, name: &str) -> Result<TrimReport> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        let pool = lv
+            .segments
+            .iter()
+            .find_map(|seg| seg.as_any().downcast_ref::<segment::ThinSegment>())
+            .ok_or_else(|| self.lv_type_err(name, "a thin LV"))?
+            .thin_pool
+            .clone();
+
+        let before = self.thin_pool_used_bytes(&pool)?;
+        let trimmed = lv.trim_throttled(&self.config.throttles.discard)?;
+        let after = self.thin_pool_used_bytes(&pool)?;
+
+        Ok(TrimReport {
+            trimmed,
+            reclaimed: before.saturating_sub(after),
+        })
+    }
+
+    /// Trim the mounted filesystems on the thin LVs in the pool `name`,
+    /// and report how much of the pool's space was freed. Unmounted thin
+    /// LVs are left alone.
+    pub fn lv_thinpool_reclaim(&self, name: &str) -> Result<TrimReport> {
+        let before = self.thin_pool_used_bytes(name)?;
+
+        let mut trimmed = 0;
+        for lv in self.lvs.values() {
+            let in_pool = lv.segments.iter().any(|seg| {
+                seg.as_any()
+                    .downcast_ref::<segment::ThinSegment>()
+                    .map(|thin| thin.thin_pool == name)
+                    .unwrap_or(false)
+            });
+            let mounted = match lv.device {
+                Some(dev) => blockdev::mount_point(dev)?.is_some(),
+                None => false,
+            };
+            if in_pool && mounted {
+                trimmed += lv.trim_throttled(&self.config.throttles.discard)?;
+            }
+        }
+
+        let after = self.thin_pool_used_bytes(name)?;
+
+        Ok(TrimReport {
+            trimmed,
+            reclaimed: before.saturating_sub(after),
+        })
+    }
+
+    // Send a message that changes the thin devices in a pool, and advance
+    // the pool's transaction id to match.
+    fn thin_pool_message(&mut self, pool_name: &str, msg: &str) -> Result<()> {
+        let tpool = self.lv_dm_layer_name(&self.lvs[pool_name], "tpool");
+        let retry = self.config.retry;
+
+        let seg = match self.thin_pool_segment_mut(pool_name) {
+            Some(seg) => seg,
+            None => return Err(self.lv_type_err(pool_name, "a thin pool")),
+        };
+        let old = seg.transaction_id;
+
+        dm::message(&tpool, msg, &retry)?;
+        dm::message(
+            &tpool,
+            &format!("set_transaction_id {} {}", old, old + 1),
+            &retry,
+        )?;
+        seg.transaction_id = old + 1;
+
+        Ok(())
+    }
+
+    /// Destroy a logical volume. Removing the COW store of a snapshot
+    /// removes the snapshot.
+    pub fn lv_remove(&mut self, name: &str) -> Result<()> {
+        self.lv_remove_with_options(name, &DeactivateOptions::default())
+    }
+
+    /// Like `lv_remove`, but with the LV's DM devices removed as
+    /// `options` says. Thin LVs cannot be removed deferred, as they are
+    /// deleted from their pool when their devices are removed.
+    pub fn lv_remove_with_options(
+        &mut self,
+        name: &str,
+        options: &DeactivateOptions,
+    ) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if options.deferred && lv.segments.iter().any(|seg| seg.dm_type() == "thin") {
+            return Err(Error::NotPermitted(
+                "Thin LVs cannot be removed deferred".to_string(),
+            ));
+        }
+
+        let snapshots = self.snapshots();
+        if snapshots.iter().any(|(snap, _, _)| snap == name) {
+            return Err(Error::NotPermitted(
+                "LV is a hidden snapshot LV, remove its COW store instead".to_string(),
+            ));
+        }
+
+        // The COW store must be torn down before the snapshot LV, which
+        // restores the origin.
+        let mut names = vec![name.to_string()];
+        if let Some((snap, _, _)) = snapshots.iter().find(|(_, _, cow)| cow == name) {
+            names.push(snap.clone());
+        } else if let Some(user) = self.lvs.values().find(|lv| {
+            lv.segments
+                .iter()
+                .any(|seg| seg.lv_dependencies().iter().any(|dep| dep == name))
+        }) {
+            return Err(self.lv_in_use(name, &user.name));
+        }
+
+        // Hidden LVs that are part of the LV, such as the metadata and
+        // data LVs of a pool, go after it.
+        let mut idx = 0;
+        while idx < names.len() {
+            let parts: Vec<_> = self.lvs[&names[idx]]
+                .segments
+                .iter()
+                .filter(|seg| seg.dm_type() != "snapshot")
+                .flat_map(|seg| seg.lv_dependencies())
+                .filter(|dep| match self.lvs.get(dep) {
+                    Some(lv) => !lv.status.iter().any(|s| s == "VISIBLE"),
+                    None => false,
+                })
+                .collect();
+            names.extend(parts);
+            idx += 1;
+        }
+
+        // The spare metadata LV goes with the last thin pool.
+        let pools_left = self
+            .lvs
+            .values()
+            .filter(|lv| !names.contains(&lv.name))
+            .flat_map(|lv| lv.segments.iter())
+            .any(|seg| seg.dm_type() == "thin-pool");
+        if !pools_left && self.lvs.contains_key(THIN_METADATA_SPARE) {
+            names.push(THIN_METADATA_SPARE.to_string());
+        }
+
+        for name in &names {
+            let lv = self.lvs.remove(name).expect("checked above");
+            match self.checkpoint {
+                Some(ref mut checkpoint) => checkpoint.removed_lvs.push((lv, *options)),
+                None => self.lv_teardown(&lv, options)?,
+            }
+        }
+
+        // Thin snapshots outlive their origins.
+        for lv in self.lvs.values_mut() {
+            for seg in lv.segments.iter_mut() {
+                if let Some(thin) = seg.as_any_mut().downcast_mut::<segment::ThinSegment>() {
+                    if thin.origin.as_ref().is_some_and(|o| names.contains(o)) {
+                        thin.origin = None;
+                    }
+                }
+            }
+        }
+
+        self.commit_op(&format!("remove LV {}", name))
+    }
+
+    // The snapshots in the VG, as (hidden LV, origin LV, COW store LV).
+    fn snapshots(&self) -> Vec<(String, String, String)> {
+        let mut v = Vec::new();
+        for lv in self.lvs.values() {
+            for seg in lv.segments.iter().filter(|seg| seg.dm_type() == "snapshot") {
+                let deps = seg.lv_dependencies();
+                v.push((lv.name.clone(), deps[0].clone(), deps[1].clone()));
+            }
+        }
+        v
+    }
+
+    // The DM table for the segments of an LV.
+    pub(crate) fn lv_table(&self, lv: &LV) -> Result<Vec<TableLine>> {
+        lv.segments
+            .iter()
+            .map(|seg| {
+                Ok((
+                    seg.start_extent() * self.extent_size,
+                    seg.extent_count() * self.extent_size,
+                    seg.dm_type().to_string(),
+                    seg.dm_params(self)?,
+                ))
+            })
+            .collect()
+    }
+
+    // The name of an LV's DM device: the usual one, unless the LV was
+    // activated under another.
+    pub(crate) fn lv_dm_name(&self, lv: &LV) -> String {
+        lv.activation_name
+            .clone()
+            .unwrap_or_else(|| lv::dm_name(&self.name, &lv.name))
+    }
+
+    // The name of a DM device for a layer beneath an LV.
+    fn lv_dm_layer_name(&self, lv: &LV, layer: &str) -> String {
+        format!("{}-{}", self.lv_dm_name(lv), layer)
+    }
+
+    // Activate an inactive LV, with its DM device named as `naming`
+    // says. LVs it depends on get their usual names. The name is not
+    // recorded in the metadata, so only this `VG` knows it.
+    pub(crate) fn lv_activate_as(&mut self, name: &str, naming: &ActivationName) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if lv.device.is_some() {
+            return Err(self.lv_active(name));
+        }
+
+        let activation_name = match naming {
+            ActivationName::Default => None,
+            ActivationName::Prefix(prefix) => {
+                Some(format!("{}{}", prefix, lv::dm_name(&self.name, name)))
+            }
+            ActivationName::Custom(dm_name) => Some(dm_name.clone()),
+        };
+        self.lvs.get_mut(name).expect("exists").activation_name = activation_name;
+
+        let res = self.lv_activate(name);
+        if res.is_err() {
+            self.lvs.get_mut(name).expect("exists").activation_name = None;
+        }
+        res
+    }
+
+    /// Activate the LV `name`, creating its DM devices. The LVs it is
+    /// built on, such as a thin LV's pool, are activated first. An LV
+    /// that is already active is left alone, and one whose DM device
+    /// already exists, such as after the VG is read again, takes it
+    /// over rather than creating it.
+    //
+    // The origin of a snapshot is a snapshot-origin device stacked on a
+    // "-real" device with its own table. The COW store of a snapshot is
+    // a snapshot device, stacked on the origin's "-real" device and a
+    // "-cow" device with the COW store's own table. Hidden snapshot LVs
+    // have no devices of their own. A thin pool is a "-tpool" device
+    // with the pool's table, with a linear device on top. Cache pools
+    // have no devices either, but their data and metadata LVs do.
+    pub fn lv_activate(&mut self, name: &str) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if let Some(seg) = opaque_segment(lv) {
+            return Err(Error::Unsupported(format!(
+                "Segment type {} is not supported",
+                seg.type_name()
+            )));
+        }
+        let snapshots = self.snapshots();
+        if lv.device.is_some() || snapshots.iter().any(|(snap, _, _)| snap == name) {
+            return Ok(());
+        }
+
+        let is_thin_pool = lv.segments.iter().any(|seg| seg.dm_type() == "thin-pool");
+        let is_cache_pool = lv.segments.iter().any(|seg| seg.dm_type() == "cache-pool");
+        let deps: Vec<_> = lv
+            .segments
+            .iter()
+            .flat_map(|seg| seg.lv_dependencies())
+            .collect();
+        for dep in deps {
+            self.lv_activate(&dep)?;
+        }
+
+        // Only the LV a cache pool is attached to has a device.
+        if is_cache_pool {
+            return Ok(());
+        }
+
+        let dm_name = self.lv_dm_name(&self.lvs[name]);
+        let retry = self.config.retry;
+
+        if let Some(device) = dm::find_device(&dm_name)? {
+            let mut layers = BTreeMap::new();
+            for layer in &["tpool", "real", "cow"] {
+                let layer_name = self.lv_dm_layer_name(&self.lvs[name], layer);
+                if let Some(dev) = dm::find_device(&layer_name)? {
+                    layers.insert(layer.to_string(), dev);
+                }
+            }
+            let lv = self.lvs.get_mut(name).expect("exists");
+            lv.device = Some(device);
+            lv.layers = layers;
+            return Ok(());
+        }
+
+        self.require_targets(name)?;
+
+        let device = if snapshots.iter().any(|(_, origin, _)| origin == name) {
+            let real = self.lv_stack_origin(name)?;
+            let len = self.lv_len(name);
+            let table = vec![(0, len, "snapshot-origin".to_string(), real.to_string())];
+            dm::create_device(&dm_name, &table, &retry)?
+        } else if let Some((snap, origin, _)) = snapshots.iter().find(|(_, _, cow)| cow == name) {
+            self.lv_activate(origin)?;
+
+            let table = self.lv_table(&self.lvs[name])?;
+            let cow_name = self.lv_dm_layer_name(&self.lvs[name], "cow");
+            let cow = dm::create_device(&cow_name, &table, &retry)?;
+            self.lvs
+                .get_mut(name)
+                .expect("exists")
+                .layers
+                .insert("cow".to_string(), cow);
+
+            let table = self.lv_table(&self.lvs[snap])?;
+            dm::create_device(&dm_name, &table, &retry)?
+        } else if is_thin_pool {
+            let table = self.lv_table(&self.lvs[name])?;
+            let tpool_name = self.lv_dm_layer_name(&self.lvs[name], "tpool");
+            let tpool = dm::create_device(&tpool_name, &table, &retry)?;
+            self.lvs
+                .get_mut(name)
+                .expect("exists")
+                .layers
+                .insert("tpool".to_string(), tpool);
+
+            let len = self.lv_len(name);
+            let table = vec![(0, len, "linear".to_string(), format!("{} 0", tpool))];
+            dm::create_device(&dm_name, &table, &retry)?
+        } else if lv_read_only(&self.lvs[name]) {
+            let table = self.lv_table(&self.lvs[name])?;
+            dm::create_read_only_device(&dm_name, &table, &retry)?
+        } else {
+            let table = self.lv_table(&self.lvs[name])?;
+            dm::create_device(&dm_name, &table, &retry)?
+        };
+
+        self.lvs.get_mut(name).expect("exists").device = Some(device);
+
+        Ok(())
+    }
+
+    // Make sure the kernel has the DM targets for an LV's devices, so
+    // that a missing one is reported by name.
+    fn require_targets(&mut self, name: &str) -> Result<()> {
+        let mut targets: BTreeSet<_> = self.lvs[name]
+            .segments
+            .iter()
+            .map(|seg| seg.dm_type())
+            .filter(|target| !capabilities::is_builtin(target))
+            .collect();
+        if self.snapshots().iter().any(|(_, origin, _)| origin == name) {
+            targets.insert("snapshot-origin");
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        if self.capabilities.is_none() {
+            self.capabilities = Some(Capabilities::probe()?);
+        }
+        let capabilities = self.capabilities.as_mut().expect("set above");
+        for target in targets {
+            capabilities.require(target)?;
+        }
 
-        self.lvs.insert(name.to_string(), lv);
+        Ok(())
+    }
 
-        self.commit()
+    /// Deactivate the LV `name`, removing its DM devices but leaving it
+    /// in the VG. Hidden LVs it is built from, such as a thin pool's data
+    /// and metadata LVs, are deactivated after it. Fails if an active LV
+    /// is built on it, such as a thin LV on a pool, or a snapshot on its
+    /// origin. Deactivating a snapshot's COW store deactivates the
+    /// snapshot. An inactive LV is left alone.
+    pub fn lv_deactivate(&mut self, name: &str) -> Result<()> {
+        self.lv_deactivate_with_options(name, &DeactivateOptions::default())
     }
 
-    /// Destroy a logical volume.
-    pub fn lv_remove(&mut self, name: &str) -> Result<()> {
-        match self.lvs.remove(name) {
-            None => Err(Error::Io(io::Error::new(Other, "LV not found in VG"))),
-            Some(lv) => {
-                let dm = DM::new()?;
-                let name = DmName::new(&lv.name)?;
-                dm.device_suspend(
-                    &DevId::Name(name),
-                    &DmOptions::new().set_flags(DmFlags::DM_SUSPEND),
+    /// Like `lv_deactivate`, but with the LV's DM devices removed as
+    /// `options` says.
+    pub fn lv_deactivate_with_options(
+        &mut self,
+        name: &str,
+        options: &DeactivateOptions,
+    ) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        let snapshots = self.snapshots();
+        if snapshots.iter().any(|(snap, _, _)| snap == name) {
+            return Err(Error::NotPermitted(
+                "LV is a hidden snapshot LV, deactivate its COW store instead".to_string(),
+            ));
+        }
+        if let Some(user) = self.lv_active_user(name) {
+            return Err(self.lv_in_use(name, user));
+        }
+
+        let dm_name = self.lv_dm_name(lv);
+        let retry = self.config.retry;
+
+        let remove = |dm_name: &str| {
+            if options.deferred {
+                dm::remove_device_deferred(dm_name, &retry)
+            } else {
+                dm::remove_device(dm_name, &retry)
+            }
+        };
+        if lv.device.is_some() {
+            remove(&dm_name)?;
+        }
+        for suffix in lv.layers.keys() {
+            remove(&self.lv_dm_layer_name(lv, suffix))?;
+        }
+        let lv = self.lvs.get_mut(name).expect("exists");
+        lv.device = None;
+        lv.layers.clear();
+        lv.activation_name = None;
+
+        if let Some((_, origin, _)) = snapshots.iter().find(|(_, _, cow)| cow == name) {
+            let in_use = snapshots
+                .iter()
+                .any(|(_, o, cow)| o == origin && cow != name && self.lvs[cow].device.is_some());
+            if !in_use {
+                self.lv_unstack_origin(origin)?;
+            }
+        }
+
+        let parts: Vec<_> = self.lvs[name]
+            .segments
+            .iter()
+            .filter(|seg| seg.dm_type() != "snapshot")
+            .flat_map(|seg| seg.lv_dependencies())
+            .filter(|dep| match self.lvs.get(dep) {
+                Some(lv) => !lv.status.iter().any(|s| s == "VISIBLE"),
+                None => false,
+            })
+            .collect();
+        for part in parts {
+            if self.lv_active_user(&part).is_none() {
+                self.lv_deactivate_with_options(&part, options)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The name of an active LV built on the LV `name`, if any. A hidden
+    // snapshot LV counts as active while its COW store is, except when
+    // `name` is that COW store.
+    fn lv_active_user(&self, name: &str) -> Option<&str> {
+        let snapshots = self.snapshots();
+        self.lvs
+            .values()
+            .filter(
+                |lv| match snapshots.iter().find(|(snap, _, _)| *snap == lv.name) {
+                    Some((_, _, cow)) => cow != name && self.lvs[cow].device.is_some(),
+                    None => lv.device.is_some(),
+                },
+            )
+            .find(|lv| {
+                lv.segments
+                    .iter()
+                    .any(|seg| seg.lv_dependencies().iter().any(|dep| dep == name))
+            })
+            .map(|lv| lv.name.as_str())
+    }
+
+    // The length of an LV, in sectors.
+    fn lv_len(&self, name: &str) -> u64 {
+        self.lvs[name].used_extents() * self.extent_size
+    }
+
+    // Make sure the "-real" device of a snapshot origin exists. If the
+    // origin is already active, a snapshot-origin table is loaded into it,
+    // to take effect when it is next resumed.
+    fn lv_stack_origin(&mut self, name: &str) -> Result<Device> {
+        let lv = &self.lvs[name];
+        if let Some(real) = lv.layers.get("real") {
+            return Ok(*real);
+        }
+
+        let dm_name = self.lv_dm_name(&self.lvs[name]);
+        let retry = self.config.retry;
+
+        let table = self.lv_table(lv)?;
+        let real_name = self.lv_dm_layer_name(&self.lvs[name], "real");
+        let real = dm::create_device(&real_name, &table, &retry)?;
+        self.lvs
+            .get_mut(name)
+            .expect("exists")
+            .layers
+            .insert("real".to_string(), real);
+
+        if self.lvs[name].device.is_some() {
+            let len = self.lv_len(name);
+            let table = vec![(0, len, "snapshot-origin".to_string(), real.to_string())];
+            dm::load_table(&dm_name, &table, &retry)?;
+        }
+
+        Ok(real)
+    }
+
+    // Stop an active LV being a snapshot origin, by loading its own table
+    // in place of snapshot-origin, then removing the "-real" device.
+    fn lv_unstack_origin(&mut self, name: &str) -> Result<()> {
+        let lv = &self.lvs[name];
+        if !lv.layers.contains_key("real") {
+            return Ok(());
+        }
+
+        let dm_name = self.lv_dm_name(&self.lvs[name]);
+        let retry = self.config.retry;
+
+        if lv.device.is_some() {
+            let table = self.lv_table(lv)?;
+            dm::load_table(&dm_name, &table, &retry)?;
+            dm::suspend_device(&dm_name, &retry)?;
+            dm::resume_device(&dm_name, &retry)?;
+        }
+
+        dm::remove_device(&self.lv_dm_layer_name(&self.lvs[name], "real"), &retry)?;
+        self.lvs
+            .get_mut(name)
+            .expect("exists")
+            .layers
+            .remove("real");
+
+        Ok(())
+    }
+
+    // Write zeroes over the first `sectors` sectors of an LV, directly to
+    // its PVs.
+    fn lv_zero_start(&self, name: &str, sectors: u64) -> Result<()> {
+        let buf = vec![0; to_usize(sectors * SECTOR_SIZE as u64)?];
+        self.lv_write_at(&self.lvs[name], &buf, 0)
+    }
+
+    // Write to an LV at an offset in bytes, directly to its PVs.
+    // Writes are limited by the wipe throttle.
+    fn lv_write_at(&self, lv: &LV, buf: &[u8], offset: u64) -> Result<()> {
+        let end = offset + buf.len() as u64;
+        let mut limiter = Limiter::new(&self.config.throttles.wipe)?;
+        let mut files = BTreeMap::new();
+
+        for range in lv.extent_map(self)? {
+            let range_start = range.lv_start * SECTOR_SIZE as u64;
+            let range_end = range_start + range.length * SECTOR_SIZE as u64;
+            if range_end <= offset {
+                continue;
+            }
+            if range_start >= end {
+                break;
+            }
+
+            let start = range_start.max(offset);
+            let stop = range_end.min(end);
+
+            let mut pos = start;
+            while pos < stop {
+                // Stay within the PV sectors that follow on from this one.
+                let in_range = pos - range_start;
+                let (device, pv_sector, sectors) = range.locate(in_range / SECTOR_SIZE as u64);
+                let within = in_range % SECTOR_SIZE as u64;
+                let run = sectors * SECTOR_SIZE as u64 - within;
+
+                if let btree_map::Entry::Vacant(entry) = files.entry(device) {
+                    let path = self
+                        .pv_get(device)
+                        .and_then(|pv| pv.path())
+                        .ok_or(Error::DeviceNotFound(device))?;
+                    entry.insert(OpenOptions::new().write(true).open(&path)?);
+                }
+                let f = &files[&device];
+
+                let len = limiter.chunk_size().min(stop - pos).min(run);
+                f.write_all_at(
+                    &buf[(pos - offset) as usize..(pos + len - offset) as usize],
+                    pv_sector * SECTOR_SIZE as u64 + within,
                 )?;
-                dm.device_remove(&DevId::Name(name), &DmOptions::new())?;
+                f.sync_all()?;
+                limiter.account(len);
+                pos += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Wipe known signatures from the start of an LV. LVs whose data is
+    // not directly on PVs are skipped.
+    fn lv_wipe_signatures(&self, lv: &LV) -> Result<()> {
+        let reader = match LvReader::new(lv, self) {
+            Ok(reader) => reader,
+            Err(_) => return Ok(()),
+        };
+
+        let mut buf = Vec::new();
+        reader.take(signature::SCAN_LEN).read_to_end(&mut buf)?;
+
+        for sig in signature::find(&buf) {
+            self.lv_write_at(lv, &vec![0; sig.len as usize], sig.offset)?;
+        }
+
+        Ok(())
+    }
+
+    // Remove the DM devices for an LV. Tearing down a hidden snapshot LV
+    // restores its origin, if it has no other snapshots. Tearing down a
+    // thin LV deletes it from its pool.
+    fn lv_teardown(&mut self, lv: &LV, options: &DeactivateOptions) -> Result<()> {
+        self.lv_teardown_wiping(lv, options, self.config.wipe_signatures_on_remove)
+    }
+
+    // Like `lv_teardown`, with signatures on the LV wiped if `wipe` is set.
+    fn lv_teardown_wiping(
+        &mut self,
+        lv: &LV,
+        options: &DeactivateOptions,
+        wipe: bool,
+    ) -> Result<()> {
+        let dm_name = self.lv_dm_name(lv);
+        let retry = self.config.retry;
+
+        let remove = |dm_name: &str| {
+            if options.deferred {
+                dm::remove_device_deferred(dm_name, &retry)
+            } else {
+                dm::remove_device(dm_name, &retry)
+            }
+        };
+        if lv.device.is_some() {
+            remove(&dm_name)?;
+        }
+        for suffix in lv.layers.keys() {
+            remove(&self.lv_dm_layer_name(lv, suffix))?;
+        }
+
+        if wipe {
+            self.lv_wipe_signatures(lv)?;
+        }
+
+        for seg in lv.segments.iter().filter(|seg| seg.dm_type() == "snapshot") {
+            let origin = &seg.lv_dependencies()[0];
+            let in_use = self.snapshots().iter().any(|(_, o, _)| o == origin);
+            if !in_use && self.lvs.contains_key(origin) {
+                self.lv_unstack_origin(origin)?;
+            }
+        }
 
-                self.commit()
+        for seg in &lv.segments {
+            if let Some(thin) = seg.as_any().downcast_ref::<segment::ThinSegment>() {
+                if self.lvs.contains_key(&thin.thin_pool) {
+                    self.thin_pool_message(&thin.thin_pool, &format!("delete {}", thin.device_id))?;
+                }
             }
         }
+
+        Ok(())
     }
 
     /// The total number of extents in use in the volume group.
     pub fn extents_in_use(&self) -> u64 {
-        self.lvs.values().map(|x| x.used_extents()).sum()
+        self.lvs
+            .values()
+            .flat_map(lv::used_areas)
+            .map(|(_, _, len)| len)
+            .sum()
+    }
+
+    // The number of extents in use on the PV `dev`.
+    pub(crate) fn pv_extents_in_use(&self, dev: Device) -> u64 {
+        self.lvs
+            .values()
+            .flat_map(lv::used_areas)
+            .filter(|(area_dev, _, _)| *area_dev == dev)
+            .map(|(_, _, len)| len)
+            .sum()
+    }
+
+    /// Which extents of each PV are used by LVs, and which are free.
+    pub fn extent_usage(&self) -> ExtentUsage {
+        let to_ranges = |areas: BTreeMap<Device, BTreeMap<u64, u64>>| {
+            areas
+                .into_iter()
+                .map(|(dev, areas)| (dev, merge_areas(&areas)))
+                .collect()
+        };
+
+        ExtentUsage {
+            used: to_ranges(self.used_areas()),
+            free: to_ranges(self.free_areas()),
+        }
     }
 
     /// The total number of free extents in the volume group.
@@ -412,45 +3790,670 @@ impl VG {
         self.extents() - self.extents_in_use()
     }
 
-    /// The total number of extents in the volume group.
-    pub fn extents(&self) -> u64 {
-        self.pvs.values().map(|x| x.pe_count).sum()
+    /// The total number of extents in the volume group.
+    pub fn extents(&self) -> u64 {
+        self.pvs.values().map(|x| x.pe_count).sum()
+    }
+
+    /// Report how much of each metadata area is in use, for each PV in
+    /// the VG that can be found.
+    pub fn metadata_usage(&self) -> Result<BTreeMap<Device, Vec<MdaUsage>>> {
+        let mut usage = BTreeMap::new();
+
+        for pv in self.pvs.values() {
+            if let Some(path) = pv.path() {
+                let pvheader = PvHeader::find_in_dev(&path)?;
+                usage.insert(pv.device, pvheader.mda_usage()?);
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Estimate how much more the metadata can grow, such as how many
+    /// more LVs can be created, before it no longer fits in the smallest
+    /// metadata area.
+    pub fn metadata_headroom(&self) -> Result<MetadataHeadroom> {
+        let capacity = self
+            .metadata_usage()?
+            .values()
+            .flatten()
+            .map(|usage| usage.capacity)
+            .min()
+            .unwrap_or(0);
+
+        let map = to_textmap(self);
+        // Text is written with a trailing null.
+        let text_size = textmap_to_buf(&self.disk_map()).len() as u64 + 1;
+        let available = capacity.saturating_sub(2 * text_size);
+
+        let lv_text_size = match map.textmap_from_textmap("logical_volumes") {
+            Some(lvs) if !lvs.is_empty() => textmap_to_buf(lvs).len() as u64 / lvs.len() as u64,
+            _ => LV_TEXT_SIZE_ESTIMATE,
+        };
+
+        Ok(MetadataHeadroom {
+            capacity,
+            text_size,
+            available,
+            lvs: available / lv_text_size.max(1),
+            segments: available / SEGMENT_TEXT_SIZE_ESTIMATE,
+        })
+    }
+
+    // Check there is likely room in the metadata for more LVs and
+    // segments. Depending on the config, fail or record a warning if not.
+    fn check_metadata_space(&mut self, lvs: u64, segments: u64) -> Result<()> {
+        let headroom = self.metadata_headroom()?;
+        if lvs <= headroom.lvs && segments <= headroom.segments {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "Metadata may not fit in metadata areas: {} bytes of {} in use",
+            headroom.text_size, headroom.capacity
+        );
+        if self.config.refuse_metadata_overflow {
+            return Err(Error::Metadata(msg));
+        }
+        self.warnings.push(msg);
+
+        Ok(())
+    }
+
+    /// Returns the warnings from operations that went ahead despite a
+    /// possible problem, and clears them.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    #[cfg(feature = "monitor")]
+    /// Whether an LV is monitored for events such as filling up. None if
+    /// it has nothing to monitor, i.e. it is not a thin pool, snapshot,
+    /// mirror or RAID LV.
+    pub fn lv_monitored(&self, name: &str) -> Result<Option<bool>> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+        if !lv
+            .segments
+            .iter()
+            .any(|seg| monitor::is_monitorable(seg.dm_type()))
+        {
+            return Ok(None);
+        }
+
+        let state = monitor::load(&self.config.monitor_dir(), &self.id)?;
+        Ok(Some(state.lv_monitored(&lv.id)))
+    }
+
+    #[cfg(feature = "monitor")]
+    /// The LVs that are monitored, for whatever watches them to act on.
+    pub fn monitored_lvs(&self) -> Result<Vec<String>> {
+        let state = monitor::load(&self.config.monitor_dir(), &self.id)?;
+        Ok(self
+            .lvs
+            .values()
+            .filter(|lv| {
+                lv.segments
+                    .iter()
+                    .any(|seg| monitor::is_monitorable(seg.dm_type()))
+            })
+            .filter(|lv| state.lv_monitored(&lv.id))
+            .map(|lv| lv.name.clone())
+            .collect())
+    }
+
+    #[cfg(feature = "monitor")]
+    /// Start or stop monitoring all of the VG's LVs, as `vgchange
+    /// --monitor y|n` does. Settings made for single LVs are forgotten.
+    pub fn set_monitoring(&self, monitor: bool) -> Result<()> {
+        let state = monitor::MonitorState {
+            monitor,
+            lvs: BTreeMap::new(),
+        };
+        monitor::save(&self.config.monitor_dir(), &self.id, &state)
+    }
+
+    #[cfg(feature = "monitor")]
+    /// Start or stop monitoring one LV, as `lvchange --monitor y|n`
+    /// does, whatever is set for the VG.
+    pub fn lv_set_monitoring(&self, name: &str, monitor: bool) -> Result<()> {
+        let lv = self.lvs.get(name).ok_or_else(|| self.lv_not_found(name))?;
+
+        let dir = self.config.monitor_dir();
+        let mut state = monitor::load(&dir, &self.id)?;
+        // LVs removed since are dropped while here.
+        state
+            .lvs
+            .retain(|id, _| self.lvs.values().any(|lv| lv.id == *id));
+        state.lvs.insert(lv.id.clone(), monitor);
+        monitor::save(&dir, &self.id, &state)
+    }
+
+    /// Register a function to be called after each commit, with the
+    /// metadata text written to the PVs and its seqno. The PVs are
+    /// already updated when it is called, so an error from it does not
+    /// fail the commit, and is reported as a warning instead.
+    pub fn add_commit_hook(&mut self, hook: CommitHook) {
+        self.commit_hooks.0.push(hook);
+    }
+
+    /// The PVs that should hold metadata but whose copy is older than
+    /// the VG's, or missing or damaged, as when a commit reached only
+    /// some of them. PVs whose MDAs are all ignored are not included.
+    pub fn stale_pvs(&self) -> Result<Vec<Device>> {
+        let mut stale = Vec::new();
+        for pv in self.pvs.values() {
+            let path = match pv.path() {
+                Some(path) => path,
+                None => continue,
+            };
+            let pvheader = PvHeader::find_in_dev(&path)?;
+            let holds_metadata = pvheader
+                .mda_info()?
+                .iter()
+                .any(|info| !matches!(info.rlocn, Some(ref rl) if rl.ignored));
+            if !holds_metadata {
+                continue;
+            }
+
+            let seqno = pvheader
+                .read_metadata()
+                .ok()
+                .and_then(|map| {
+                    map.textmap_from_textmap(&self.name)?
+                        .i64_from_textmap("seqno")
+                })
+                .map(|seqno| seqno as u64);
+            if seqno.is_none_or(|seqno| seqno < self.seqno) {
+                stale.push(pv.device);
+            }
+        }
+
+        Ok(stale)
+    }
+
+    /// Rewrite the metadata on the PVs `stale_pvs` finds, so that every
+    /// PV agrees with the VG. The seqno is not changed. Returns the PVs
+    /// that were rewritten.
+    pub fn repair_stale_metadata(&mut self) -> Result<Vec<Device>> {
+        self.check_writable()?;
+        // The metadata written must be what the seqno was committed as.
+        if self.dirty {
+            return Err(Error::NotPermitted(
+                "VG has changes that have not been committed".to_string(),
+            ));
+        }
+
+        let _lock = Flock::lock_exclusive(LockScope::VG(self.name.clone()))?;
+        let stale = self.stale_pvs()?;
+        let disk_map = self.disk_map();
+        for dev in &stale {
+            if let Some(path) = self.pvs[dev].path() {
+                PvHeader::find_in_dev(&path)?.write_metadata(&disk_map)?;
+            }
+        }
+
+        Ok(stale)
+    }
+
+    /// Drop content from the metadata that isn't needed to use the VG:
+    /// the records LVM2 keeps of removed LVs. Returns whether anything
+    /// was dropped.
+    pub fn compact_metadata(&mut self) -> bool {
+        self.historical_lvs.take().is_some()
+    }
+
+    fn disk_map(&self) -> LvmTextMap {
+        let map: LvmTextMap = to_textmap(self);
+
+        let mut disk_map = LvmTextMap::new();
+        disk_map.insert(
+            "contents".to_string(),
+            Entry::String("Melvin Text Format Volume Group".to_string()),
+        );
+        disk_map.insert("version".to_string(), Entry::Number(1));
+        disk_map.insert(
+            "description".to_string(),
+            Entry::String(self.description.clone()),
+        );
+        disk_map.insert(
+            "creation_host".to_string(),
+            Entry::String(self.config.creation_host()),
+        );
+        disk_map.insert(
+            "creation_time".to_string(),
+            Entry::Number(self.modified_time),
+        );
+        disk_map.insert(self.name.clone(), Entry::TextMap(Box::new(map)));
+
+        disk_map
+    }
+
+    /// The VG's metadata as text in a canonical form, for keeping in
+    /// version control: the same VG gives the same text on any host, and
+    /// a change to it changes as few lines as it can. What changes with
+    /// every commit, i.e. the seqno and when and why the VG was last
+    /// changed, is left out, as are PVs' device numbers, which differ
+    /// between hosts. PVs are numbered in order of UUID. The text is not
+    /// metadata that can be written to PVs.
+    pub fn to_canonical_text(&self) -> String {
+        let mut pvs: Vec<_> = self.pvs.values().collect();
+        pvs.sort_by(|a, b| a.id.cmp(&b.id));
+        let dev_to_idx = pvs
+            .iter()
+            .enumerate()
+            .map(|(num, pv)| (pv.device, num))
+            .collect();
+
+        let mut map = to_textmap_with_order(self, &dev_to_idx);
+        for key in &["seqno", "melvin_modified_time", "melvin_description"] {
+            map.remove(*key);
+        }
+        if let Some(Entry::TextMap(pvs)) = map.get_mut("physical_volumes") {
+            for pv in pvs.values_mut() {
+                if let Entry::TextMap(pv) = pv {
+                    pv.remove("device");
+                }
+            }
+        }
+
+        let mut outer = LvmTextMap::new();
+        outer.insert(self.name.clone(), Entry::TextMap(Box::new(map)));
+        String::from_utf8_lossy(&textmap_to_canonical_buf(&outer)).into_owned()
+    }
+
+    /// The VG's metadata as LVM2 would write it to a backup file, with
+    /// its "# Generated by" header, values in LVM2's order, and LVM2's
+    /// indentation and blank lines, so that it can be compared line by
+    /// line with metadata LVM2 wrote.
+    pub fn to_lvm2_text(&self) -> String {
+        String::from_utf8_lossy(&backup::metadata_file_text(
+            &self.disk_map(),
+            &self.description,
+        ))
+        .into_owned()
+    }
+
+    /// Perform several operations on the VG, such as creating many LVs,
+    /// and write the metadata to the PVs once when they are done, rather
+    /// than after each operation.
+    ///
+    /// If `f` fails, the changes it made up to that point are still
+    /// written, since they may already have been made in device-mapper.
+    pub fn batch<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut VG) -> Result<()>,
+    {
+        // Batches may nest; only the outermost one commits.
+        let outer_deferred = self.defer_commit;
+
+        self.defer_commit = true;
+        let res = f(self);
+        self.defer_commit = outer_deferred;
+
+        if self.dirty && !self.defer_commit {
+            self.commit()?;
+        }
+
+        res
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            seqno: self.seqno,
+            status: self.status.clone(),
+            flags: self.flags.clone(),
+            tags: self.tags.clone(),
+            profile: self.profile.clone(),
+            metadata_copies: self.metadata_copies,
+            metadata_tags: self.metadata_tags.clone(),
+            historical_lvs: self.historical_lvs.clone(),
+            write_version: self.write_version,
+            pvs: self.pvs.clone(),
+            lvs: self.copy_lvs()?,
+            removed_lvs: Vec::new(),
+        })
+    }
+
+    // A copy of the VG's LVs and their DM devices. Segments can't be
+    // cloned, so the LVs are written out as metadata and read back.
+    fn copy_lvs(&self) -> Result<BTreeMap<String, LV>> {
+        let dev_to_idx: BTreeMap<Device, usize> = self
+            .pvs
+            .values()
+            .enumerate()
+            .map(|(num, pv)| (pv.device, num))
+            .collect();
+        let str_to_pv: BTreeMap<String, PV> = self
+            .pvs
+            .values()
+            .enumerate()
+            .map(|(num, pv)| (format!("pv{}", num), pv.clone()))
+            .collect();
+
+        self.lvs
+            .iter()
+            .map(|(name, lv)| {
+                let map = lv::to_textmap(lv, &dev_to_idx);
+                let mut copy = lv::from_textmap(name, &map, &str_to_pv)?;
+                copy.device = lv.device;
+                copy.layers = lv.layers.clone();
+                copy.activation_name = lv.activation_name.clone();
+                Ok((name.clone(), copy))
+            })
+            .collect()
+    }
+
+    // Start deferring commits until flush_deferred() is called.
+    pub(crate) fn begin_deferred(&mut self) -> Result<()> {
+        self.checkpoint = Some(self.checkpoint()?);
+        self.defer_commit = true;
+        Ok(())
+    }
+
+    // Write out changes made since begin_deferred() or the last flush,
+    // and finish removing LVs that were removed in the meantime.
+    pub(crate) fn flush_deferred(&mut self) -> Result<()> {
+        if let Some(mut checkpoint) = self.checkpoint.take() {
+            // In order of removal, since COW stores must go before their
+            // snapshot LVs.
+            while !checkpoint.removed_lvs.is_empty() {
+                let (lv, options) = checkpoint.removed_lvs.remove(0);
+                if let Err(e) = self.lv_teardown(&lv, &options) {
+                    checkpoint.removed_lvs.insert(0, (lv, options));
+                    self.checkpoint = Some(checkpoint);
+                    return Err(e);
+                }
+            }
+        }
+
+        if self.dirty {
+            self.defer_commit = false;
+            let res = self.commit();
+            self.defer_commit = true;
+            res?;
+        }
+
+        self.checkpoint = Some(self.checkpoint()?);
+        Ok(())
+    }
+
+    // Undo changes made since begin_deferred() or the last flush. The
+    // LVs return to their state at the checkpoint, and their DM devices
+    // are made to match it again, on a best-effort basis: see
+    // `rollback_devices`.
+    pub(crate) fn rollback_deferred(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            // The tables of the active LVs, by UUID, to tell which changed.
+            let tables: BTreeMap<String, Vec<TableLine>> = self
+                .lvs
+                .values()
+                .filter(|lv| lv.device.is_some())
+                .filter_map(|lv| Some((lv.id.clone(), self.lv_table(lv).ok()?)))
+                .collect();
+
+            // LVs removed since the checkpoint still have their devices.
+            let mut current: BTreeMap<String, LV> = std::mem::take(&mut self.lvs)
+                .into_values()
+                .chain(checkpoint.removed_lvs.into_iter().map(|(lv, _)| lv))
+                .map(|lv| (lv.id.clone(), lv))
+                .collect();
+
+            // Each LV at the checkpoint gets the devices it has now, so
+            // that they can be changed back from there.
+            let mut lvs = checkpoint.lvs;
+            let mut was_active = BTreeSet::new();
+            let mut renamed = Vec::new();
+            for lv in lvs.values_mut() {
+                if lv.device.is_some() {
+                    was_active.insert(lv.name.clone());
+                }
+                if let Some(now) = current.remove(&lv.id) {
+                    if now.name != lv.name {
+                        renamed.push((now.name, lv.name.clone()));
+                    }
+                    lv.device = now.device;
+                    lv.layers = now.layers;
+                    lv.activation_name = now.activation_name;
+                }
+            }
+            // What is left was created since the checkpoint.
+            let created = current
+                .into_values()
+                .map(|lv| (lv.name.clone(), lv))
+                .collect();
+
+            self.lvs = lvs;
+            self.seqno = checkpoint.seqno;
+            self.pending_ops.clear();
+            self.status = checkpoint.status;
+            self.flags = checkpoint.flags;
+            self.tags = checkpoint.tags;
+            self.profile = checkpoint.profile;
+            self.metadata_copies = checkpoint.metadata_copies;
+            self.metadata_tags = checkpoint.metadata_tags;
+            self.historical_lvs = checkpoint.historical_lvs;
+            self.write_version = checkpoint.write_version;
+            self.pvs = checkpoint.pvs;
+
+            self.rollback_devices(&tables, &renamed, &was_active, created);
+        }
+
+        self.dirty = false;
+        self.defer_commit = false;
+    }
+
+    // Make the DM devices match the LVs again after they were rolled
+    // back. `tables` are the tables the active LVs had before, by UUID,
+    // `renamed` the LVs that had been renamed, as (name before the
+    // rollback, name after), `was_active` the LVs active at the
+    // checkpoint, and `created` the LVs created since then. Failures are
+    // ignored, so that as much as possible is put back.
+    fn rollback_devices(
+        &mut self,
+        tables: &BTreeMap<String, Vec<TableLine>>,
+        renamed: &[(String, String)],
+        was_active: &BTreeSet<String>,
+        mut created: BTreeMap<String, LV>,
+    ) {
+        for (now, before) in renamed {
+            let lv = &self.lvs[before];
+            if lv.activation_name.is_none() {
+                let _ = self.rename_dm_devices(lv, now, before);
+            }
+        }
+
+        // Devices removed since the checkpoint are created again, such as
+        // a cache's origin LV once the cache was detached.
+        let gone: Vec<_> = was_active
+            .iter()
+            .filter(|name| {
+                let lv = &self.lvs[*name];
+                lv.device.is_none() || !matches!(dm::find_device(&self.lv_dm_name(lv)), Ok(Some(_)))
+            })
+            .cloned()
+            .collect();
+        for name in &gone {
+            let lv = self.lvs.get_mut(name).expect("exists");
+            lv.device = None;
+            lv.layers.clear();
+        }
+        for name in &gone {
+            let _ = self.lv_activate(name);
+        }
+
+        // Tables that changed, such as by extending an LV or attaching a
+        // cache to it, are loaded again, lowest first.
+        for name in dependency_order(&self.lvs) {
+            let lv = &self.lvs[&name];
+            let changed = lv.device.is_some()
+                && tables
+                    .get(&lv.id)
+                    .is_some_and(|table| self.lv_table(lv).ok().as_ref() != Some(table));
+            if changed {
+                let _ = self.lv_refresh(&name);
+            }
+        }
+
+        // The devices of LVs created since the checkpoint are removed, and
+        // then LVs activated since then, such as to build the new ones
+        // on, are deactivated, users first. Created LVs may be layers over
+        // existing data, such as a cache's origin, so their signatures
+        // are left alone.
+        for name in dependency_order(&created).iter().rev() {
+            let lv = created.remove(name).expect("exists");
+            let _ = self.lv_teardown_wiping(&lv, &DeactivateOptions::default(), false);
+        }
+        for name in dependency_order(&self.lvs).iter().rev() {
+            if self.lvs[name].device.is_some() && !was_active.contains(name) {
+                let _ = self.lv_deactivate(name);
+            }
+        }
+    }
+
+    // Whether there are changes that have not been written to the PVs.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // Commit the changes made by an operation, described by `op`, e.g.
+    // "create LV data". If commits are deferred, the description is
+    // kept until they are written.
+    fn commit_op(&mut self, op: &str) -> Result<()> {
+        self.pending_ops.push(op.to_string());
+        self.commit()
     }
 
     fn commit(&mut self) -> Result<()> {
+        self.check_writable()?;
+
+        // Writing would lose whatever the newer version added.
+        if self.metadata_version > migrate::METADATA_VERSION {
+            return Err(Error::Metadata(format!(
+                "VG {} is from a newer version of Melvin ({} > {})",
+                self.name,
+                self.metadata_version,
+                migrate::METADATA_VERSION
+            )));
+        }
+
+        if self.defer_commit {
+            self.dirty = true;
+            return Ok(());
+        }
+
+        // Held until the new metadata is current on every PV, so that no
+        // one takes the commit for an interrupted one and undoes it.
+        let _lock = Flock::lock_exclusive(LockScope::VG(self.name.clone()))?;
+
+        self.dirty = false;
         self.seqno += 1;
+        self.modified_time = now().to_timespec().sec;
+        self.description = self.pending_ops.drain(..).collect::<Vec<_>>().join("; ");
 
-        let map: LvmTextMap = to_textmap(self);
+        let mut disk_map = self.disk_map();
 
-        let mut disk_map = LvmTextMap::new();
-        disk_map.insert(
-            "contents".to_string(),
-            Entry::String("Melvin Text Format Volume Group".to_string()),
-        );
-        disk_map.insert("version".to_string(), Entry::Number(1));
-        disk_map.insert("description".to_string(), Entry::String("".to_string()));
-        disk_map.insert(
-            "creation_host".to_string(),
-            Entry::String(uname().nodename().to_string()),
-        );
-        disk_map.insert(
-            "creation_time".to_string(),
-            Entry::Number(now().to_timespec().sec),
-        );
-        disk_map.insert(self.name.clone(), Entry::TextMap(Box::new(map)));
+        // The text is always regenerated from the parsed VG, so it is
+        // already free of stale keys and extra whitespace. Compacting only
+        // needs to happen if LVM2 left optional content behind.
+        if self.historical_lvs.is_some() {
+            let text_len = textmap_to_buf(&disk_map).len() as u64;
+            let capacity = self
+                .metadata_usage()?
+                .values()
+                .flatten()
+                .map(|usage| usage.capacity)
+                .min();
 
-        // TODO: atomicity of updating pvs, metad, dm
-        for pv in self.pvs.values() {
-            if let Some(path) = pv.path() {
-                let mut pvheader = PvHeader::find_in_dev(&path).expect("could not find pvheader");
+            if let Some(capacity) = capacity {
+                if text_len * 100 / capacity > METADATA_COMPACT_THRESHOLD {
+                    self.compact_metadata();
+                    disk_map = self.disk_map();
+                }
+            }
+        }
 
-                pvheader.write_metadata(&disk_map)?;
+        self.balance_metadata()?;
+
+        if self.config.backup.archive {
+            if let Err(e) = self.archive_metadata() {
+                self.warnings
+                    .push(format!("could not archive metadata: {:?}", e));
+            }
+        }
+
+        // The new metadata is written to every PV before it is made
+        // current on any, so that if this is interrupted, `recover_commit`
+        // can tell whether to finish or undo it. If a PV can't be found
+        // or written, the metadata precommitted so far is discarded.
+        let mut precommitted = Vec::new();
+        for path in self.pvs.values().filter_map(|pv| pv.path()) {
+            let result = PvHeader::find_in_dev(&path).and_then(|pvheader| {
+                match pvheader.precommit_metadata(&disk_map) {
+                    Ok(rlocns) => Ok((pvheader, rlocns)),
+                    Err(e) => {
+                        let _ = pvheader.discard_precommitted();
+                        Err(e)
+                    }
+                }
+            });
+            match result {
+                Ok(pv_precommitted) => precommitted.push(pv_precommitted),
+                Err(e) => {
+                    for (pvheader, _) in &precommitted {
+                        let _ = pvheader.discard_precommitted();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for (pvheader, rlocns) in &precommitted {
+            pvheader.commit_precommitted(rlocns)?;
+        }
+
+        if self.config.backup.backup {
+            if let Err(e) = backup::backup(
+                &self.config.backup,
+                &self.name,
+                &disk_map,
+                &self.description,
+            ) {
+                self.warnings
+                    .push(format!("could not back up metadata: {:?}", e));
+            }
+        }
+
+        if !self.commit_hooks.0.is_empty() {
+            let text = textmap_to_buf(&disk_map);
+            for hook in &self.commit_hooks.0 {
+                if let Err(e) = hook(&text, self.seqno) {
+                    self.warnings.push(format!(
+                        "commit hook failed for seqno {}: {:?}",
+                        self.seqno, e
+                    ));
+                }
             }
         }
 
         Ok(())
     }
 
+    // Archive the metadata on the PVs, before it is replaced by a commit
+    // of the pending operations.
+    fn archive_metadata(&self) -> Result<()> {
+        let path = match self.pvs.values().find_map(|pv| pv.path()) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // A new VG has nothing to archive yet.
+        let map = match PvHeader::find_in_dev(&path)?.read_metadata() {
+            Ok(map) => map,
+            Err(_) => return Ok(()),
+        };
+
+        backup::archive(&self.config.backup, &self.name, &map, &self.description)
+    }
+
     // Returns used areas in the format: {Device: {start: len} }
     //
     // e.g. with {<Device 3:1>: {0: 45, 47: 100, 147: 200} }
@@ -482,41 +4485,53 @@ impl VG {
     // extents, calling free_areas would result in:
     // {<Device 3:1>: {45: 2, 347: 653} }
     //
+    // Every PV is in the outer map, with an empty map if it is full.
+    //
     fn free_areas(&self) -> BTreeMap<Device, BTreeMap<u64, u64>> {
+        let mut used_map = self.used_areas();
         let mut free_map = BTreeMap::new();
 
-        for (dev, mut area_map) in self.used_areas() {
+        for (dev, pv) in &self.pvs {
+            let mut area_map = used_map.remove(dev).unwrap_or_default();
+
+            // Extents overlapping the bootloader area are never free
+            if let Some((start, len)) = pv.ba_extents(self.extent_size) {
+                let entry = area_map.entry(start).or_insert(0);
+                *entry = (*entry).max(len);
+            }
+
             // Insert an entry to mark the end of the PV so the fold works
             // correctly
-            let pv = self
-                .pvs
-                .get(&dev)
-                .expect("area map name refers to nonexistent PV");
             area_map.insert(pv.pe_count, 0);
 
+            let mut free = BTreeMap::new();
             area_map.iter().fold(0, |prev_end, (start, len)| {
                 if prev_end < *start {
-                    free_map
-                        .entry(dev)
-                        .or_insert_with(BTreeMap::new)
-                        .insert(prev_end, start - prev_end);
+                    free.insert(prev_end, start - prev_end);
                 }
-                start + len
+                prev_end.max(start + len)
             });
-        }
-
-        // Also return completely-unused PVs
-        for (dev, pv) in &self.pvs {
-            if !free_map.contains_key(dev) {
-                let mut map = BTreeMap::new();
-                map.insert(0, pv.pe_count);
-                free_map.insert(*dev, map);
-            }
+            free_map.insert(*dev, free);
         }
 
         free_map
     }
 
+    /// How many extents `size` is in this VG. Byte sizes are rounded
+    /// up to whole extents, and percentages down.
+    pub fn size_to_extents(&self, size: Size) -> Result<u64> {
+        let extent_bytes = self.extent_size * SECTOR_SIZE as u64;
+        match size {
+            Size::Extents(extents) => Ok(extents),
+            Size::Bytes(bytes) => Ok(bytes.div_ceil(extent_bytes)),
+            Size::PercentFree(pct) if pct <= 100 => Ok(self.extents_free() * pct as u64 / 100),
+            Size::PercentVg(pct) if pct <= 100 => Ok(self.extents() * pct as u64 / 100),
+            _ => Err(Error::InvalidArgument(
+                "Percentages must be at most 100".to_string(),
+            )),
+        }
+    }
+
     /// Returns a list of PV Devices that make up the VG.
     pub fn pv_list(&self) -> Vec<Device> {
         self.pvs.keys().copied().collect()
@@ -551,15 +4566,293 @@ impl VG {
     pub fn extent_size(&self) -> u64 {
         self.extent_size
     }
+
+    /// Returns the generation of metadata the VG represents. It goes up
+    /// by one each time the metadata is written, so a cache of anything
+    /// derived from the metadata is stale if the seqno has changed.
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    /// Returns when the metadata was last written, in seconds since the
+    /// epoch, or 0 if it was last written by something other than
+    /// Melvin.
+    pub fn modified_time(&self) -> i64 {
+        self.modified_time
+    }
+
+    /// Returns a description of the operations that produced the current
+    /// seqno, e.g. "create LV data; extend LV data by 10 extents". Empty
+    /// if the metadata was last written by something other than Melvin.
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub(crate) fn status(&self) -> &[String] {
+        &self.status
+    }
+
+    pub(crate) fn flags(&self) -> &[String] {
+        &self.flags
+    }
+
+    /// What would change in going from this VG's state to `other`'s.
+    pub fn diff(&self, other: &VG) -> VgDiff {
+        VgDiff::new(&self.clone_state(), &other.clone_state())
+    }
+
+    /// Returns the VG's tags.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns the name of the VG's profile, if it has one.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Take an immutable view of the VG's current state, which can be
+    /// shared with other threads while this one goes on changing the VG.
+    pub fn clone_state(&self) -> VgState {
+        VgState::new(self)
+    }
+
+    /// Returns the version of Melvin's metadata extensions the VG was
+    /// loaded with. Metadata written by LVM2 is version 0. VGs with a
+    /// version newer than `METADATA_VERSION` can be read but not changed.
+    pub fn metadata_version(&self) -> u64 {
+        self.metadata_version
+    }
+
+    /// Write the VG's metadata as an older `version` of Melvin's metadata
+    /// extensions, so that deployments of Melvin that only know that
+    /// version can change the VG, not only read it. Settings that later
+    /// versions added, such as the VG's description or LVs' snapshot
+    /// schedules, are left out. Later commits through this VG are
+    /// written as `version` too.
+    pub fn downgrade_metadata(&mut self, version: u64) -> Result<()> {
+        if version > migrate::METADATA_VERSION {
+            return Err(Error::Metadata(format!(
+                "Can't write VG {} as version {}, newer than {}",
+                self.name,
+                version,
+                migrate::METADATA_VERSION
+            )));
+        }
+        if version == self.write_version {
+            return Ok(());
+        }
+        self.write_version = version;
+
+        self.commit_op(&format!("downgrade metadata to version {}", version))
+    }
+
+    /// Returns the runtime settings used by the VG.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Change the runtime settings used by the VG.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Returns the name that will be recorded as the creation host of
+    /// new LVs and metadata.
+    pub fn creation_host(&self) -> String {
+        self.config.creation_host()
+    }
+}
+
+// Tags may hold letters, digits, and "_+.-/=!:&#", may not start with
+// a hyphen, and are at most 1024 characters, as in LVM2.
+fn check_tag(tag: &str) -> Result<()> {
+    let valid = !tag.is_empty()
+        && tag.len() <= 1024
+        && !tag.starts_with('-')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+.-/=!:&#".contains(c));
+    if !valid {
+        return Err(Error::InvalidArgument(format!("Invalid tag {}", tag)));
+    }
+    Ok(())
+}
+
+// Add a tag to a list if it isn't there already. Returns whether it
+// was added.
+fn add_tag(tags: &mut Vec<String>, tag: &str) -> bool {
+    if tags.iter().any(|t| t == tag) {
+        return false;
+    }
+    tags.push(tag.to_string());
+    true
+}
+
+// Remove a tag from a list. Returns whether it was there.
+fn del_tag(tags: &mut Vec<String>, tag: &str) -> bool {
+    let len = tags.len();
+    tags.retain(|t| t != tag);
+    tags.len() != len
+}
+
+// A PV's reserved areas, as (start, end) in sectors.
+fn reserved_sectors(pvh: &PvHeader) -> Vec<(u64, u64)> {
+    pvh.reserved_areas()
+        .iter()
+        .map(|area| {
+            (
+                area.offset / SECTOR_SIZE as u64,
+                align_to(area.offset + area.size, SECTOR_SIZE as u64) / SECTOR_SIZE as u64,
+            )
+        })
+        .collect()
+}
+
+// Where the extents of a new PV start, in sectors, and how many fit in
+// its data area `da`. pe_start is aligned to the extent size, and moved
+// past any reserved area it would overlap.
+fn pv_extents(pvh: &PvHeader, da: &PvArea, extent_size: u64) -> (u64, u64) {
+    let reserved = reserved_sectors(pvh);
+
+    let mut pe_start = (da.offset / SECTOR_SIZE as u64).div_ceil(extent_size) * extent_size;
+    while let Some(&(_, end)) = reserved
+        .iter()
+        .find(|(start, end)| *start <= pe_start && pe_start < *end)
+    {
+        pe_start = end.div_ceil(extent_size) * extent_size;
+    }
+
+    (pe_start, extents_after(pvh, pe_start, extent_size))
+}
+
+// Whether extents `start..start + len` of the PV `pv` overlap any of
+// its reserved areas `reserved`.
+fn overlaps_reserved(pv: &PV, reserved: &[PvArea], extent_size: u64, start: u64, len: u64) -> bool {
+    let sector_bytes = SECTOR_SIZE as u64;
+    let range_start = (pv.pe_start + start * extent_size) * sector_bytes;
+    let range_end = range_start + len * extent_size * sector_bytes;
+
+    reserved
+        .iter()
+        .any(|area| area.offset < range_end && range_start < area.offset + area.size)
+}
+
+// How many extents fit between pe_start and the first reserved area
+// after it, such as the trailing metadata area, or the end of the PV.
+fn extents_after(pvh: &PvHeader, pe_start: u64, extent_size: u64) -> u64 {
+    let data_end = reserved_sectors(pvh)
+        .iter()
+        .map(|(start, _)| *start)
+        .filter(|start| *start > pe_start)
+        .fold(pvh.size / SECTOR_SIZE as u64, u64::min);
+    data_end.saturating_sub(pe_start) / extent_size
+}
+
+// Remove an allocated area from a map of free areas, as from
+// `VG::free_areas`.
+fn take_area(
+    free_areas: &mut BTreeMap<Device, BTreeMap<u64, u64>>,
+    dev: Device,
+    start: u64,
+    len: u64,
+) {
+    let areas = match free_areas.get_mut(&dev) {
+        Some(areas) => areas,
+        None => return,
+    };
+    let (area_start, area_len) = match areas.range(..=start).next_back() {
+        Some((area_start, area_len)) => (*area_start, *area_len),
+        None => return,
+    };
+
+    areas.remove(&area_start);
+    if start > area_start {
+        areas.insert(area_start, start - area_start);
+    }
+    if area_start + area_len > start + len {
+        areas.insert(start + len, area_start + area_len - (start + len));
+    }
+}
+
+// Whether an LV's device must reject writes, because the LV is not
+// writable or has segments that are only activated read-only.
+fn lv_read_only(lv: &LV) -> bool {
+    !lv.status.iter().any(|s| s == "WRITE") || lv.segments.iter().any(|seg| seg.read_only())
+}
+
+// The first segment of an LV whose type is not supported, if any.
+fn opaque_segment(lv: &LV) -> Option<&segment::OpaqueSegment> {
+    lv.segments
+        .iter()
+        .find_map(|seg| seg.as_any().downcast_ref::<segment::OpaqueSegment>())
+}
+
+// The names of the LVs in `lvs`, each after the LVs it is built on.
+fn dependency_order(lvs: &BTreeMap<String, LV>) -> Vec<String> {
+    fn visit(
+        name: &str,
+        lvs: &BTreeMap<String, LV>,
+        seen: &mut BTreeSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        let lv = match lvs.get(name) {
+            Some(lv) => lv,
+            None => return,
+        };
+        if !seen.insert(name.to_string()) {
+            return;
+        }
+        for dep in lv.segments.iter().flat_map(|seg| seg.lv_dependencies()) {
+            visit(&dep, lvs, seen, order);
+        }
+        order.push(name.to_string());
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut order = Vec::new();
+    for name in lvs.keys() {
+        visit(name, lvs, &mut seen, &mut order);
+    }
+
+    order
 }
 
 fn to_textmap(vg: &VG) -> LvmTextMap {
+    // See comment in from_textmap() - we need to assign ordinals to
+    // the PV map so the textmap can use "pv0"-style strings to link
+    // pvs with LV stripes.
+    let dev_to_idx: BTreeMap<Device, usize> = vg
+        .pvs
+        .values()
+        .enumerate()
+        .map(|(num, pv)| (pv.device, num))
+        .collect();
+
+    to_textmap_with_order(vg, &dev_to_idx)
+}
+
+// Like `to_textmap`, with the PVs numbered as `dev_to_idx` says.
+fn to_textmap_with_order(vg: &VG, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
     let mut map = LvmTextMap::new();
 
     map.insert("id".to_string(), Entry::String(vg.id.clone()));
     map.insert("seqno".to_string(), Entry::Number(vg.seqno as i64));
     map.insert("format".to_string(), Entry::String(vg.format.clone()));
 
+    if vg.modified_time != 0 {
+        map.insert(
+            "melvin_modified_time".to_string(),
+            Entry::Number(vg.modified_time),
+        );
+    }
+    if !vg.description.is_empty() {
+        map.insert(
+            "melvin_description".to_string(),
+            Entry::String(vg.description.clone()),
+        );
+    }
+
     map.insert("max_pv".to_string(), Entry::Number(0));
     map.insert("max_lv".to_string(), Entry::Number(0));
 
@@ -573,6 +4866,13 @@ fn to_textmap(vg: &VG) -> LvmTextMap {
         Entry::List(vg.flags.iter().map(|x| Entry::String(x.clone())).collect()),
     );
 
+    if !vg.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(vg.tags.iter().map(|x| Entry::String(x.clone())).collect()),
+        );
+    }
+
     map.insert(
         "extent_size".to_string(),
         Entry::Number(vg.extent_size as i64),
@@ -581,16 +4881,21 @@ fn to_textmap(vg: &VG) -> LvmTextMap {
         "metadata_copies".to_string(),
         Entry::Number(vg.metadata_copies as i64),
     );
+    if !vg.metadata_tags.is_empty() {
+        map.insert(
+            "melvin_metadata_tags".to_string(),
+            Entry::List(
+                vg.metadata_tags
+                    .iter()
+                    .map(|x| Entry::String(x.clone()))
+                    .collect(),
+            ),
+        );
+    }
 
-    // See comment in from_textmap() - we need to assign ordinals to
-    // the PV map so the textmap can use "pv0"-style strings to link
-    // pvs with LV stripes.
-    let dev_to_idx: BTreeMap<Device, usize> = vg
-        .pvs
-        .values()
-        .enumerate()
-        .map(|(num, pv)| (pv.device, num))
-        .collect();
+    if let Some(ref profile) = vg.profile {
+        map.insert("profile".to_string(), Entry::String(profile.clone()));
+    }
 
     map.insert(
         "physical_volumes".to_string(),
@@ -607,6 +4912,13 @@ fn to_textmap(vg: &VG) -> LvmTextMap {
         )),
     );
 
+    if let Some(ref historical_lvs) = vg.historical_lvs {
+        map.insert(
+            "historical_logical_volumes".to_string(),
+            Entry::TextMap(Box::new(historical_lvs.clone())),
+        );
+    }
+
     if !vg.lvs.is_empty() {
         map.insert(
             "logical_volumes".to_string(),
@@ -616,7 +4928,7 @@ fn to_textmap(vg: &VG) -> LvmTextMap {
                     .map(|(k, v)| {
                         (
                             k.clone(),
-                            Entry::TextMap(Box::new(lv::to_textmap(v, &dev_to_idx))),
+                            Entry::TextMap(Box::new(lv::to_textmap(v, dev_to_idx))),
                         )
                     })
                     .collect(),
@@ -624,5 +4936,422 @@ fn to_textmap(vg: &VG) -> LvmTextMap {
         );
     }
 
+    migrate::set_version(&mut map, vg.write_version);
+
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::parser::buf_to_textmap;
+
+    const MIB: u64 = 1 << 20;
+    // 4 MiB, in sectors
+    const EXTENT_SIZE: u64 = 8192;
+
+    // A pvheader as `PvHeader::initialize` lays one out, on a device of
+    // `size` bytes, with a metadata area at the start and another at the
+    // end, and the given bootloader areas.
+    fn synthetic_pvh(size: u64, bootloader_areas: Vec<PvArea>) -> PvHeader {
+        let mda0_offset = 8 * SECTOR_SIZE as u64;
+        let mda1_offset = (size - DEFAULT_MDA_SIZE) / SECTOR_SIZE as u64 * SECTOR_SIZE as u64;
+
+        PvHeader {
+            uuid: "Bd2Q1V3sWkpQ3vXTbZfmSrfE5G2rDq0v".to_string(),
+            size,
+            ext_version: 2,
+            ext_flags: 0,
+            data_areas: vec![PvArea {
+                offset: DEFAULT_MDA_SIZE,
+                size: 0,
+            }],
+            metadata_areas: vec![
+                PvArea {
+                    offset: mda0_offset,
+                    size: DEFAULT_MDA_SIZE - mda0_offset,
+                },
+                PvArea {
+                    offset: mda1_offset,
+                    size: size - mda1_offset,
+                },
+            ],
+            bootloader_areas,
+            dev_path: PathBuf::from("/dev/null"),
+        }
+    }
+
+    // A VG with one PV on the device of `pvh`, with its extents placed
+    // as `pv_add` places them.
+    fn vg_with_pv(pvh: &PvHeader) -> VG {
+        vg_with_pv_and_lvs(pvh, "")
+    }
+
+    // Likewise, with the LVs in the text of a "logical_volumes" section.
+    fn vg_with_pv_and_lvs(pvh: &PvHeader, lvs: &str) -> VG {
+        let (pe_start, pe_count) = pv_extents(pvh, &pvh.data_areas[0], EXTENT_SIZE);
+        let ba = pvh
+            .bootloader_areas
+            .first()
+            .copied()
+            .unwrap_or(PvArea { offset: 0, size: 0 });
+
+        let text = format!(
+            "vg0 {{
+id = \"Hc1mpX-7Yxq-iBv0-0oTG-0EGm-Tv9N-5CuoSZ\"
+seqno = 1
+format = \"lvm2\"
+status = [\"RESIZEABLE\", \"READ\", \"WRITE\"]
+flags = []
+extent_size = {}
+max_lv = 0
+max_pv = 0
+metadata_copies = 0
+physical_volumes {{
+pv0 {{
+id = \"Bd2Q1V-3sWk-pQ3v-XTbZ-fmSr-fE5G-2rDq0v\"
+device = 2049
+status = [\"ALLOCATABLE\"]
+flags = []
+dev_size = {}
+pe_start = {}
+pe_count = {}
+ba_start = {}
+ba_size = {}
+}}
+}}
+logical_volumes {{
+{}
+}}
+}}
+",
+            EXTENT_SIZE,
+            pvh.size / SECTOR_SIZE as u64,
+            pe_start,
+            pe_count,
+            ba.offset / SECTOR_SIZE as u64,
+            ba.size / SECTOR_SIZE as u64,
+            lvs,
+        );
+        let map = buf_to_textmap(text.as_bytes()).unwrap();
+        let vg = map.textmap_from_textmap("vg0").unwrap();
+
+        VG::from_textmap("vg0", vg).unwrap()
+    }
+
+    // Check that neither the free areas of a VG with a PV on `pvh`, nor
+    // anything allocated from them, overlap the PV's reserved areas or
+    // run past the end of the device.
+    fn check_never_reserved(pvh: &PvHeader) {
+        let vg = vg_with_pv(pvh);
+        let pv = vg.pvs.values().next().unwrap();
+        let reserved = pvh.reserved_areas();
+        let dev_end = pvh.size / SECTOR_SIZE as u64;
+        assert!(pv.pe_count > 0);
+        assert!(pv.pe_start + pv.pe_count * EXTENT_SIZE <= dev_end);
+
+        let free_areas = vg.free_areas();
+        let free: u64 = free_areas.values().flat_map(|areas| areas.values()).sum();
+        for (start, len) in &free_areas[&pv.device] {
+            assert!(!overlaps_reserved(pv, &reserved, EXTENT_SIZE, *start, *len));
+        }
+
+        for policy in &[AllocPolicy::Normal, AllocPolicy::Contiguous] {
+            let options = AllocOptions {
+                policy: *policy,
+                allowed_pvs: Vec::new(),
+            };
+            for extents in &[1, free / 2, free] {
+                let areas = match vg.alloc_from(free_areas.clone(), &[], *extents, &options) {
+                    Ok(areas) => areas,
+                    // Not all of it need be contiguous.
+                    Err(Error::Alloc {
+                        contiguous: true, ..
+                    }) => continue,
+                    Err(e) => panic!("{}", e),
+                };
+                assert_eq!(areas.iter().map(|(_, _, len)| len).sum::<u64>(), *extents);
+                for (_, start, len) in areas {
+                    assert!(!overlaps_reserved(pv, &reserved, EXTENT_SIZE, start, len));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_sectors_round_out() {
+        let pvh = synthetic_pvh(
+            64 * MIB + 7 * SECTOR_SIZE as u64,
+            vec![PvArea {
+                offset: 20 * MIB + 100,
+                size: 1000,
+            }],
+        );
+
+        // The label, both MDAs and the BA, which starts and ends within
+        // sectors and so takes up the whole of each.
+        assert_eq!(
+            reserved_sectors(&pvh),
+            vec![(0, 4), (8, 2048), (129031, 131079), (40960, 40963),]
+        );
+    }
+
+    #[test]
+    fn extents_end_before_trailing_mda() {
+        // Not a whole number of extents, so the trailing MDA does not
+        // start on an extent boundary.
+        let pvh = synthetic_pvh(1024 * MIB + 3 * MIB + 7 * SECTOR_SIZE as u64, Vec::new());
+        let (pe_start, pe_count) = pv_extents(&pvh, &pvh.data_areas[0], EXTENT_SIZE);
+
+        assert_eq!(pe_start, EXTENT_SIZE);
+        // 1027 MiB and a bit, less the trailing 1 MiB MDA and the first
+        // extent, leaves 1022 MiB, or 255 whole extents.
+        assert_eq!(pe_count, 255);
+        check_never_reserved(&pvh);
+    }
+
+    #[test]
+    fn extents_skip_bootloader_area() {
+        // A BA within the data area, as tools other than LVM2 may place
+        // one, starting and ending partway through extents.
+        let pvh = synthetic_pvh(
+            1024 * MIB + 5 * SECTOR_SIZE as u64,
+            vec![PvArea {
+                offset: 301 * MIB + 512,
+                size: 2 * MIB + 512,
+            }],
+        );
+
+        check_never_reserved(&pvh);
+    }
+
+    #[test]
+    fn extents_start_after_bootloader_area() {
+        // A BA where LVM2 puts one, after the first MDA, running past
+        // where the first extent would otherwise be.
+        let pvh = synthetic_pvh(
+            512 * MIB + 13 * SECTOR_SIZE as u64,
+            vec![PvArea {
+                offset: DEFAULT_MDA_SIZE,
+                size: 4 * MIB + 4096,
+            }],
+        );
+        let (pe_start, _) = pv_extents(&pvh, &pvh.data_areas[0], EXTENT_SIZE);
+
+        assert_eq!(pe_start, 2 * EXTENT_SIZE);
+        check_never_reserved(&pvh);
+    }
+
+    #[test]
+    fn size_to_extents_past_32_bits() {
+        const TIB: u64 = 1 << 40;
+        let extent_bytes = EXTENT_SIZE * SECTOR_SIZE as u64;
+
+        // 2^32 sectors, and 100 TiB
+        for size in &[2 * TIB, 100 * TIB] {
+            let vg = vg_with_pv(&synthetic_pvh(*size, Vec::new()));
+            let extents = vg.extents();
+            // All but the first extent, and the last, part of which the
+            // trailing MDA takes up
+            assert_eq!(extents, size / extent_bytes - 2);
+            assert_eq!(vg.extents_free(), extents);
+
+            let bytes = |bytes| vg.size_to_extents(Size::Bytes(bytes)).unwrap();
+            assert_eq!(bytes(*size), size / extent_bytes);
+            assert_eq!(bytes(size - extent_bytes + 1), size / extent_bytes);
+            assert_eq!(bytes(extents * extent_bytes), extents);
+            assert_eq!(vg.size_to_extents(Size::Extents(extents)).unwrap(), extents);
+            assert_eq!(vg.size_to_extents(Size::PercentVg(100)).unwrap(), extents);
+            assert_eq!(
+                vg.size_to_extents(Size::PercentFree(50)).unwrap(),
+                extents / 2
+            );
+            assert!(vg.size_to_extents(Size::PercentVg(101)).is_err());
+        }
+    }
+
+    #[test]
+    fn dm_table_of_thin_lv_needs_active_pool() {
+        let lv = |name: &str, visible: bool, segment: &str| {
+            format!(
+                "{} {{
+id = \"{:0>6}-Yxq7-iBv0-0oTG-0EGm-Tv9N-5CuoSZ\"
+status = [\"READ\", \"WRITE\"{}]
+flags = []
+creation_host = \"host\"
+creation_time = 1600000000
+segment_count = 1
+segment1 {{
+start_extent = 0
+{}
+}}
+}}",
+                name,
+                name,
+                if visible { ", \"VISIBLE\"" } else { "" },
+                segment
+            )
+        };
+        let linear = |start: u64| {
+            format!(
+                "extent_count = 1
+type = \"striped\"
+stripe_count = 1
+stripes = [\"pv0\", {}]",
+                start
+            )
+        };
+        let lvs = [
+            lv("pool_tmeta", false, &linear(0)),
+            lv("pool_tdata", false, &linear(1)),
+            lv(
+                "pool",
+                true,
+                "extent_count = 1
+type = \"thin-pool\"
+metadata = \"pool_tmeta\"
+pool = \"pool_tdata\"
+transaction_id = 1
+chunk_size = 128",
+            ),
+            lv(
+                "thin",
+                true,
+                "extent_count = 4
+type = \"thin\"
+thin_pool = \"pool\"
+transaction_id = 1
+device_id = 1",
+            ),
+        ];
+        let pvh = synthetic_pvh(64 * MIB, Vec::new());
+        let vg = vg_with_pv_and_lvs(&pvh, &lvs.join("\n"));
+
+        // Linear LVs refer only to PVs.
+        let table = vg.lv_get("pool_tdata").unwrap().dm_table(&vg).unwrap();
+        assert_eq!(table[0].3, format!("8:1 {}", 2 * EXTENT_SIZE));
+
+        for name in &["pool", "thin"] {
+            match vg.lv_get(name).unwrap().dm_table(&vg) {
+                Err(Error::Io(e)) => assert!(e.to_string().ends_with("is not active")),
+                other => panic!("table of {}: {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rollback_restores_vg_settings() {
+        let pvh = synthetic_pvh(64 * MIB, Vec::new());
+        let mut vg = vg_with_pv(&pvh);
+        vg.begin_deferred().unwrap();
+
+        vg.add_tag("fast").unwrap();
+        vg.set_metadata_copies(1, vec!["fast".to_string()]).unwrap();
+        vg.historical_lvs = Some(LvmTextMap::new());
+        vg.rollback_deferred();
+
+        assert!(vg.tags.is_empty());
+        assert_eq!(vg.metadata_copies, 0);
+        assert!(vg.metadata_tags.is_empty());
+        assert!(vg.historical_lvs.is_none());
+        assert_eq!(vg.seqno, 1);
+    }
+
+    #[test]
+    fn downgraded_metadata_leaves_out_later_keys() {
+        let lvs = "data {
+id = \"Lc1mpX-7Yxq-iBv0-0oTG-0EGm-Tv9N-5CuoSZ\"
+status = [\"READ\", \"WRITE\", \"VISIBLE\"]
+flags = []
+creation_host = \"host\"
+creation_time = 1600000000
+melvin_snapshot_interval = 3600
+melvin_schedule_hourly = \"4\"
+segment_count = 1
+segment1 {
+start_extent = 0
+extent_count = 1
+type = \"striped\"
+stripe_count = 1
+stripes = [\"pv0\", 0]
+}
+}";
+        let pvh = synthetic_pvh(64 * MIB, Vec::new());
+        let mut vg = vg_with_pv_and_lvs(&pvh, lvs);
+        vg.metadata_tags = vec!["ssd".to_string()];
+        vg.description = "scratch".to_string();
+        vg.modified_time = 1600000000;
+
+        let lv_keys = |map: &LvmTextMap| {
+            let lvs = map.textmap_from_textmap("logical_volumes").unwrap();
+            let lv = lvs.textmap_from_textmap("data").unwrap();
+            lv.keys().filter(|k| k.starts_with("melvin_")).count()
+        };
+        let map = to_textmap(&vg);
+        assert_eq!(
+            map.i64_from_textmap("melvin_version"),
+            Some(migrate::METADATA_VERSION as i64)
+        );
+        assert_eq!(lv_keys(&map), 2);
+
+        vg.write_version = 2;
+        let map = to_textmap(&vg);
+        assert_eq!(map.i64_from_textmap("melvin_version"), Some(2));
+        assert!(map.list_from_textmap("melvin_metadata_tags").is_some());
+        assert!(map.string_from_textmap("melvin_description").is_none());
+        assert!(map.i64_from_textmap("melvin_modified_time").is_none());
+        assert_eq!(lv_keys(&map), 0);
+        let read = VG::from_textmap("vg0", &map).unwrap();
+        assert_eq!(read.metadata_version(), 2);
+
+        vg.write_version = 0;
+        let map = to_textmap(&vg);
+        assert!(!map.keys().any(|k| k.starts_with("melvin_")));
+
+        assert!(vg
+            .downgrade_metadata(migrate::METADATA_VERSION + 1)
+            .is_err());
+    }
+
+    #[test]
+    fn errors_say_what_is_wrong() {
+        let lvs = "data {
+id = \"Lc1mpX-7Yxq-iBv0-0oTG-0EGm-Tv9N-5CuoSZ\"
+status = [\"READ\", \"WRITE\", \"VISIBLE\"]
+flags = []
+creation_host = \"host\"
+creation_time = 1600000000
+segment_count = 1
+segment1 {
+start_extent = 0
+extent_count = 1
+type = \"striped\"
+stripe_count = 1
+stripes = [\"pv0\", 0]
+}
+}";
+        let pvh = synthetic_pvh(64 * MIB, Vec::new());
+        let mut vg = vg_with_pv_and_lvs(&pvh, lvs);
+
+        match vg.lv_thinpool_status("data") {
+            Err(Error::InvalidLvType { vg, lv, expected }) => {
+                assert_eq!(
+                    (vg.as_str(), lv.as_str(), expected),
+                    ("vg0", "data", "a thin pool")
+                )
+            }
+            other => panic!("thin pool status of a linear LV: {:?}", other.map(|_| ())),
+        }
+        match vg.lv_trim("data") {
+            Err(Error::InvalidLvType { expected, .. }) => assert_eq!(expected, "a thin LV"),
+            other => panic!("trim of a linear LV: {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(
+            vg.add_tag("no spaces"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}